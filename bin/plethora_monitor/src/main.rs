@@ -1,6 +1,12 @@
-use axum::{Router, routing::post};
+use axum::{
+    Router,
+    routing::{delete, get, post},
+};
 use dotenvy::dotenv;
-use server::handler::create_monitor;
+use server::handler::{
+    backtest_monitor, clear_abi_cache, create_monitor, evaluate_rule, invalidate_abi,
+    list_cached_abis, rpc_metrics,
+};
 use server::state::AppState;
 use std::env;
 use std::sync::Arc;
@@ -16,6 +22,11 @@ async fn main() -> Result<(), anyhow::Error> {
     // routes
     let app = Router::new()
         .route("/monitors", post(create_monitor))
+        .route("/monitors/backtest", post(backtest_monitor))
+        .route("/rules/evaluate", post(evaluate_rule))
+        .route("/metrics/rpc", get(rpc_metrics))
+        .route("/abis", get(list_cached_abis).delete(clear_abi_cache))
+        .route("/abis/:chain/:address", delete(invalidate_abi))
         .with_state(shared_state);
 
     // start server
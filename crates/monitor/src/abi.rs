@@ -0,0 +1,691 @@
+//! # ABI Sources
+//! This module provides tools for getting hold of the ABIs monitors decode with.
+//!
+//! An ABI is either given in the monitor's config, as the ABI JSON itself, as a human-readable
+//! list of signatures or as the path of a Foundry or Hardhat artifact, or it is looked up by the
+//! contract's address. Lookups go through the monitor's `AbiProvider`s in the configured order
+//! until one of them knows the contract. Besides Etherscan, a provider can be a local directory
+//! of ABIs laid out by chain id and address the way a Sourcify repository is, which covers
+//! unverified and private contracts without a network call or an API key.
+//!
+//! A proxy is looked up by its implementation's address, read from the proxy's
+//! `implementation()` function.
+//!
+//! What Etherscan returns goes into an `AbiCache` shared by every monitor, kept in memory and
+//! as one JSON file per chain and implementation address, so creating monitors on the same
+//! contracts again, or restarting, doesn't ask Etherscan until the cached ABI expires. Local
+//! files are read fresh every time, so edits to them show up right away.
+//!
+//! Artifact paths and ABI directories come from requests, so they are only read from under the
+//! directory the operator sets in `ABI_DIR`, relative to it. Absolute paths, `..` and symlinks
+//! leading out of it are turned down, and without `ABI_DIR` no ABI file is read at all.
+
+use crate::primitives::models::{AbiProvider, AbiSource};
+use alloy::json_abi::JsonAbi;
+use alloy::{
+    network::TransactionBuilder,
+    primitives::{Address, Bytes},
+    providers::{Provider, ProviderBuilder},
+    rpc::types::TransactionRequest,
+};
+use alloy_chains::{Chain, NamedChain};
+use foundry_block_explorers::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where cached ABIs go unless `ABI_CACHE_DIR` says otherwise.
+pub const DEFAULT_ABI_CACHE_DIR: &str = "data/abis";
+
+/// How long a cached ABI is used unless `ABI_CACHE_TTL_SECS` says otherwise.
+pub const DEFAULT_ABI_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// ABIs fetched from Etherscan, by chain id and implementation address.
+pub struct AbiCache {
+    dir: PathBuf,
+    ttl: Duration,
+    entries: Mutex<HashMap<(u64, Address), CachedAbi>>, // what was read from or written to disk
+    fetching: Mutex<FetchLocks>, // held while asking Etherscan, see `AbiFetcher::ask`
+}
+
+// One lock per chain id and implementation address being looked up.
+type FetchLocks = HashMap<(u64, Address), Arc<tokio::sync::Mutex<()>>>;
+
+/// A cached ABI as it is stored on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedAbi {
+    fetched_at: u64, // unix seconds
+    abi: JsonAbi,
+}
+
+/// What the cache holds for one contract.
+#[derive(Debug, Serialize)]
+pub struct CachedAbiEntry {
+    pub chain_id: u64,
+    pub address: Address,
+    pub fetched_at: u64,
+    pub expires_at: u64,
+}
+
+impl Default for AbiCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AbiCache {
+    /// A cache under `ABI_CACHE_DIR`, picking up what earlier runs saved there.
+    pub fn new() -> Self {
+        let dir = env::var("ABI_CACHE_DIR").unwrap_or(DEFAULT_ABI_CACHE_DIR.to_string());
+        let ttl = env::var("ABI_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_ABI_CACHE_TTL);
+
+        Self::in_dir(PathBuf::from(dir), ttl)
+    }
+
+    fn in_dir(dir: PathBuf, ttl: Duration) -> Self {
+        Self {
+            dir,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            fetching: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The cached ABI of `address` on `chain_id`, `None` if there is none or it expired.
+    pub fn get(&self, chain_id: u64, address: Address) -> Option<JsonAbi> {
+        let key = (chain_id, address);
+        let cached = self.entries.lock().unwrap().get(&key).cloned();
+        let cached = match cached {
+            Some(cached) => cached,
+            None => {
+                let cached = self.load(key)?;
+                self.entries.lock().unwrap().insert(key, cached.clone());
+                cached
+            }
+        };
+
+        if cached.fetched_at + self.ttl.as_secs() <= now() {
+            self.invalidate(chain_id, address);
+            return None;
+        }
+        Some(cached.abi)
+    }
+
+    /// Caches `abi` as the ABI of `address` on `chain_id`, from now on.
+    pub fn insert(&self, chain_id: u64, address: Address, abi: JsonAbi) {
+        let key = (chain_id, address);
+        let cached = CachedAbi {
+            fetched_at: now(),
+            abi,
+        };
+        if let Err(e) = self.save(key, &cached) {
+            eprintln!("Error saving the ABI of {:?} to the cache: {}", address, e);
+        }
+        self.entries.lock().unwrap().insert(key, cached);
+    }
+
+    /// Drops the cached ABI of `address` on `chain_id`, `false` if there was none.
+    pub fn invalidate(&self, chain_id: u64, address: Address) -> bool {
+        let key = (chain_id, address);
+        let in_memory = self.entries.lock().unwrap().remove(&key).is_some();
+        let on_disk = fs::remove_file(self.path(key)).is_ok();
+        in_memory || on_disk
+    }
+
+    /// Drops every cached ABI and tells how many there were.
+    pub fn clear(&self) -> usize {
+        let count = self.entries().len();
+        self.entries.lock().unwrap().clear();
+
+        // only the files we wrote, the directory may be shared with other things
+        for (chain_dir, files) in self.files() {
+            for path in files {
+                if let Err(e) = fs::remove_file(&path)
+                    && e.kind() != std::io::ErrorKind::NotFound
+                {
+                    eprintln!("Error removing cached ABI {:?}: {}", path, e);
+                }
+            }
+            // left alone unless it's empty now
+            let _ = fs::remove_dir(chain_dir);
+        }
+        count
+    }
+
+    /// Everything cached, expired ABIs included until they are next asked for.
+    pub fn entries(&self) -> Vec<CachedAbiEntry> {
+        // the files earlier runs left behind aren't in memory until they are asked for
+        let mut keys: Vec<(u64, Address)> = self.entries.lock().unwrap().keys().copied().collect();
+        for key in self
+            .files()
+            .into_iter()
+            .flat_map(|(_, files)| files)
+            .filter_map(|path| cache_key(&path))
+        {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+
+        let mut entries: Vec<CachedAbiEntry> = keys
+            .into_iter()
+            .filter_map(|key| {
+                let cached = self.entries.lock().unwrap().get(&key).cloned();
+                let cached = cached.or_else(|| self.load(key))?;
+                Some(CachedAbiEntry {
+                    chain_id: key.0,
+                    address: key.1,
+                    fetched_at: cached.fetched_at,
+                    expires_at: cached.fetched_at + self.ttl.as_secs(),
+                })
+            })
+            .collect();
+        entries.sort_by_key(|entry| (entry.chain_id, entry.address));
+        entries
+    }
+
+    // The `{chain_id}/{address}.json` files under the cache's directory, by chain directory.
+    fn files(&self) -> Vec<(PathBuf, Vec<PathBuf>)> {
+        fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|chain_dir| {
+                chain_dir
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|name| name.parse::<u64>().is_ok())
+            })
+            .map(|chain_dir| {
+                let files = fs::read_dir(chain_dir.path())
+                    .into_iter()
+                    .flatten()
+                    .flatten()
+                    .map(|file| file.path())
+                    .filter(|path| cache_key(path).is_some())
+                    .collect();
+                (chain_dir.path(), files)
+            })
+            .collect()
+    }
+
+    // The lock a lookup of `key` holds, one per contract so lookups of others go ahead.
+    fn fetch_lock(&self, key: (u64, Address)) -> FetchLock<'_> {
+        let lock = self
+            .fetching
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_default()
+            .clone();
+        FetchLock {
+            cache: self,
+            key,
+            lock,
+        }
+    }
+
+    fn path(&self, (chain_id, address): (u64, Address)) -> PathBuf {
+        self.dir
+            .join(chain_id.to_string())
+            .join(format!("{:?}.json", address))
+    }
+
+    fn load(&self, key: (u64, Address)) -> Option<CachedAbi> {
+        let bytes = fs::read(self.path(key)).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+
+    fn save(&self, key: (u64, Address), cached: &CachedAbi) -> Result<(), anyhow::Error> {
+        let path = self.path(key);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // write next to the file and swap it in, so a crash can't leave half a file behind
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(cached)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+// A lookup's hold on its contract's fetch lock. The last lookup to let go of it removes it,
+// so the locks don't pile up for every contract ever looked up.
+struct FetchLock<'a> {
+    cache: &'a AbiCache,
+    key: (u64, Address),
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Drop for FetchLock<'_> {
+    fn drop(&mut self) {
+        let mut fetching = self.cache.fetching.lock().unwrap();
+        // one reference in the map and ours, nobody else is waiting on it
+        if Arc::strong_count(&self.lock) == 2 {
+            fetching.remove(&self.key);
+        }
+    }
+}
+
+/// Gets the ABIs of one chain's contracts, from the config or from the providers.
+pub struct AbiFetcher {
+    chain: String,
+    rpc_url: String,
+    providers: Vec<AbiProvider>,
+    cache: Option<Arc<AbiCache>>,
+}
+
+impl AbiFetcher {
+    /// A fetcher that only asks Etherscan.
+    pub fn new(chain: &str, rpc_url: &str) -> Self {
+        Self {
+            chain: chain.to_string(),
+            rpc_url: rpc_url.to_string(),
+            providers: vec![AbiProvider::Etherscan],
+            cache: None,
+        }
+    }
+
+    /// Keeps what Etherscan returns in `cache`, and looks there first.
+    pub fn with_cache(mut self, cache: Arc<AbiCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Asks `providers` instead, in their order.
+    pub fn with_providers(mut self, providers: Vec<AbiProvider>) -> Self {
+        self.providers = providers;
+        self
+    }
+
+    /// The ABI `source` gives, or else the one the providers have for `address`.
+    pub async fn resolve(
+        &self,
+        source: Option<&AbiSource>,
+        address: Option<Address>,
+    ) -> Result<JsonAbi, anyhow::Error> {
+        match (source, address) {
+            (Some(source), _) => load_abi(source),
+            (None, Some(address)) => self.fetch(address).await,
+            (None, None) => Err(anyhow::anyhow!(
+                "Neither an ABI nor an address to fetch it for"
+            )),
+        }
+    }
+
+    /// Looks the contract at `address` up with each provider in turn, by its implementation's
+    /// address when it is a proxy.
+    pub async fn fetch(&self, address: Address) -> Result<JsonAbi, anyhow::Error> {
+        let implementation = self.implementation(address).await?;
+
+        let mut failures = Vec::new();
+        for provider in &self.providers {
+            match self.ask(provider, address, implementation).await {
+                Ok(Some(abi)) => return Ok(abi),
+                Ok(None) => failures.push(format!("{}: not found", provider_name(provider))),
+                Err(e) => failures.push(format!("{}: {}", provider_name(provider), e)),
+            }
+        }
+
+        Err(anyhow::anyhow!(
+            "No ABI for {:?} ({})",
+            implementation,
+            failures.join("; ")
+        ))
+    }
+
+    /// The implementation behind `address` if it is a proxy, `address` itself otherwise.
+    pub async fn implementation(&self, address: Address) -> Result<Address, anyhow::Error> {
+        let provider = ProviderBuilder::new().connect_http(self.rpc_url.parse()?);
+        let tx = TransactionRequest::default()
+            .with_to(address)
+            .with_input("0x5c60da1b".parse::<Bytes>()?); // implementation function selector
+
+        Ok(match provider.call(tx).await {
+            Ok(bytes) if bytes.len() >= 32 && bytes[12..32].iter().any(|b| *b != 0) => {
+                Address::from_slice(&bytes[12..32])
+            }
+            _ => address,
+        })
+    }
+
+    async fn ask(
+        &self,
+        provider: &AbiProvider,
+        address: Address,
+        implementation: Address,
+    ) -> Result<Option<JsonAbi>, anyhow::Error> {
+        match provider {
+            AbiProvider::Etherscan => {
+                let chain = Chain::from(NamedChain::from_str(&self.chain)?);
+                // what is cached doesn't need an API key
+                if let Some(abi) = self
+                    .cache
+                    .as_ref()
+                    .and_then(|cache| cache.get(chain.id(), implementation))
+                {
+                    return Ok(Some(abi));
+                }
+
+                let key = env::var("ETHERSCAN_API_KEY")
+                    .map_err(|_| anyhow::anyhow!("ETHERSCAN_API_KEY is not set"))?;
+                let Some(cache) = &self.cache else {
+                    let client = Client::new(chain, &key)?;
+                    return Ok(Some(client.contract_abi(implementation).await?));
+                };
+
+                // one lookup per contract at a time, so monitors created together on the same
+                // contract wait for the first one's ABI instead of each asking Etherscan for it
+                let fetch = cache.fetch_lock((chain.id(), implementation));
+                let _fetching = fetch.lock.lock().await;
+                if let Some(abi) = cache.get(chain.id(), implementation) {
+                    return Ok(Some(abi));
+                }
+                let client = Client::new(chain, &key)?;
+                let abi = client.contract_abi(implementation).await?;
+                cache.insert(chain.id(), implementation, abi.clone());
+                Ok(Some(abi))
+            }
+            AbiProvider::Directory(dir) => {
+                let base = abi_dir()?;
+                let dir = confine_path(&base, dir)?;
+
+                // a proxy's ABI may just as well have been filed under the proxy's address
+                let chain_id = Chain::from_str(&self.chain)?.id();
+                let mut candidates = vec![implementation];
+                if address != implementation {
+                    candidates.push(address);
+                }
+                for candidate in candidates {
+                    if let Some(path) = find_abi_file(&dir, chain_id, candidate)
+                        .and_then(|path| inside(&base, &path))
+                    {
+                        return read_abi_file(&path).map(Some);
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Reads an ABI given in the config.
+pub fn load_abi(source: &AbiSource) -> Result<JsonAbi, anyhow::Error> {
+    match source {
+        AbiSource::Json(abi) => Ok(abi.clone()),
+        AbiSource::HumanReadable(items) => JsonAbi::parse(items.iter().map(|item| item.as_str()))
+            .map_err(|e| anyhow::anyhow!("Invalid human-readable ABI: {}", e)),
+        AbiSource::Artifact(path) => read_abi_file(&confine_path(&abi_dir()?, path)?),
+    }
+}
+
+/// Resolves a path from a monitor's config inside `base`, which has to be canonical already.
+/// Only relative paths that stay inside `base` are accepted, and a path that doesn't exist is
+/// reported the same way as one outside, so callers can't probe the file system.
+pub fn confine_path(base: &Path, path: &Path) -> Result<PathBuf, anyhow::Error> {
+    if path
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(anyhow::anyhow!(
+            "{} has to be a relative path inside ABI_DIR",
+            path.display()
+        ));
+    }
+    inside(base, &base.join(path))
+        .ok_or_else(|| anyhow::anyhow!("Nothing at {} in ABI_DIR", path.display()))
+}
+
+// Helper functions
+
+// The chain id and address a `{chain_id}/{address}.json` cache file is for.
+fn cache_key(path: &Path) -> Option<(u64, Address)> {
+    if path.extension().is_none_or(|ext| ext != "json") {
+        return None;
+    }
+    let address = Address::from_str(path.file_stem()?.to_str()?).ok()?;
+    let chain_id = path.parent()?.file_name()?.to_str()?.parse().ok()?;
+    Some((chain_id, address))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+// The operator's `ABI_DIR`, canonical, the only place ABI files are read from.
+fn abi_dir() -> Result<PathBuf, anyhow::Error> {
+    let dir = env::var("ABI_DIR")
+        .map_err(|_| anyhow::anyhow!("ABI files can't be read, ABI_DIR is not set"))?;
+    fs::canonicalize(&dir).map_err(|e| anyhow::anyhow!("ABI_DIR {} is unusable: {}", dir, e))
+}
+
+// `path` with every symlink resolved, if it exists and that is still under `base`.
+fn inside(base: &Path, path: &Path) -> Option<PathBuf> {
+    fs::canonicalize(path)
+        .ok()
+        .filter(|resolved| resolved.starts_with(base))
+}
+
+fn provider_name(provider: &AbiProvider) -> String {
+    match provider {
+        AbiProvider::Etherscan => "Etherscan".to_string(),
+        AbiProvider::Directory(dir) => format!("directory {}", dir.display()),
+    }
+}
+
+// `{dir}/{chain_id}/{address}/metadata.json` as Sourcify keeps them, or a single
+// `{dir}/{chain_id}/{address}.json`, the address checksummed or in lowercase.
+fn find_abi_file(dir: &Path, chain_id: u64, address: Address) -> Option<PathBuf> {
+    let chain_dir = dir.join(chain_id.to_string());
+    let checksummed = address.to_checksum(None);
+    let lowercase = format!("{:?}", address);
+
+    [
+        chain_dir.join(&checksummed).join("metadata.json"),
+        chain_dir.join(&lowercase).join("metadata.json"),
+        chain_dir.join(format!("{}.json", checksummed)),
+        chain_dir.join(format!("{}.json", lowercase)),
+    ]
+    .into_iter()
+    .find(|path| path.is_file())
+}
+
+// A plain ABI, a Foundry or Hardhat artifact (`abi`) or a Sourcify `metadata.json`
+// (`output.abi`).
+fn read_abi_file(path: &Path) -> Result<JsonAbi, anyhow::Error> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Could not read {}: {}", path.display(), e))?;
+    let json: Value = serde_json::from_str(&contents)
+        .map_err(|e| anyhow::anyhow!("{} is not JSON: {}", path.display(), e))?;
+
+    let abi = match json {
+        Value::Array(_) => json,
+        _ => json
+            .get("abi")
+            .or_else(|| json.pointer("/output/abi"))
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("{} has no ABI in it", path.display()))?,
+    };
+
+    serde_json::from_value(abi)
+        .map_err(|e| anyhow::anyhow!("Invalid ABI in {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an empty directory of the test's own under the system's temp dir, canonical
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("abi-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::canonicalize(dir).unwrap()
+    }
+
+    #[test]
+    fn reads_paths_inside_the_abi_dir() {
+        let base = scratch_dir("inside");
+        fs::create_dir_all(base.join("out")).unwrap();
+        fs::write(base.join("out/Token.json"), r#"{"abi": []}"#).unwrap();
+
+        let path = confine_path(&base, Path::new("out/Token.json")).unwrap();
+        assert_eq!(path, base.join("out/Token.json"));
+        assert!(read_abi_file(&path).is_ok());
+        assert!(confine_path(&base, Path::new("./out")).is_ok());
+    }
+
+    #[test]
+    fn rejects_paths_leaving_the_abi_dir() {
+        let base = scratch_dir("outside");
+        fs::create_dir_all(base.join("out")).unwrap();
+
+        for path in ["../outside.json", "out/../../outside.json", "/etc/passwd"] {
+            assert!(confine_path(&base, Path::new(path)).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn missing_and_outside_paths_look_the_same() {
+        let base = scratch_dir("probe");
+        let outside = scratch_dir("probe-target");
+        fs::write(outside.join("secret.json"), "[]").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&outside, base.join("link")).unwrap();
+
+        let missing = confine_path(&base, Path::new("missing.json")).unwrap_err();
+        let escaped = confine_path(&base, Path::new("link/secret.json")).unwrap_err();
+        assert_eq!(
+            missing.to_string().replace("missing.json", ""),
+            escaped.to_string().replace("link/secret.json", "")
+        );
+    }
+
+    fn abi() -> JsonAbi {
+        JsonAbi::parse(["function balanceOf(address) view returns (uint256)"]).unwrap()
+    }
+
+    const TOKEN: Address = Address::repeat_byte(0x11);
+    const OTHER: Address = Address::repeat_byte(0x22);
+
+    #[test]
+    fn reads_cache_keys_out_of_file_names() {
+        let checksummed = format!("data/abis/1/{}.json", TOKEN.to_checksum(None));
+        assert_eq!(cache_key(Path::new(&checksummed)), Some((1, TOKEN)));
+        let lowercase = format!("data/abis/137/{:?}.json", TOKEN);
+        assert_eq!(cache_key(Path::new(&lowercase)), Some((137, TOKEN)));
+
+        for path in [
+            format!("data/abis/1/{:?}.json.tmp", TOKEN),
+            format!("data/abis/1/{:?}.txt", TOKEN),
+            format!("data/abis/mainnet/{:?}.json", TOKEN),
+            "data/abis/1/token.json".to_string(),
+        ] {
+            assert_eq!(cache_key(Path::new(&path)), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn keeps_abis_between_runs_until_they_expire() {
+        let dir = scratch_dir("cache-ttl");
+        let cache = AbiCache::in_dir(dir.clone(), Duration::from_secs(3600));
+        cache.insert(1, TOKEN, abi());
+        assert_eq!(cache.get(1, TOKEN), Some(abi()));
+        assert_eq!(cache.get(10, TOKEN), None);
+
+        let restarted = AbiCache::in_dir(dir.clone(), Duration::from_secs(3600));
+        assert_eq!(restarted.get(1, TOKEN), Some(abi()));
+
+        // fetched two hours ago
+        let stale = CachedAbi {
+            fetched_at: now() - 7200,
+            abi: abi(),
+        };
+        restarted.save((1, OTHER), &stale).unwrap();
+        assert_eq!(restarted.entries().len(), 2);
+        assert_eq!(restarted.get(1, OTHER), None);
+        assert!(!restarted.path((1, OTHER)).exists());
+        assert_eq!(restarted.entries().len(), 1);
+    }
+
+    #[test]
+    fn invalidates_one_contract() {
+        let cache = AbiCache::in_dir(scratch_dir("cache-invalidate"), DEFAULT_ABI_CACHE_TTL);
+        cache.insert(1, TOKEN, abi());
+        cache.insert(1, OTHER, abi());
+
+        assert!(cache.invalidate(1, TOKEN));
+        assert!(!cache.invalidate(1, TOKEN));
+        assert_eq!(cache.get(1, TOKEN), None);
+        assert!(!cache.path((1, TOKEN)).exists());
+        assert_eq!(cache.get(1, OTHER), Some(abi()));
+    }
+
+    #[test]
+    fn clears_only_the_files_it_wrote() {
+        let dir = scratch_dir("cache-clear");
+        let cache = AbiCache::in_dir(dir.clone(), DEFAULT_ABI_CACHE_TTL);
+        cache.insert(1, TOKEN, abi());
+        cache.insert(10, TOKEN, abi());
+
+        // someone else's files in the same directory
+        fs::write(dir.join("1/notes.json"), "{}").unwrap();
+        fs::write(dir.join("1/README"), "").unwrap();
+        fs::create_dir_all(dir.join("other")).unwrap();
+        fs::write(dir.join(format!("other/{:?}.json", TOKEN)), "{}").unwrap();
+
+        assert_eq!(cache.clear(), 2);
+        assert!(cache.entries().is_empty());
+        assert!(!cache.path((1, TOKEN)).exists());
+        assert!(!dir.join("10").exists());
+        assert!(dir.join("1/notes.json").exists());
+        assert!(dir.join("1/README").exists());
+        assert!(dir.join(format!("other/{:?}.json", TOKEN)).exists());
+    }
+
+    #[tokio::test]
+    async fn answers_from_the_cache_without_an_api_key() {
+        let cache = Arc::new(AbiCache::in_dir(
+            scratch_dir("cache-no-key"),
+            DEFAULT_ABI_CACHE_TTL,
+        ));
+        cache.insert(1, TOKEN, abi());
+        let fetcher = AbiFetcher::new("mainnet", "http://127.0.0.1:1").with_cache(cache);
+
+        let found = fetcher
+            .ask(&AbiProvider::Etherscan, TOKEN, TOKEN)
+            .await
+            .unwrap();
+        assert_eq!(found, Some(abi()));
+    }
+
+    #[tokio::test]
+    async fn drops_fetch_locks_nobody_holds() {
+        let cache = AbiCache::in_dir(scratch_dir("cache-locks"), DEFAULT_ABI_CACHE_TTL);
+
+        let first = cache.fetch_lock((1, TOKEN));
+        let guard = first.lock.lock().await;
+        let second = cache.fetch_lock((1, TOKEN));
+        assert!(Arc::ptr_eq(&first.lock, &second.lock));
+
+        drop(guard);
+        drop(first);
+        assert_eq!(cache.fetching.lock().unwrap().len(), 1);
+        drop(second);
+        assert!(cache.fetching.lock().unwrap().is_empty());
+    }
+}
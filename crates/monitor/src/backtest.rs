@@ -0,0 +1,469 @@
+//! # Backtesting
+//! This module provides tools for running a monitor's matchers over past blocks.
+//!
+//! A backtest feeds a range of historical blocks through the same `TxScanner` and
+//! `EventScanner` a running monitor uses, but collects the matches instead of sending
+//! alerts. The report counts the matches per rule and per event, which tells how noisy a
+//! monitor would have been, and lists every match with the details its alert would carry.
+//!
+//! The range is history, so rules don't wait for confirmations, and contracts a factory
+//! creates during the range aren't followed, only the children it was already known to have.
+//!
+//! Both matchers go through the range side by side, `MAX_LOG_RANGE` blocks at a time. A
+//! backtest that runs out of time returns what it found up to the last block both got through.
+
+use crate::PollingMonitor;
+use crate::events::{EventScanner, MAX_LOG_RANGE, get_event_details};
+use crate::primitives::models::MonitorRule;
+use crate::primitives::utils::select_events;
+use crate::tx::{AlertStatus, TxMatch, TxScanner};
+use alloy::consensus::Transaction;
+use alloy::network::TransactionResponse;
+use alloy::primitives::{Address, B256};
+use alloy::rpc::types::Log;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::{Instant, timeout_at};
+
+/// The most blocks a single backtest may cover.
+pub const MAX_BACKTEST_BLOCKS: u64 = 10_000;
+
+/// How long a backtest may take before it stops and reports what it found so far.
+pub const BACKTEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+// log queries in a row that may fail before the backtest gives up
+const MAX_LOG_FAILURES: u32 = 3;
+
+#[derive(Debug, Serialize)]
+pub struct BacktestReport {
+    pub from_block: u64,
+    pub to_block: u64,
+
+    // the last block the backtest got through, short of `to_block` when it ran out of time,
+    // `None` when it didn't get through a single one
+    pub scanned_to: Option<u64>,
+    pub timed_out: bool,
+
+    // every rule and event of the monitor, the ones that never matched with 0
+    pub rule_counts: BTreeMap<String, usize>,
+    pub event_counts: BTreeMap<String, usize>,
+
+    pub transactions: Vec<BacktestTx>,
+    pub logs: Vec<BacktestLog>,
+}
+
+/// A transaction a rule matched.
+#[derive(Debug, Serialize)]
+pub struct BacktestTx {
+    pub rule: String,
+    pub block_number: u64,
+    pub hash: B256,
+    pub from: Address,
+    pub to: Option<Address>,
+    pub details: String, // what the alert would have said about it
+}
+
+/// A log one of the monitored events matched.
+#[derive(Debug, Serialize)]
+pub struct BacktestLog {
+    pub event: String,
+    pub block_number: Option<u64>,
+    pub tx_hash: Option<B256>,
+    pub address: Address,
+    pub details: String,
+}
+
+/// Runs the monitor's rules and events over blocks `from_block` to `to_block`, both included.
+pub async fn backtest(
+    monitor: &PollingMonitor,
+    rules: Vec<MonitorRule>,
+    event_names: &[String],
+    from_block: u64,
+    to_block: u64,
+) -> Result<BacktestReport, anyhow::Error> {
+    backtest_within(
+        monitor,
+        rules,
+        event_names,
+        (from_block, to_block),
+        BACKTEST_TIMEOUT,
+    )
+    .await
+}
+
+// Helper functions
+
+async fn backtest_within(
+    monitor: &PollingMonitor,
+    mut rules: Vec<MonitorRule>,
+    event_names: &[String],
+    (from_block, to_block): (u64, u64),
+    time_limit: Duration,
+) -> Result<BacktestReport, anyhow::Error> {
+    if from_block > to_block {
+        return Err(anyhow::anyhow!(
+            "from_block {} is after to_block {}",
+            from_block,
+            to_block
+        ));
+    }
+    if to_block - from_block + 1 > MAX_BACKTEST_BLOCKS {
+        return Err(anyhow::anyhow!(
+            "A backtest covers at most {} blocks",
+            MAX_BACKTEST_BLOCKS
+        ));
+    }
+
+    // the scanners keep retrying blocks that aren't there yet
+    let head = monitor.processing_head().await?;
+    if to_block > head {
+        return Err(anyhow::anyhow!(
+            "to_block {} is past the chain head {}",
+            to_block,
+            head
+        ));
+    }
+
+    let mut report = BacktestReport {
+        from_block,
+        to_block,
+        scanned_to: None,
+        timed_out: false,
+        rule_counts: rules.iter().map(|rule| (rule.name.clone(), 0)).collect(),
+        event_counts: event_names.iter().map(|name| (name.clone(), 0)).collect(),
+        transactions: Vec::new(),
+        logs: Vec::new(),
+    };
+
+    let deadline = Instant::now() + time_limit;
+
+    // the blocks are long confirmed, nothing is held back
+    for rule in &mut rules {
+        rule.confirmations = None;
+    }
+    let start = from_block.saturating_sub(1);
+    let scans_txs = !rules.is_empty() || monitor.transfers.is_some();
+    let mut tx_scanner = scans_txs.then(|| TxScanner::new(monitor, rules, start));
+    let refs: Vec<&str> = event_names.iter().map(|s| s.as_str()).collect();
+    let mut log_scanner =
+        (!event_names.is_empty()).then(|| EventScanner::new(monitor, &refs, start));
+
+    let mut matches: Vec<TxMatch> = Vec::new();
+    let mut logs: Vec<Log> = Vec::new();
+    let mut scanned = start; // both scanners got through this block
+    while scanned < to_block {
+        let chunk_end = (scanned + MAX_LOG_RANGE).min(to_block);
+        let chunk = async {
+            if let Some(scanner) = tx_scanner.as_mut() {
+                scan_transactions(monitor, scanner, chunk_end, &mut matches).await;
+            }
+            if let Some(scanner) = log_scanner.as_mut() {
+                scan_logs(monitor, scanner, chunk_end, &mut logs).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+
+        match timeout_at(deadline, chunk).await {
+            Ok(result) => result?,
+            Err(_) => {
+                eprintln!(
+                    "Backtest took longer than {:?}, stopping after block {}",
+                    time_limit, scanned
+                );
+                report.timed_out = true;
+                break;
+            }
+        }
+        scanned = chunk_end;
+    }
+
+    // the scanners were cut off wherever they were in the chunk, only what both got
+    // through counts
+    if report.timed_out {
+        scanned = tx_scanner
+            .iter()
+            .map(|scanner| scanner.current_block())
+            .chain(log_scanner.iter().map(|scanner| scanner.current_block()))
+            .min()
+            .unwrap_or(scanned);
+        matches.retain(|m| m.block_number <= scanned);
+        logs.retain(|log| log.block_number.is_none_or(|number| number <= scanned));
+    }
+    report.scanned_to = Some(scanned).filter(|scanned| *scanned >= from_block);
+
+    report.transactions = describe_matches(monitor, matches);
+    report.logs = describe_logs(monitor, event_names, logs);
+    for tx in &report.transactions {
+        *report.rule_counts.entry(tx.rule.clone()).or_default() += 1;
+    }
+    for log in &report.logs {
+        *report.event_counts.entry(log.event.clone()).or_default() += 1;
+    }
+
+    Ok(report)
+}
+
+// Collects the matches up to `to_block`, a match a reorg retracted is dropped again.
+async fn scan_transactions(
+    monitor: &PollingMonitor,
+    scanner: &mut TxScanner,
+    to_block: u64,
+    matches: &mut Vec<TxMatch>,
+) {
+    scanner
+        .scan_to(monitor, to_block, &mut |m: TxMatch| {
+            if m.status == AlertStatus::Retracted {
+                matches.retain(|kept| {
+                    kept.tx.tx_hash() != m.tx.tx_hash() || kept.block_number != m.block_number
+                });
+            } else {
+                matches.push(m);
+            }
+        })
+        .await;
+}
+
+// Collects the logs up to `to_block`, a log a reorg removed is dropped again.
+async fn scan_logs(
+    monitor: &PollingMonitor,
+    scanner: &mut EventScanner,
+    to_block: u64,
+    logs: &mut Vec<Log>,
+) -> Result<(), anyhow::Error> {
+    let mut failures = 0;
+
+    while scanner.current_block() < to_block {
+        let before = scanner.current_block();
+        let chunk_end = (before + MAX_LOG_RANGE).min(to_block);
+        scanner
+            .scan_to(monitor, chunk_end, &mut |log: Log| {
+                if log.removed {
+                    logs.retain(|kept| {
+                        kept.transaction_hash != log.transaction_hash
+                            || kept.log_index != log.log_index
+                    });
+                } else {
+                    logs.push(log);
+                }
+            })
+            .await;
+
+        // the scanner leaves the range as it was when a query failed
+        if scanner.current_block() == before {
+            failures += 1;
+            if failures >= MAX_LOG_FAILURES {
+                return Err(anyhow::anyhow!(
+                    "Could not fetch logs after block {}",
+                    before
+                ));
+            }
+        } else {
+            failures = 0;
+        }
+    }
+
+    Ok(())
+}
+
+fn describe_matches(monitor: &PollingMonitor, matches: Vec<TxMatch>) -> Vec<BacktestTx> {
+    matches
+        .into_iter()
+        .map(|m| BacktestTx {
+            details: monitor.describe_tx_match(&m),
+            rule: m.rule,
+            block_number: m.block_number,
+            hash: m.tx.tx_hash(),
+            from: m.tx.from(),
+            to: m.tx.to(),
+        })
+        .collect()
+}
+
+fn describe_logs(
+    monitor: &PollingMonitor,
+    event_names: &[String],
+    logs: Vec<Log>,
+) -> Vec<BacktestLog> {
+    logs.into_iter()
+        .map(|log| {
+            let abi = monitor.abi_for(Some(log.address()));
+            // the configured name the log matched, as the counts are keyed by it
+            let event = log
+                .topic0()
+                .and_then(|topic| {
+                    event_names.iter().find(|name| {
+                        select_events(&abi, name)
+                            .iter()
+                            .any(|event| event.selector() == *topic)
+                    })
+                })
+                .cloned()
+                .unwrap_or_else(|| "Unknown".to_string());
+
+            BacktestLog {
+                event,
+                block_number: log.block_number,
+                tx_hash: log.transaction_hash,
+                address: log.address(),
+                details: get_event_details(&log, &abi),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MonitoredContract;
+    use crate::pool::ProviderPool;
+    use crate::testing::{Reply, RpcStub, block_json, transaction};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    const HEAD: u64 = 20;
+    const TOKEN: Address = Address::repeat_byte(0x02);
+
+    fn block_hash(number: u64) -> B256 {
+        B256::with_last_byte(number as u8)
+    }
+
+    fn tx_hash(number: u64) -> B256 {
+        B256::left_padding_from(&[0xee, number as u8])
+    }
+
+    // a chain with one transaction to the token per block, blocks from `slow_from` on take
+    // longer than any backtest here may
+    async fn node(slow_from: u64) -> RpcStub {
+        RpcStub::start(move |method, params| match method {
+            "eth_blockNumber" => Reply::Result(json!(format!("{:#x}", HEAD))),
+            "eth_getBlockByNumber" => {
+                let number = params[0].as_str().unwrap().trim_start_matches("0x");
+                let number = u64::from_str_radix(number, 16).unwrap();
+                let mut block = block_json(number, block_hash(number));
+                block["parentHash"] = json!(block_hash(number.saturating_sub(1)));
+                block["transactions"] = json!([transaction(json!({
+                    "hash": tx_hash(number),
+                    "blockHash": block_hash(number),
+                    "blockNumber": format!("{:#x}", number),
+                    "to": TOKEN,
+                }))]);
+
+                let reply = Reply::Result(block);
+                if number >= slow_from {
+                    Reply::After(Duration::from_secs(5), Box::new(reply))
+                } else {
+                    reply
+                }
+            }
+            _ => Reply::Error(-32601, "Method not found"),
+        })
+        .await
+    }
+
+    fn monitor(stub: &RpcStub) -> PollingMonitor {
+        let pool = ProviderPool::new("mainnet", std::slice::from_ref(&stub.url), None).unwrap();
+        PollingMonitor::new(
+            Arc::new(pool),
+            vec![MonitoredContract::new(TOKEN, Arc::default())],
+        )
+    }
+
+    fn to_token() -> Vec<MonitorRule> {
+        vec![MonitorRule {
+            name: "to token".to_string(),
+            conditions: serde_json::from_value(json!([{ "To": TOKEN }])).unwrap(),
+            confirmations: Some(5),
+            spawn: None,
+            abi_functions: Vec::new(),
+        }]
+    }
+
+    fn matched_blocks(report: &BacktestReport) -> Vec<u64> {
+        report
+            .transactions
+            .iter()
+            .map(|tx| tx.block_number)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reports_every_match_in_the_range() {
+        let stub = node(u64::MAX).await;
+        let report = backtest(&monitor(&stub), to_token(), &[], 3, 7)
+            .await
+            .unwrap();
+
+        assert_eq!((report.scanned_to, report.timed_out), (Some(7), false));
+        // confirmations don't hold anything back
+        assert_eq!(matched_blocks(&report), vec![3, 4, 5, 6, 7]);
+        assert_eq!(report.transactions[0].hash, tx_hash(3));
+        assert_eq!(report.rule_counts["to token"], 5);
+    }
+
+    #[tokio::test]
+    async fn returns_what_it_found_when_it_runs_out_of_time() {
+        let stub = node(4).await;
+        let report = backtest_within(
+            &monitor(&stub),
+            to_token(),
+            &[],
+            (1, 10),
+            Duration::from_millis(500),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!((report.scanned_to, report.timed_out), (Some(3), true));
+        assert_eq!(matched_blocks(&report), vec![1, 2, 3]);
+        assert_eq!(report.rule_counts["to token"], 3);
+    }
+
+    #[tokio::test]
+    async fn says_so_when_it_ran_out_of_time_before_the_first_block() {
+        let stub = node(0).await;
+        let report = backtest_within(
+            &monitor(&stub),
+            to_token(),
+            &[],
+            (1, 10),
+            Duration::from_millis(200),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!((report.scanned_to, report.timed_out), (None, true));
+        assert!(report.transactions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_range_it_cannot_cover() {
+        let stub = node(u64::MAX).await;
+        let monitor = monitor(&stub);
+        let error = |from_block, to_block| {
+            let monitor = &monitor;
+            async move {
+                backtest(monitor, to_token(), &[], from_block, to_block)
+                    .await
+                    .unwrap_err()
+                    .to_string()
+            }
+        };
+
+        assert_eq!(error(5, 4).await, "from_block 5 is after to_block 4");
+        assert_eq!(
+            error(1, MAX_BACKTEST_BLOCKS + 1).await,
+            "A backtest covers at most 10000 blocks"
+        );
+        // neither of those needed the node
+        assert_eq!(stub.requests(), 0);
+
+        assert_eq!(
+            error(HEAD - 1, HEAD + 1).await,
+            "to_block 21 is past the chain head 20"
+        );
+        // the whole range up to the head is fine
+        let report = backtest(&monitor, Vec::new(), &[], HEAD - 1, HEAD).await;
+        assert_eq!(report.unwrap().scanned_to, Some(HEAD));
+    }
+}
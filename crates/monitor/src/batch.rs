@@ -0,0 +1,544 @@
+//! # Batched Calls
+//! This module provides tools for unwrapping calls that arrive bundled in another call.
+//!
+//! Plenty of interactions reach a contract wrapped in a batching call: a contract's own
+//! `multicall(bytes[])`, Multicall3's `aggregate` family, a Gnosis Safe's `execTransaction`,
+//! the Safe `multiSend` library, an ERC-4337 EntryPoint's `handleOps` or a smart account's
+//! `execute`. The transaction then only shows the wrapper. The decoders
+//! here take the wrapper apart, recursively, into the calls it makes, each with the sender the
+//! called contract sees and where it sat in the wrapper.
+
+use crate::userop::UserOperation;
+use alloy::primitives::{Address, Bytes, U256};
+use alloy::sol;
+use alloy::sol_types::SolCall;
+
+// how many wrappers deep we look, a user operation batching multicalls is three
+const MAX_DEPTH: usize = 4;
+
+sol! {
+    interface ISelfMulticall {
+        function multicall(bytes[] data) external payable returns (bytes[] results);
+    }
+
+    interface IDeadlineMulticall {
+        function multicall(uint256 deadline, bytes[] data) external payable returns (bytes[] results);
+    }
+
+    interface IMulticall3 {
+        struct Call {
+            address target;
+            bytes callData;
+        }
+
+        struct Call3 {
+            address target;
+            bool allowFailure;
+            bytes callData;
+        }
+
+        struct Call3Value {
+            address target;
+            bool allowFailure;
+            uint256 value;
+            bytes callData;
+        }
+
+        function aggregate(Call[] calls) external payable returns (uint256 blockNumber, bytes[] returnData);
+        function tryAggregate(bool requireSuccess, Call[] calls) external payable returns (bytes[] returnData);
+        function aggregate3(Call3[] calls) external payable returns (bytes[] returnData);
+        function aggregate3Value(Call3Value[] calls) external payable returns (bytes[] returnData);
+    }
+
+    interface ISafe {
+        function execTransaction(
+            address to,
+            uint256 value,
+            bytes data,
+            uint8 operation,
+            uint256 safeTxGas,
+            uint256 baseGas,
+            uint256 gasPrice,
+            address gasToken,
+            address refundReceiver,
+            bytes signatures
+        ) external payable returns (bool success);
+    }
+
+    interface IMultiSend {
+        function multiSend(bytes transactions) external payable;
+    }
+
+    interface IEntryPointV06 {
+        struct UserOperation {
+            address sender;
+            uint256 nonce;
+            bytes initCode;
+            bytes callData;
+            uint256 callGasLimit;
+            uint256 verificationGasLimit;
+            uint256 preVerificationGas;
+            uint256 maxFeePerGas;
+            uint256 maxPriorityFeePerGas;
+            bytes paymasterAndData;
+            bytes signature;
+        }
+
+        function handleOps(UserOperation[] ops, address beneficiary) external;
+    }
+
+    interface IEntryPointV07 {
+        struct PackedUserOperation {
+            address sender;
+            uint256 nonce;
+            bytes initCode;
+            bytes callData;
+            bytes32 accountGasLimits;
+            uint256 preVerificationGas;
+            bytes32 gasFees;
+            bytes paymasterAndData;
+            bytes signature;
+        }
+
+        function handleOps(PackedUserOperation[] ops, address beneficiary) external;
+    }
+
+    interface ISmartAccount {
+        function execute(address dest, uint256 value, bytes func) external;
+        function executeBatch(address[] dest, bytes[] func) external;
+    }
+
+    interface ISmartAccountValues {
+        function executeBatch(address[] dest, uint256[] value, bytes[] func) external;
+    }
+
+    interface ISafe4337Module {
+        function executeUserOp(address to, uint256 value, bytes data, uint8 operation) external;
+    }
+}
+
+/// A call made by a batching wrapper.
+#[derive(Debug, Clone)]
+pub struct InnerCall {
+    // where the call sat, e.g. `execTransaction > multiSend[1]`
+    pub context: String,
+
+    pub from: Address, // the sender the called contract sees
+    pub to: Address,
+    pub value: U256,
+    pub input: Bytes,
+
+    // the user operation the call was made for, when it came out of a `handleOps` bundle
+    pub user_op: Option<UserOperation>,
+}
+
+/// The name of the batching wrapper the input calls, if it is one we can unwrap.
+pub fn wrapper_name(input: &[u8]) -> Option<&'static str> {
+    let selector: [u8; 4] = input.get(..4)?.try_into().ok()?;
+    match selector {
+        ISelfMulticall::multicallCall::SELECTOR | IDeadlineMulticall::multicallCall::SELECTOR => {
+            Some("multicall")
+        }
+        IMulticall3::aggregateCall::SELECTOR => Some("aggregate"),
+        IMulticall3::tryAggregateCall::SELECTOR => Some("tryAggregate"),
+        IMulticall3::aggregate3Call::SELECTOR => Some("aggregate3"),
+        IMulticall3::aggregate3ValueCall::SELECTOR => Some("aggregate3Value"),
+        ISafe::execTransactionCall::SELECTOR => Some("execTransaction"),
+        IMultiSend::multiSendCall::SELECTOR => Some("multiSend"),
+        IEntryPointV06::handleOpsCall::SELECTOR | IEntryPointV07::handleOpsCall::SELECTOR => {
+            Some("handleOps")
+        }
+        ISmartAccount::executeCall::SELECTOR => Some("execute"),
+        ISmartAccount::executeBatchCall::SELECTOR
+        | ISmartAccountValues::executeBatchCall::SELECTOR => Some("executeBatch"),
+        ISafe4337Module::executeUserOpCall::SELECTOR => Some("executeUserOp"),
+        _ => None,
+    }
+}
+
+/// Every call a wrapper sent by `from` to `to` makes, wrappers inside it included. Empty when
+/// the input isn't a wrapper we know or doesn't decode.
+pub fn unwrap_calls(from: Address, to: Address, input: &[u8]) -> Vec<InnerCall> {
+    let mut calls = Vec::new();
+    unwrap_into(from, to, input, "", None, 0, &mut calls);
+    calls
+}
+
+// Helper functions
+
+// `sender` is who called the wrapper and `this` the account its code runs as. A plain call
+// from the wrapper is sent by `this`, a delegate call runs the target's code as `this` again.
+fn unwrap_into(
+    sender: Address,
+    this: Address,
+    input: &[u8],
+    context: &str,
+    user_op: Option<&UserOperation>,
+    depth: usize,
+    calls: &mut Vec<InnerCall>,
+) {
+    if depth >= MAX_DEPTH {
+        return;
+    }
+    let Some(name) = wrapper_name(input) else {
+        return;
+    };
+
+    // (position, delegate, to, value, data) of every call the wrapper makes
+    let mut steps: Vec<(Option<usize>, bool, Address, U256, Bytes)> = Vec::new();
+    // the user operation each step belongs to, for a `handleOps` bundle
+    let mut ops: Vec<UserOperation> = Vec::new();
+
+    if let Ok(call) = ISelfMulticall::multicallCall::abi_decode(input) {
+        // a multicall delegate-calls the contract itself
+        for (idx, data) in call.data.into_iter().enumerate() {
+            steps.push((Some(idx), true, this, U256::ZERO, data));
+        }
+    } else if let Ok(call) = IDeadlineMulticall::multicallCall::abi_decode(input) {
+        for (idx, data) in call.data.into_iter().enumerate() {
+            steps.push((Some(idx), true, this, U256::ZERO, data));
+        }
+    } else if let Ok(call) = IMulticall3::aggregateCall::abi_decode(input) {
+        for (idx, c) in call.calls.into_iter().enumerate() {
+            steps.push((Some(idx), false, c.target, U256::ZERO, c.callData));
+        }
+    } else if let Ok(call) = IMulticall3::tryAggregateCall::abi_decode(input) {
+        for (idx, c) in call.calls.into_iter().enumerate() {
+            steps.push((Some(idx), false, c.target, U256::ZERO, c.callData));
+        }
+    } else if let Ok(call) = IMulticall3::aggregate3Call::abi_decode(input) {
+        for (idx, c) in call.calls.into_iter().enumerate() {
+            steps.push((Some(idx), false, c.target, U256::ZERO, c.callData));
+        }
+    } else if let Ok(call) = IMulticall3::aggregate3ValueCall::abi_decode(input) {
+        for (idx, c) in call.calls.into_iter().enumerate() {
+            steps.push((Some(idx), false, c.target, c.value, c.callData));
+        }
+    } else if let Ok(call) = ISafe::execTransactionCall::abi_decode(input) {
+        steps.push((None, call.operation == 1, call.to, call.value, call.data));
+    } else if let Ok(call) = IMultiSend::multiSendCall::abi_decode(input) {
+        for (idx, step) in decode_multi_send(&call.transactions)
+            .into_iter()
+            .enumerate()
+        {
+            let (delegate, to, value, data) = step;
+            steps.push((Some(idx), delegate, to, value, data));
+        }
+    } else if let Ok(call) = IEntryPointV06::handleOpsCall::abi_decode(input) {
+        // the EntryPoint calls every account with its operation's call data
+        for (idx, op) in call.ops.into_iter().enumerate() {
+            ops.push(user_operation(this, op.sender, op.nonce));
+            steps.push((Some(idx), false, op.sender, U256::ZERO, op.callData));
+        }
+    } else if let Ok(call) = IEntryPointV07::handleOpsCall::abi_decode(input) {
+        for (idx, op) in call.ops.into_iter().enumerate() {
+            ops.push(user_operation(this, op.sender, op.nonce));
+            steps.push((Some(idx), false, op.sender, U256::ZERO, op.callData));
+        }
+    } else if let Ok(call) = ISmartAccount::executeCall::abi_decode(input) {
+        steps.push((None, false, call.dest, call.value, call.func));
+    } else if let Ok(call) = ISmartAccount::executeBatchCall::abi_decode(input) {
+        for (idx, (dest, func)) in call.dest.into_iter().zip(call.func).enumerate() {
+            steps.push((Some(idx), false, dest, U256::ZERO, func));
+        }
+    } else if let Ok(call) = ISmartAccountValues::executeBatchCall::abi_decode(input) {
+        let calls = call.dest.into_iter().zip(call.value).zip(call.func);
+        for (idx, ((dest, value), func)) in calls.enumerate() {
+            steps.push((Some(idx), false, dest, value, func));
+        }
+    } else if let Ok(call) = ISafe4337Module::executeUserOpCall::abi_decode(input) {
+        // the module has the Safe make the call
+        steps.push((None, call.operation == 1, call.to, call.value, call.data));
+    }
+
+    for (idx, (position, delegate, to, value, data)) in steps.into_iter().enumerate() {
+        let user_op = ops.get(idx).or(user_op);
+        let step = match position {
+            Some(idx) => format!("{}[{}]", name, idx),
+            None => name.to_string(),
+        };
+        let context = if context.is_empty() {
+            step
+        } else {
+            format!("{} > {}", context, step)
+        };
+
+        // a delegate call still reaches `to`'s code, but it runs as `this` for `sender`
+        let (inner_sender, inner_this) = if delegate { (sender, this) } else { (this, to) };
+
+        calls.push(InnerCall {
+            context: context.clone(),
+            from: inner_sender,
+            to,
+            value,
+            input: data.clone(),
+            user_op: user_op.cloned(),
+        });
+        unwrap_into(
+            inner_sender,
+            inner_this,
+            &data,
+            &context,
+            user_op,
+            depth + 1,
+            calls,
+        );
+    }
+}
+
+fn user_operation(entry_point: Address, sender: Address, nonce: U256) -> UserOperation {
+    UserOperation {
+        entry_point,
+        sender,
+        nonce,
+        success: None,
+    }
+}
+
+// MultiSend packs every transaction as operation (1 byte), to (20), value (32), data length
+// (32) and the data itself, one after the other.
+fn decode_multi_send(packed: &[u8]) -> Vec<(bool, Address, U256, Bytes)> {
+    let mut transactions = Vec::new();
+    let mut rest = packed;
+
+    while rest.len() >= 85 {
+        let operation = rest[0];
+        let to = Address::from_slice(&rest[1..21]);
+        let value = U256::from_be_slice(&rest[21..53]);
+        let Ok(length) = usize::try_from(U256::from_be_slice(&rest[53..85])) else {
+            break;
+        };
+        let Some(data) = 85usize
+            .checked_add(length)
+            .and_then(|end| rest.get(85..end))
+        else {
+            break;
+        };
+
+        transactions.push((operation == 1, to, value, Bytes::copy_from_slice(data)));
+        rest = &rest[85 + length..];
+    }
+
+    transactions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::address;
+
+    const OWNER: Address = address!("0x00000000000000000000000000000000000000e0");
+    const ROUTER: Address = address!("0x00000000000000000000000000000000000000a1");
+    const MULTICALL3: Address = address!("0xca11bde05977b3631167028862be2a173976ca11");
+    const SAFE: Address = address!("0x00000000000000000000000000000000000000a2");
+    const MULTI_SEND: Address = address!("0x00000000000000000000000000000000000000a3");
+    const TOKEN: Address = address!("0x00000000000000000000000000000000000000a4");
+    const LIBRARY: Address = address!("0x00000000000000000000000000000000000000a5");
+
+    // the call data of a call that isn't a wrapper
+    fn plain(byte: u8) -> Bytes {
+        Bytes::from(vec![0x12, 0x34, 0x56, byte])
+    }
+
+    fn summary(calls: &[InnerCall]) -> Vec<(&str, Address, Address, U256)> {
+        calls
+            .iter()
+            .map(|call| (call.context.as_str(), call.from, call.to, call.value))
+            .collect()
+    }
+
+    fn exec_transaction(to: Address, value: u64, data: Bytes, operation: u8) -> Bytes {
+        ISafe::execTransactionCall {
+            to,
+            value: U256::from(value),
+            data,
+            operation,
+            safeTxGas: U256::ZERO,
+            baseGas: U256::ZERO,
+            gasPrice: U256::ZERO,
+            gasToken: Address::ZERO,
+            refundReceiver: Address::ZERO,
+            signatures: Bytes::new(),
+        }
+        .abi_encode()
+        .into()
+    }
+
+    fn pack(operation: u8, to: Address, value: u64, data: &[u8]) -> Vec<u8> {
+        let mut packed = vec![operation];
+        packed.extend_from_slice(to.as_slice());
+        packed.extend_from_slice(&U256::from(value).to_be_bytes::<32>());
+        packed.extend_from_slice(&U256::from(data.len()).to_be_bytes::<32>());
+        packed.extend_from_slice(data);
+        packed
+    }
+
+    #[test]
+    fn decodes_packed_transactions() {
+        let mut packed = pack(0, Address::repeat_byte(1), 5, &[0xaa, 0xbb]);
+        packed.extend(pack(1, Address::repeat_byte(2), 0, &[]));
+
+        let transactions = decode_multi_send(&packed);
+        assert_eq!(
+            transactions,
+            vec![
+                (
+                    false,
+                    Address::repeat_byte(1),
+                    U256::from(5),
+                    Bytes::from(vec![0xaa, 0xbb])
+                ),
+                (true, Address::repeat_byte(2), U256::ZERO, Bytes::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_operation_one_is_a_delegate_call() {
+        let packed = pack(2, Address::repeat_byte(1), 0, &[]);
+        assert!(!decode_multi_send(&packed)[0].0);
+    }
+
+    #[test]
+    fn stops_at_a_truncated_header() {
+        let mut packed = pack(0, Address::repeat_byte(1), 0, &[0x01]);
+        let header = pack(0, Address::repeat_byte(2), 0, &[]);
+        packed.extend_from_slice(&header[..84]);
+
+        let transactions = decode_multi_send(&packed);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].1, Address::repeat_byte(1));
+
+        assert!(decode_multi_send(&[]).is_empty());
+        assert!(decode_multi_send(&header[..84]).is_empty());
+    }
+
+    #[test]
+    fn stops_at_truncated_data() {
+        let mut packed = pack(0, Address::repeat_byte(1), 0, &[]);
+        let mut short = pack(0, Address::repeat_byte(2), 0, &[0x01, 0x02, 0x03]);
+        short.pop();
+        packed.extend(short);
+
+        let transactions = decode_multi_send(&packed);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].1, Address::repeat_byte(1));
+    }
+
+    #[test]
+    fn data_running_to_the_end_is_decoded() {
+        let packed = pack(0, Address::repeat_byte(1), 0, &[0x01, 0x02, 0x03]);
+        assert_eq!(decode_multi_send(&packed)[0].3.len(), 3);
+    }
+
+    #[test]
+    fn oversized_length_does_not_overflow() {
+        let mut packed = pack(0, Address::repeat_byte(1), 0, &[]);
+        packed[53..85].copy_from_slice(&U256::MAX.to_be_bytes::<32>());
+        assert!(decode_multi_send(&packed).is_empty());
+
+        let mut packed = pack(0, Address::repeat_byte(1), 0, &[]);
+        packed[53..85].copy_from_slice(&U256::from(usize::MAX).to_be_bytes::<32>());
+        assert!(decode_multi_send(&packed).is_empty());
+    }
+
+    #[test]
+    fn leaves_plain_calls_alone() {
+        assert!(unwrap_calls(OWNER, TOKEN, &plain(1)).is_empty());
+        assert!(unwrap_calls(OWNER, TOKEN, &[]).is_empty());
+        // a wrapper's selector without arguments that decode
+        let selector = ISelfMulticall::multicallCall::SELECTOR;
+        assert!(unwrap_calls(OWNER, ROUTER, &selector).is_empty());
+    }
+
+    #[test]
+    fn a_multicall_calls_the_contract_itself_for_the_sender() {
+        let input = ISelfMulticall::multicallCall {
+            data: vec![plain(1), plain(2)],
+        }
+        .abi_encode();
+
+        let calls = unwrap_calls(OWNER, ROUTER, &input);
+        assert_eq!(
+            summary(&calls),
+            vec![
+                ("multicall[0]", OWNER, ROUTER, U256::ZERO),
+                ("multicall[1]", OWNER, ROUTER, U256::ZERO),
+            ]
+        );
+        assert_eq!(calls[1].input, plain(2));
+        assert!(calls[0].user_op.is_none());
+    }
+
+    #[test]
+    fn attributes_aggregate3_value_to_each_call() {
+        let call = |target, value: u64, byte| IMulticall3::Call3Value {
+            target,
+            allowFailure: false,
+            value: U256::from(value),
+            callData: plain(byte),
+        };
+        let input = IMulticall3::aggregate3ValueCall {
+            calls: vec![call(TOKEN, 0, 1), call(ROUTER, 7, 2)],
+        }
+        .abi_encode();
+
+        assert_eq!(
+            summary(&unwrap_calls(OWNER, MULTICALL3, &input)),
+            vec![
+                ("aggregate3Value[0]", MULTICALL3, TOKEN, U256::ZERO),
+                ("aggregate3Value[1]", MULTICALL3, ROUTER, U256::from(7)),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_safe_sends_its_transactions_itself() {
+        let input = exec_transaction(TOKEN, 3, plain(1), 0);
+
+        assert_eq!(
+            summary(&unwrap_calls(OWNER, SAFE, &input)),
+            vec![("execTransaction", SAFE, TOKEN, U256::from(3))]
+        );
+    }
+
+    #[test]
+    fn delegate_calls_run_as_the_safe() {
+        let mut packed = pack(0, TOKEN, 1, &plain(1));
+        packed.extend(pack(1, LIBRARY, 0, &plain(2)));
+        let multi_send = IMultiSend::multiSendCall {
+            transactions: packed.into(),
+        }
+        .abi_encode();
+        let input = exec_transaction(MULTI_SEND, 0, multi_send.into(), 1);
+
+        // MultiSend runs as the Safe, so its plain calls come from the Safe and its delegate
+        // calls run as the Safe again, for the owner who called it
+        assert_eq!(
+            summary(&unwrap_calls(OWNER, SAFE, &input)),
+            vec![
+                ("execTransaction", OWNER, MULTI_SEND, U256::ZERO),
+                ("execTransaction > multiSend[0]", SAFE, TOKEN, U256::from(1)),
+                ("execTransaction > multiSend[1]", OWNER, LIBRARY, U256::ZERO),
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_unwrapping_at_max_depth() {
+        let mut input = plain(1);
+        for _ in 0..MAX_DEPTH + 2 {
+            input = ISelfMulticall::multicallCall { data: vec![input] }
+                .abi_encode()
+                .into();
+        }
+
+        let calls = unwrap_calls(OWNER, ROUTER, &input);
+        assert_eq!(calls.len(), MAX_DEPTH);
+        assert_eq!(
+            calls.last().unwrap().context,
+            ["multicall[0]"; MAX_DEPTH].join(" > ")
+        );
+        assert!(wrapper_name(&calls.last().unwrap().input).is_some());
+    }
+}
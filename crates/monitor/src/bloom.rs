@@ -0,0 +1,102 @@
+//! # Bloom Pre-filtering
+//! This module provides tools for ruling blocks out by their `logsBloom` before fetching them.
+//!
+//! Every block header carries a 2048-bit bloom filter over the addresses and topics of all the
+//! logs emitted in the block. A bloom can give false positives but never false negatives, so
+//! when none of the monitored contracts' addresses or none of their event topics are in the
+//! bloom, the block can't contain a log we care about and there's no point downloading its
+//! logs.
+
+use alloy::primitives::{Address, B256, Bloom, BloomInput};
+
+/// The logs a monitor is looking for: the contract addresses and, optionally, the event
+/// topics one of them has to emit. No topics means any log from the contracts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogInterest {
+    pub addresses: Vec<Address>,
+    pub topics: Vec<B256>,
+}
+
+impl LogInterest {
+    pub fn new(addresses: Vec<Address>, topics: Vec<B256>) -> Self {
+        Self { addresses, topics }
+    }
+
+    /// Whether a block with this bloom may contain a matching log. `false` is certain,
+    /// `true` only means the logs have to be fetched to find out.
+    pub fn may_match(&self, bloom: &Bloom) -> bool {
+        self.addresses
+            .iter()
+            .any(|address| bloom.contains_input(BloomInput::Raw(address.as_slice())))
+            && (self.topics.is_empty()
+                || self
+                    .topics
+                    .iter()
+                    .any(|topic| bloom.contains_input(BloomInput::Raw(topic.as_slice()))))
+    }
+}
+
+/// Whether a block with this bloom may contain a log any of the interests match.
+pub fn any_may_match(interests: &[LogInterest], bloom: &Bloom) -> bool {
+    interests.iter().any(|interest| interest.may_match(bloom))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::{address, b256};
+
+    const TOKEN: Address = address!("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48");
+    const OTHER: Address = address!("0xdac17f958d2ee523a2206206994597c13d831ec7");
+    // Transfer(address,address,uint256) and Approval(address,address,uint256)
+    const TRANSFER: B256 =
+        b256!("0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef");
+    const APPROVAL: B256 =
+        b256!("0x8c5be1e5ebec7d5bd14f71427d1e84f3dd0314c0f7b2291e5b200ac8c7c3b925");
+
+    // the bloom of a block with one Transfer log from the token
+    fn bloom() -> Bloom {
+        let mut bloom = Bloom::default();
+        bloom.accrue_raw_log(
+            TOKEN,
+            &[TRANSFER, B256::left_padding_from(OTHER.as_slice())],
+        );
+        bloom
+    }
+
+    #[test]
+    fn may_match_a_block_with_the_log() {
+        assert!(LogInterest::new(vec![TOKEN], vec![TRANSFER]).may_match(&bloom()));
+        assert!(LogInterest::new(vec![OTHER, TOKEN], vec![APPROVAL, TRANSFER]).may_match(&bloom()));
+        // any log from the contract
+        assert!(LogInterest::new(vec![TOKEN], Vec::new()).may_match(&bloom()));
+    }
+
+    #[test]
+    fn rules_out_other_contracts() {
+        assert!(!LogInterest::new(vec![OTHER], vec![TRANSFER]).may_match(&bloom()));
+        assert!(!LogInterest::new(vec![OTHER], Vec::new()).may_match(&bloom()));
+    }
+
+    #[test]
+    fn rules_out_other_events() {
+        assert!(!LogInterest::new(vec![TOKEN], vec![APPROVAL]).may_match(&bloom()));
+    }
+
+    #[test]
+    fn an_empty_interest_matches_nothing() {
+        assert!(!LogInterest::new(Vec::new(), Vec::new()).may_match(&bloom()));
+        assert!(!LogInterest::new(Vec::new(), vec![TRANSFER]).may_match(&bloom()));
+        assert!(!any_may_match(&[], &bloom()));
+    }
+
+    #[test]
+    fn an_empty_block_matches_nothing() {
+        let interest = LogInterest::new(vec![TOKEN], Vec::new());
+        assert!(!interest.may_match(&Bloom::default()));
+        assert!(any_may_match(
+            &[LogInterest::new(vec![OTHER], Vec::new()), interest],
+            &bloom()
+        ));
+    }
+}
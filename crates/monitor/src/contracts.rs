@@ -0,0 +1,163 @@
+//! # Monitored Contracts
+//! This module provides the set of contracts a monitor watches.
+//!
+//! A monitor starts with the contracts it was configured with, but the set can grow while it
+//! runs, for instance when a factory it follows creates a new child. The set is shared by all
+//! of the monitor's tasks, each of which works on a snapshot and picks up changes on its next
+//! scan. Tasks that hold a subscription built from the set can watch for changes and rebuild it.
+//!
+//! A contract added while the monitor runs can carry the block it was created in. A scanner
+//! that is already past that block goes back and scans the blocks it missed for that contract.
+
+use alloy::json_abi::JsonAbi;
+use alloy::primitives::Address;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// A contract a monitor watches, with the ABI its calls and logs are decoded against.
+#[derive(Debug, Clone)]
+pub struct MonitoredContract {
+    pub address: Address,
+    pub abi: Arc<JsonAbi>,
+    pub from_block: Option<u64>, // the block it was created in, when it joined late
+}
+
+impl MonitoredContract {
+    pub fn new(address: Address, abi: Arc<JsonAbi>) -> Self {
+        Self {
+            address,
+            abi,
+            from_block: None,
+        }
+    }
+
+    /// Marks the contract as created in `block`, so scanners past it go back for it.
+    pub fn starting_at(mut self, block: u64) -> Self {
+        self.from_block = Some(block);
+        self
+    }
+}
+
+/// The contracts in `contracts` that aren't in `previous` and were created in or before
+/// `current_block`, with the block each was created in.
+pub fn joined_late(
+    previous: &[MonitoredContract],
+    contracts: &[MonitoredContract],
+    current_block: u64,
+) -> Vec<(Address, u64)> {
+    contracts
+        .iter()
+        .filter(|contract| !previous.iter().any(|c| c.address == contract.address))
+        .filter_map(|contract| {
+            let from_block = contract.from_block?;
+            (from_block <= current_block).then_some((contract.address, from_block))
+        })
+        .collect()
+}
+
+/// The contracts a monitor watches, shared between its tasks.
+pub struct ContractSet {
+    contracts: watch::Sender<Arc<Vec<MonitoredContract>>>,
+}
+
+impl ContractSet {
+    pub fn new(contracts: Vec<MonitoredContract>) -> Self {
+        Self {
+            contracts: watch::Sender::new(Arc::new(contracts)),
+        }
+    }
+
+    /// The contracts as they are right now. A snapshot is never modified, a change replaces it.
+    pub fn snapshot(&self) -> Arc<Vec<MonitoredContract>> {
+        self.contracts.borrow().clone()
+    }
+
+    /// Adds a contract, returning `false` if it was already watched.
+    pub fn add(&self, contract: MonitoredContract) -> bool {
+        self.contracts.send_if_modified(|contracts| {
+            if contracts.iter().any(|c| c.address == contract.address) {
+                return false;
+            }
+            Arc::make_mut(contracts).push(contract);
+            true
+        })
+    }
+
+    /// Removes a contract, returning `false` if it wasn't watched.
+    pub fn remove(&self, address: Address) -> bool {
+        self.contracts.send_if_modified(|contracts| {
+            if !contracts.iter().any(|c| c.address == address) {
+                return false;
+            }
+            Arc::make_mut(contracts).retain(|c| c.address != address);
+            true
+        })
+    }
+
+    /// Notified every time a contract is added or removed.
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Vec<MonitoredContract>>> {
+        self.contracts.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contract(byte: u8) -> MonitoredContract {
+        MonitoredContract::new(Address::repeat_byte(byte), Arc::default())
+    }
+
+    fn addresses(contracts: &[MonitoredContract]) -> Vec<Address> {
+        contracts.iter().map(|c| c.address).collect()
+    }
+
+    #[test]
+    fn adds_and_removes_contracts() {
+        let set = ContractSet::new(vec![contract(1)]);
+        let before = set.snapshot();
+
+        assert!(set.add(contract(2)));
+        assert!(!set.add(contract(2)));
+        assert!(set.remove(Address::repeat_byte(1)));
+        assert!(!set.remove(Address::repeat_byte(1)));
+
+        assert_eq!(addresses(&set.snapshot()), vec![Address::repeat_byte(2)]);
+        // a snapshot taken earlier stays as it was
+        assert_eq!(addresses(&before), vec![Address::repeat_byte(1)]);
+    }
+
+    #[test]
+    fn notifies_changes_only() {
+        let set = ContractSet::new(Vec::new());
+        let mut changes = set.subscribe();
+
+        set.add(contract(1));
+        assert!(changes.has_changed().unwrap());
+        changes.mark_unchanged();
+
+        set.add(contract(1));
+        set.remove(Address::repeat_byte(2));
+        assert!(!changes.has_changed().unwrap());
+    }
+
+    #[test]
+    fn joined_late_takes_new_contracts_created_up_to_the_current_block() {
+        let previous = vec![contract(1).starting_at(10)];
+        let contracts = vec![
+            contract(1).starting_at(10), // was there already
+            contract(2),                 // no creation block, scanned from now on
+            contract(3).starting_at(99),
+            contract(4).starting_at(100), // the block we're at was scanned without it
+            contract(5).starting_at(101), // not scanned yet
+        ];
+
+        assert_eq!(
+            joined_late(&previous, &contracts, 100),
+            vec![
+                (Address::repeat_byte(3), 99),
+                (Address::repeat_byte(4), 100)
+            ]
+        );
+    }
+}
@@ -0,0 +1,132 @@
+//! # Deployments
+//! This module provides tools for recognising contract deployments.
+//!
+//! A transaction deploys a contract either directly, with no `to` address (CREATE), or by
+//! calling the deterministic deployment proxy with a salt followed by the init code (CREATE2).
+//! For CREATE the receipt tells us the new address, and without one it follows from the
+//! sender and its nonce. For CREATE2 it follows from the proxy, the salt and the init code.
+
+use alloy::consensus::Transaction;
+use alloy::network::{
+    AnyRpcTransaction, AnyTransactionReceipt, ReceiptResponse, TransactionResponse,
+};
+use alloy::primitives::{Address, B256, address};
+
+/// The deterministic deployment proxy most CREATE2 deployments go through, deployed at the
+/// same address on nearly every chain.
+pub const CREATE2_DEPLOYER: Address = address!("0x4e59b44847b379578588920ca78fbf26c0b4956c");
+
+/// Whether the transaction deploys a contract.
+pub fn is_deployment(tx: &AnyRpcTransaction) -> bool {
+    match tx.to() {
+        None => true,
+        Some(to) => to == CREATE2_DEPLOYER && tx.input().len() >= 32,
+    }
+}
+
+/// The address of the contract the transaction deploys, if it deploys one.
+pub fn deployed_address(
+    tx: &AnyRpcTransaction,
+    receipt: Option<&AnyTransactionReceipt>,
+) -> Option<Address> {
+    match tx.to() {
+        None => receipt
+            .and_then(|receipt| receipt.contract_address())
+            .or_else(|| Some(tx.from().create(tx.nonce()))),
+        Some(to) if to == CREATE2_DEPLOYER && tx.input().len() >= 32 => {
+            let (salt, init_code) = tx.input().split_at(32);
+            Some(CREATE2_DEPLOYER.create2_from_code(B256::from_slice(salt), init_code))
+        }
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{receipt, transaction};
+    use alloy::hex;
+    use serde_json::json;
+
+    const DEPLOYER: Address = address!("0x6ac7ea33f8831ea9dcc53393aaa88b25a785dbf0");
+
+    // a contract creation, with no `to`
+    fn create(nonce: u64) -> AnyRpcTransaction {
+        transaction(json!({
+            "from": DEPLOYER,
+            "to": null,
+            "nonce": format!("{:#x}", nonce),
+            "input": "0x6080604052",
+        }))
+    }
+
+    // a call to the deterministic deployment proxy with a salt of 42 and the init code of a
+    // contract that returns 42
+    fn create2(input: &str) -> AnyRpcTransaction {
+        transaction(json!({ "to": CREATE2_DEPLOYER, "input": input }))
+    }
+
+    const CREATE2_INPUT: &str = concat!(
+        "0x000000000000000000000000000000000000000000000000000000000000002a",
+        "600a600c600039600a6000f3602a60005260206000f3"
+    );
+
+    #[test]
+    fn recognises_deployments() {
+        assert!(is_deployment(&create(0)));
+        assert!(is_deployment(&create2(CREATE2_INPUT)));
+
+        // too short to hold a salt
+        assert!(!is_deployment(&create2("0x1234")));
+        assert!(!is_deployment(&transaction(
+            json!({ "input": CREATE2_INPUT })
+        )));
+    }
+
+    #[test]
+    fn derives_create_addresses_from_the_sender_and_nonce() {
+        assert_eq!(
+            deployed_address(&create(0), None),
+            Some(address!("0xcd234a471b72ba2f1ccf0a70fcaba648a5eecd8d"))
+        );
+        assert_eq!(
+            deployed_address(&create(1), None),
+            Some(address!("0x343c43a37d37dff08ae8c4a11544c718abb4fcf8"))
+        );
+    }
+
+    #[test]
+    fn prefers_the_receipts_contract_address() {
+        let created = Address::repeat_byte(0xcc);
+        let with_address = receipt(json!({ "to": null, "contractAddress": created }));
+        assert_eq!(
+            deployed_address(&create(0), Some(&with_address)),
+            Some(created)
+        );
+
+        // a receipt without one still leaves the sender and nonce
+        let without = receipt(json!({ "to": null }));
+        assert_eq!(
+            deployed_address(&create(0), Some(&without)),
+            Some(address!("0xcd234a471b72ba2f1ccf0a70fcaba648a5eecd8d"))
+        );
+    }
+
+    #[test]
+    fn derives_create2_addresses_from_the_salt_and_init_code() {
+        assert_eq!(
+            deployed_address(&create2(CREATE2_INPUT), None),
+            Some(address!("0xa1338f29f6cba6772a1554fea1dbeecf5598b63e"))
+        );
+
+        // an empty init code right after the salt
+        let salt_only = format!("0x{}", hex::encode([0u8; 32]));
+        assert!(deployed_address(&create2(&salt_only), None).is_some());
+    }
+
+    #[test]
+    fn plain_calls_deploy_nothing() {
+        assert_eq!(deployed_address(&transaction(json!({})), None), None);
+        assert_eq!(deployed_address(&create2("0x1234"), None), None);
+    }
+}
@@ -0,0 +1,738 @@
+//! # Rule Evaluation
+//! This module provides tools for explaining why a rule did or didn't match a transaction.
+//!
+//! `evaluate_transaction` fetches a transaction and its receipt and checks a monitor's rules
+//! against it condition by condition, with the same checks the running monitor makes. Every
+//! condition is reported with whether it passed and the value it was checked against, such
+//! as the decoded argument or the gas the transaction used. The receipt's logs are checked
+//! against the monitor's events the way the event monitor would.
+//!
+//! As in the scanner, the rules are also checked against the calls a batching wrapper made
+//! and, when the monitor traces calls, against the calls made to the monitored contracts from
+//! inside the transaction. The native transfers the monitor watches for are listed too.
+
+use crate::batch::{InnerCall, unwrap_calls};
+use crate::deploy::{deployed_address, is_deployment};
+use crate::events::{event_topics, get_event_details};
+use crate::filter::{call_succeeded, effective_gas_price, revert_condition_match};
+use crate::primitives::models::{Condition, MonitorRule};
+use crate::primitives::utils::format_value;
+use crate::revert::{RevertReason, decode_revert, replay_revert_data};
+use crate::trace::{CallTracer, InternalCall};
+use crate::transfers::NativeTransfer;
+use crate::tx::{map_rules_to_abi, top_level_transfer};
+use crate::{MonitoredContract, PollingMonitor};
+use alloy::consensus::{Transaction, Typed2718};
+use alloy::json_abi::JsonAbi;
+use alloy::network::{
+    AnyRpcTransaction, AnyTransactionReceipt, ReceiptResponse, TransactionResponse,
+};
+use alloy::primitives::utils::format_units;
+use alloy::primitives::{Address, B256, Bytes, U256, hex};
+use alloy::providers::Provider;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct EvaluationReport {
+    pub tx_hash: B256,
+    pub block_number: Option<u64>, // `None` while the transaction is pending
+    pub rules: Vec<RuleTrace>,
+    pub calls: Vec<CallTrace>,
+    pub transfers: Vec<NativeTransfer>, // the ones the monitor watches for
+    pub events: Vec<EventTrace>,
+}
+
+/// How a rule fared against the transaction.
+#[derive(Debug, Serialize)]
+pub struct RuleTrace {
+    pub rule: String,
+    pub matched: bool,
+    pub conditions: Vec<ConditionTrace>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConditionTrace {
+    pub condition: String,
+    pub passed: bool,
+    pub actual: String, // what the condition was checked against
+}
+
+/// How the rules fared against a call inside the transaction, one a batching wrapper made or
+/// one the trace shows going to a monitored contract.
+#[derive(Debug, Serialize)]
+pub struct CallTrace {
+    // where the call sat, e.g. `execTransaction > multiSend[1]` or `tx > 0 > 2`
+    pub call: String,
+    pub from: Address,
+    pub to: Option<Address>,
+    pub rules: Vec<RuleTrace>,
+}
+
+/// A log in the receipt that one of the monitor's events matches.
+#[derive(Debug, Serialize)]
+pub struct EventTrace {
+    pub address: Address,
+    pub log_index: Option<u64>,
+    pub details: String,
+}
+
+/// Checks the monitor's rules and events against the transaction `hash`, `None` if the node
+/// doesn't know the transaction.
+pub async fn evaluate_transaction(
+    monitor: &PollingMonitor,
+    rules: Vec<MonitorRule>,
+    event_names: &[String],
+    hash: B256,
+) -> Result<Option<EvaluationReport>, anyhow::Error> {
+    let Some(tx) = monitor
+        .pool
+        .request(|p| async move { p.get_transaction_by_hash(hash).await })
+        .await?
+    else {
+        return Ok(None);
+    };
+    let receipt = monitor
+        .pool
+        .request(|p| async move { p.get_transaction_receipt(hash).await })
+        .await?;
+
+    let contracts = monitor.contracts.snapshot();
+    let abi = monitor.abi_for(tx.to());
+    let unmapped = rules.clone();
+    let rules = map_rules_to_abi(rules, &abi);
+
+    // the transaction is replayed at most once, and only if it reverted and a rule asks why
+    let reverted = receipt.as_ref().is_some_and(|receipt| !receipt.status());
+    let reason = match (tx.block_number(), rules.iter().any(|r| r.needs_revert())) {
+        (Some(block_number), true) if reverted => {
+            replay_revert_data(&monitor.pool, &tx, block_number)
+                .await?
+                .and_then(|data| decode_revert(&abi, &data))
+        }
+        _ => None,
+    };
+    let outcome = (&tx, receipt.as_ref(), reason.as_ref());
+    let traces = trace_rules(&rules, Target::Transaction, outcome, &abi, &contracts);
+
+    // calls made by batching wrappers, Multicall, Safe, ERC-4337 bundles and the like
+    let mut calls = Vec::new();
+    let wrapped = match tx.to() {
+        Some(to) => unwrap_calls(tx.from(), to, tx.input()),
+        None => Vec::new(),
+    };
+    for call in &wrapped {
+        let abi = monitor.abi_for(Some(call.to));
+        let rules = rules_for(&unmapped, &contracts, call.to);
+        calls.push(CallTrace {
+            call: call.context.clone(),
+            from: call.from,
+            to: Some(call.to),
+            rules: trace_rules(&rules, Target::Wrapped(call), outcome, &abi, &contracts),
+        });
+    }
+
+    // every call frame of the transaction, when calls or transfers inside it are watched
+    let traced = if monitor.trace_calls
+        || monitor
+            .transfers
+            .as_ref()
+            .is_some_and(|watch| watch.internal)
+    {
+        trace_transaction(monitor, &tx).await?
+    } else {
+        Vec::new()
+    };
+
+    // calls to the monitored contracts made from inside the transaction
+    if monitor.trace_calls {
+        for call in traced.iter().filter(|call| !call.path.is_empty()) {
+            let Some(contract) = contracts.iter().find(|c| Some(c.address) == call.to) else {
+                continue;
+            };
+            let rules = map_rules_to_abi(unmapped.clone(), &contract.abi);
+            calls.push(CallTrace {
+                call: call.describe_path(),
+                from: call.from,
+                to: call.to,
+                rules: trace_rules(
+                    &rules,
+                    Target::Traced(call),
+                    outcome,
+                    &contract.abi,
+                    &contracts,
+                ),
+            });
+        }
+    }
+
+    // transfers only happened if the transaction went through
+    let succeeded = receipt.as_ref().is_some_and(|receipt| receipt.status());
+    let mut transfers: Vec<NativeTransfer> = top_level_transfer(monitor, &tx)
+        .filter(|_| succeeded)
+        .into_iter()
+        .collect();
+    if let Some(watch) = monitor.transfers.as_ref().filter(|watch| watch.internal) {
+        for call in &traced {
+            if call.path.is_empty() || !call.moves_value() {
+                continue;
+            }
+            if let Some(to) = call.to
+                && watch.matches(call.from, to, call.value)
+            {
+                transfers.push(NativeTransfer {
+                    from: call.from,
+                    to,
+                    value: call.value,
+                    call_path: Some(call.describe_path()),
+                });
+            }
+        }
+    }
+
+    // the logs the event monitor would have delivered
+    let names: Vec<&str> = event_names.iter().map(|s| s.as_str()).collect();
+    let topics = event_topics(&contracts, &names);
+    let events = receipt
+        .iter()
+        .flat_map(|receipt| receipt.inner.logs())
+        .filter(|log| {
+            contracts.iter().any(|c| c.address == log.address())
+                && log.topic0().is_some_and(|topic| topics.contains(topic))
+        })
+        .map(|log| EventTrace {
+            address: log.address(),
+            log_index: log.log_index,
+            details: get_event_details(log, &monitor.abi_for(Some(log.address()))),
+        })
+        .collect();
+
+    Ok(Some(EvaluationReport {
+        tx_hash: hash,
+        block_number: tx.block_number(),
+        rules: traces,
+        calls,
+        transfers,
+        events,
+    }))
+}
+
+// Helper functions
+
+// What a rule is checked against, the transaction as it was sent or a call inside it.
+#[derive(Clone, Copy)]
+enum Target<'a> {
+    Transaction,
+    Traced(&'a InternalCall),
+    Wrapped(&'a InnerCall),
+}
+
+impl<'a> Target<'a> {
+    // the sender, recipient, input and value the conditions on the call look at
+    fn call(self, tx: &'a AnyRpcTransaction) -> (Address, Option<Address>, &'a Bytes, U256) {
+        match self {
+            Target::Transaction => (tx.from(), tx.to(), tx.input(), tx.value()),
+            Target::Traced(call) => (call.from, call.to, &call.input, call.value),
+            Target::Wrapped(call) => (call.from, Some(call.to), &call.input, call.value),
+        }
+    }
+}
+
+// Checks the rules against `target` condition by condition, with the checks the scanner makes.
+fn trace_rules(
+    rules: &[MonitorRule],
+    target: Target,
+    outcome: (
+        &AnyRpcTransaction,
+        Option<&AnyTransactionReceipt>,
+        Option<&RevertReason>,
+    ),
+    abi: &JsonAbi,
+    contracts: &[MonitoredContract],
+) -> Vec<RuleTrace> {
+    let (tx, receipt, reason) = outcome;
+
+    rules
+        .iter()
+        .map(|rule| {
+            let conditions: Vec<ConditionTrace> = rule
+                .conditions
+                .iter()
+                .map(|condition| {
+                    // a condition passes when every stage that looks at it lets it through
+                    let on_call = match target {
+                        Target::Transaction => rule.tx_condition_match(condition, tx),
+                        Target::Traced(call) => rule.call_condition_match(condition, tx, call),
+                        Target::Wrapped(call) => rule.inner_condition_match(condition, tx, call),
+                    };
+                    let on_receipt = match (receipt, is_receipt_condition(condition)) {
+                        (Some(receipt), _) => match target {
+                            Target::Wrapped(call) => rule
+                                .inner_receipt_condition_match(condition, receipt, contracts, call),
+                            _ => rule.receipt_condition_match(condition, receipt, contracts),
+                        },
+                        (None, needed) => !needed,
+                    };
+                    // the revert data a replay gives back belongs to the transaction as a
+                    // whole, so errors are only checked against the transaction itself
+                    let on_revert = match target {
+                        Target::Transaction => revert_condition_match(condition, reason),
+                        _ => !is_revert_condition(condition),
+                    };
+
+                    ConditionTrace {
+                        condition: format!("{:?}", condition),
+                        passed: on_call && on_receipt && on_revert,
+                        actual: observed(rule, condition, target, outcome, abi, contracts),
+                    }
+                })
+                .collect();
+
+            RuleTrace {
+                rule: rule.name.clone(),
+                matched: conditions.iter().all(|c| c.passed),
+                conditions,
+            }
+        })
+        .collect()
+}
+
+// The rules a call to `to` is checked against, mapped to the ABI of the contract it calls
+// when it's one of ours, as the scanner has them.
+fn rules_for(
+    rules: &[MonitorRule],
+    contracts: &[MonitoredContract],
+    to: Address,
+) -> Vec<MonitorRule> {
+    match contracts.iter().find(|contract| contract.address == to) {
+        Some(contract) => map_rules_to_abi(rules.to_vec(), &contract.abi),
+        None => rules.to_vec(),
+    }
+}
+
+// Every call frame of the transaction. The whole block is traced, as the scanner does, and a
+// pending transaction has nothing to trace yet.
+async fn trace_transaction(
+    monitor: &PollingMonitor,
+    tx: &AnyRpcTransaction,
+) -> Result<Vec<InternalCall>, anyhow::Error> {
+    let (Some(block_hash), Some(block_number)) = (tx.block_hash(), tx.block_number()) else {
+        return Ok(Vec::new());
+    };
+    let Some(block) = monitor
+        .pool
+        .request(|p| async move { p.get_block_by_hash(block_hash).await })
+        .await?
+    else {
+        return Ok(Vec::new());
+    };
+    let tx_hashes: Vec<B256> = block.transactions.hashes().collect();
+
+    let calls = CallTracer::new()
+        .trace_block(&monitor.pool, block_number, &tx_hashes)
+        .await?;
+    Ok(calls
+        .into_iter()
+        .filter(|call| call.tx_hash == tx.tx_hash())
+        .collect())
+}
+
+fn is_revert_condition(condition: &Condition) -> bool {
+    matches!(
+        condition,
+        Condition::Error(_) | Condition::ErrorArgument { .. }
+    )
+}
+
+fn is_receipt_condition(condition: &Condition) -> bool {
+    matches!(
+        condition,
+        Condition::Status(_)
+            | Condition::GasUsed { .. }
+            | Condition::EmitsEvent(_)
+            | Condition::Deploys(_)
+            | Condition::Error(_)
+            | Condition::ErrorArgument { .. }
+    )
+}
+
+// Describes the value a condition was checked against.
+// `abi` is the one the input of the transaction or call is decoded against.
+fn observed(
+    rule: &MonitorRule,
+    condition: &Condition,
+    target: Target,
+    (tx, receipt, reason): (
+        &AnyRpcTransaction,
+        Option<&AnyTransactionReceipt>,
+        Option<&RevertReason>,
+    ),
+    abi: &JsonAbi,
+    contracts: &[MonitoredContract],
+) -> String {
+    let not_mined = || "no receipt, the transaction isn't mined yet".to_string();
+    let (from, to, input, value) = target.call(tx);
+
+    match condition {
+        Condition::From(_) => format!("{:?}", from),
+        Condition::To(_) => match to {
+            Some(to) => format!("{:?}", to),
+            None => "none, a contract deployment".to_string(),
+        },
+        Condition::Deploys(_) => {
+            if !matches!(target, Target::Transaction) {
+                "a call inside the transaction, not a deployment".to_string()
+            } else if !is_deployment(tx) {
+                format!("not a deployment, sent by {:?}", tx.from())
+            } else {
+                match deployed_address(tx, receipt) {
+                    Some(address) => format!("deploys {:?}, sent by {:?}", address, tx.from()),
+                    None => format!("a deployment sent by {:?}", tx.from()),
+                }
+            }
+        }
+        Condition::Function(_) => {
+            let Some(selector) = input.get(..4) else {
+                return "no function call, the input is empty".to_string();
+            };
+            // named after the ABI of the contract it went to when we know the function
+            let called = abi
+                .functions()
+                .find(|f| f.selector().as_slice() == selector);
+            match called {
+                Some(f) => format!("{} (0x{})", f.signature(), hex::encode(selector)),
+                None => format!("selector 0x{}", hex::encode(selector)),
+            }
+        }
+        Condition::Argument { name, .. } => match rule.decoded_argument(input, name) {
+            Some(value) => format_value(&value),
+            None => format!("{} couldn't be decoded from the input", name),
+        },
+        Condition::Value { .. } => format!("{} wei", value),
+        Condition::GasPrice { .. } => format!(
+            "{} gwei",
+            format_units(effective_gas_price(tx), "gwei").unwrap_or_default()
+        ),
+        Condition::MaxPriorityFee { .. } => match tx.max_priority_fee_per_gas() {
+            Some(tip) => format!("{} gwei", format_units(tip, "gwei").unwrap_or_default()),
+            None => "none, not a typed transaction".to_string(),
+        },
+        Condition::TxType(_) => format!("type {}", tx.ty()),
+        Condition::Nonce { .. } => tx.nonce().to_string(),
+        Condition::Status(_) => match (receipt, target) {
+            // a bundle goes through even when one of its user operations fails
+            (Some(receipt), Target::Wrapped(call)) if call.user_op.is_some() => {
+                if call_succeeded(receipt, call) {
+                    "Success (the user operation)".to_string()
+                } else {
+                    "Reverted (the user operation)".to_string()
+                }
+            }
+            (Some(receipt), _) if receipt.status() => "Success".to_string(),
+            (Some(_), _) => "Reverted".to_string(),
+            (None, _) => not_mined(),
+        },
+        Condition::GasUsed { .. } => match receipt {
+            Some(receipt) => receipt.gas_used().to_string(),
+            None => not_mined(),
+        },
+        Condition::EmitsEvent(_) => match receipt {
+            Some(receipt) => {
+                // what the monitored contracts emitted, by name where their ABI knows it
+                let emitted: Vec<String> = receipt
+                    .inner
+                    .logs()
+                    .iter()
+                    .filter_map(|log| {
+                        let contract = contracts.iter().find(|c| c.address == log.address())?;
+                        let topic = log.topic0()?;
+                        Some(
+                            contract
+                                .abi
+                                .events()
+                                .find(|e| e.selector() == *topic)
+                                .map(|e| e.name.clone())
+                                .unwrap_or_else(|| format!("{:?}", topic)),
+                        )
+                    })
+                    .collect();
+                if emitted.is_empty() {
+                    "no events from the monitored contracts".to_string()
+                } else {
+                    format!("emitted {}", emitted.join(", "))
+                }
+            }
+            None => not_mined(),
+        },
+        Condition::Error(_) | Condition::ErrorArgument { .. } => match (receipt, reason) {
+            _ if !matches!(target, Target::Transaction) => {
+                "errors are only checked against the transaction itself".to_string()
+            }
+            (None, _) => not_mined(),
+            (Some(receipt), _) if receipt.status() => "the transaction didn't revert".to_string(),
+            (Some(_), Some(reason)) => reason.to_string(),
+            (Some(_), None) => "reverted without a custom error of the ABI".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::IMulticall3;
+    use crate::pool::ProviderPool;
+    use crate::primitives::models::TransferConfig;
+    use crate::testing::{Reply, RpcStub, block_json, receipt, transaction};
+    use crate::transfers::TransferWatch;
+    use alloy::primitives::address;
+    use alloy::sol;
+    use alloy::sol_types::SolCall;
+    use serde_json::{Value, json};
+    use std::sync::Arc;
+
+    sol! {
+        function transfer(address to, uint256 amount) external returns (bool);
+    }
+
+    const TOKEN: Address = address!("0x00000000000000000000000000000000000000a4");
+    const ROUTER: Address = address!("0x0000000000000000000000000000000000000002");
+    const MULTICALL3: Address = address!("0xca11bde05977b3631167028862be2a173976ca11");
+    const RECIPIENT: Address = address!("0x00000000000000000000000000000000000000c1");
+
+    fn transfer_input(amount: u64) -> Bytes {
+        transferCall {
+            to: RECIPIENT,
+            amount: U256::from(amount),
+        }
+        .abi_encode()
+        .into()
+    }
+
+    // a node that knows one transaction, its receipt and the trace of its block
+    async fn node(tx: Value, receipt: Value, trace: Value) -> RpcStub {
+        // a transaction that isn't `Value::Null` is a `transaction()` with those fields changed
+        let tx = match tx {
+            Value::Null => tx,
+            fields => json!(transaction(fields)),
+        };
+        RpcStub::start(move |method, _| match method {
+            "eth_getTransactionByHash" => Reply::Result(tx.clone()),
+            "eth_getTransactionReceipt" => Reply::Result(receipt.clone()),
+            "eth_getBlockByHash" => {
+                let mut block = block_json(1, B256::repeat_byte(0xbb));
+                block["transactions"] = json!([B256::repeat_byte(0xaa)]);
+                Reply::Result(block)
+            }
+            "debug_traceBlockByNumber" => Reply::Result(json!([{ "result": trace.clone() }])),
+            _ => Reply::Error(-32601, "Method not found"),
+        })
+        .await
+    }
+
+    fn monitor(stub: &RpcStub) -> PollingMonitor {
+        let pool = ProviderPool::new("mainnet", std::slice::from_ref(&stub.url), None).unwrap();
+        let abi = JsonAbi::parse([
+            "function transfer(address to, uint256 amount) returns (bool)",
+            "event Transfer(address indexed from, address indexed to, uint256 value)",
+        ])
+        .unwrap();
+        PollingMonitor::new(
+            Arc::new(pool),
+            vec![MonitoredContract::new(TOKEN, Arc::new(abi))],
+        )
+    }
+
+    fn rule(conditions: Value) -> MonitorRule {
+        MonitorRule {
+            name: "test".to_string(),
+            conditions: serde_json::from_value(conditions).unwrap(),
+            confirmations: None,
+            spawn: None,
+            abi_functions: Vec::new(),
+        }
+    }
+
+    // each condition's outcome and the value it was checked against
+    fn summary(trace: &RuleTrace) -> Vec<(bool, &str)> {
+        trace
+            .conditions
+            .iter()
+            .map(|c| (c.passed, c.actual.as_str()))
+            .collect()
+    }
+
+    async fn evaluate(monitor: &PollingMonitor, rules: Vec<MonitorRule>) -> EvaluationReport {
+        evaluate_transaction(monitor, rules, &[], B256::repeat_byte(0xaa))
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reports_every_condition_with_the_value_it_was_checked_against() {
+        let tx = json!({ "to": TOKEN, "input": transfer_input(500) });
+        let stub = node(tx, json!(receipt(json!({ "to": TOKEN }))), Value::Null).await;
+        let rules = vec![rule(json!([
+            { "Function": "transfer" },
+            { "Argument": { "name": "amount", "operator": "Gt", "value": "100" } },
+            { "Status": "Success" },
+            { "GasUsed": { "operator": "Gt", "value": 50000 } },
+        ]))];
+
+        let report = evaluate(&monitor(&stub), rules).await;
+        assert_eq!(report.block_number, Some(1));
+        assert!(!report.rules[0].matched);
+        assert_eq!(
+            summary(&report.rules[0]),
+            vec![
+                (true, "transfer(address,uint256) (0xa9059cbb)"),
+                (true, "500"),
+                (true, "Success"),
+                (false, "21000"),
+            ]
+        );
+        assert!(report.calls.is_empty() && report.transfers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn checks_the_calls_a_batching_wrapper_made() {
+        let input = IMulticall3::aggregate3Call {
+            calls: vec![IMulticall3::Call3 {
+                target: TOKEN,
+                allowFailure: false,
+                callData: transfer_input(500),
+            }],
+        }
+        .abi_encode();
+        let tx = json!({ "to": MULTICALL3, "input": Bytes::from(input) });
+        let stub = node(tx, json!(receipt(json!({ "to": MULTICALL3 }))), Value::Null).await;
+        let rules = vec![rule(json!([
+            { "Function": "transfer" },
+            { "To": TOKEN },
+            { "Error": "Paused" },
+        ]))];
+
+        let report = evaluate(&monitor(&stub), rules).await;
+        // the transaction itself calls aggregate3 on Multicall3
+        assert!(!report.rules[0].matched);
+
+        let call = &report.calls[0];
+        assert_eq!(report.calls.len(), 1);
+        assert_eq!(
+            (call.call.as_str(), call.from, call.to),
+            ("aggregate3[0]", MULTICALL3, Some(TOKEN))
+        );
+        assert_eq!(
+            summary(&call.rules[0]),
+            vec![
+                (true, "transfer(address,uint256) (0xa9059cbb)"),
+                (true, "0x00000000000000000000000000000000000000a4"),
+                (
+                    false,
+                    "errors are only checked against the transaction itself"
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn checks_traced_calls_and_lists_internal_transfers() {
+        let frame = |to: Address, value: &str, input: Bytes| {
+            json!({
+                "type": "CALL",
+                "from": ROUTER,
+                "to": to,
+                "value": value,
+                "gas": "0x10000",
+                "gasUsed": "0x5000",
+                "input": input,
+            })
+        };
+        let trace = json!({
+            "type": "CALL",
+            "from": Address::repeat_byte(0x01),
+            "to": ROUTER,
+            "value": "0x0",
+            "gas": "0x30000",
+            "gasUsed": "0x20000",
+            "input": "0x12345678",
+            "calls": [
+                frame(TOKEN, "0x0", transfer_input(500)),
+                frame(RECIPIENT, "0x7", Bytes::new()),
+            ],
+        });
+        let tx = json!({ "to": ROUTER, "input": "0x12345678" });
+        let stub = node(tx, json!(receipt(json!({}))), trace).await;
+        let watch = TransferWatch::new(&TransferConfig {
+            addresses: vec![RECIPIENT],
+            direction: None,
+            min_value: None,
+            max_value: None,
+            allow: None,
+            deny: None,
+            internal: Some(true),
+        })
+        .unwrap();
+        let monitor = monitor(&stub).with_call_tracing(true).with_transfers(watch);
+        let rules = vec![rule(
+            json!([{ "Function": "transfer" }, { "From": ROUTER }]),
+        )];
+
+        let report = evaluate(&monitor, rules).await;
+        assert!(!report.rules[0].matched);
+
+        // the call to the monitored token, not the one to the recipient
+        assert_eq!(report.calls.len(), 1);
+        let call = &report.calls[0];
+        assert_eq!((call.call.as_str(), call.to), ("tx > 0", Some(TOKEN)));
+        assert!(call.rules[0].matched);
+        assert_eq!(
+            call.rules[0].conditions[1].actual,
+            "0x0000000000000000000000000000000000000002"
+        );
+
+        let transfers: Vec<(Address, Address, U256, Option<&str>)> = report
+            .transfers
+            .iter()
+            .map(|t| (t.from, t.to, t.value, t.call_path.as_deref()))
+            .collect();
+        assert_eq!(
+            transfers,
+            vec![(ROUTER, RECIPIENT, U256::from(7), Some("tx > 1"))]
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_a_pending_transaction_untraced() {
+        let pending = json!({
+            "to": TOKEN,
+            "input": transfer_input(500),
+            "blockHash": null,
+            "blockNumber": null,
+            "transactionIndex": null,
+        });
+        // the node would turn a trace request down
+        let stub = node(pending, Value::Null, Value::Null).await;
+        let monitor = monitor(&stub).with_call_tracing(true);
+        let rules = vec![rule(
+            json!([{ "Function": "transfer" }, { "Status": "Success" }]),
+        )];
+
+        let report = evaluate(&monitor, rules).await;
+        assert_eq!(report.block_number, None);
+        assert_eq!(
+            summary(&report.rules[0]),
+            vec![
+                (true, "transfer(address,uint256) (0xa9059cbb)"),
+                (false, "no receipt, the transaction isn't mined yet"),
+            ]
+        );
+        assert!(report.calls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn knows_nothing_of_a_transaction_the_node_does_not_have() {
+        let stub = node(Value::Null, Value::Null, Value::Null).await;
+        let report = evaluate_transaction(&monitor(&stub), Vec::new(), &[], B256::ZERO).await;
+        assert!(report.unwrap().is_none());
+    }
+}
@@ -5,17 +5,29 @@
 //! events emitted by smart contracts on the blockchain. The monitoring process involves polling
 //! the blockchain for new blocks, filtering logs based on event signatures, and invoking user-defined
 //! handlers for each detected event.
+//!
+//! Logs carry `removed: true` when the block they were emitted in has been orphaned. Those are
+//! passed straight to the handler, and when the monitor itself detects a reorg it re-sends the
+//! logs it already delivered from the orphaned blocks with `removed` set before rescanning.
 
-use crate::PollingMonitor;
-use crate::primitives::utils::format_value;
+use crate::bloom::LogInterest;
+use crate::contracts::joined_late;
+use crate::primitives::utils::{format_value, select_events};
+use crate::reorg::{BlockWindow, DEFAULT_REORG_WINDOW, find_common_ancestor};
+use crate::{MonitoredContract, PollingMonitor};
 use alloy::dyn_abi::EventExt;
 use alloy::json_abi::JsonAbi;
-use alloy::primitives::B256;
+use alloy::primitives::{Address, B256};
 use alloy::providers::Provider;
 use alloy::rpc::types::{Filter, Log};
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use tokio::time::sleep;
 
+/// The most blocks a single `eth_getLogs` query covers, longer ranges are split up so a
+/// monitor catching up after downtime stays within what providers allow.
+pub const MAX_LOG_RANGE: u64 = 1_000;
+
 #[allow(async_fn_in_trait)]
 pub trait EventMonitor {
     async fn monitor_events_polling<F>(
@@ -38,59 +50,378 @@ impl EventMonitor for PollingMonitor {
     {
         println!(
             "EventsMonitor: Watching transactions for {:?}",
-            self.contract_address
+            self.addresses()
         );
 
-        // this prepares the event topics from the ABI for efficient filtering.
-        let mut topics: Vec<B256> = Vec::new();
+        // this initialises the starting block for polling to the current processing head.
+        let mut scanner = EventScanner::new(&self, event_names, self.processing_head().await?);
+        poll_events(&self, &mut scanner, &mut handler).await
+    }
+}
+
+/// Polls for new blocks every `poll_interval` and queries their logs through the scanner.
+pub(crate) async fn poll_events<F>(
+    monitor: &PollingMonitor,
+    scanner: &mut EventScanner,
+    handler: &mut F,
+) -> Result<(), anyhow::Error>
+where
+    F: FnMut(Log),
+{
+    loop {
+        match monitor.processing_head().await {
+            Ok(latest_block) => scanner.scan_to(monitor, latest_block, handler).await,
+            Err(e) => eprintln!("Error fetching block number: {}", e),
+        }
+
+        // wait for a short duration before the next poll.
+        sleep(monitor.poll_interval).await;
+    }
+}
+
+/// Resolves event names to the topic hashes we filter logs on, in every contract's ABI. A
+/// name covers every overload of the event, a full signature just the one.
+pub fn event_topics(contracts: &[MonitoredContract], event_names: &[&str]) -> Vec<B256> {
+    let mut topics: Vec<B256> = Vec::new();
+    for contract in contracts {
         for event_name in event_names {
-            if let Some(events) = self.contract_abi.events.get(*event_name) {
-                if let Some(event) = events.first() {
-                    // The event selector is a hash of the event signature.
+            for event in select_events(&contract.abi, event_name) {
+                // The event selector is a hash of the event signature.
+                if !topics.contains(&event.selector()) {
                     topics.push(event.selector());
                 }
             }
         }
+    }
+    topics
+}
+
+/// The log scanning state of an event monitor, shared by the polling and subscription loops.
+pub struct EventScanner {
+    event_names: Vec<String>,
+    current_block: u64,
+
+    // the monitored contracts as of the last scan, and the topics of the events in their ABIs
+    contracts: Arc<Vec<MonitoredContract>>,
+    topics: Vec<B256>,
+
+    // recent block hashes and the logs we delivered from them, so a reorg can be unwound
+    window: BlockWindow,
+    delivered: BTreeMap<u64, Vec<Log>>,
+
+    // contracts that joined after we passed the block they were created in, with the next
+    // block to fetch their logs from
+    backfill: Vec<(Address, u64)>,
+}
+
+impl EventScanner {
+    pub fn new(monitor: &PollingMonitor, event_names: &[&str], start_block: u64) -> Self {
+        // the contracts there are to begin with are scanned from `start_block` on, like the rest
+        let contracts = monitor.contracts.snapshot();
+        Self {
+            event_names: event_names.iter().map(|name| name.to_string()).collect(),
+            current_block: start_block,
+            topics: event_topics(&contracts, event_names),
+            contracts,
+            window: BlockWindow::new(DEFAULT_REORG_WINDOW),
+            delivered: BTreeMap::new(),
+            backfill: Vec::new(),
+        }
+    }
+
+    /// Picks up contracts added to or removed from the monitor since the last scan, returning
+    /// whether anything changed.
+    pub fn refresh(&mut self, monitor: &PollingMonitor) -> bool {
+        let contracts = monitor.contracts.snapshot();
+        if Arc::ptr_eq(&contracts, &self.contracts) {
+            return false;
+        }
 
-        // this initialises the starting block for polling to the current block number.
-        let current_block = self.provider.get_block_number().await?;
+        let names: Vec<&str> = self.event_names.iter().map(|name| name.as_str()).collect();
+        self.topics = event_topics(&contracts, &names);
+        self.backfill
+            .extend(joined_late(&self.contracts, &contracts, self.current_block));
+        self.contracts = contracts;
+        true
+    }
 
-        loop {
-            let latest_block = match self.provider.get_block_number().await {
-                Ok(num) => num,
+    pub fn current_block(&self) -> u64 {
+        self.current_block
+    }
+
+    /// The log filter for the monitored contracts and events, without a block range.
+    pub fn filter(&self) -> Filter {
+        Filter::new()
+            .address(self.addresses())
+            .event_signature(self.topics.clone())
+    }
+
+    /// What the monitor is looking for, to check block blooms against.
+    pub fn interest(&self) -> LogInterest {
+        LogInterest::new(self.addresses(), self.topics.clone())
+    }
+
+    fn addresses(&self) -> Vec<Address> {
+        self.contracts
+            .iter()
+            .map(|contract| contract.address)
+            .collect()
+    }
+
+    /// Queries the logs of every block after the last one we scanned, up to `latest_block`, at
+    /// most `MAX_LOG_RANGE` blocks at a time. It stops early when a query fails or a reorg
+    /// rewinds the scanner, leaving the rest to the next scan.
+    pub async fn scan_to<F>(&mut self, monitor: &PollingMonitor, latest_block: u64, handler: &mut F)
+    where
+        F: FnMut(Log),
+    {
+        self.refresh(monitor);
+        self.scan_backfill(monitor, handler).await;
+
+        while self.current_block < latest_block {
+            let before = self.current_block;
+            let to_block = latest_block.min(before + MAX_LOG_RANGE);
+            self.scan_range(monitor, to_block, handler).await;
+            if self.current_block <= before {
+                return;
+            }
+        }
+    }
+
+    // Fetches the logs of late-joining contracts for the blocks we scanned before they joined.
+    // A failed query leaves the rest for the next scan.
+    async fn scan_backfill<F>(&mut self, monitor: &PollingMonitor, handler: &mut F)
+    where
+        F: FnMut(Log),
+    {
+        while let Some((address, from_block)) = self.backfill.first().copied() {
+            if from_block > self.current_block {
+                self.backfill.remove(0);
+                continue;
+            }
+
+            let to_block = self.current_block.min(from_block + MAX_LOG_RANGE - 1);
+            let filter = Filter::new()
+                .address(address)
+                .event_signature(self.topics.clone())
+                .from_block(from_block)
+                .to_block(to_block);
+            let logs = match monitor
+                .pool
+                .request(|p| {
+                    let filter = filter.clone();
+                    async move { p.get_logs(&filter).await }
+                })
+                .await
+            {
+                Ok(logs) => logs,
                 Err(e) => {
-                    eprintln!("Error fetching block number: {}", e);
-                    // wait before retrying to avoid spamming the node on failure.
-                    sleep(Duration::from_secs(2)).await;
-                    continue;
+                    eprintln!("Error fetching logs of {:?}: {}", address, e);
+                    return;
                 }
             };
 
-            if latest_block > current_block {
-                let from_block = current_block + 1;
-                let to_block = latest_block;
+            println!(
+                "EventsMonitor: Backfilled blocks {} to {} for {:?}",
+                from_block, to_block, address
+            );
+            for log in logs {
+                self.deliver_backfilled(log, handler);
+            }
+            self.backfill[0].1 = to_block + 1;
+        }
+    }
 
+    // Like `deliver`, but for a block behind the tip, which mustn't move the window back.
+    fn deliver_backfilled<F>(&mut self, log: Log, handler: &mut F)
+    where
+        F: FnMut(Log),
+    {
+        if let Some(number) = log.block_number
+            && self.window.oldest().is_some_and(|oldest| number >= oldest)
+        {
+            self.delivered.entry(number).or_default().push(log.clone());
+        }
+        handler(log);
+    }
+
+    // Scans the blocks after the last one we scanned up to `latest_block` in one query.
+    async fn scan_range<F>(&mut self, monitor: &PollingMonitor, latest_block: u64, handler: &mut F)
+    where
+        F: FnMut(Log),
+    {
+        if latest_block <= self.current_block {
+            return;
+        }
+
+        self.refresh(monitor);
+
+        // an address-less filter would match every contract on the chain
+        if self.contracts.is_empty() {
+            self.current_block = latest_block;
+            return;
+        }
+
+        let from_block = self.current_block + 1;
+        let to_block = latest_block;
+
+        // blocks still held by the shared block stream are filtered locally instead of queried.
+        // blocks whose bloom rules our logs out don't need their logs fetched at all.
+        let interest = self.interest();
+        let cached = monitor
+            .stream
+            .as_ref()
+            .and_then(|stream| stream.cached_range(from_block, to_block, &interest));
+
+        // this checks the first new block still builds on the last block we scanned.
+        let parent_hash = match &cached {
+            Some(blocks) => blocks[0].block.header.parent_hash,
+            None => match monitor
+                .pool
+                .request(|p| async move { p.get_block_by_number(from_block.into()).await })
+                .await
+            {
+                Ok(Some(block)) => block.header.parent_hash,
+                Ok(None) => return,
+                Err(e) => {
+                    eprintln!("Error fetching block {}: {}", from_block, e);
+                    return;
+                }
+            },
+        };
+
+        if !self.window.extends(from_block, parent_hash) {
+            match find_common_ancestor(&monitor.pool, &self.window).await {
+                Ok(ancestor) => {
+                    println!(
+                        "EventsMonitor: Reorg detected at block {}, rewinding to {}",
+                        from_block, ancestor
+                    );
+                    self.rewind(ancestor, handler);
+                }
+                Err(e) => eprintln!("Error resolving reorg: {}", e),
+            }
+            return;
+        }
+
+        let (logs, tip_hash) = match cached {
+            Some(blocks) => {
+                let logs = blocks
+                    .iter()
+                    .flat_map(|data| data.logs.iter().flatten())
+                    .filter(|log| self.matches(log))
+                    .cloned()
+                    .collect();
+                (logs, blocks.last().map(|data| data.block.header.hash))
+            }
+            None => {
                 // this builds a filter to query for logs in the specified block range.
-                let filter = Filter::new()
-                    .address(self.contract_address)
-                    .event_signature(topics.clone())
-                    .from_block(from_block)
-                    .to_block(to_block);
+                let filter = self.filter().from_block(from_block).to_block(to_block);
 
                 // this fetches the logs from the provider.
-                match self.provider.get_logs(&filter).await {
-                    Ok(logs) => {
-                        for log in logs {
-                            handler(log);
-                        }
+                let logs = match monitor
+                    .pool
+                    .request(|p| {
+                        let filter = filter.clone();
+                        async move { p.get_logs(&filter).await }
+                    })
+                    .await
+                {
+                    Ok(logs) => logs,
+                    Err(e) => {
+                        eprintln!("Error fetching logs: {}", e);
+                        return;
                     }
-                    Err(e) => eprintln!("Error fetching logs: {}", e),
-                }
+                };
+
+                let tip = monitor
+                    .pool
+                    .request(|p| async move { p.get_block_by_number(to_block.into()).await })
+                    .await;
+                (logs, tip.ok().flatten().map(|block| block.header.hash))
+            }
+        };
+
+        // high-value monitors only act on a range whose tip a quorum of endpoints agree on
+        if monitor.quorum
+            && (tip_hash.is_none() || monitor.pool.quorum_block_hash(to_block).await != tip_hash)
+        {
+            eprintln!(
+                "EventsMonitor: No quorum on block {} yet, retrying",
+                to_block
+            );
+            return;
+        }
+
+        for log in logs {
+            self.deliver(log, handler);
+        }
+
+        // this records the tip of the range, so the next poll can check its parent hash.
+        if let Some(hash) = tip_hash {
+            self.window.push(to_block, hash);
+        }
+        if let Some(oldest) = self.window.oldest() {
+            self.delivered = self.delivered.split_off(&oldest);
+        }
+        self.current_block = to_block;
+    }
+
+    /// Whether a log from a cached block belongs to the monitored contracts and events.
+    fn matches(&self, log: &Log) -> bool {
+        self.contracts
+            .iter()
+            .any(|contract| contract.address == log.address())
+            && (self.topics.is_empty()
+                || log
+                    .topic0()
+                    .is_some_and(|topic| self.topics.contains(topic)))
+    }
+
+    /// Hands a log to the handler and records it, skipping logs we already delivered.
+    pub fn deliver<F>(&mut self, log: Log, handler: &mut F)
+    where
+        F: FnMut(Log),
+    {
+        if let (Some(number), Some(hash)) = (log.block_number, log.block_hash) {
+            let seen = self.delivered.entry(number).or_default();
+            let is_same = |l: &Log| {
+                l.block_hash == log.block_hash
+                    && l.transaction_hash == log.transaction_hash
+                    && l.log_index == log.log_index
+            };
+
+            if log.removed {
+                seen.retain(|l| !is_same(l));
+            } else if seen.iter().any(is_same) {
+                return;
+            } else {
+                self.window.push(number, hash);
+                seen.push(log.clone());
             }
+        }
+
+        handler(log);
+    }
 
-            // wait for a short duration before the next poll.
-            sleep(Duration::from_secs(2)).await;
+    /// Marks every block up to `block` as scanned, used when logs arrive from a subscription.
+    pub fn advance_to(&mut self, block: u64) {
+        self.current_block = self.current_block.max(block);
+    }
+
+    /// Unwinds everything above `ancestor`, re-sending the delivered logs as removed.
+    fn rewind<F>(&mut self, ancestor: u64, handler: &mut F)
+    where
+        F: FnMut(Log),
+    {
+        self.window.truncate_from(ancestor + 1);
+        for (_, logs) in self.delivered.split_off(&(ancestor + 1)) {
+            for mut log in logs {
+                log.removed = true;
+                handler(log);
+            }
         }
+        self.current_block = ancestor;
     }
 }
 
@@ -0,0 +1,355 @@
+//! # Factory Tracking
+//! This module provides tools for following the contracts a factory creates.
+//!
+//! A `FactoryWatch` listens for the factory's creation event, reads the new child's address
+//! out of one of the event's arguments and adds the child to the monitor's contracts with the
+//! ABI configured for children, so the monitor's rules and events apply to it from the next
+//! scan on. A creation that is orphaned by a reorg removes the child again.
+//!
+//! The children found so far and the last block scanned for creations are kept in a small
+//! JSON file per chain id and factory. A monitor started on the same factory again picks its
+//! children up from there and carries on scanning where the last one stopped.
+
+use crate::events::{EventScanner, MAX_LOG_RANGE};
+use crate::primitives::models::FactoryConfig;
+use crate::primitives::utils::select_events;
+use crate::{ContractSet, MonitoredContract, PollingMonitor};
+use alloy::dyn_abi::{DynSolValue, EventExt};
+use alloy::json_abi::{Event, JsonAbi};
+use alloy::primitives::Address;
+use alloy::rpc::types::Log;
+use alloy_chains::Chain;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::time::sleep;
+
+/// Where factory state files go unless `FACTORY_STATE_DIR` says otherwise.
+pub const DEFAULT_FACTORY_STATE_DIR: &str = "data/factories";
+
+/// What we know about a factory, as persisted between runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FactoryState {
+    pub last_processed_block: u64,
+    pub children: Vec<Address>,
+}
+
+/// A factory whose children are added to a monitor.
+pub struct FactoryWatch {
+    pub address: Address,
+    pub abi: Arc<JsonAbi>,
+    pub child_abi: Arc<JsonAbi>,
+    event: Event,
+    argument: String,
+    path: PathBuf,
+    state: Mutex<Option<FactoryState>>, // `None` until the factory was scanned once
+}
+
+impl FactoryWatch {
+    /// Checks the creation event against the factory's ABI and loads what earlier runs
+    /// found out about the factory.
+    pub fn new(
+        chain: &str,
+        config: &FactoryConfig,
+        abi: JsonAbi,
+        child_abi: JsonAbi,
+    ) -> Result<Self, anyhow::Error> {
+        let dir = env::var("FACTORY_STATE_DIR").unwrap_or(DEFAULT_FACTORY_STATE_DIR.to_string());
+        Self::in_dir(PathBuf::from(dir), chain, config, abi, child_abi)
+    }
+
+    fn in_dir(
+        dir: PathBuf,
+        chain: &str,
+        config: &FactoryConfig,
+        abi: JsonAbi,
+        child_abi: JsonAbi,
+    ) -> Result<Self, anyhow::Error> {
+        // a plain name takes the first overload, a signature picks one
+        let event = select_events(&abi, &config.event)
+            .into_iter()
+            .next()
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Factory ABI has no event {}", config.event))?;
+
+        if !event
+            .inputs
+            .iter()
+            .any(|input| input.name == config.argument && input.ty == "address")
+        {
+            return Err(anyhow::anyhow!(
+                "Event {} has no address argument {}",
+                config.event,
+                config.argument
+            ));
+        }
+
+        // by chain id, the chain's name comes from the request
+        let chain_id = Chain::from_str(chain)
+            .map_err(|_| anyhow::anyhow!("Unknown chain {}", chain))?
+            .id();
+        let path = dir.join(format!("{}-{:?}.json", chain_id, config.address));
+        let state = match fs::read(&path) {
+            Ok(bytes) => Some(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            address: config.address,
+            abi: Arc::new(abi),
+            child_abi: Arc::new(child_abi),
+            event,
+            argument: config.argument.clone(),
+            path,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Every child found so far.
+    pub fn children(&self) -> Vec<Address> {
+        self.state
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|state| state.children.clone())
+            .unwrap_or_default()
+    }
+
+    /// The child address a creation event carries.
+    pub fn child_address(&self, log: &Log) -> Option<Address> {
+        if log.topic0() != Some(&self.event.selector()) {
+            return None;
+        }
+
+        let decoded = self.event.decode_log(log.data()).ok()?;
+        let mut indexed = decoded.indexed.into_iter();
+        let mut body = decoded.body.into_iter();
+
+        for input in &self.event.inputs {
+            let value = if input.indexed {
+                indexed.next()
+            } else {
+                body.next()
+            };
+            if input.name == self.argument {
+                return match value {
+                    Some(DynSolValue::Address(address)) => Some(address),
+                    _ => None,
+                };
+            }
+        }
+        None
+    }
+
+    // Persisting
+
+    fn update(&self, change: impl FnOnce(&mut FactoryState)) {
+        let mut state = self.state.lock().unwrap();
+        let state = state.get_or_insert_with(FactoryState::default);
+        change(state);
+
+        if let Err(e) = self.save(state) {
+            eprintln!("Error saving factory state to {:?}: {}", self.path, e);
+        }
+    }
+
+    fn save(&self, state: &FactoryState) -> Result<(), anyhow::Error> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // write next to the file and swap it in, so a crash can't leave half a file behind
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(state)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    fn last_processed_block(&self) -> Option<u64> {
+        self.state
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|state| state.last_processed_block)
+    }
+}
+
+/// Scans the factory for creation events and keeps the monitor's contracts in step with them.
+/// Picks up from the last block an earlier run scanned, or from the chain head the first time.
+pub async fn follow_factory(
+    monitor: PollingMonitor,
+    factory: Arc<FactoryWatch>,
+    name: String,
+) -> Result<(), anyhow::Error> {
+    println!(
+        "FactoryMonitor: Following contracts created by {:?}",
+        factory.address
+    );
+
+    // the factory is scanned on its own, its logs aren't the monitor's
+    let factory_monitor = PollingMonitor {
+        contracts: Arc::new(ContractSet::new(vec![MonitoredContract::new(
+            factory.address,
+            factory.abi.clone(),
+        )])),
+        transfers: None,
+        factory: None,
+        ..monitor.clone()
+    };
+
+    let start_block = match factory.last_processed_block() {
+        Some(block) => block,
+        None => factory_monitor.processing_head().await?,
+    };
+    // by signature, the factory's other overloads of the event don't announce children
+    let signature = factory.event.signature();
+    let mut scanner = EventScanner::new(&factory_monitor, &[signature.as_str()], start_block);
+
+    let mut on_log = |log: Log| {
+        let Some(child) = factory.child_address(&log) else {
+            return;
+        };
+
+        if log.removed {
+            // the creation was orphaned, so was the child
+            if monitor.contracts.remove(child) {
+                println!("[FACTORY] {}: Dropping orphaned child {:?}", name, child);
+                factory.update(|state| state.children.retain(|c| *c != child));
+            }
+        } else if monitor.contracts.add(
+            // the monitor's scanners may be past the creation already, they go back for it
+            MonitoredContract::new(child, factory.child_abi.clone())
+                .starting_at(log.block_number.unwrap_or_default()),
+        ) {
+            println!("[FACTORY] {}: Monitoring new child {:?}", name, child);
+            factory.update(|state| state.children.push(child));
+        }
+    };
+
+    loop {
+        match factory_monitor.processing_head().await {
+            Ok(latest_block) => {
+                // a chunk at a time, so a long catch-up is saved as it goes
+                while scanner.current_block() < latest_block {
+                    let before = scanner.current_block();
+                    let target = latest_block.min(before + MAX_LOG_RANGE);
+                    scanner.scan_to(&factory_monitor, target, &mut on_log).await;
+
+                    let scanned = scanner.current_block();
+                    if factory.last_processed_block() != Some(scanned) {
+                        factory.update(|state| state.last_processed_block = scanned);
+                    }
+                    if scanned <= before {
+                        break; // failed or rewound, try again on the next poll
+                    }
+                }
+            }
+            Err(e) => eprintln!("Error fetching block number: {}", e),
+        }
+
+        sleep(factory_monitor.poll_interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::{LogData, U256};
+    use std::path::Path;
+
+    const EVENT: &str = "event PairCreated(address indexed token0, address indexed token1, address pair, uint256 index)";
+
+    fn config() -> FactoryConfig {
+        serde_json::from_value(serde_json::json!({
+            "address": format!("{:?}", Address::repeat_byte(0xfa)),
+            "event": "PairCreated",
+            "argument": "pair",
+        }))
+        .unwrap()
+    }
+
+    fn factory_watch(dir: &Path, chain: &str) -> Result<FactoryWatch, anyhow::Error> {
+        let abi = JsonAbi::parse([EVENT]).unwrap();
+        FactoryWatch::in_dir(dir.to_path_buf(), chain, &config(), abi, JsonAbi::default())
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("factory-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn creation(event: &Event, pair: Address) -> Log {
+        let topics = vec![
+            event.selector(),
+            Address::repeat_byte(1).into_word(),
+            Address::repeat_byte(2).into_word(),
+        ];
+        let data = DynSolValue::Tuple(vec![
+            DynSolValue::Address(pair),
+            DynSolValue::Uint(U256::from(7), 256),
+        ])
+        .abi_encode_params();
+        Log {
+            inner: alloy::primitives::Log {
+                address: Address::repeat_byte(0xfa),
+                data: LogData::new_unchecked(topics, data.into()),
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reads_the_child_out_of_the_creation_event() {
+        let watch = factory_watch(&scratch_dir("child"), "mainnet").unwrap();
+        let log = creation(&watch.event, Address::repeat_byte(3));
+        assert_eq!(watch.child_address(&log), Some(Address::repeat_byte(3)));
+
+        // another event of the factory
+        let mut other = log.clone();
+        other.inner.data = LogData::new_unchecked(
+            vec![alloy::primitives::B256::repeat_byte(9)],
+            other.inner.data.data.clone(),
+        );
+        assert_eq!(watch.child_address(&other), None);
+    }
+
+    #[test]
+    fn rejects_an_argument_that_is_not_an_address() {
+        let mut config = config();
+        config.argument = "index".to_string();
+        let abi = JsonAbi::parse([EVENT]).unwrap();
+        let dir = scratch_dir("argument");
+        assert!(FactoryWatch::in_dir(dir, "mainnet", &config, abi, JsonAbi::default()).is_err());
+    }
+
+    #[test]
+    fn keeps_its_state_between_runs() {
+        let dir = scratch_dir("state");
+        let watch = factory_watch(&dir, "mainnet").unwrap();
+        assert_eq!(watch.last_processed_block(), None);
+        watch.update(|state| state.children.push(Address::repeat_byte(3)));
+        watch.update(|state| state.last_processed_block = 42);
+
+        // stored by chain id
+        assert!(
+            dir.join(format!("1-{:?}.json", Address::repeat_byte(0xfa)))
+                .is_file()
+        );
+
+        let again = factory_watch(&dir, "1").unwrap();
+        assert_eq!(again.children(), vec![Address::repeat_byte(3)]);
+        assert_eq!(again.last_processed_block(), Some(42));
+    }
+
+    #[test]
+    fn rejects_chains_it_does_not_know() {
+        let dir = scratch_dir("chain");
+        assert!(factory_watch(&dir, "../../tmp/x").is_err());
+        assert!(!dir.exists());
+    }
+}
@@ -1,81 +1,373 @@
-use crate::primitives::models::{Condition, MonitorRule, Operator};
-use alloy::consensus::Transaction;
+use crate::MonitoredContract;
+use crate::batch::InnerCall;
+use crate::bloom::LogInterest;
+use crate::deploy::is_deployment;
+use crate::primitives::models::{Condition, MonitorRule, Operator, TxStatus};
+use crate::primitives::utils::{parse_selector, select_events};
+use crate::revert::RevertReason;
+use crate::trace::InternalCall;
+use alloy::consensus::{Transaction, Typed2718};
 use alloy::dyn_abi::{DynSolValue, JsonAbiExt};
-use alloy::network::{AnyRpcTransaction, TransactionResponse};
-use alloy::primitives::{Address, U256};
+use alloy::json_abi::JsonAbi;
+use alloy::network::{
+    AnyRpcTransaction, AnyTransactionReceipt, ReceiptResponse, TransactionResponse,
+};
+use alloy::primitives::utils::parse_units;
+use alloy::primitives::{Address, B256, Bytes, U256};
 use std::str::FromStr;
 
 impl MonitorRule {
+    /// Checks the conditions on the transaction itself. Conditions on the receipt are left
+    /// to `receipt_match`.
     pub fn tx_match(&self, tx: &AnyRpcTransaction) -> bool {
-        for condition in &self.conditions {
-            match condition {
-                Condition::From(expected) => {
-                    if tx.from() != *expected {
-                        return false;
-                    }
-                }
-                Condition::To(expected) => {
-                    if tx.to().unwrap() != *expected {
-                        return false;
-                    }
-                }
+        self.conditions_match(tx, None)
+    }
 
-                Condition::Function(expected) => {
-                    if let Some(func_abi) = &self.abi_function {
-                        if func_abi.name != *expected {
-                            return false;
-                        }
+    /// Checks the conditions on a call made inside the transaction. Sender, recipient,
+    /// function, arguments and value are the call's, everything else is the transaction's.
+    pub fn call_match(&self, tx: &AnyRpcTransaction, call: &InternalCall) -> bool {
+        self.conditions_match(tx, Some((call.from, call.to, &call.input, call.value)))
+    }
 
-                        let selector = func_abi.selector();
-                        if !tx.input().starts_with(selector.as_slice()) {
-                            return false;
-                        }
-                    }
+    /// Checks the conditions on a call a batching wrapper in the transaction made, the same
+    /// way `call_match` does for traced calls.
+    pub fn inner_match(&self, tx: &AnyRpcTransaction, call: &InnerCall) -> bool {
+        self.conditions_match(
+            tx,
+            Some((call.from, Some(call.to), &call.input, call.value)),
+        )
+    }
+
+    // `call` is the sender, recipient, input and value of a call inside the transaction
+    fn conditions_match(
+        &self,
+        tx: &AnyRpcTransaction,
+        call: Option<(Address, Option<Address>, &Bytes, U256)>,
+    ) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| self.condition_match(condition, tx, call))
+    }
+
+    /// Checks one of the rule's conditions on the transaction itself, the way `tx_match`
+    /// does. Conditions on the receipt pass here.
+    pub fn tx_condition_match(&self, condition: &Condition, tx: &AnyRpcTransaction) -> bool {
+        self.condition_match(condition, tx, None)
+    }
+
+    /// Checks one of the rule's conditions on a traced call, the way `call_match` does.
+    pub fn call_condition_match(
+        &self,
+        condition: &Condition,
+        tx: &AnyRpcTransaction,
+        call: &InternalCall,
+    ) -> bool {
+        self.condition_match(
+            condition,
+            tx,
+            Some((call.from, call.to, &call.input, call.value)),
+        )
+    }
+
+    /// Checks one of the rule's conditions on a call a batching wrapper made, the way
+    /// `inner_match` does.
+    pub fn inner_condition_match(
+        &self,
+        condition: &Condition,
+        tx: &AnyRpcTransaction,
+        call: &InnerCall,
+    ) -> bool {
+        self.condition_match(
+            condition,
+            tx,
+            Some((call.from, Some(call.to), &call.input, call.value)),
+        )
+    }
+
+    fn condition_match(
+        &self,
+        condition: &Condition,
+        tx: &AnyRpcTransaction,
+        call: Option<(Address, Option<Address>, &Bytes, U256)>,
+    ) -> bool {
+        let (from, to, input, sent) =
+            call.unwrap_or_else(|| (tx.from(), tx.to(), tx.input(), tx.value()));
+
+        match condition {
+            Condition::From(expected) => from == *expected,
+            // contract deployments have no `to`
+            Condition::To(expected) => to == Some(*expected),
+            // only deployments sent as a transaction count
+            Condition::Deploys(deployer) => {
+                call.is_none() && tx.from() == *deployer && is_deployment(tx)
+            }
+
+            Condition::Function(expected) => {
+                // any of the overloads the condition picked, a selector the ABI doesn't
+                // know is still checked as it is, and a name it doesn't know never matches
+                if !self.abi_functions.is_empty() {
+                    self.abi_functions
+                        .iter()
+                        .any(|f| input.starts_with(f.selector().as_slice()))
+                } else if let Some(selector) = parse_selector(expected) {
+                    input.starts_with(&selector)
+                } else {
+                    false
                 }
-                Condition::Argument {
-                    name,
-                    operator,
-                    value,
-                } => {
-                    let func_abi = match &self.abi_function {
-                        Some(f) => f,
-                        None => return false,
-                    };
-
-                    //we ensure that we are filtering non-function interactions such as eth-transfer
-                    if tx.input().len() < 4 {
-                        return false;
-                    }
+            }
+            Condition::Argument {
+                name,
+                operator,
+                value,
+            } => self
+                .decoded_argument(input, name)
+                .is_some_and(|actual_value| check_value(&actual_value, operator, value)),
+
+            Condition::Value { operator, value } => {
+                U256::from_str(value).is_ok_and(|expected| compare(sent, operator, expected))
+            }
+            Condition::GasPrice { operator, value } => {
+                compare_gwei(effective_gas_price(tx), operator, value)
+            }
+            Condition::MaxPriorityFee { operator, value } => tx
+                .max_priority_fee_per_gas()
+                .is_some_and(|tip| compare_gwei(tip, operator, value)),
+            Condition::TxType(expected) => tx.ty() == expected.type_byte(),
+            Condition::Nonce { operator, value } => compare(tx.nonce(), operator, *value),
+
+            Condition::Status(_)
+            | Condition::GasUsed { .. }
+            | Condition::EmitsEvent(_)
+            | Condition::Error(_)
+            | Condition::ErrorArgument { .. } => true,
+        }
+    }
+
+    /// The value of the argument `name` in `input`, decoded against whichever of the rule's
+    /// functions was called.
+    pub fn decoded_argument(&self, input: &[u8], name: &str) -> Option<DynSolValue> {
+        // the overload that was called
+        let func_abi = self
+            .abi_functions
+            .iter()
+            .find(|f| input.starts_with(f.selector().as_slice()))?;
 
-                    // here we are slicing away the function selector(first 4bytes)
-                    // to ensure we only get the actual tx parameters
-                    let input_data = &tx.input()[4..];
-
-                    if let Ok(decoded_input) = func_abi.abi_decode_input(input_data) {
-                        //this gets the index of an argument based on the name of the argument provided
-                        // e.g. if we need to filter based on the amount arg, it tells us what
-                        // index in the tx amount is at
-                        let arg_index = func_abi.inputs.iter().position(|i| i.name == *name);
-
-                        match arg_index {
-                            Some(idx) => {
-                                let actual_value = &decoded_input[idx];
-                                if !check_value(actual_value, operator, value) {
-                                    return false;
-                                }
-                            }
-                            None => return false,
+        //we ensure that we are filtering non-function interactions such as eth-transfer
+        if input.len() < 4 {
+            return None;
+        }
+
+        // here we are slicing away the function selector(first 4bytes)
+        // to ensure we only get the actual tx parameters
+        let decoded_input = func_abi.abi_decode_input(&input[4..]).ok()?;
+
+        //this gets the index of an argument based on the name of the argument provided
+        // e.g. if we need to filter based on the amount arg, it tells us what
+        // index in the tx amount is at
+        let arg_index = func_abi.inputs.iter().position(|i| i.name == name)?;
+        decoded_input.into_iter().nth(arg_index)
+    }
+
+    /// Whether any condition of this rule can only be checked against the receipt.
+    pub fn needs_receipt(&self) -> bool {
+        self.conditions.iter().any(|condition| {
+            matches!(
+                condition,
+                Condition::Status(_)
+                    | Condition::GasUsed { .. }
+                    | Condition::EmitsEvent(_)
+                    | Condition::Deploys(_)
+            )
+        }) || self.needs_revert()
+    }
+
+    /// Whether any condition of this rule is on the error the transaction reverted with.
+    pub fn needs_revert(&self) -> bool {
+        self.conditions.iter().any(|condition| {
+            matches!(
+                condition,
+                Condition::Error(_) | Condition::ErrorArgument { .. }
+            )
+        })
+    }
+
+    /// Checks the conditions on the transaction's receipt, the ones `tx_match` skips.
+    pub fn receipt_match(
+        &self,
+        receipt: &AnyTransactionReceipt,
+        contracts: &[MonitoredContract],
+    ) -> bool {
+        self.outcome_match(receipt, contracts, receipt.status())
+    }
+
+    /// Checks the receipt conditions for a call a batching wrapper made. A bundle's
+    /// transaction goes through even when one of its user operations fails, so for a call
+    /// made for a user operation the status is the operation's.
+    pub fn inner_receipt_match(
+        &self,
+        receipt: &AnyTransactionReceipt,
+        contracts: &[MonitoredContract],
+        call: &InnerCall,
+    ) -> bool {
+        self.outcome_match(receipt, contracts, call_succeeded(receipt, call))
+    }
+
+    // `succeeded` is the status `Status` conditions are checked against
+    fn outcome_match(
+        &self,
+        receipt: &AnyTransactionReceipt,
+        contracts: &[MonitoredContract],
+        succeeded: bool,
+    ) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| outcome_condition_match(condition, receipt, contracts, succeeded))
+    }
+
+    /// Checks one of the rule's conditions on the receipt, the way `receipt_match` does.
+    /// Conditions on the transaction itself pass here.
+    pub fn receipt_condition_match(
+        &self,
+        condition: &Condition,
+        receipt: &AnyTransactionReceipt,
+        contracts: &[MonitoredContract],
+    ) -> bool {
+        outcome_condition_match(condition, receipt, contracts, receipt.status())
+    }
+
+    /// Checks one of the rule's conditions on the receipt for a call a batching wrapper made,
+    /// the way `inner_receipt_match` does.
+    pub fn inner_receipt_condition_match(
+        &self,
+        condition: &Condition,
+        receipt: &AnyTransactionReceipt,
+        contracts: &[MonitoredContract],
+        call: &InnerCall,
+    ) -> bool {
+        outcome_condition_match(condition, receipt, contracts, call_succeeded(receipt, call))
+    }
+
+    /// Checks the conditions on the decoded revert of a transaction that passed `receipt_match`.
+    pub fn revert_match(&self, reason: Option<&RevertReason>) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| revert_condition_match(condition, reason))
+    }
+
+    /// The logs a block has to contain for this rule to match, if the rule requires one.
+    /// Blocks whose bloom rules these out can't hold a match.
+    pub fn log_interest(&self, contracts: &[MonitoredContract]) -> Option<LogInterest> {
+        let mut topics: Vec<B256> = Vec::new();
+        for condition in &self.conditions {
+            if let Condition::EmitsEvent(name) = condition {
+                for contract in contracts {
+                    for topic in event_selectors(&contract.abi, name) {
+                        if !topics.contains(&topic) {
+                            topics.push(topic);
                         }
-                    } else {
-                        return false;
                     }
                 }
             }
         }
-        return true;
+
+        if topics.is_empty() {
+            return None;
+        }
+        let addresses = contracts.iter().map(|contract| contract.address).collect();
+        Some(LogInterest::new(addresses, topics))
     }
 }
 
+/// Whether a call a batching wrapper made went through, the status of its user operation
+/// when it was made for one.
+pub fn call_succeeded(receipt: &AnyTransactionReceipt, call: &InnerCall) -> bool {
+    match &call.user_op {
+        Some(op) => op.outcome(receipt).unwrap_or(false),
+        None => receipt.status(),
+    }
+}
+
+// `succeeded` is the status `Status` conditions are checked against
+fn outcome_condition_match(
+    condition: &Condition,
+    receipt: &AnyTransactionReceipt,
+    contracts: &[MonitoredContract],
+    succeeded: bool,
+) -> bool {
+    match condition {
+        Condition::Status(TxStatus::Success) => succeeded,
+        Condition::Status(TxStatus::Reverted) => !succeeded,
+        Condition::GasUsed { operator, value } => compare(receipt.gas_used(), operator, *value),
+        Condition::EmitsEvent(name) => receipt.inner.logs().iter().any(|log| {
+            contracts.iter().any(|contract| {
+                log.address() == contract.address
+                    && log
+                        .topic0()
+                        .is_some_and(|topic| event_selectors(&contract.abi, name).contains(topic))
+            })
+        }),
+        // a reverted deployment didn't deploy anything
+        Condition::Deploys(_) => receipt.status(),
+        // only a call to one of the contracts that reverted can revert with its errors
+        Condition::Error(_) | Condition::ErrorArgument { .. } => {
+            !receipt.status()
+                && receipt
+                    .to()
+                    .is_some_and(|to| contracts.iter().any(|c| c.address == to))
+        }
+        _ => true,
+    }
+}
+
+/// Checks a condition on the error a transaction reverted with, the way `revert_match` does.
+/// Every other condition passes here.
+pub fn revert_condition_match(condition: &Condition, reason: Option<&RevertReason>) -> bool {
+    match condition {
+        Condition::Error(expected) => reason.is_some_and(|reason| reason.name == *expected),
+        Condition::ErrorArgument {
+            name,
+            operator,
+            value,
+        } => reason
+            .and_then(|reason| reason.arguments.iter().find(|(arg, _)| arg == name))
+            .is_some_and(|(_, actual)| check_value(actual, operator, value)),
+        _ => true,
+    }
+}
+
+// numeric comparison, `Contains` makes no sense for numbers
+fn compare<T: PartialOrd>(actual: T, operator: &Operator, expected: T) -> bool {
+    match operator {
+        Operator::Gt => actual > expected,
+        Operator::Lt => actual < expected,
+        Operator::Eq => actual == expected,
+        Operator::Contains => false,
+    }
+}
+
+/// What a transaction paid per gas. Nodes report what a mined transaction actually paid,
+/// the fee cap is the most it could have paid.
+pub fn effective_gas_price(tx: &AnyRpcTransaction) -> u128 {
+    tx.inner
+        .effective_gas_price
+        .unwrap_or_else(|| Transaction::max_fee_per_gas(tx))
+}
+
+fn compare_gwei(actual_wei: u128, operator: &Operator, gwei: &str) -> bool {
+    match parse_units(gwei, "gwei") {
+        Ok(expected) => compare(U256::from(actual_wei), operator, expected.get_absolute()),
+        Err(_) => false,
+    }
+}
+
+// every overload of the event counts, unless it was picked by signature
+fn event_selectors(abi: &JsonAbi, name: &str) -> Vec<B256> {
+    select_events(abi, name)
+        .into_iter()
+        .map(|event| event.selector())
+        .collect()
+}
+
 pub fn check_value(actual_value: &DynSolValue, operator: &Operator, value: &str) -> bool {
     match actual_value {
         //numeric check
@@ -109,3 +401,248 @@ pub fn check_value(actual_value: &DynSolValue, operator: &Operator, value: &str)
         _ => false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::models::TxType;
+    use crate::testing::{log, receipt, transaction};
+    use alloy::primitives::address;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    const GWEI: u128 = 1_000_000_000;
+
+    fn rule(conditions: Vec<Condition>) -> MonitorRule {
+        MonitorRule {
+            name: "test".to_string(),
+            conditions,
+            confirmations: None,
+            spawn: None,
+            abi_functions: Vec::new(),
+        }
+    }
+
+    fn condition(json: serde_json::Value) -> Condition {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn legacy(gas_price: u128) -> AnyRpcTransaction {
+        transaction(json!({
+            "type": "0x0",
+            "gasPrice": format!("{:#x}", gas_price),
+            "maxFeePerGas": null,
+            "maxPriorityFeePerGas": null,
+            "accessList": null,
+            "yParity": null,
+            "v": "0x25",
+        }))
+    }
+
+    #[test]
+    fn compares_gwei_with_decimals() {
+        let price = 1_500_000_000;
+        assert!(compare_gwei(price, &Operator::Eq, "1.5"));
+        assert!(compare_gwei(price, &Operator::Gt, "1.4999"));
+        assert!(compare_gwei(price, &Operator::Lt, "2"));
+        assert!(!compare_gwei(price, &Operator::Gt, "1.5"));
+        assert!(compare_gwei(1, &Operator::Eq, "0.000000001"));
+
+        assert!(!compare_gwei(price, &Operator::Eq, "one and a half"));
+        assert!(!compare_gwei(price, &Operator::Contains, "1.5"));
+    }
+
+    #[test]
+    fn reads_what_a_transaction_paid_per_gas() {
+        assert_eq!(effective_gas_price(&legacy(20 * GWEI)), 20 * GWEI);
+
+        // a mined 1559 transaction reports the price it paid, below its fee cap
+        assert_eq!(effective_gas_price(&transaction(json!({}))), 3 * GWEI / 2);
+
+        let pending = transaction(
+            json!({ "gasPrice": null, "blockHash": null, "blockNumber": null, "transactionIndex": null }),
+        );
+        assert_eq!(effective_gas_price(&pending), 2 * GWEI);
+    }
+
+    #[test]
+    fn matches_fee_conditions() {
+        let tx = transaction(json!({}));
+        let matches = |json| rule(vec![condition(json)]).tx_match(&tx);
+
+        assert!(matches(
+            json!({ "GasPrice": { "operator": "Eq", "value": "1.5" } })
+        ));
+        assert!(!matches(
+            json!({ "GasPrice": { "operator": "Gt", "value": "1.5" } })
+        ));
+        assert!(matches(
+            json!({ "MaxPriorityFee": { "operator": "Eq", "value": "1" } })
+        ));
+        assert!(matches(
+            json!({ "MaxPriorityFee": { "operator": "Lt", "value": "1.01" } })
+        ));
+
+        // a legacy transaction has no tip to compare
+        let condition =
+            condition(json!({ "MaxPriorityFee": { "operator": "Lt", "value": "100" } }));
+        assert!(!rule(vec![condition]).tx_match(&legacy(GWEI)));
+    }
+
+    #[test]
+    fn matches_value_and_nonce() {
+        let tx = transaction(json!({ "value": "0xde0b6b3a7640000", "nonce": "0x7" }));
+        let matches = |json| rule(vec![condition(json)]).tx_match(&tx);
+
+        assert!(matches(
+            json!({ "Value": { "operator": "Eq", "value": "1000000000000000000" } })
+        ));
+        assert!(matches(
+            json!({ "Value": { "operator": "Gt", "value": "0" } })
+        ));
+        assert!(!matches(
+            json!({ "Value": { "operator": "Lt", "value": "1000" } })
+        ));
+        assert!(!matches(
+            json!({ "Value": { "operator": "Gt", "value": "a lot" } })
+        ));
+
+        assert!(matches(
+            json!({ "Nonce": { "operator": "Eq", "value": 7 } })
+        ));
+        assert!(matches(
+            json!({ "Nonce": { "operator": "Lt", "value": 8 } })
+        ));
+        assert!(!matches(
+            json!({ "Nonce": { "operator": "Gt", "value": 7 } })
+        ));
+    }
+
+    #[test]
+    fn checks_the_value_of_an_inner_call() {
+        let tx = transaction(json!({ "value": "0x0" }));
+        let call = InternalCall {
+            tx_hash: B256::ZERO,
+            path: vec![0],
+            call_type: "CALL".to_string(),
+            from: Address::repeat_byte(0x02),
+            to: Some(Address::repeat_byte(0x03)),
+            value: U256::from(5),
+            input: Bytes::new(),
+            reverted: false,
+        };
+        let rule = rule(vec![condition(
+            json!({ "Value": { "operator": "Eq", "value": "5" } }),
+        )]);
+
+        assert!(rule.call_match(&tx, &call));
+        assert!(!rule.tx_match(&tx));
+    }
+
+    #[test]
+    fn reads_tx_types_by_their_eip_numbers() {
+        for (name, tx_type, byte) in [
+            ("legacy", TxType::Legacy, 0),
+            ("2930", TxType::AccessList, 1),
+            ("1559", TxType::Eip1559, 2),
+            ("4844", TxType::Blob, 3),
+            ("7702", TxType::SetCode, 4),
+        ] {
+            assert_eq!(
+                serde_json::from_value::<TxType>(json!(name)).unwrap(),
+                tx_type
+            );
+            assert_eq!(tx_type.type_byte(), byte);
+        }
+        assert!(serde_json::from_value::<TxType>(json!("Eip1559")).is_err());
+
+        let is_1559 = rule(vec![condition(json!({ "TxType": "1559" }))]);
+        assert!(is_1559.tx_match(&transaction(json!({}))));
+        assert!(!is_1559.tx_match(&legacy(GWEI)));
+        let is_legacy = rule(vec![condition(json!({ "TxType": "legacy" }))]);
+        assert!(is_legacy.tx_match(&legacy(GWEI)));
+    }
+
+    const TOKEN: Address = address!("0x00000000000000000000000000000000000000a0");
+
+    fn token() -> MonitoredContract {
+        let abi = JsonAbi::parse([
+            "event Transfer(address indexed from, address indexed to, uint256 value)",
+            "event Approval(address indexed owner, address indexed spender, uint256 value)",
+        ])
+        .unwrap();
+        MonitoredContract::new(TOKEN, Arc::new(abi))
+    }
+
+    fn transfer_topic() -> B256 {
+        token().abi.event("Transfer").unwrap()[0].selector()
+    }
+
+    #[test]
+    fn matches_the_status() {
+        let succeeded = receipt(json!({}));
+        let reverted = receipt(json!({ "status": "0x0" }));
+        let success = rule(vec![condition(json!({ "Status": "Success" }))]);
+        let revert = rule(vec![condition(json!({ "Status": "Reverted" }))]);
+
+        assert!(success.receipt_match(&succeeded, &[]));
+        assert!(!success.receipt_match(&reverted, &[]));
+        assert!(revert.receipt_match(&reverted, &[]));
+        assert!(!revert.receipt_match(&succeeded, &[]));
+
+        // conditions on the transaction itself are left to `tx_match`
+        let from = rule(vec![condition(
+            json!({ "From": Address::repeat_byte(0x09) }),
+        )]);
+        assert!(from.receipt_match(&succeeded, &[]));
+    }
+
+    #[test]
+    fn matches_the_gas_used() {
+        let receipt = receipt(json!({ "gasUsed": "0xc350" }));
+        let matches = |json| rule(vec![condition(json)]).receipt_match(&receipt, &[]);
+
+        assert!(matches(
+            json!({ "GasUsed": { "operator": "Eq", "value": 50000 } })
+        ));
+        assert!(matches(
+            json!({ "GasUsed": { "operator": "Gt", "value": 21000 } })
+        ));
+        assert!(!matches(
+            json!({ "GasUsed": { "operator": "Lt", "value": 50000 } })
+        ));
+    }
+
+    #[test]
+    fn matches_events_emitted_by_the_monitored_contracts() {
+        let emits = rule(vec![condition(json!({ "EmitsEvent": "Transfer" }))]);
+        let with_log =
+            |address, topic| receipt(json!({ "logs": [log(address, &[topic], Bytes::new())] }));
+
+        assert!(emits.receipt_match(&with_log(TOKEN, transfer_topic()), &[token()]));
+        // the same event from a contract nobody monitors
+        assert!(!emits.receipt_match(
+            &with_log(Address::repeat_byte(0x09), transfer_topic()),
+            &[token()]
+        ));
+        // another event from the monitored contract
+        let approval = token().abi.event("Approval").unwrap()[0].selector();
+        assert!(!emits.receipt_match(&with_log(TOKEN, approval), &[token()]));
+        assert!(!emits.receipt_match(&receipt(json!({})), &[token()]));
+
+        // an event the ABI doesn't have never matches
+        let unknown = rule(vec![condition(json!({ "EmitsEvent": "Burn" }))]);
+        assert!(!unknown.receipt_match(&with_log(TOKEN, transfer_topic()), &[token()]));
+    }
+
+    #[test]
+    fn needs_a_reverted_call_to_a_monitored_contract_for_errors() {
+        let error = rule(vec![condition(json!({ "Error": "InsufficientBalance" }))]);
+        assert!(error.needs_receipt());
+
+        let reverted = receipt(json!({ "status": "0x0", "to": TOKEN }));
+        assert!(error.receipt_match(&reverted, &[token()]));
+        assert!(!error.receipt_match(&receipt(json!({ "to": TOKEN })), &[token()]));
+        assert!(!error.receipt_match(&receipt(json!({ "status": "0x0" })), &[token()]));
+    }
+}
@@ -1,23 +1,66 @@
+pub mod abi;
+pub mod backtest;
+pub mod batch;
+pub mod bloom;
+pub mod contracts;
+pub mod deploy;
+pub mod evaluate;
 pub mod events;
+pub mod factory;
 pub mod filter;
+pub mod mempool;
+pub mod pool;
+pub mod prefetch;
 pub mod primitives;
+pub mod ratelimit;
+pub mod receipts;
+pub mod reorg;
+pub mod revert;
+pub mod stream;
+pub mod subscription;
+#[cfg(test)]
+mod testing;
+pub mod trace;
+pub mod transfers;
 pub mod tx;
+pub mod userop;
+pub mod validate;
 
+pub use contracts::{ContractSet, MonitoredContract};
 pub use events::EventMonitor;
+pub use mempool::MempoolMonitor;
+pub use stream::StreamMonitor;
+pub use subscription::SubscriptionMonitor;
 pub use tx::TransactionMonitor;
 
-use crate::primitives::models::MonitorRule;
-use crate::tx::get_tx_details;
+use crate::factory::{FactoryWatch, follow_factory};
+use crate::mempool::{PendingMatch, PendingStatus};
+use crate::pool::ProviderPool;
+use crate::prefetch::DEFAULT_FETCH_CONCURRENCY;
+use crate::primitives::models::{BlockTarget, ChildMonitorConfig, MonitorRule};
+use crate::primitives::utils::DEFAULT_POLL_INTERVAL;
+use crate::ratelimit::attribute;
+use crate::stream::{BlockData, BlockStream, fetch_block_data};
+use crate::transfers::{TransferWatch, get_transfer_details};
+use crate::tx::{
+    AlertStatus, TxMatch, get_call_details, get_inner_call_details, get_receipt_details,
+    get_tx_details, map_rules_to_abi,
+};
 use notifications::{Alert, NotificationDestination, send_notification};
 
+use alloy::consensus::Transaction;
 use alloy::json_abi::JsonAbi;
 use alloy::network::{AnyNetwork, TransactionResponse};
 use alloy::primitives::Address;
-use alloy::providers::{ProviderBuilder, RootProvider};
+use alloy::providers::{Provider, RootProvider};
+use alloy::rpc::types::{BlockNumberOrTag, Log};
 use serde::{Deserialize, Serialize};
 
-use futures::future::join_all;
-use tokio::task::JoinHandle;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tokio::task::{JoinHandle, JoinSet};
 
 pub type HttpProvider = RootProvider<AnyNetwork>;
 
@@ -26,32 +69,228 @@ pub struct MonitorState {
     pub last_processed_block: u64,
 }
 
+// what a monitor decodes with when nothing it watches matches, a transfers monitor has no ABI
+static NO_ABI: LazyLock<Arc<JsonAbi>> = LazyLock::new(Arc::default);
+
 #[derive(Clone)]
 pub struct PollingMonitor {
-    pub provider: HttpProvider,
-    pub contract_address: Address,
-    pub contract_abi: JsonAbi,
+    pub pool: Arc<ProviderPool>,
+    pub contracts: Arc<ContractSet>,
+    pub confirmations: u64,
+    pub block_target: BlockTarget,
+    pub ws_url: Option<String>,
+    pub stream: Option<Arc<BlockStream>>,
+    pub quorum: bool,
+    pub poll_interval: Duration,
+    pub fetch_concurrency: usize,
+    pub batch_size: usize,
+    pub transfers: Option<Arc<TransferWatch>>,
+    pub trace_calls: bool,
+    pub factory: Option<Arc<FactoryWatch>>,
+    pub pending: bool,
 }
 
 impl PollingMonitor {
-    pub fn new(
-        rpc_url: &str,
-        contract_address: Address,
-        contract_abi: JsonAbi,
-    ) -> Result<Self, anyhow::Error> {
-        let url = rpc_url.parse()?;
-        let provider = ProviderBuilder::new()
-            .disable_recommended_fillers()
-            .network::<AnyNetwork>()
-            .connect_http(url);
-
-        Ok(Self {
-            provider,
-            contract_address,
-            contract_abi,
-        })
+    pub fn new(pool: Arc<ProviderPool>, contracts: Vec<MonitoredContract>) -> Self {
+        Self {
+            pool,
+            contracts: Arc::new(ContractSet::new(contracts)),
+            confirmations: 0,
+            block_target: BlockTarget::Latest,
+            ws_url: None,
+            stream: None,
+            quorum: false,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            fetch_concurrency: DEFAULT_FETCH_CONCURRENCY,
+            batch_size: 1,
+            transfers: None,
+            trace_calls: false,
+            factory: None,
+            pending: false,
+        }
+    }
+
+    /// The addresses of every contract the monitor watches.
+    pub fn addresses(&self) -> Vec<Address> {
+        self.contracts
+            .snapshot()
+            .iter()
+            .map(|contract| contract.address)
+            .collect()
+    }
+
+    /// Whether `address` is one of the monitored contracts.
+    pub fn watches(&self, address: Address) -> bool {
+        self.contracts
+            .snapshot()
+            .iter()
+            .any(|contract| contract.address == address)
+    }
+
+    /// The ABI for something sent to or emitted by `address`, the first contract's when the
+    /// address isn't one we watch.
+    pub fn abi_for(&self, address: Option<Address>) -> Arc<JsonAbi> {
+        let contracts = self.contracts.snapshot();
+        contracts
+            .iter()
+            .find(|contract| Some(contract.address) == address)
+            .or(contracts.first())
+            .map(|contract| contract.abi.clone())
+            .unwrap_or_else(|| NO_ABI.clone())
+    }
+
+    /// Follow a factory, adding every contract it creates to the monitored contracts. The
+    /// children it created before are added right away.
+    pub fn with_factory(mut self, factory: FactoryWatch) -> Self {
+        for child in factory.children() {
+            self.contracts
+                .add(MonitoredContract::new(child, factory.child_abi.clone()));
+        }
+        self.factory = Some(Arc::new(factory));
+        self
+    }
+
+    /// Keep up to `concurrency` block fetches in flight while catching up, each fetching
+    /// `batch_size` blocks in one JSON-RPC batch request (1 sends plain requests).
+    pub fn with_fetch_concurrency(mut self, concurrency: usize, batch_size: usize) -> Self {
+        self.fetch_concurrency = concurrency.max(1);
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Also alert on native ETH transfers to and from the watched addresses.
+    pub fn with_transfers(mut self, transfers: TransferWatch) -> Self {
+        self.transfers = Some(Arc::new(transfers));
+        self
+    }
+
+    /// Trace every block to also check the rules against calls made to the monitored
+    /// contracts from inside a transaction, through a router, multisig or aggregator.
+    pub fn with_call_tracing(mut self, trace_calls: bool) -> Self {
+        self.trace_calls = trace_calls;
+        self
+    }
+
+    /// Also check pending transactions against the rules, alerting before they are mined.
+    /// Needs a WebSocket endpoint.
+    pub fn with_pending(mut self, pending: bool) -> Self {
+        self.pending = pending;
+        self
+    }
+
+    /// How long the polling loops wait before asking for new blocks again.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
     }
 
+    /// Only process a block once a quorum of the pool's endpoints agree on its hash.
+    pub fn with_quorum(mut self, quorum: bool) -> Self {
+        self.quorum = quorum;
+        self
+    }
+
+    /// Take blocks from a shared per-chain block stream instead of fetching them ourselves.
+    pub fn with_stream(mut self, stream: Arc<BlockStream>) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+
+    /// Use `eth_subscribe` over this WebSocket endpoint instead of polling the HTTP one.
+    /// The HTTP provider pool is still used to fetch blocks and to backfill after a reconnect.
+    pub fn with_ws_url(mut self, ws_url: impl Into<String>) -> Self {
+        self.ws_url = Some(ws_url.into());
+        self
+    }
+
+    /// Only process blocks that are `confirmations` deep behind the given block target.
+    pub fn with_confirmations(mut self, confirmations: u64, block_target: BlockTarget) -> Self {
+        self.confirmations = confirmations;
+        self.block_target = block_target;
+        self
+    }
+
+    /// What an alert says about a matched transaction: the call, transfer or inner call that
+    /// matched, decoded against the ABI of the contract it went to, and the receipt's outcome.
+    pub fn describe_tx_match(&self, m: &TxMatch) -> String {
+        // a transfer is described by what it moved, not by the call it came with
+        let summary = if let Some(transfer) = &m.transfer {
+            get_transfer_details(transfer)
+        } else if let Some(call) = &m.call {
+            get_call_details(call, &self.abi_for(call.to))
+        } else if let Some(inner) = &m.inner {
+            get_inner_call_details(inner, &self.abi_for(Some(inner.to)))
+        } else {
+            get_tx_details(&m.tx, &self.abi_for(m.tx.to()))
+        };
+        format!("{}\n{}", summary.trim_end(), get_receipt_details(m))
+    }
+
+    /// The highest block the monitor is allowed to process right now.
+    pub async fn processing_head(&self) -> Result<u64, anyhow::Error> {
+        let tag = match self.block_target {
+            BlockTarget::Latest => {
+                let head = self
+                    .pool
+                    .request(|p| async move { p.get_block_number().await })
+                    .await?;
+                return Ok(head.saturating_sub(self.confirmations));
+            }
+            BlockTarget::Safe => BlockNumberOrTag::Safe,
+            BlockTarget::Finalized => BlockNumberOrTag::Finalized,
+        };
+
+        let block = self
+            .pool
+            .request(|p| async move { p.get_block_by_number(tag).await })
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Node returned no {} block", tag))?;
+
+        Ok(block.header.number.saturating_sub(self.confirmations))
+    }
+
+    /// Turns the number of a freshly announced block into the block we may process, saving
+    /// an RPC call when the monitor follows `latest`.
+    pub(crate) async fn head_from_notification(&self, head: u64) -> Result<u64, anyhow::Error> {
+        match self.block_target {
+            BlockTarget::Latest => Ok(head.saturating_sub(self.confirmations)),
+            _ => self.processing_head().await,
+        }
+    }
+
+    /// A full block, read from the shared block stream's cache when it still holds it.
+    pub async fn fetch_full_block(
+        &self,
+        number: u64,
+    ) -> Result<Option<Arc<BlockData>>, anyhow::Error> {
+        if let Some(data) = self.stream.as_ref().and_then(|s| s.cached_full(number)) {
+            return Ok(Some(data));
+        }
+
+        Ok(fetch_block_data(&self.pool, number, true, &[])
+            .await?
+            .map(Arc::new))
+    }
+
+    /// A monitor on a contract this one saw deployed, reading blocks the way this one does.
+    /// It leaves the mempool to its parent, and follows the shared block stream or polls
+    /// rather than opening a socket of its own.
+    fn child_monitor(&self, contract: MonitoredContract) -> PollingMonitor {
+        let mut child = PollingMonitor::new(self.pool.clone(), vec![contract])
+            .with_quorum(self.quorum)
+            .with_call_tracing(self.trace_calls)
+            .with_poll_interval(self.poll_interval)
+            .with_fetch_concurrency(self.fetch_concurrency, self.batch_size)
+            .with_confirmations(self.confirmations, self.block_target);
+        if let Some(stream) = &self.stream {
+            child = child.with_stream(stream.clone());
+        }
+        child
+    }
+
+    /// Starts the monitor's sub-tasks and returns the handle of the task running them.
+    /// Aborting it stops the sub-tasks too, along with the monitors started on contracts the
+    /// monitor saw deployed.
     pub fn start_background_monitoring(
         self,
         name: String,
@@ -60,92 +299,282 @@ impl PollingMonitor {
         email_recipient: Option<String>,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
-            let mut sub_tasks = vec![];
+            // dropping the set when this task is aborted aborts every sub-task in it
+            let mut sub_tasks = JoinSet::new();
+
+            // Mempool Sub-Task
+            if self.pending && !tx_rules.is_empty() {
+                let monitor_pending = self.clone();
+                let n = name.clone();
+                let caller = format!("{}:pending", name);
+                let email_addr = email_recipient.clone();
+                let rules = tx_rules.clone();
+                let decoder = self.clone();
+
+                sub_tasks.spawn(async move {
+                    let on_pending = move |m: PendingMatch| {
+                        let tx = &m.tx;
+                        let (subject, outcome) = match &m.status {
+                            PendingStatus::Pending => {
+                                println!("[TX PENDING] {}: {:?}", n, tx.tx_hash());
+                                ("TX PENDING", "Seen in the mempool, not mined yet.".to_string())
+                            }
+                            PendingStatus::Mined { block_number } => {
+                                println!("[TX MINED] {}: {:?}", n, tx.tx_hash());
+                                ("TX MINED", format!("Mined in block {}.", block_number))
+                            }
+                            PendingStatus::Replaced { by, block_number } => {
+                                println!("[TX REPLACED] {}: {:?}", n, tx.tx_hash());
+                                (
+                                    "TX REPLACED",
+                                    format!(
+                                        "Replaced by {:?}, mined in block {} with the same sender and nonce.",
+                                        by, block_number
+                                    ),
+                                )
+                            }
+                            PendingStatus::Dropped => {
+                                println!("[TX DROPPED] {}: {:?}", n, tx.tx_hash());
+                                (
+                                    "TX DROPPED",
+                                    "Dropped from the mempool without being mined.".to_string(),
+                                )
+                            }
+                        };
+
+                        let msg = format!(
+                            "Pending Transaction: {}\n{}\nHash: {:?}\nFrom: {:?}\nRule: {}\n{}",
+                            n,
+                            outcome,
+                            tx.tx_hash(),
+                            tx.from(),
+                            m.rule,
+                            get_tx_details(tx, &decoder.abi_for(tx.to())).trim_end()
+                        );
+
+                        if let Some(email) = &email_addr {
+                            let destination = NotificationDestination::Email(email.clone());
+                            let alert = Alert {
+                                source: n.clone(),
+                                subject: subject.to_string(),
+                                message: msg,
+                            };
+
+                            tokio::spawn(async move {
+                                let _ = send_notification(&destination, &alert).await;
+                            });
+                        }
+                    };
+
+                    let _ = attribute(caller, async move {
+                        monitor_pending
+                            .monitor_pending_transactions(rules, on_pending)
+                            .await
+                    })
+                    .await;
+                });
+            }
 
             // Transaction Sub-Task
-            if !tx_rules.is_empty() {
+            if !tx_rules.is_empty() || self.transfers.is_some() {
                 let monitor_tx = self.clone();
                 let n = name.clone();
+                // RPC usage is reported per monitor task
+                let caller = format!("{}:transactions", name);
                 let email_addr = email_recipient.clone();
 
-                let abi = self.contract_abi.clone();
+                // monitors started on contracts deployed by a watched deployer
+                let parent = self.clone();
+                let spawn_configs: HashMap<String, ChildMonitorConfig> = tx_rules
+                    .iter()
+                    .filter_map(|rule| rule.spawn.clone().map(|spawn| (rule.name.clone(), spawn)))
+                    .collect();
+                // the child monitors live as long as this sub-task, dropping them stops them
+                let mut children: HashMap<Address, AbortOnDrop> = HashMap::new();
+
+                sub_tasks.spawn(async move {
+                    let on_tx = move |m: TxMatch| {
+                        let tx = &m.tx;
+                        let retracted = m.status == AlertStatus::Retracted;
 
-                sub_tasks.push(tokio::spawn(async move {
-                    let _ = monitor_tx
-                        .monitor_transactions_polling(tx_rules, move |tx| {
+                        if retracted {
+                            println!("[TX RETRACTED] {}: {:?}", n, tx.tx_hash());
+                        } else {
                             println!("[TX ALERT] {}: {:?}", n, tx.tx_hash());
+                        }
 
-                            let details = get_tx_details(&tx, &abi);
+                        let details = parent.describe_tx_match(&m);
 
-                            let msg = format!(
+                        let msg = if retracted {
+                            format!(
+                                "Transaction Retracted: {}\n\
+                                Block {} was orphaned by a chain reorganization, the earlier alert no longer holds.\n\
+                                Hash: {:?}\nRule: {}\n{}",
+                                n,
+                                m.block_number,
+                                tx.tx_hash(),
+                                m.rule,
+                                details
+                            )
+                        } else {
+                            format!(
                                 "Transaction Alert: {}\nHash: {:?}\nFrom: {:?}\n{}",
                                 n,
                                 tx.tx_hash(),
                                 tx.from(),
                                 details
-                            );
-
-                            if let Some(email) = &email_addr {
-                                let destination = NotificationDestination::Email(email.clone());
-                                let alert = Alert {
-                                    source: n.clone(),
-                                    subject: "TX ALERT".to_string(),
-                                    message: msg,
-                                };
-
-                                tokio::spawn(async move {
-                                    let _ = send_notification(&destination, &alert).await;
-                                });
+                            )
+                        };
+
+                        if let Some(email) = &email_addr {
+                            let destination = NotificationDestination::Email(email.clone());
+                            let alert = Alert {
+                                source: n.clone(),
+                                subject: if retracted { "TX RETRACTED" } else { "TX ALERT" }
+                                    .to_string(),
+                                message: msg,
+                            };
+
+                            tokio::spawn(async move {
+                                let _ = send_notification(&destination, &alert).await;
+                            });
+                        }
+
+                        if let Some(address) = m.deployed
+                            && let Some(spawn) = spawn_configs.get(&m.rule)
+                        {
+                            if retracted {
+                                // the deployment was orphaned, so was the contract
+                                children.remove(&address);
+                            } else if let Entry::Vacant(entry) = children.entry(address) {
+                                println!("[SPAWN] {}: Monitoring new contract {:?}", n, address);
+                                // the deployer's contract is the template for what it deploys
+                                let abi = parent.abi_for(None);
+                                let rules = map_rules_to_abi(
+                                    spawn.functions.clone().unwrap_or_default(),
+                                    &abi,
+                                );
+                                let handle = parent
+                                    .child_monitor(MonitoredContract::new(address, abi))
+                                    .start_background_monitoring(
+                                        format!("{} / {:?}", n, address),
+                                        rules,
+                                        spawn.events.clone().unwrap_or_default(),
+                                        email_addr.clone(),
+                                    );
+                                entry.insert(AbortOnDrop(handle));
                             }
-                        })
-                        .await;
-                }));
+                        }
+                    };
+
+                    // a shared block stream or a WebSocket endpoint saves us polling on our own
+                    let _ = attribute(caller, async move {
+                        if monitor_tx.stream.is_some() {
+                            monitor_tx.monitor_transactions_stream(tx_rules, on_tx).await
+                        } else if monitor_tx.ws_url.is_some() {
+                            monitor_tx
+                                .monitor_transactions_subscription(tx_rules, on_tx)
+                                .await
+                        } else {
+                            monitor_tx.monitor_transactions_polling(tx_rules, on_tx).await
+                        }
+                    })
+                    .await;
+                });
             }
 
             // Event Sub-Task
             if !event_names.is_empty() {
                 let monitor_events = self.clone();
                 let n = name.clone();
+                let caller = format!("{}:events", name);
                 let email_addr = email_recipient.clone();
                 let events_ref: Vec<String> = event_names.clone();
 
-                let abi = self.contract_abi.clone();
+                let decoder = self.clone();
 
-                sub_tasks.push(tokio::spawn(async move {
+                sub_tasks.spawn(async move {
                     // convert String -> &str for the trait
                     let refs: Vec<&str> = events_ref.iter().map(|s| s.as_str()).collect();
-                    let _ = monitor_events
-                        .monitor_events_polling(&refs, move |log| {
+                    let on_log = move |log: Log| {
+                        // a removed log comes from a block that was orphaned by a reorg
+                        let retracted = log.removed;
+
+                        if retracted {
+                            println!("[EVENT RETRACTED] {}: Block {:?}", n, log.block_number);
+                        } else {
                             println!("[EVENT ALERT] {}: Block {:?}", n, log.block_number);
+                        }
 
-                            let event_details = events::get_event_details(&log, &abi);
+                        let event_details =
+                            events::get_event_details(&log, &decoder.abi_for(Some(log.address())));
 
-                            let msg = format!(
+                        let msg = if retracted {
+                            format!(
+                                "Event Retracted: {}\n\
+                                Block {:?} was orphaned by a chain reorganization, the earlier alert no longer holds.\n\
+                                Tx: {:?}\n{}",
+                                n, log.block_number, log.transaction_hash, event_details
+                            )
+                        } else {
+                            format!(
                                 "Event Alert: {}\nBlock: {:?}\n{}",
                                 n, log.block_number, event_details
-                            );
-
-                            if let Some(email) = &email_addr {
-                                let destination = NotificationDestination::Email(email.clone());
-                                let alert = Alert {
-                                    source: n.clone(),
-                                    subject: "Event ALERT".to_string(),
-                                    message: msg,
-                                };
-
-                                tokio::spawn(async move {
-                                    let _ = send_notification(&destination, &alert).await;
-                                });
-                            }
-                        })
-                        .await;
-                }));
+                            )
+                        };
+
+                        if let Some(email) = &email_addr {
+                            let destination = NotificationDestination::Email(email.clone());
+                            let alert = Alert {
+                                source: n.clone(),
+                                subject: if retracted { "Event RETRACTED" } else { "Event ALERT" }
+                                    .to_string(),
+                                message: msg,
+                            };
+
+                            tokio::spawn(async move {
+                                let _ = send_notification(&destination, &alert).await;
+                            });
+                        }
+                    };
+
+                    let _ = attribute(caller, async move {
+                        if monitor_events.stream.is_some() {
+                            monitor_events.monitor_events_stream(&refs, on_log).await
+                        } else if monitor_events.ws_url.is_some() {
+                            monitor_events
+                                .monitor_events_subscription(&refs, on_log)
+                                .await
+                        } else {
+                            monitor_events.monitor_events_polling(&refs, on_log).await
+                        }
+                    })
+                    .await;
+                });
             }
 
-            // Keep alive
-            if !sub_tasks.is_empty() {
-                join_all(sub_tasks).await;
+            // Factory Sub-Task
+            if let Some(factory) = self.factory.clone() {
+                let monitor_factory = self.clone();
+                let n = name.clone();
+                let caller = format!("{}:factory", name);
+
+                sub_tasks.spawn(async move {
+                    let _ = attribute(caller, follow_factory(monitor_factory, factory, n)).await;
+                });
             }
+
+            // Keep alive
+            while sub_tasks.join_next().await.is_some() {}
         })
     }
 }
+
+/// Aborts the task when dropped, so a child monitor stops with the parent holding it.
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
@@ -0,0 +1,419 @@
+//! # Mempool Monitor
+//! This module provides tools for monitoring pending transactions.
+//!
+//! It defines the `MempoolMonitor` trait, which subscribes to `newPendingTransactions` over
+//! the monitor's WebSocket endpoint and checks every pending transaction against the rules
+//! the way `tx_match` does for mined ones. Nodes that can send full transaction bodies do,
+//! for the others every announced hash is looked up, up to the monitor's fetch concurrency at
+//! a time, while the announcements keep being read into a channel. Conditions on the receipt can't be
+//! checked before the transaction is mined, so a pending alert is an early warning only.
+//!
+//! Matched transactions are followed until they leave the mempool. Every new head is
+//! searched for them: the transaction itself being mined links the alert to its block, and
+//! another transaction from the same sender with the same nonce means it was replaced. One
+//! that the node stops knowing about without either happening was dropped.
+
+use crate::primitives::models::MonitorRule;
+use crate::subscription::{connect_ws, reconnect_backoff};
+use crate::tx::map_rules_to_abi;
+use crate::{MonitoredContract, PollingMonitor};
+use alloy::consensus::Transaction;
+use alloy::network::{AnyNetwork, AnyRpcTransaction, TransactionResponse};
+use alloy::primitives::{Address, B256};
+use alloy::providers::{Provider, RootProvider};
+use alloy::rpc::types::BlockTransactions;
+use futures::channel::mpsc;
+use futures::stream::BoxStream;
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// How long a matched transaction may sit in the mempool before we ask the node whether it
+/// still has it.
+pub const DROP_CHECK_AFTER: Duration = Duration::from_secs(120);
+
+/// What became of a pending transaction that matched a rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PendingStatus {
+    /// The transaction was just seen in the mempool.
+    Pending,
+    /// The transaction was mined in this block.
+    Mined { block_number: u64 },
+    /// Another transaction with the same sender and nonce was mined instead.
+    Replaced { by: B256, block_number: u64 },
+    /// The node no longer knows the transaction and it was never mined.
+    Dropped,
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingMatch {
+    pub tx: AnyRpcTransaction,
+    pub rule: String,
+    pub status: PendingStatus,
+}
+
+#[allow(async_fn_in_trait)]
+pub trait MempoolMonitor {
+    async fn monitor_pending_transactions<F>(
+        self,
+        rules: Vec<MonitorRule>,
+        handler: F,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(PendingMatch) + Send + 'static;
+}
+
+impl MempoolMonitor for PollingMonitor {
+    async fn monitor_pending_transactions<F>(
+        self,
+        rules: Vec<MonitorRule>,
+        mut handler: F,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(PendingMatch) + Send + 'static,
+    {
+        let ws_url = self
+            .ws_url
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Pending transaction monitoring needs a ws_url"))?;
+
+        println!(
+            "MempoolMonitor: Watching pending transactions for {:?}",
+            self.addresses()
+        );
+
+        let mut tracker = PendingTracker::new(&self, rules);
+        let mut failures = 0;
+
+        // there is nothing to fall back to without a socket, so we keep trying
+        loop {
+            match connect_ws(&ws_url).await {
+                Ok(ws) => match pending_stream(&ws, self.fetch_concurrency).await {
+                    Ok(mut pending) => match ws.subscribe_blocks().await {
+                        Ok(heads) => {
+                            failures = 0;
+
+                            let mut heads = heads.into_stream();
+                            loop {
+                                tokio::select! {
+                                    tx = pending.next() => {
+                                        let Some(tx) = tx else {
+                                            break;
+                                        };
+                                        tracker.check(&self, tx, &mut handler);
+                                    }
+                                    header = heads.next() => {
+                                        let Some(header) = header else {
+                                            break;
+                                        };
+                                        tracker.settle(&self, &ws, header.number, &mut handler).await;
+                                    }
+                                }
+                            }
+
+                            eprintln!("MempoolMonitor: subscription closed, reconnecting");
+                        }
+                        Err(e) => eprintln!("Error subscribing to new heads: {}", e),
+                    },
+                    Err(e) => eprintln!("Error subscribing to pending transactions: {}", e),
+                },
+                Err(e) => eprintln!("Error connecting to {}: {}", ws_url, e),
+            }
+
+            failures += 1;
+            sleep(reconnect_backoff(failures)).await;
+        }
+    }
+}
+
+/// The pending transactions that matched a rule, followed until they are mined, replaced
+/// or dropped.
+pub struct PendingTracker {
+    rules: Vec<MonitorRule>,
+
+    // the contracts the rules were last mapped against, and the rules mapped to each one's ABI
+    contracts: Arc<Vec<MonitoredContract>>,
+    contract_rules: HashMap<Address, Vec<MonitorRule>>,
+
+    tracked: HashMap<B256, Tracked>,
+    last_block: Option<u64>,
+}
+
+struct Tracked {
+    tx: AnyRpcTransaction,
+    rule: String,
+    checked: Instant, // when we last made sure the node still has it
+}
+
+impl PendingTracker {
+    pub fn new(monitor: &PollingMonitor, rules: Vec<MonitorRule>) -> Self {
+        let mut tracker = Self {
+            rules,
+            contracts: Arc::default(),
+            contract_rules: HashMap::new(),
+            tracked: HashMap::new(),
+            last_block: None,
+        };
+        tracker.refresh(monitor);
+        tracker
+    }
+
+    // Picks up contracts added to the monitor since the last pending transaction.
+    fn refresh(&mut self, monitor: &PollingMonitor) {
+        let contracts = monitor.contracts.snapshot();
+        if Arc::ptr_eq(&contracts, &self.contracts) {
+            return;
+        }
+
+        self.contract_rules = contracts
+            .iter()
+            .map(|contract| {
+                (
+                    contract.address,
+                    map_rules_to_abi(self.rules.clone(), &contract.abi),
+                )
+            })
+            .collect();
+        self.contracts = contracts;
+    }
+
+    /// Checks a pending transaction against the rules and starts following it if one matches.
+    pub fn check<F>(&mut self, monitor: &PollingMonitor, tx: AnyRpcTransaction, handler: &mut F)
+    where
+        F: FnMut(PendingMatch),
+    {
+        if self.tracked.contains_key(&tx.tx_hash()) {
+            return;
+        }
+        self.refresh(monitor);
+
+        let rules = tx
+            .to()
+            .and_then(|to| self.contract_rules.get(&to))
+            .unwrap_or(&self.rules);
+        let Some(rule) = rules.iter().find(|rule| rule.tx_match(&tx)) else {
+            return;
+        };
+        println!("Pending match found for rule: {}", rule.name);
+
+        let rule = rule.name.clone();
+        handler(PendingMatch {
+            tx: tx.clone(),
+            rule: rule.clone(),
+            status: PendingStatus::Pending,
+        });
+        self.tracked.insert(
+            tx.tx_hash(),
+            Tracked {
+                tx,
+                rule,
+                checked: Instant::now(),
+            },
+        );
+    }
+
+    /// Searches the blocks up to `head` for the transactions we follow, and asks the node
+    /// about the ones that have been waiting for a while.
+    pub async fn settle<F>(
+        &mut self,
+        monitor: &PollingMonitor,
+        ws: &RootProvider<AnyNetwork>,
+        head: u64,
+        handler: &mut F,
+    ) where
+        F: FnMut(PendingMatch),
+    {
+        // nothing we follow can be in the blocks before we started
+        let from = self.last_block.map_or(head, |last| last + 1);
+        self.last_block = Some(head.max(self.last_block.unwrap_or_default()));
+        if self.tracked.is_empty() {
+            return;
+        }
+
+        for number in from..=head {
+            let block = match monitor.fetch_full_block(number).await {
+                Ok(Some(block)) => block,
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("Error fetching block {}: {}", number, e);
+                    continue;
+                }
+            };
+            let BlockTransactions::Full(txs) = &block.block.transactions else {
+                continue;
+            };
+
+            for mined in txs {
+                let status = if self.tracked.contains_key(&mined.tx_hash()) {
+                    Some((
+                        mined.tx_hash(),
+                        PendingStatus::Mined {
+                            block_number: number,
+                        },
+                    ))
+                } else {
+                    self.tracked
+                        .iter()
+                        .find(|(_, t)| t.tx.from() == mined.from() && t.tx.nonce() == mined.nonce())
+                        .map(|(hash, _)| {
+                            (
+                                *hash,
+                                PendingStatus::Replaced {
+                                    by: mined.tx_hash(),
+                                    block_number: number,
+                                },
+                            )
+                        })
+                };
+
+                if let Some((hash, status)) = status {
+                    self.resolve(hash, status, handler);
+                }
+            }
+        }
+
+        let stale: Vec<B256> = self
+            .tracked
+            .iter()
+            .filter(|(_, t)| t.checked.elapsed() >= DROP_CHECK_AFTER)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in stale {
+            match ws.get_transaction_by_hash(hash).await {
+                Ok(None) => self.resolve(hash, PendingStatus::Dropped, handler),
+                // mined in a block we couldn't fetch
+                Ok(Some(tx)) if tx.block_number().is_some() => {
+                    let block_number = tx.block_number().unwrap_or_default();
+                    self.resolve(hash, PendingStatus::Mined { block_number }, handler);
+                }
+                Ok(Some(_)) => {
+                    if let Some(t) = self.tracked.get_mut(&hash) {
+                        t.checked = Instant::now();
+                    }
+                }
+                Err(e) => eprintln!("Error checking pending transaction {:?}: {}", hash, e),
+            }
+        }
+    }
+
+    fn resolve<F>(&mut self, hash: B256, status: PendingStatus, handler: &mut F)
+    where
+        F: FnMut(PendingMatch),
+    {
+        if let Some(t) = self.tracked.remove(&hash) {
+            handler(PendingMatch {
+                tx: t.tx,
+                rule: t.rule,
+                status,
+            });
+        }
+    }
+}
+
+// Helper functions
+
+// Full transaction bodies where the node sends them. Otherwise the announced hashes are read
+// into a channel by a task of their own and looked up `concurrency` at a time, so slow lookups
+// don't hold up the subscription.
+async fn pending_stream(
+    ws: &RootProvider<AnyNetwork>,
+    concurrency: usize,
+) -> Result<BoxStream<'static, AnyRpcTransaction>, anyhow::Error> {
+    match ws.subscribe_full_pending_transactions().await {
+        Ok(sub) => Ok(sub.into_stream().boxed()),
+        Err(e) => {
+            eprintln!(
+                "MempoolMonitor: Full pending transactions unavailable ({}), subscribing to hashes",
+                e
+            );
+            let sub = ws.subscribe_pending_transactions().await?;
+
+            let (mut sender, hashes) = mpsc::channel(concurrency * 16);
+            tokio::spawn(async move {
+                let mut announced = sub.into_stream();
+                while let Some(hash) = announced.next().await {
+                    // the stream was dropped, the connection is being replaced
+                    if sender.send(hash).await.is_err() {
+                        break;
+                    }
+                }
+            });
+
+            let ws = ws.clone();
+            Ok(hashes
+                .map(move |hash: B256| {
+                    let ws = ws.clone();
+                    async move {
+                        match ws.get_transaction_by_hash(hash).await {
+                            Ok(tx) => tx, // `None` when it was gone again before we asked
+                            Err(e) => {
+                                eprintln!("Error fetching pending transaction {:?}: {}", hash, e);
+                                None
+                            }
+                        }
+                    }
+                })
+                .buffer_unordered(concurrency)
+                .filter_map(futures::future::ready)
+                .boxed())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::ProviderPool;
+    use crate::primitives::models::Condition;
+    use crate::testing::Anvil;
+    use tokio::sync::mpsc::unbounded_channel;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    #[ignore = "needs anvil"]
+    async fn follows_a_pending_transaction_until_it_is_mined() {
+        let anvil = Anvil::start(&["--no-mining"]).await;
+        let accounts = anvil.accounts().await;
+        let (from, to) = (accounts[0], accounts[1]);
+
+        let pool = Arc::new(ProviderPool::new("anvil", &[anvil.http_url()], None).unwrap());
+        let monitor = PollingMonitor::new(pool, Vec::new()).with_ws_url(anvil.ws_url());
+        let rule = MonitorRule {
+            name: "to second account".to_string(),
+            conditions: vec![Condition::To(to)],
+            confirmations: None,
+            spawn: None,
+            abi_functions: Vec::new(),
+        };
+
+        let (sender, mut matches) = unbounded_channel();
+        let watching = tokio::spawn(monitor.monitor_pending_transactions(
+            vec![rule],
+            move |pending: PendingMatch| {
+                let _ = sender.send(pending);
+            },
+        ));
+        // let the monitor subscribe before anything is sent
+        sleep(Duration::from_secs(1)).await;
+
+        let hash = anvil.send(from, to).await;
+        let pending = timeout(Duration::from_secs(10), matches.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pending.tx.tx_hash(), hash);
+        assert_eq!(pending.status, PendingStatus::Pending);
+
+        anvil.mine().await;
+        let mined = timeout(Duration::from_secs(10), matches.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(mined.tx.tx_hash(), hash);
+        assert_eq!(mined.status, PendingStatus::Mined { block_number: 1 });
+
+        watching.abort();
+    }
+}
@@ -0,0 +1,569 @@
+//! # Provider Pool
+//! This module provides a pool of RPC endpoints for a single chain.
+//!
+//! Every request goes through `ProviderPool::request`, which tries the endpoints from the
+//! healthiest down and fails over to the next one when an endpoint can't be reached, times
+//! out, answers with a 5xx or says it is rate limiting. A JSON-RPC error like a revert or
+//! invalid params is the answer to the request and is returned as it is. Health is scored from
+//! a moving average of each endpoint's latency and its recent error rate, and an endpoint
+//! that keeps failing is benched for a cooldown that grows with every failure. Each endpoint
+//! spends from its own `EndpointBudget`, shared with every other pool that uses it.
+//!
+//! For high-value monitors `quorum_block_hash` asks several endpoints for the same block and
+//! only trusts a hash that a majority of them agree on.
+
+use crate::HttpProvider;
+use crate::ratelimit::{BudgetedHttp, EndpointBudget, EndpointUsage, is_rate_limit};
+use alloy::network::AnyNetwork;
+use alloy::primitives::B256;
+use alloy::providers::{Provider, ProviderBuilder};
+use alloy::rpc::client::RpcClient;
+use alloy::transports::{RpcError, TransportError, TransportErrorKind, TransportResult};
+use futures::future::join_all;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// How many endpoints are asked in a quorum read, and how many of them have to agree.
+pub const QUORUM_SIZE: usize = 3;
+pub const QUORUM_THRESHOLD: usize = 2;
+
+/// Consecutive failures after which an endpoint is benched.
+const MAX_CONSECUTIVE_ERRORS: u32 = 3;
+
+/// The longest an endpoint stays benched.
+const MAX_COOLDOWN: Duration = Duration::from_secs(60);
+
+// weight of the newest sample in the latency and error rate averages
+const EWMA_ALPHA: f64 = 0.2;
+
+#[derive(Debug, Default)]
+struct Health {
+    latency_ms: f64,
+    error_rate: f64,
+    consecutive_errors: u32,
+    benched_until: Option<Instant>,
+}
+
+pub struct Endpoint {
+    pub url: String,
+    provider: HttpProvider,
+    health: Mutex<Health>,
+}
+
+impl Endpoint {
+    fn new(budget: Arc<EndpointBudget>) -> Result<Self, anyhow::Error> {
+        let transport = BudgetedHttp::new(budget.url.parse()?, budget.clone());
+        let provider = ProviderBuilder::new()
+            .disable_recommended_fillers()
+            .network::<AnyNetwork>()
+            .connect_client(RpcClient::new(transport, false));
+
+        Ok(Self {
+            url: budget.url.clone(),
+            provider,
+            health: Mutex::new(Health::default()),
+        })
+    }
+
+    /// Lower is better. Benched endpoints sort after every healthy one.
+    fn score(&self) -> (bool, f64) {
+        let health = self.health.lock().unwrap();
+        let benched = health
+            .benched_until
+            .is_some_and(|until| until > Instant::now());
+
+        (
+            benched,
+            health.latency_ms * (1.0 + 10.0 * health.error_rate),
+        )
+    }
+
+    fn record_success(&self, latency: Duration) {
+        let mut health = self.health.lock().unwrap();
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+
+        health.latency_ms = if health.latency_ms == 0.0 {
+            latency_ms
+        } else {
+            EWMA_ALPHA * latency_ms + (1.0 - EWMA_ALPHA) * health.latency_ms
+        };
+        health.error_rate *= 1.0 - EWMA_ALPHA;
+        health.consecutive_errors = 0;
+        health.benched_until = None;
+    }
+
+    fn record_failure(&self) {
+        let mut health = self.health.lock().unwrap();
+
+        health.error_rate = EWMA_ALPHA + (1.0 - EWMA_ALPHA) * health.error_rate;
+        health.consecutive_errors += 1;
+
+        if health.consecutive_errors >= MAX_CONSECUTIVE_ERRORS {
+            // 2s, 4s, 8s ... for every failure past the limit
+            let exponent = (health.consecutive_errors - MAX_CONSECUTIVE_ERRORS).min(5);
+            let cooldown = (Duration::from_secs(2) * 2u32.pow(exponent)).min(MAX_COOLDOWN);
+            health.benched_until = Some(Instant::now() + cooldown);
+        }
+    }
+}
+
+pub struct ProviderPool {
+    pub chain: String,
+    endpoints: Vec<Endpoint>,
+}
+
+impl ProviderPool {
+    /// Builds a pool from the endpoint urls, the first one being the primary, each with a
+    /// budget of its own.
+    pub fn new(
+        chain: &str,
+        rpc_urls: &[String],
+        max_requests_per_second: Option<u32>,
+    ) -> Result<Self, anyhow::Error> {
+        let budgets = rpc_urls
+            .iter()
+            .map(|url| Arc::new(EndpointBudget::new(url, max_requests_per_second)))
+            .collect();
+
+        Self::with_budgets(chain, budgets)
+    }
+
+    /// Builds a pool over existing endpoint budgets, the first one being the primary.
+    pub fn with_budgets(
+        chain: &str,
+        budgets: Vec<Arc<EndpointBudget>>,
+    ) -> Result<Self, anyhow::Error> {
+        if budgets.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Provider pool for {} has no endpoints",
+                chain
+            ));
+        }
+
+        let endpoints = budgets
+            .into_iter()
+            .map(Endpoint::new)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            chain: chain.to_string(),
+            endpoints,
+        })
+    }
+
+    pub fn primary_url(&self) -> &str {
+        &self.endpoints[0].url
+    }
+
+    /// Endpoint indices from the healthiest to the least healthy.
+    fn ranked(&self) -> Vec<usize> {
+        let scores: Vec<(bool, f64)> = self.endpoints.iter().map(Endpoint::score).collect();
+        let mut order: Vec<usize> = (0..self.endpoints.len()).collect();
+        order.sort_by(|a, b| {
+            let (a, b) = (scores[*a], scores[*b]);
+            a.0.cmp(&b.0).then(a.1.total_cmp(&b.1))
+        });
+        order
+    }
+
+    /// Runs an RPC call against the healthiest endpoint, failing over to the next one when the
+    /// endpoint fails. Other errors are returned without trying the rest.
+    pub async fn request<T, F, Fut>(&self, call: F) -> Result<T, anyhow::Error>
+    where
+        F: Fn(HttpProvider) -> Fut,
+        Fut: Future<Output = TransportResult<T>>,
+    {
+        let mut last_error = None;
+
+        for idx in self.ranked() {
+            let endpoint = &self.endpoints[idx];
+            let started = Instant::now();
+            match call(endpoint.provider.clone()).await {
+                Ok(value) => {
+                    endpoint.record_success(started.elapsed());
+                    return Ok(value);
+                }
+                Err(e) if is_endpoint_failure(&e) => {
+                    endpoint.record_failure();
+                    eprintln!("RPC error on {}: {}", endpoint.url, e);
+                    last_error = Some(e);
+                }
+                Err(e) => {
+                    endpoint.record_success(started.elapsed());
+                    return Err(e.into());
+                }
+            }
+        }
+
+        Err(last_error.map(anyhow::Error::from).unwrap_or_else(|| {
+            anyhow::anyhow!("Provider pool for {} has no endpoints", self.chain)
+        }))
+    }
+
+    /// Asks up to `QUORUM_SIZE` endpoints for the hash of a block and returns the hash that
+    /// `QUORUM_THRESHOLD` of them agree on, or `None` if they don't agree. Smaller pools need
+    /// every endpoint they have to agree.
+    pub async fn quorum_block_hash(&self, number: u64) -> Option<B256> {
+        let voters: Vec<&Endpoint> = self
+            .ranked()
+            .into_iter()
+            .take(QUORUM_SIZE)
+            .map(|idx| &self.endpoints[idx])
+            .collect();
+        let required = QUORUM_THRESHOLD.min(voters.len());
+
+        let answers = join_all(voters.iter().map(|endpoint| async move {
+            let started = Instant::now();
+            let result = endpoint.provider.get_block_by_number(number.into()).await;
+            match &result {
+                Err(e) if is_endpoint_failure(e) => endpoint.record_failure(),
+                _ => endpoint.record_success(started.elapsed()),
+            }
+            result
+        }))
+        .await;
+
+        let mut votes: HashMap<B256, usize> = HashMap::new();
+        for block in answers.into_iter().flatten().flatten() {
+            *votes.entry(block.header.hash).or_default() += 1;
+        }
+
+        votes
+            .into_iter()
+            .find(|(_, count)| *count >= required)
+            .map(|(hash, _)| hash)
+    }
+}
+
+// chain name and endpoint urls, primary first
+type PoolKey = (String, Vec<String>);
+
+/// All provider pools, keyed by chain and endpoint list so monitors using the same
+/// endpoints share their health scores. Budgets are kept per endpoint url, so they are
+/// shared even between pools that only have some endpoints in common.
+#[derive(Default)]
+pub struct ProviderPools {
+    pools: RwLock<HashMap<PoolKey, Arc<ProviderPool>>>,
+    budgets: RwLock<HashMap<String, Arc<EndpointBudget>>>,
+}
+
+impl ProviderPools {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_or_create(
+        &self,
+        chain: &str,
+        rpc_urls: &[String],
+        max_requests_per_second: Option<u32>,
+    ) -> Result<Arc<ProviderPool>, anyhow::Error> {
+        let key = (chain.to_string(), rpc_urls.to_vec());
+        let mut pools = self.pools.write().unwrap();
+
+        if let Some(pool) = pools.get(&key) {
+            return Ok(pool.clone());
+        }
+
+        // the first monitor to use an endpoint sets its rate limit
+        let budgets = {
+            let mut budgets = self.budgets.write().unwrap();
+            rpc_urls
+                .iter()
+                .map(|url| {
+                    budgets
+                        .entry(url.clone())
+                        .or_insert_with(|| {
+                            Arc::new(EndpointBudget::new(url, max_requests_per_second))
+                        })
+                        .clone()
+                })
+                .collect()
+        };
+
+        let pool = Arc::new(ProviderPool::with_budgets(chain, budgets)?);
+        pools.insert(key, pool.clone());
+        Ok(pool)
+    }
+
+    /// How much of each endpoint's budget every monitor has used.
+    pub fn usage(&self) -> Vec<EndpointUsage> {
+        let mut usage: Vec<EndpointUsage> = self
+            .budgets
+            .read()
+            .unwrap()
+            .values()
+            .map(|budget| budget.usage())
+            .collect();
+        usage.sort_by(|a, b| a.url.cmp(&b.url));
+        usage
+    }
+}
+
+/// Whether an error is the endpoint's fault rather than the request's: it couldn't be reached
+/// or timed out, answered with a 5xx or 429, or sent a JSON-RPC rate-limit error.
+fn is_endpoint_failure(error: &TransportError) -> bool {
+    match error {
+        RpcError::Transport(TransportErrorKind::HttpError(http)) => {
+            http.status == 429 || http.status >= 500
+        }
+        RpcError::Transport(_) => true,
+        RpcError::ErrorResp(payload) => is_rate_limit(payload),
+        _ => false,
+    }
+}
+
+/// Whether an RPC call failed because the endpoint doesn't have the method: JSON-RPC's
+/// "method not found" (-32601), or geth's "the method ... does not exist". Errors like "block
+/// not found" from a node that is lagging behind are worth retrying and don't count.
+pub fn is_unsupported(error: &anyhow::Error) -> bool {
+    let Some(payload) = error
+        .downcast_ref::<TransportError>()
+        .and_then(|e| e.as_error_resp())
+    else {
+        return false;
+    };
+
+    let message = payload.message.to_lowercase();
+    payload.code == -32601
+        || message.contains("method not found")
+        || (message.contains("the method") && message.contains("does not exist"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Reply, RpcStub, block_json};
+    use alloy::rpc::json_rpc::ErrorPayload;
+
+    fn rpc_error(code: i64, message: &str) -> anyhow::Error {
+        let payload: ErrorPayload = ErrorPayload {
+            code,
+            message: message.to_string().into(),
+            data: None,
+        };
+        TransportError::ErrorResp(payload).into()
+    }
+
+    #[test]
+    fn method_not_found_is_unsupported() {
+        assert!(is_unsupported(&rpc_error(-32601, "Method not found")));
+        assert!(is_unsupported(&rpc_error(
+            -32000,
+            "the method debug_traceBlockByNumber does not exist/is not available"
+        )));
+        assert!(is_unsupported(&rpc_error(-32000, "method not found")));
+    }
+
+    #[test]
+    fn missing_blocks_are_not_unsupported() {
+        assert!(!is_unsupported(&rpc_error(-32000, "block not found")));
+        assert!(!is_unsupported(&rpc_error(-32000, "header not found")));
+        assert!(!is_unsupported(&rpc_error(
+            -32000,
+            "transaction does not exist"
+        )));
+        assert!(!is_unsupported(&anyhow::anyhow!("method not found")));
+    }
+
+    fn pool(urls: &[&str]) -> ProviderPool {
+        let urls: Vec<String> = urls.iter().map(|url| url.to_string()).collect();
+        ProviderPool::new("mainnet", &urls, None).unwrap()
+    }
+
+    // an endpoint that says every block has the same hash
+    async fn voter(hash: B256) -> RpcStub {
+        RpcStub::start(move |_, _| Reply::Result(block_json(1, hash))).await
+    }
+
+    async fn chain_id(pool: &ProviderPool) -> Result<u64, anyhow::Error> {
+        pool.request(|provider| async move { provider.get_chain_id().await })
+            .await
+    }
+
+    #[test]
+    fn latency_and_errors_are_moving_averages() {
+        let pool = pool(&["http://127.0.0.1:1"]);
+        let endpoint = &pool.endpoints[0];
+
+        endpoint.record_success(Duration::from_millis(100));
+        assert_eq!(endpoint.score(), (false, 100.0));
+
+        endpoint.record_success(Duration::from_millis(200));
+        assert!((endpoint.score().1 - 120.0).abs() < 1e-6);
+
+        endpoint.record_failure();
+        let health = endpoint.health.lock().unwrap();
+        assert!((health.error_rate - EWMA_ALPHA).abs() < 1e-9);
+        drop(health);
+        assert!((endpoint.score().1 - 120.0 * (1.0 + 10.0 * EWMA_ALPHA)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn ranks_slow_and_failing_endpoints_last() {
+        let pool = pool(&[
+            "http://127.0.0.1:1",
+            "http://127.0.0.1:2",
+            "http://127.0.0.1:3",
+        ]);
+        pool.endpoints[0].record_success(Duration::from_millis(300));
+        pool.endpoints[1].record_success(Duration::from_millis(50));
+        pool.endpoints[2].record_success(Duration::from_millis(40));
+        pool.endpoints[2].record_failure();
+
+        assert_eq!(pool.ranked(), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn benches_after_consecutive_errors_until_a_success() {
+        let pool = pool(&["http://127.0.0.1:1", "http://127.0.0.1:2"]);
+        let endpoint = &pool.endpoints[0];
+
+        for _ in 1..MAX_CONSECUTIVE_ERRORS {
+            endpoint.record_failure();
+        }
+        assert!(!endpoint.score().0);
+
+        endpoint.record_failure();
+        assert!(endpoint.score().0);
+        assert_eq!(pool.ranked(), vec![1, 0]);
+        let benched_until = endpoint.health.lock().unwrap().benched_until.unwrap();
+        assert!(benched_until <= Instant::now() + Duration::from_secs(2));
+
+        // every further failure doubles the cooldown, up to the longest one
+        for _ in 0..10 {
+            endpoint.record_failure();
+        }
+        let benched_until = endpoint.health.lock().unwrap().benched_until.unwrap();
+        assert!(benched_until > Instant::now() + Duration::from_secs(32));
+        assert!(benched_until <= Instant::now() + MAX_COOLDOWN);
+
+        endpoint.record_success(Duration::from_millis(10));
+        assert!(!endpoint.score().0);
+        assert_eq!(endpoint.health.lock().unwrap().consecutive_errors, 0);
+    }
+
+    #[test]
+    fn only_the_endpoints_faults_are_failures() {
+        let http = |status| TransportErrorKind::http_error(status, String::new());
+        let rpc = |code, message: &str| {
+            TransportError::ErrorResp(ErrorPayload {
+                code,
+                message: message.to_string().into(),
+                data: None,
+            })
+        };
+
+        assert!(is_endpoint_failure(&http(502)));
+        assert!(is_endpoint_failure(&http(429)));
+        assert!(is_endpoint_failure(&TransportErrorKind::custom_str(
+            "operation timed out"
+        )));
+        assert!(is_endpoint_failure(&rpc(-32005, "rate limit exceeded")));
+
+        assert!(!is_endpoint_failure(&http(404)));
+        assert!(!is_endpoint_failure(&rpc(-32601, "Method not found")));
+        assert!(!is_endpoint_failure(&rpc(3, "execution reverted")));
+        assert!(!is_endpoint_failure(&rpc(-32602, "invalid params")));
+        assert!(!is_endpoint_failure(&rpc(
+            -32005,
+            "query returned more than 10000 results"
+        )));
+    }
+
+    #[tokio::test]
+    async fn fails_over_when_an_endpoint_fails() {
+        let down = RpcStub::start(|_, _| Reply::Status(503)).await;
+        let up = RpcStub::start(|_, _| Reply::Result("0x1".into())).await;
+        let pool = pool(&[&down.url, &up.url]);
+
+        assert_eq!(chain_id(&pool).await.unwrap(), 1);
+        assert_eq!(down.requests(), 1);
+        assert_eq!(
+            pool.endpoints[0].health.lock().unwrap().consecutive_errors,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn returns_json_rpc_errors_without_failing_over() {
+        let primary = RpcStub::start(|_, _| Reply::Error(-32601, "Method not found")).await;
+        let backup = RpcStub::start(|_, _| Reply::Result("0x1".into())).await;
+        let pool = pool(&[&primary.url, &backup.url]);
+
+        let error = chain_id(&pool).await.unwrap_err();
+        assert!(is_unsupported(&error));
+        assert_eq!(backup.requests(), 0);
+
+        let health = pool.endpoints[0].health.lock().unwrap();
+        assert_eq!(health.consecutive_errors, 0);
+        assert_eq!(health.error_rate, 0.0);
+    }
+
+    #[tokio::test]
+    async fn a_single_endpoint_is_its_own_quorum() {
+        let hash = B256::repeat_byte(1);
+        let only = voter(hash).await;
+
+        assert_eq!(pool(&[&only.url]).quorum_block_hash(1).await, Some(hash));
+    }
+
+    #[tokio::test]
+    async fn two_endpoints_have_to_agree() {
+        let (a, b) = (B256::repeat_byte(1), B256::repeat_byte(2));
+        let (first, agreeing, disagreeing) = (voter(a).await, voter(a).await, voter(b).await);
+
+        assert_eq!(
+            pool(&[&first.url, &agreeing.url])
+                .quorum_block_hash(1)
+                .await,
+            Some(a)
+        );
+        assert_eq!(
+            pool(&[&first.url, &disagreeing.url])
+                .quorum_block_hash(1)
+                .await,
+            None
+        );
+
+        let down = RpcStub::start(|_, _| Reply::Status(500)).await;
+        assert_eq!(
+            pool(&[&first.url, &down.url]).quorum_block_hash(1).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn two_of_three_endpoints_make_a_quorum() {
+        let (a, b, c) = (
+            B256::repeat_byte(1),
+            B256::repeat_byte(2),
+            B256::repeat_byte(3),
+        );
+        let (first, second, third) = (voter(a).await, voter(b).await, voter(a).await);
+        assert_eq!(
+            pool(&[&first.url, &second.url, &third.url])
+                .quorum_block_hash(1)
+                .await,
+            Some(a)
+        );
+
+        let down = RpcStub::start(|_, _| Reply::Status(500)).await;
+        assert_eq!(
+            pool(&[&first.url, &down.url, &third.url])
+                .quorum_block_hash(1)
+                .await,
+            Some(a)
+        );
+
+        let other = voter(c).await;
+        assert_eq!(
+            pool(&[&first.url, &second.url, &other.url])
+                .quorum_block_hash(1)
+                .await,
+            None
+        );
+    }
+}
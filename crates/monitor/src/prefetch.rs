@@ -0,0 +1,406 @@
+//! # Block Prefetching
+//! This module provides a bounded-concurrency pipeline for fetching full blocks ahead of the
+//! scanner.
+//!
+//! Fetching one block at a time means a monitor catching up after downtime spends almost all
+//! of its time waiting on round trips. The `BlockPrefetcher` keeps up to `concurrency` fetches
+//! in flight ahead of the block being scanned, each covering a single block or, when batching
+//! is enabled, a JSON-RPC batch of `batch_size` blocks. Blocks are handed out strictly in
+//! order no matter which fetch finishes first, so the scanner sees exactly the same sequence
+//! it would have fetched by itself.
+//!
+//! When the scanner asks for a block the pipeline didn't expect, after a reorg rewind or a
+//! retry, the pipeline is dropped and restarted from that block.
+//!
+//! Given a bloom gate, the logs some rule requires, only the headers are fetched first and
+//! the full block only when its `logsBloom` says it may hold one of those logs. Blocks ruled
+//! out are handed to the scanner without their transactions.
+
+use crate::PollingMonitor;
+use crate::bloom::{LogInterest, any_may_match};
+use crate::pool::ProviderPool;
+use crate::ratelimit::{attribute, current_caller};
+use crate::stream::{BlockData, BlockStream, fetch_block_data};
+use alloy::network::AnyRpcBlock;
+use alloy::providers::Provider;
+use alloy::rpc::client::BatchRequest;
+use alloy::rpc::types::BlockNumberOrTag;
+use alloy::transports::TransportResult;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+/// How many fetches are kept in flight unless the monitor asks for something else.
+pub const DEFAULT_FETCH_CONCURRENCY: usize = 8;
+
+type FetchResult = Result<Vec<Option<Arc<BlockData>>>, anyhow::Error>;
+
+// the first block a fetch covers, and the task fetching it and the blocks after it
+type Fetch = (u64, JoinHandle<FetchResult>);
+
+#[derive(Default)]
+pub struct BlockPrefetcher {
+    // fetched blocks waiting for the scanner, in block order
+    ready: VecDeque<(u64, Arc<BlockData>)>,
+
+    // fetches still running, in block order
+    in_flight: VecDeque<Fetch>,
+
+    // the first block no fetch has been started for yet
+    next_block: u64,
+}
+
+impl BlockPrefetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The full block `number`, fetching ahead up to `latest_block` while the scanner works on
+    /// it. Returns `None` if the node doesn't have the block yet.
+    pub async fn next(
+        &mut self,
+        monitor: &PollingMonitor,
+        number: u64,
+        latest_block: u64,
+        gate: Option<&Arc<[LogInterest]>>,
+    ) -> Result<Option<Arc<BlockData>>, anyhow::Error> {
+        if self.expected() != Some(number) {
+            self.reset(number);
+        }
+
+        self.top_up(monitor, latest_block, gate);
+
+        if self.ready.is_empty() {
+            let Some((start, handle)) = self.in_flight.pop_front() else {
+                return Ok(None);
+            };
+
+            let blocks = match handle.await {
+                Ok(Ok(blocks)) => blocks,
+                Ok(Err(e)) => {
+                    self.reset(number);
+                    return Err(e);
+                }
+                Err(e) => {
+                    self.reset(number);
+                    return Err(e.into());
+                }
+            };
+
+            for (offset, block) in blocks.into_iter().enumerate() {
+                let block_number = start + offset as u64;
+                match block {
+                    Some(data) => self.ready.push_back((block_number, data)),
+                    None => {
+                        // everything after a block the node doesn't have yet is refetched later
+                        self.abort_in_flight();
+                        self.next_block = block_number;
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(self.ready.pop_front().map(|(_, data)| data))
+    }
+
+    /// Drops everything fetched or being fetched, the next block handed out will be `number`.
+    pub fn reset(&mut self, number: u64) {
+        self.ready.clear();
+        self.abort_in_flight();
+        self.next_block = number;
+    }
+
+    // the block the pipeline will hand out next, if it has anything in the works
+    fn expected(&self) -> Option<u64> {
+        self.ready
+            .front()
+            .map(|(n, _)| *n)
+            .or_else(|| self.in_flight.front().map(|(n, _)| *n))
+    }
+
+    fn top_up(
+        &mut self,
+        monitor: &PollingMonitor,
+        latest_block: u64,
+        gate: Option<&Arc<[LogInterest]>>,
+    ) {
+        let concurrency = monitor.fetch_concurrency.max(1);
+        let batch_size = monitor.batch_size.max(1) as u64;
+
+        while self.in_flight.len() < concurrency && self.next_block <= latest_block {
+            let from = self.next_block;
+            let to = (from + batch_size - 1).min(latest_block);
+
+            let pool = monitor.pool.clone();
+            let stream = monitor.stream.clone();
+            let gate = gate.cloned();
+            let handle = tokio::spawn(attribute(current_caller(), async move {
+                fetch_range(&pool, stream.as_deref(), from, to, gate.as_deref()).await
+            }));
+
+            self.in_flight.push_back((from, handle));
+            self.next_block = to + 1;
+        }
+    }
+
+    fn abort_in_flight(&mut self) {
+        for (_, handle) in self.in_flight.drain(..) {
+            handle.abort();
+        }
+    }
+}
+
+impl Drop for BlockPrefetcher {
+    fn drop(&mut self) {
+        self.abort_in_flight();
+    }
+}
+
+// Helper functions
+
+// blocks the shared stream still has cached are taken from there, the rest are fetched on
+// their own or as one batch. With a gate, blocks whose bloom rules out every gated log only
+// need their header.
+async fn fetch_range(
+    pool: &ProviderPool,
+    stream: Option<&BlockStream>,
+    from: u64,
+    to: u64,
+    gate: Option<&[LogInterest]>,
+) -> FetchResult {
+    let ruled_out =
+        |data: &BlockData| gate.is_some_and(|g| !any_may_match(g, &data.block.header.logs_bloom));
+
+    let mut blocks: Vec<Option<Arc<BlockData>>> = (from..=to)
+        .map(|n| {
+            let cached = stream.and_then(|s| s.cached(n))?;
+            (cached.has_transactions() || ruled_out(&cached)).then_some(cached)
+        })
+        .collect();
+
+    if gate.is_some() {
+        let missing = missing_blocks(&blocks, from);
+        for (number, header) in missing
+            .iter()
+            .zip(fetch_blocks(pool, &missing, false).await?)
+        {
+            // a block the node doesn't have yet stays missing and fails the full fetch as well
+            if let Some(header) = header
+                && ruled_out(&header)
+            {
+                blocks[(number - from) as usize] = Some(header);
+            }
+        }
+    }
+
+    let missing = missing_blocks(&blocks, from);
+    for (number, block) in missing
+        .iter()
+        .zip(fetch_blocks(pool, &missing, true).await?)
+    {
+        blocks[(number - from) as usize] = block;
+    }
+
+    Ok(blocks)
+}
+
+fn missing_blocks(blocks: &[Option<Arc<BlockData>>], from: u64) -> Vec<u64> {
+    (from..)
+        .zip(blocks)
+        .filter(|(_, block)| block.is_none())
+        .map(|(number, _)| number)
+        .collect()
+}
+
+// one request for a single block, a batch for more
+async fn fetch_blocks(
+    pool: &ProviderPool,
+    numbers: &[u64],
+    full: bool,
+) -> Result<Vec<Option<Arc<BlockData>>>, anyhow::Error> {
+    match numbers {
+        [] => Ok(Vec::new()),
+        [number] => Ok(vec![
+            fetch_block_data(pool, *number, full, &[])
+                .await?
+                .map(Arc::new),
+        ]),
+        _ => Ok(fetch_block_batch(pool, numbers, full)
+            .await?
+            .into_iter()
+            .map(|block| block.map(|block| Arc::new(BlockData { block, logs: None })))
+            .collect()),
+    }
+}
+
+/// Fetches the given blocks, with their full transactions if `full`, in a single JSON-RPC
+/// batch request.
+pub async fn fetch_block_batch(
+    pool: &ProviderPool,
+    numbers: &[u64],
+    full: bool,
+) -> Result<Vec<Option<AnyRpcBlock>>, anyhow::Error> {
+    pool.request(|p| async move {
+        let mut batch = BatchRequest::new(p.client());
+        let waiters = numbers
+            .iter()
+            .map(|n| {
+                batch.add_call::<_, Option<AnyRpcBlock>>(
+                    "eth_getBlockByNumber",
+                    &(BlockNumberOrTag::Number(*n), full),
+                )
+            })
+            .collect::<TransportResult<Vec<_>>>()?;
+
+        batch.send().await?;
+
+        let mut blocks = Vec::with_capacity(waiters.len());
+        for waiter in waiters {
+            blocks.push(waiter.await?);
+        }
+        Ok(blocks)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Reply, RpcStub, block_json};
+    use alloy::primitives::B256;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    // A node with blocks up to `head`. The lower a block, the longer it takes, so fetches
+    // started in block order finish the other way round.
+    async fn node(head: u64) -> (RpcStub, Arc<Mutex<Vec<u64>>>) {
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let requests = requested.clone();
+        let stub = RpcStub::start(move |method, params| {
+            if method != "eth_getBlockByNumber" {
+                return Reply::Error(-32601, "Method not found");
+            }
+            let number = params[0].as_str().unwrap().trim_start_matches("0x");
+            let number = u64::from_str_radix(number, 16).unwrap();
+            requests.lock().unwrap().push(number);
+            if number > head {
+                return Reply::Result(serde_json::Value::Null);
+            }
+            let delay = Duration::from_millis(30 * head.saturating_sub(number));
+            let block = block_json(number, B256::with_last_byte(number as u8));
+            Reply::After(delay, Box::new(Reply::Result(block)))
+        })
+        .await;
+        (stub, requested)
+    }
+
+    fn monitor(stub: &RpcStub, batch_size: usize) -> PollingMonitor {
+        let pool = ProviderPool::new("mainnet", std::slice::from_ref(&stub.url), None).unwrap();
+        let mut monitor = PollingMonitor::new(Arc::new(pool), Vec::new());
+        monitor.fetch_concurrency = 4;
+        monitor.batch_size = batch_size;
+        monitor
+    }
+
+    async fn next(
+        prefetcher: &mut BlockPrefetcher,
+        monitor: &PollingMonitor,
+        number: u64,
+        latest: u64,
+    ) -> Option<u64> {
+        prefetcher
+            .next(monitor, number, latest, None)
+            .await
+            .unwrap()
+            .map(|data| data.number())
+    }
+
+    #[tokio::test]
+    async fn hands_blocks_out_in_order_however_the_fetches_finish() {
+        for batch_size in [1, 2] {
+            let (stub, requested) = node(8).await;
+            let monitor = monitor(&stub, batch_size);
+            let mut prefetcher = BlockPrefetcher::new();
+
+            for number in 1..=8 {
+                assert_eq!(
+                    next(&mut prefetcher, &monitor, number, 8).await,
+                    Some(number)
+                );
+            }
+
+            // every block was asked for once
+            let mut requested = requested.lock().unwrap().clone();
+            requested.sort();
+            assert_eq!(requested, (1..=8).collect::<Vec<_>>());
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_ahead_up_to_its_concurrency() {
+        let (stub, requested) = node(8).await;
+        let monitor = monitor(&stub, 1);
+        let mut prefetcher = BlockPrefetcher::new();
+
+        assert_eq!(next(&mut prefetcher, &monitor, 1, 8).await, Some(1));
+        let mut requested = requested.lock().unwrap().clone();
+        requested.sort();
+        assert_eq!(requested, vec![1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn restarts_from_a_block_it_did_not_expect() {
+        let (stub, requested) = node(8).await;
+        let monitor = monitor(&stub, 1);
+        let mut prefetcher = BlockPrefetcher::new();
+
+        assert_eq!(next(&mut prefetcher, &monitor, 1, 8).await, Some(1));
+        assert_eq!(next(&mut prefetcher, &monitor, 2, 8).await, Some(2));
+
+        // a rewind after a reorg goes back to a block it handed out already
+        assert_eq!(next(&mut prefetcher, &monitor, 2, 8).await, Some(2));
+        assert_eq!(next(&mut prefetcher, &monitor, 3, 8).await, Some(3));
+        assert_eq!(
+            requested
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|n| **n == 2)
+                .count(),
+            2
+        );
+
+        // and skipping ahead starts over from there
+        assert_eq!(next(&mut prefetcher, &monitor, 7, 8).await, Some(7));
+        assert_eq!(next(&mut prefetcher, &monitor, 8, 8).await, Some(8));
+    }
+
+    #[tokio::test]
+    async fn reset_drops_everything_in_the_works() {
+        let (stub, _) = node(8).await;
+        let monitor = monitor(&stub, 1);
+        let mut prefetcher = BlockPrefetcher::new();
+
+        assert_eq!(next(&mut prefetcher, &monitor, 1, 8).await, Some(1));
+        assert_eq!(prefetcher.expected(), Some(2));
+
+        prefetcher.reset(5);
+        assert!(prefetcher.ready.is_empty() && prefetcher.in_flight.is_empty());
+        assert_eq!(prefetcher.expected(), None);
+        assert_eq!(next(&mut prefetcher, &monitor, 5, 8).await, Some(5));
+        assert_eq!(prefetcher.expected(), Some(6));
+    }
+
+    #[tokio::test]
+    async fn waits_for_blocks_the_node_does_not_have_yet() {
+        let (stub, _) = node(2).await;
+        let monitor = monitor(&stub, 1);
+        let mut prefetcher = BlockPrefetcher::new();
+
+        // the node reported a head it can't serve yet
+        assert_eq!(next(&mut prefetcher, &monitor, 1, 4).await, Some(1));
+        assert_eq!(next(&mut prefetcher, &monitor, 2, 4).await, Some(2));
+        assert_eq!(next(&mut prefetcher, &monitor, 3, 4).await, None);
+    }
+}
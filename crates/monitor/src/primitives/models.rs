@@ -1,6 +1,7 @@
-use alloy::json_abi::Function;
+use alloy::json_abi::{Function, JsonAbi};
 use alloy::primitives::Address;
 use serde::Deserialize;
+use std::path::PathBuf;
 
 #[derive(Debug, Clone, Deserialize)]
 pub enum Operator {
@@ -10,6 +11,40 @@ pub enum Operator {
     Contains,
 }
 
+/// Whether a transaction went through or reverted, read from its receipt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum TxStatus {
+    Success,
+    Reverted,
+}
+
+/// The EIP-2718 type of a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum TxType {
+    #[serde(rename = "legacy")]
+    Legacy,
+    #[serde(rename = "2930")]
+    AccessList,
+    #[serde(rename = "1559")]
+    Eip1559,
+    #[serde(rename = "4844")]
+    Blob,
+    #[serde(rename = "7702")]
+    SetCode,
+}
+
+impl TxType {
+    pub fn type_byte(&self) -> u8 {
+        match self {
+            TxType::Legacy => 0,
+            TxType::AccessList => 1,
+            TxType::Eip1559 => 2,
+            TxType::Blob => 3,
+            TxType::SetCode => 4,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub enum Condition {
     From(Address),
@@ -20,6 +55,54 @@ pub enum Condition {
         operator: Operator,
         value: String,
     },
+    Value {
+        operator: Operator,
+        value: String, // in wei
+    },
+    GasPrice {
+        operator: Operator,
+        value: String, // in gwei, the effective price paid
+    },
+    MaxPriorityFee {
+        operator: Operator,
+        value: String, // in gwei, the tip cap of typed transactions
+    },
+    TxType(TxType),
+    Nonce {
+        operator: Operator,
+        value: u64,
+    },
+
+    // these need the transaction's receipt
+    Status(TxStatus),
+    GasUsed {
+        operator: Operator,
+        value: u64,
+    },
+    EmitsEvent(String), // emitted by the monitored contract
+
+    // a call to the monitored contract that reverted with this custom error, and conditions
+    // on the error's arguments. These replay the transaction to get the revert data
+    Error(String),
+    ErrorArgument {
+        name: String,
+        operator: Operator,
+        value: String,
+    },
+
+    // a successful contract deployment (CREATE, or CREATE2 through the deployment proxy)
+    // sent by this deployer
+    Deploys(Address),
+}
+
+/// Which block the monitor treats as the chain head before applying its confirmation depth.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockTarget {
+    #[default]
+    Latest,
+    Safe,
+    Finalized,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -27,17 +110,109 @@ pub struct MonitorRule {
     pub name: String,
     pub conditions: Vec<Condition>,
 
+    // blocks this rule waits before alerting, it can only be deeper than the monitor's own depth
+    pub confirmations: Option<u64>,
+
+    // a monitor to start on every contract a `Deploys` match creates
+    pub spawn: Option<ChildMonitorConfig>,
+
+    // every overload the `Function` condition picks, see `select_functions`
     #[serde(skip)] //skip this, because we are not fetching the abi function from the toml
-    pub abi_function: Option<Function>,
+    pub abi_functions: Vec<Function>,
+}
+
+/// What to watch on a freshly deployed contract. The child monitor uses its parent's ABI, so
+/// this fits deployers that keep deploying the same contract.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChildMonitorConfig {
+    pub events: Option<Vec<String>>,
+    pub functions: Option<Vec<MonitorRule>>,
+}
+
+/// Which native transfers of a watched address to alert on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferDirection {
+    Incoming,
+    Outgoing,
+    #[default]
+    Both,
+}
+
+/// Native ETH transfers to and from a set of addresses, no ABI needed.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TransferConfig {
+    pub addresses: Vec<Address>,
+    pub direction: Option<TransferDirection>,
+    pub min_value: Option<String>, // in wei
+    pub max_value: Option<String>, // in wei
+
+    // the other side of the transfer, the sender of incoming and the recipient of outgoing ones
+    pub allow: Option<Vec<Address>>,
+    pub deny: Option<Vec<Address>>,
+
+    // also look for ETH moved by contracts inside a transaction, this traces every block
+    pub internal: Option<bool>,
+}
+
+/// An ABI given in the config instead of being fetched: the ABI JSON itself, a human-readable
+/// list like `["event Transfer(address indexed,address indexed,uint256)"]` or the path of a
+/// Foundry or Hardhat artifact (or a plain ABI file), relative to `ABI_DIR`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum AbiSource {
+    HumanReadable(Vec<String>),
+    Json(JsonAbi),
+    Artifact(PathBuf),
+}
+
+/// Somewhere to look a contract's ABI up by its address.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AbiProvider {
+    Etherscan, // needs ETHERSCAN_API_KEY
+    // a local tree of ABIs by chain id and address, like a Sourcify repository's `full_match`,
+    // relative to `ABI_DIR`
+    Directory(PathBuf),
+}
+
+/// A factory whose children the monitor follows: the event it announces a new child with and
+/// the event argument holding the child's address.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FactoryConfig {
+    pub address: Address,
+    pub event: String,
+    pub argument: String,
+    pub abi: Option<AbiSource>, // the factory's own ABI, looked up by its address otherwise
+
+    // the children's ABI, either given or looked up by the address of one of the children
+    pub child_abi: Option<AbiSource>,
+    pub child_abi_address: Option<Address>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct MonitorConfig {
     pub name: String,
     pub rpc_url: String,
+    pub fallback_rpc_urls: Option<Vec<String>>,
+    pub max_requests_per_second: Option<u32>, // applied to each endpoint separately
+    pub quorum: Option<bool>,
+    pub ws_url: Option<String>,
     pub chain: String,
-    pub address: Address, // We make use of the String type for config, we'll conver to Address during implementation
+    pub address: Option<Address>, // only a transfers monitor goes without a contract
+    pub addresses: Option<Vec<Address>>, // more contracts checked against the same rules
+    pub shared_abi: Option<bool>, // decode every contract with the first one's ABI, the default
+    pub abi: Option<AbiSource>,   // the ABI of every contract, nothing is looked up then
+    pub abi_providers: Option<Vec<AbiProvider>>, // tried in order, only Etherscan by default
     pub events: Option<Vec<String>>,
     pub functions: Option<Vec<MonitorRule>>,
+    pub transfers: Option<TransferConfig>,
+    pub factory: Option<FactoryConfig>,
+    pub trace_calls: Option<bool>, // also match calls made from inside transactions, traces every block
+    pub pending: Option<bool>, // also alert on matching transactions in the mempool, needs ws_url
     pub email_recipient: Option<String>,
+    pub confirmations: Option<u64>,
+    pub block_target: Option<BlockTarget>,
+    pub fetch_concurrency: Option<usize>, // block fetches kept in flight while catching up
+    pub batch_size: Option<usize>,        // blocks per JSON-RPC batch request, 1 disables batching
 }
@@ -1,42 +1,69 @@
 use alloy::dyn_abi::DynSolValue;
-use alloy::json_abi::JsonAbi;
-use alloy::{
-    network::TransactionBuilder,
-    primitives::{Address, Bytes, hex},
-    providers::{Provider, ProviderBuilder},
-    rpc::types::TransactionRequest,
-};
-use alloy_chains::{Chain, NamedChain};
-use foundry_block_explorers::Client;
-use std::env;
+use alloy::json_abi::{Event, Function, JsonAbi};
+use alloy::primitives::{B256, hex};
+use alloy_chains::NamedChain;
 use std::str::FromStr;
+use std::time::Duration;
 
-pub async fn fetch_abi(
-    chain_name: &str,
-    address: Address,
-    rpc_url: &str,
-) -> Result<JsonAbi, anyhow::Error> {
-    let key = env::var("ETHERSCAN_API_KEY")?;
-    let named_chain = NamedChain::from_str(chain_name)?;
-    let chain = Chain::from(named_chain);
-    let client = Client::new(chain, &key)?;
-    let addr = address;
-
-    // Check the contract address whether its a proxy contract and then return the impl contract address
-    // and get the abi for that
-    let provider = ProviderBuilder::new().connect_http(rpc_url.parse()?);
-    let tx = TransactionRequest::default()
-        .with_to(addr)
-        .with_input("0x5c60da1b".parse::<Bytes>()?); // implementation function selector
-
-    let target_addr = match provider.call(tx).await {
-        Ok(bytes) if bytes.len() >= 32 => Address::from_slice(&bytes[12..32]),
-        _ => addr,
-    };
-
-    let abi = client.contract_abi(target_addr).await?;
-
-    Ok(abi)
+/// How long to wait between polls when we don't know the chain's block time.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(12);
+
+/// Polls about twice per block, so a new block is picked up within half a block time without
+/// spending requests on polls that can't find anything new.
+pub fn poll_interval_for(chain_name: &str) -> Duration {
+    NamedChain::from_str(chain_name)
+        .ok()
+        .and_then(|chain| chain.average_blocktime_hint())
+        .map(|block_time| (block_time / 2).clamp(MIN_POLL_INTERVAL, MAX_POLL_INTERVAL))
+        .unwrap_or(DEFAULT_POLL_INTERVAL)
+}
+
+/// The functions a rule's `Function` condition picks out of the ABI. A name picks every
+/// overload, a full signature like `safeTransferFrom(address,address,uint256,bytes)` or a
+/// 4-byte selector like `0x42842e0e` picks just that one.
+pub fn select_functions<'a>(abi: &'a JsonAbi, spec: &str) -> Vec<&'a Function> {
+    let spec = normalize_signature(spec);
+    if let Some(selector) = parse_selector(&spec) {
+        return abi
+            .functions()
+            .filter(|f| f.selector().as_slice() == selector)
+            .collect();
+    }
+    if spec.contains('(') {
+        return abi.functions().filter(|f| f.signature() == spec).collect();
+    }
+    abi.function(&spec)
+        .map(|functions| functions.iter().collect())
+        .unwrap_or_default()
+}
+
+/// The events a name, a full signature like `Transfer(address,address,uint256)` or a topic
+/// hash picks out of the ABI, every overload for a plain name.
+pub fn select_events<'a>(abi: &'a JsonAbi, spec: &str) -> Vec<&'a Event> {
+    let spec = normalize_signature(spec);
+    if spec.len() == 66
+        && let Ok(topic) = spec.parse::<B256>()
+    {
+        return abi.events().filter(|e| e.selector() == topic).collect();
+    }
+    if spec.contains('(') {
+        return abi.events().filter(|e| e.signature() == spec).collect();
+    }
+    abi.events
+        .get(&spec)
+        .map(|events| events.iter().collect())
+        .unwrap_or_default()
+}
+
+/// The selector a `0x`-prefixed 4-byte hex string stands for.
+pub fn parse_selector(spec: &str) -> Option<[u8; 4]> {
+    let hex_part = spec.strip_prefix("0x")?;
+    if hex_part.len() != 8 {
+        return None;
+    }
+    hex::decode(hex_part).ok()?.try_into().ok()
 }
 
 pub fn format_value(val: &DynSolValue) -> String {
@@ -61,3 +88,84 @@ pub fn format_value(val: &DynSolValue) -> String {
         _ => format!("{:?}", val),
     }
 }
+
+// Helper functions
+
+// signatures are compared the way the ABI prints them, without spaces or `indexed`
+fn normalize_signature(spec: &str) -> String {
+    spec.replace(" indexed", "")
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn abi() -> JsonAbi {
+        JsonAbi::parse([
+            "function transfer(address to, uint256 amount) returns (bool)",
+            "function transfer(address to, uint256 amount, bytes data) returns (bool)",
+            "function approve(address spender, uint256 amount) returns (bool)",
+        ])
+        .unwrap()
+    }
+
+    fn signatures(functions: Vec<&Function>) -> Vec<String> {
+        let mut signatures: Vec<String> = functions.iter().map(|f| f.signature()).collect();
+        signatures.sort();
+        signatures
+    }
+
+    #[test]
+    fn a_plain_name_picks_every_overload() {
+        let abi = abi();
+        assert_eq!(
+            signatures(select_functions(&abi, "transfer")),
+            vec![
+                "transfer(address,uint256)",
+                "transfer(address,uint256,bytes)"
+            ]
+        );
+    }
+
+    #[test]
+    fn a_full_signature_picks_one_overload() {
+        let abi = abi();
+        assert_eq!(
+            signatures(select_functions(&abi, "transfer(address, uint256)")),
+            vec!["transfer(address,uint256)"]
+        );
+    }
+
+    #[test]
+    fn a_selector_picks_its_function() {
+        let abi = abi();
+        assert_eq!(
+            signatures(select_functions(&abi, "0xa9059cbb")),
+            vec!["transfer(address,uint256)"]
+        );
+        assert_eq!(
+            signatures(select_functions(&abi, "0xA9059CBB")),
+            vec!["transfer(address,uint256)"]
+        );
+    }
+
+    #[test]
+    fn unknown_functions_pick_nothing() {
+        let abi = abi();
+        assert!(select_functions(&abi, "mint").is_empty());
+        assert!(select_functions(&abi, "transfer(address)").is_empty());
+        assert!(select_functions(&abi, "0xdeadbeef").is_empty());
+    }
+
+    #[test]
+    fn parses_selectors() {
+        assert_eq!(parse_selector("0xa9059cbb"), Some([0xa9, 0x05, 0x9c, 0xbb]));
+        assert_eq!(parse_selector("a9059cbb"), None);
+        assert_eq!(parse_selector("0xa9059c"), None);
+        assert_eq!(parse_selector("0xa9059cbb00"), None);
+        assert_eq!(parse_selector("0xzz059cbb"), None);
+    }
+}
@@ -0,0 +1,502 @@
+//! # Rate Limiting
+//! This module provides request budgeting for RPC endpoints.
+//!
+//! Every endpoint has one `EndpointBudget`, shared by all monitors and pools that use the
+//! endpoint. The budget is a token bucket: requests spend a token each and tokens refill at
+//! the endpoint's configured rate. When the endpoint pushes back, either with HTTP 429 or a
+//! JSON-RPC rate-limit error, the budget pauses for the `Retry-After` the endpoint asked for
+//! (or an exponential backoff when it didn't say) but never longer than a minute, halves its
+//! refill rate and then slowly earns the configured rate back with every successful request.
+//!
+//! Requests are sent through `BudgetedHttp`, a small HTTP transport that applies the budget
+//! to every call and, unlike the stock transport, can see the `Retry-After` header. Each
+//! request is attributed to the monitor whose task made it, see `attribute`, so the budget
+//! can report how much of it each monitor consumes.
+
+use alloy::rpc::json_rpc::{ErrorPayload, RequestPacket, ResponsePacket};
+use alloy::transports::{TransportError, TransportErrorKind, TransportFut};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tower::Service;
+
+/// First pause after being rate limited without a `Retry-After`, doubled on every repeat.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The refill rate never drops below this fraction of the configured rate.
+const MIN_RATE_FRACTION: f64 = 0.1;
+
+/// Fraction of the configured rate earned back with every successful request.
+const RECOVERY_FRACTION: f64 = 0.05;
+
+/// Who requests are attributed to when they don't come from a monitor task.
+const UNATTRIBUTED: &str = "unattributed";
+
+tokio::task_local! {
+    static RPC_CALLER: String;
+}
+
+/// Runs `future` with every RPC request it makes attributed to `caller`.
+pub async fn attribute<F: Future>(caller: String, future: F) -> F::Output {
+    RPC_CALLER.scope(caller, future).await
+}
+
+/// Who the requests made by the current task are attributed to.
+pub(crate) fn current_caller() -> String {
+    RPC_CALLER
+        .try_with(|caller| caller.clone())
+        .unwrap_or_else(|_| UNATTRIBUTED.to_string())
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    rate: Option<f64>,
+    last_refill: Instant,
+    paused_until: Option<Instant>,
+    backoff: Duration,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct CallerUsage {
+    pub requests: u64,
+    pub rate_limited: u64,
+}
+
+pub struct EndpointBudget {
+    pub url: String,
+    configured_rate: Option<f64>,
+    bucket: Mutex<Bucket>,
+    usage: Mutex<HashMap<String, CallerUsage>>,
+    started: Instant,
+}
+
+impl EndpointBudget {
+    pub fn new(url: &str, max_requests_per_second: Option<u32>) -> Self {
+        let configured_rate = max_requests_per_second
+            .filter(|rps| *rps > 0)
+            .map(f64::from);
+
+        Self {
+            url: url.to_string(),
+            configured_rate,
+            bucket: Mutex::new(Bucket {
+                tokens: configured_rate.unwrap_or_default(),
+                rate: configured_rate,
+                last_refill: Instant::now(),
+                paused_until: None,
+                backoff: INITIAL_BACKOFF,
+            }),
+            usage: Mutex::new(HashMap::new()),
+            started: Instant::now(),
+        }
+    }
+
+    /// Waits until the budget allows `requests` more requests, then spends them.
+    pub async fn acquire(&self, caller: &str, requests: usize) {
+        for _ in 0..requests {
+            loop {
+                let wait = {
+                    let mut bucket = self.bucket.lock().unwrap();
+                    let now = Instant::now();
+
+                    match (bucket.paused_until, bucket.rate) {
+                        (Some(until), _) if until > now => until - now,
+                        (_, None) => break,
+                        (_, Some(rate)) => {
+                            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+                            // one second worth of requests is the largest burst we allow
+                            let capacity = self.configured_rate.unwrap_or(rate);
+                            bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+                            bucket.last_refill = now;
+
+                            if bucket.tokens >= 1.0 {
+                                bucket.tokens -= 1.0;
+                                break;
+                            }
+                            Duration::from_secs_f64((1.0 - bucket.tokens) / rate)
+                        }
+                    }
+                };
+
+                sleep(wait).await;
+            }
+        }
+
+        self.usage
+            .lock()
+            .unwrap()
+            .entry(caller.to_string())
+            .or_default()
+            .requests += requests as u64;
+    }
+
+    /// The endpoint pushed back: pause, and slow the refill rate down.
+    pub fn throttled(&self, caller: &str, retry_after: Option<Duration>) {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        // whatever the endpoint asks for, we don't stop for more than a minute
+        let pause = retry_after.unwrap_or(bucket.backoff).min(MAX_BACKOFF);
+        bucket.backoff = (bucket.backoff * 2).min(MAX_BACKOFF);
+        bucket.paused_until = Some(Instant::now() + pause);
+        bucket.tokens = 0.0;
+
+        if let (Some(rate), Some(configured)) = (bucket.rate, self.configured_rate) {
+            bucket.rate = Some((rate / 2.0).max(configured * MIN_RATE_FRACTION));
+        }
+        drop(bucket);
+
+        eprintln!("RPC {} rate limited us, pausing for {:?}", self.url, pause);
+        self.usage
+            .lock()
+            .unwrap()
+            .entry(caller.to_string())
+            .or_default()
+            .rate_limited += 1;
+    }
+
+    /// A request went through: reset the backoff and earn some of the rate back.
+    pub fn succeeded(&self) {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        bucket.backoff = INITIAL_BACKOFF;
+        if let (Some(rate), Some(configured)) = (bucket.rate, self.configured_rate) {
+            bucket.rate = Some((rate + configured * RECOVERY_FRACTION).min(configured));
+        }
+    }
+
+    pub fn usage(&self) -> EndpointUsage {
+        let bucket = self.bucket.lock().unwrap();
+        let usage = self.usage.lock().unwrap();
+
+        let minutes = self.started.elapsed().as_secs_f64().max(1.0) / 60.0;
+        let total: u64 = usage.values().map(|u| u.requests).sum();
+
+        let mut callers: Vec<CallerBudget> = usage
+            .iter()
+            .map(|(caller, u)| CallerBudget {
+                caller: caller.clone(),
+                requests: u.requests,
+                rate_limited: u.rate_limited,
+                requests_per_minute: u.requests as f64 / minutes,
+                share_of_requests: if total == 0 {
+                    0.0
+                } else {
+                    u.requests as f64 / total as f64
+                },
+                share_of_budget: self.configured_rate.map(|rate| {
+                    u.requests as f64 / (rate * self.started.elapsed().as_secs_f64().max(1.0))
+                }),
+            })
+            .collect();
+        callers.sort_by_key(|c| std::cmp::Reverse(c.requests));
+
+        EndpointUsage {
+            url: self.url.clone(),
+            configured_requests_per_second: self.configured_rate,
+            current_requests_per_second: bucket.rate,
+            paused: bucket
+                .paused_until
+                .is_some_and(|until| until > Instant::now()),
+            total_requests: total,
+            callers,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CallerBudget {
+    pub caller: String,
+    pub requests: u64,
+    pub rate_limited: u64,
+    pub requests_per_minute: f64,
+    pub share_of_requests: f64,
+    // share of everything the endpoint could have served since it was first used
+    pub share_of_budget: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointUsage {
+    pub url: String,
+    pub configured_requests_per_second: Option<f64>,
+    pub current_requests_per_second: Option<f64>,
+    pub paused: bool,
+    pub total_requests: u64,
+    pub callers: Vec<CallerBudget>,
+}
+
+/// An HTTP transport that spends the endpoint's budget on every request and reports the
+/// endpoint's rate-limit responses back to it.
+#[derive(Clone)]
+pub struct BudgetedHttp {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    budget: Arc<EndpointBudget>,
+}
+
+impl BudgetedHttp {
+    pub fn new(url: reqwest::Url, budget: Arc<EndpointBudget>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+            budget,
+        }
+    }
+
+    async fn send(
+        self,
+        req: RequestPacket,
+        caller: String,
+    ) -> Result<ResponsePacket, TransportError> {
+        let requests = match &req {
+            RequestPacket::Single(_) => 1,
+            RequestPacket::Batch(batch) => batch.len(),
+        };
+        self.budget.acquire(&caller, requests).await;
+
+        let resp = self
+            .client
+            .post(self.url)
+            .json(&req)
+            .send()
+            .await
+            .map_err(TransportErrorKind::custom)?;
+
+        let status = resp.status();
+        let retry_after = resp
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+
+        let body = resp.bytes().await.map_err(TransportErrorKind::custom)?;
+
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            self.budget.throttled(&caller, retry_after);
+        }
+        if !status.is_success() {
+            return Err(TransportErrorKind::http_error(
+                status.as_u16(),
+                String::from_utf8_lossy(&body).into_owned(),
+            ));
+        }
+
+        let packet: ResponsePacket = serde_json::from_slice(&body)
+            .map_err(|err| TransportError::deser_err(err, String::from_utf8_lossy(&body)))?;
+
+        // plenty of providers report rate limits as a JSON-RPC error on a 200 response
+        match rate_limit_error(&packet) {
+            Some(error) => self
+                .budget
+                .throttled(&caller, retry_after.or_else(|| backoff_hint(error))),
+            None => self.budget.succeeded(),
+        }
+
+        Ok(packet)
+    }
+}
+
+impl Service<RequestPacket> for BudgetedHttp {
+    type Response = ResponsePacket;
+    type Error = TransportError;
+    type Future = TransportFut<'static>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: RequestPacket) -> Self::Future {
+        // read the caller here, `call` still runs inside the requesting monitor's task
+        let caller = current_caller();
+        Box::pin(self.clone().send(req, caller))
+    }
+}
+
+// Helper functions
+
+fn rate_limit_error(packet: &ResponsePacket) -> Option<&ErrorPayload> {
+    let responses = match packet {
+        ResponsePacket::Single(resp) => std::slice::from_ref(resp),
+        ResponsePacket::Batch(resps) => resps.as_slice(),
+    };
+
+    responses
+        .iter()
+        .filter_map(|resp| resp.payload.as_error())
+        .find(|error| is_rate_limit(error))
+}
+
+// 429 is what some providers copy from HTTP. The EIP-1474 "limit exceeded" code -32005 alone
+// isn't enough, Infura also sends it for a getLogs query with too many results, so it takes
+// Infura's `rate` data or a message saying so.
+pub(crate) fn is_rate_limit(error: &ErrorPayload) -> bool {
+    let message = error.message.to_lowercase();
+    error.code == 429
+        || rate_data(error).is_some()
+        || message.contains("rate limit")
+        || message.contains("too many requests")
+        || message.contains("exceeded its compute units")
+}
+
+// Infura-style `{"rate": {"backoff_seconds": 30}}` error data
+fn rate_data(error: &ErrorPayload) -> Option<serde_json::Value> {
+    let data: serde_json::Value = serde_json::from_str(error.data.as_ref()?.get()).ok()?;
+    data.get("rate").cloned()
+}
+
+// The pause the error data asks for, at most `MAX_BACKOFF`.
+fn backoff_hint(error: &ErrorPayload) -> Option<Duration> {
+    let seconds = rate_data(error)?["backoff_seconds"].as_f64()?;
+    // too large for a `Duration` is still just longer than we wait
+    (seconds >= 0.0).then(|| {
+        Duration::try_from_secs_f64(seconds)
+            .unwrap_or(MAX_BACKOFF)
+            .min(MAX_BACKOFF)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(code: i64, message: &str, data: Option<serde_json::Value>) -> ErrorPayload {
+        ErrorPayload {
+            code,
+            message: message.to_string().into(),
+            data: data.map(|data| serde_json::value::to_raw_value(&data).unwrap()),
+        }
+    }
+
+    fn rate(budget: &EndpointBudget) -> Option<f64> {
+        budget.usage().current_requests_per_second
+    }
+
+    #[test]
+    fn recognizes_rate_limits() {
+        let backoff = serde_json::json!({ "rate": { "backoff_seconds": 30 } });
+        for (error, limited) in [
+            (error(-32005, "request rate limited", None), true),
+            (
+                error(-32005, "daily request count exceeded", Some(backoff)),
+                true,
+            ),
+            (error(429, "slow down", None), true),
+            (error(-32000, "Too Many Requests", None), true),
+            (
+                error(-32005, "query returned more than 10000 results", None),
+                false,
+            ),
+            (error(-32000, "execution reverted", None), false),
+        ] {
+            assert_eq!(is_rate_limit(&error), limited, "{}", error.message);
+        }
+    }
+
+    #[test]
+    fn backoff_hints_are_bounded() {
+        let hint = |seconds: serde_json::Value| {
+            backoff_hint(&error(
+                -32005,
+                "rate limited",
+                Some(serde_json::json!({ "rate": { "backoff_seconds": seconds } })),
+            ))
+        };
+
+        assert_eq!(hint(30.into()), Some(Duration::from_secs(30)));
+        assert_eq!(hint(1.5.into()), Some(Duration::from_millis(1500)));
+        assert_eq!(hint(1e300.into()), Some(MAX_BACKOFF));
+        assert_eq!(hint((-5).into()), None);
+        assert_eq!(hint("soon".into()), None);
+        assert_eq!(backoff_hint(&error(-32005, "rate limited", None)), None);
+    }
+
+    #[tokio::test]
+    async fn spends_a_second_of_requests_then_waits_for_tokens() {
+        let budget = EndpointBudget::new("http://node", Some(10));
+
+        let started = Instant::now();
+        budget.acquire("test", 10).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        budget.acquire("test", 1).await;
+        assert!(started.elapsed() >= Duration::from_millis(80));
+        assert_eq!(budget.usage().total_requests, 11);
+    }
+
+    #[tokio::test]
+    async fn an_unlimited_budget_never_waits() {
+        let budget = EndpointBudget::new("http://node", None);
+        let started = Instant::now();
+        budget.acquire("test", 1000).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        // and has no rate to slow down
+        budget.throttled("test", Some(Duration::ZERO));
+        assert_eq!(rate(&budget), None);
+    }
+
+    #[test]
+    fn halves_the_rate_when_throttled_and_earns_it_back() {
+        let budget = EndpointBudget::new("http://node", Some(10));
+
+        budget.throttled("test", Some(Duration::ZERO));
+        assert_eq!(rate(&budget), Some(5.0));
+        budget.throttled("test", Some(Duration::ZERO));
+        assert_eq!(rate(&budget), Some(2.5));
+        for _ in 0..10 {
+            budget.throttled("test", Some(Duration::ZERO));
+        }
+        assert_eq!(rate(&budget), Some(10.0 * MIN_RATE_FRACTION));
+
+        budget.succeeded();
+        assert_eq!(rate(&budget), Some(1.0 + 10.0 * RECOVERY_FRACTION));
+        for _ in 0..100 {
+            budget.succeeded();
+        }
+        assert_eq!(rate(&budget), Some(10.0));
+        assert_eq!(budget.usage().callers[0].rate_limited, 12);
+    }
+
+    #[test]
+    fn backs_off_exponentially_until_a_request_succeeds() {
+        let budget = EndpointBudget::new("http://node", None);
+        let backoff = || budget.bucket.lock().unwrap().backoff;
+
+        budget.throttled("test", None);
+        assert_eq!(backoff(), INITIAL_BACKOFF * 2);
+        for _ in 0..10 {
+            budget.throttled("test", None);
+        }
+        assert_eq!(backoff(), MAX_BACKOFF);
+
+        budget.succeeded();
+        assert_eq!(backoff(), INITIAL_BACKOFF);
+    }
+
+    #[test]
+    fn pauses_for_at_most_the_longest_backoff() {
+        let budget = EndpointBudget::new("http://node", None);
+        budget.throttled("test", Some(Duration::from_secs(u64::MAX / 2)));
+
+        let until = budget.bucket.lock().unwrap().paused_until.unwrap();
+        assert!(until <= Instant::now() + MAX_BACKOFF);
+        assert!(budget.usage().paused);
+    }
+
+    #[tokio::test]
+    async fn waits_out_a_pause() {
+        let budget = EndpointBudget::new("http://node", None);
+        budget.throttled("test", Some(Duration::from_millis(200)));
+
+        let started = Instant::now();
+        budget.acquire("test", 1).await;
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert!(!budget.usage().paused);
+    }
+}
@@ -0,0 +1,178 @@
+//! # Receipts
+//! This module provides tools for fetching transaction receipts.
+//!
+//! Rules with conditions on a transaction's outcome (its status, the gas it used or the events
+//! it emitted) need its receipt. Receipts are only fetched for transactions that already
+//! passed every other condition, in one `eth_getBlockReceipts` call when the endpoint
+//! supports it and there's more than one to fetch, and one `eth_getTransactionReceipt` call
+//! each otherwise.
+
+use crate::pool::{ProviderPool, is_unsupported};
+use alloy::eips::BlockId;
+use alloy::network::{AnyTransactionReceipt, ReceiptResponse};
+use alloy::primitives::B256;
+use alloy::providers::Provider;
+use futures::future::join_all;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Fetches receipts, remembering whether the endpoints support `eth_getBlockReceipts`.
+pub struct ReceiptFetcher {
+    block_receipts: AtomicBool,
+}
+
+impl Default for ReceiptFetcher {
+    fn default() -> Self {
+        Self {
+            block_receipts: AtomicBool::new(true),
+        }
+    }
+}
+
+impl ReceiptFetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The receipts of the given transactions in block `block_hash`, keyed by tx hash.
+    pub async fn fetch(
+        &self,
+        pool: &ProviderPool,
+        block_hash: B256,
+        tx_hashes: &[B256],
+    ) -> Result<HashMap<B256, AnyTransactionReceipt>, anyhow::Error> {
+        if tx_hashes.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        if tx_hashes.len() > 1 && self.block_receipts.load(Ordering::Relaxed) {
+            match pool
+                .request(|p| async move { p.get_block_receipts(BlockId::hash(block_hash)).await })
+                .await
+            {
+                Ok(Some(receipts)) => {
+                    return Ok(receipts
+                        .into_iter()
+                        .filter(|receipt| tx_hashes.contains(&receipt.transaction_hash()))
+                        .map(|receipt| (receipt.transaction_hash(), receipt))
+                        .collect());
+                }
+                Ok(None) => return Err(anyhow::anyhow!("No receipts for block {}", block_hash)),
+                Err(e) if is_unsupported(&e) => {
+                    eprintln!(
+                        "eth_getBlockReceipts is not supported, fetching receipts one by one"
+                    );
+                    self.block_receipts.store(false, Ordering::Relaxed);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let receipts = join_all(tx_hashes.iter().map(|hash| {
+            let hash = *hash;
+            pool.request(move |p| async move { p.get_transaction_receipt(hash).await })
+        }))
+        .await;
+
+        let mut by_hash = HashMap::new();
+        for (hash, receipt) in tx_hashes.iter().zip(receipts) {
+            match receipt? {
+                Some(receipt) => {
+                    by_hash.insert(*hash, receipt);
+                }
+                None => return Err(anyhow::anyhow!("No receipt for transaction {}", hash)),
+            }
+        }
+        Ok(by_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Reply, RpcStub, receipt};
+    use serde_json::json;
+
+    fn hash(byte: u8) -> B256 {
+        B256::repeat_byte(byte)
+    }
+
+    fn receipt_json(tx_hash: B256) -> serde_json::Value {
+        serde_json::to_value(receipt(json!({ "transactionHash": tx_hash }))).unwrap()
+    }
+
+    // an endpoint with the receipts of transactions 1 to 3, with or without eth_getBlockReceipts
+    async fn endpoint(block_receipts: bool) -> RpcStub {
+        RpcStub::start(move |method, params| match method {
+            "eth_getBlockReceipts" if block_receipts => {
+                Reply::Result((1..=3).map(|byte| receipt_json(hash(byte))).collect())
+            }
+            "eth_getBlockReceipts" => Reply::Error(-32601, "Method not found"),
+            "eth_getTransactionReceipt" => {
+                let tx_hash: B256 = serde_json::from_value(params[0].clone()).unwrap();
+                Reply::Result(receipt_json(tx_hash))
+            }
+            _ => Reply::Error(-32601, "Method not found"),
+        })
+        .await
+    }
+
+    fn pool(stub: &RpcStub) -> ProviderPool {
+        ProviderPool::new("mainnet", std::slice::from_ref(&stub.url), None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn fetches_the_blocks_receipts_in_one_call() {
+        let stub = endpoint(true).await;
+        let fetcher = ReceiptFetcher::new();
+
+        let receipts = fetcher
+            .fetch(&pool(&stub), hash(0xbb), &[hash(1), hash(3)])
+            .await
+            .unwrap();
+        assert_eq!(receipts.len(), 2);
+        assert!(receipts.contains_key(&hash(1)) && receipts.contains_key(&hash(3)));
+        assert_eq!(stub.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_one_call_per_transaction() {
+        let stub = endpoint(false).await;
+        let pool = pool(&stub);
+        let fetcher = ReceiptFetcher::new();
+
+        let receipts = fetcher
+            .fetch(&pool, hash(0xbb), &[hash(1), hash(2)])
+            .await
+            .unwrap();
+        assert_eq!(receipts[&hash(2)].transaction_hash(), hash(2));
+        assert_eq!(stub.requests(), 3);
+
+        // and doesn't ask for the block's receipts again
+        fetcher
+            .fetch(&pool, hash(0xbb), &[hash(1), hash(2)])
+            .await
+            .unwrap();
+        assert_eq!(stub.requests(), 5);
+    }
+
+    #[tokio::test]
+    async fn fetches_a_single_receipt_on_its_own() {
+        let stub = endpoint(true).await;
+        let receipts = ReceiptFetcher::new()
+            .fetch(&pool(&stub), hash(0xbb), &[hash(2)])
+            .await
+            .unwrap();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(stub.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_missing_receipt() {
+        let stub = RpcStub::start(|_, _| Reply::Result(serde_json::Value::Null)).await;
+        let fetched = ReceiptFetcher::new()
+            .fetch(&pool(&stub), hash(0xbb), &[hash(1)])
+            .await;
+        assert!(fetched.is_err());
+    }
+}
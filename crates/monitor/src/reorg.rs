@@ -0,0 +1,177 @@
+//! # Reorg Detection
+//! This module provides tools for detecting chain reorganizations.
+//!
+//! It defines the `BlockWindow`, a rolling record of the most recent block hashes a monitor
+//! has processed. Every new block is checked against the window by comparing its parent hash
+//! with the hash we recorded for the previous height. When they disagree the chain has been
+//! reorganized, and `find_common_ancestor` walks the window backwards against the canonical
+//! chain to find the last block we can still trust, so the monitor can rewind and rescan.
+
+use crate::pool::ProviderPool;
+use alloy::primitives::B256;
+use alloy::providers::Provider;
+use std::collections::VecDeque;
+
+/// How many recent blocks we remember. Reorgs deeper than this can't be unwound.
+pub const DEFAULT_REORG_WINDOW: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct BlockWindow {
+    blocks: VecDeque<(u64, B256)>,
+    capacity: usize,
+}
+
+impl BlockWindow {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            blocks: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn hash_of(&self, number: u64) -> Option<B256> {
+        self.blocks
+            .iter()
+            .rev()
+            .find(|(n, _)| *n == number)
+            .map(|(_, h)| *h)
+    }
+
+    pub fn tip(&self) -> Option<(u64, B256)> {
+        self.blocks.back().copied()
+    }
+
+    /// The lowest block number still held in the window.
+    pub fn oldest(&self) -> Option<u64> {
+        self.blocks.front().map(|(n, _)| *n)
+    }
+
+    /// Checks that a block at `number` builds on the block we recorded at `number - 1`.
+    /// If we have no record of the parent there's nothing to compare against, so it passes.
+    pub fn extends(&self, number: u64, parent_hash: B256) -> bool {
+        match number
+            .checked_sub(1)
+            .and_then(|parent| self.hash_of(parent))
+        {
+            Some(expected) => expected == parent_hash,
+            None => true,
+        }
+    }
+
+    pub fn push(&mut self, number: u64, hash: B256) {
+        // a block at the same height replaces whatever we had there
+        self.blocks.retain(|(n, _)| *n < number);
+        self.blocks.push_back((number, hash));
+
+        while self.blocks.len() > self.capacity {
+            self.blocks.pop_front();
+        }
+    }
+
+    /// Where to rewind to when no block in the window is canonical any more: the block just
+    /// before the oldest one we know about.
+    pub fn rewind_floor(&self) -> u64 {
+        self.oldest().unwrap_or_default().saturating_sub(1)
+    }
+
+    /// Drops every block at or above `number` and returns them, oldest first.
+    pub fn truncate_from(&mut self, number: u64) -> Vec<(u64, B256)> {
+        let split = self
+            .blocks
+            .iter()
+            .position(|(n, _)| *n >= number)
+            .unwrap_or(self.blocks.len());
+
+        self.blocks.split_off(split).into_iter().collect()
+    }
+}
+
+/// Walks the window from the tip backwards and returns the highest block whose recorded hash
+/// still matches the canonical chain. If nothing matches, the reorg is deeper than the window
+/// and we fall back to the block just before the oldest one we know about.
+pub async fn find_common_ancestor(
+    pool: &ProviderPool,
+    window: &BlockWindow,
+) -> Result<u64, anyhow::Error> {
+    for (number, hash) in window.blocks.iter().rev() {
+        let number = *number;
+        if let Some(block) = pool
+            .request(|p| async move { p.get_block_by_number(number.into()).await })
+            .await?
+            && block.header.hash == *hash
+        {
+            return Ok(number);
+        }
+    }
+
+    let floor = window.rewind_floor();
+    eprintln!(
+        "Reorg is deeper than the {} block window, rewinding to block {}",
+        window.capacity, floor
+    );
+    Ok(floor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(n: u8) -> B256 {
+        B256::repeat_byte(n)
+    }
+
+    #[test]
+    fn detects_parent_hash_mismatch() {
+        let mut window = BlockWindow::new(8);
+        window.push(10, hash(10));
+        window.push(11, hash(11));
+
+        assert!(window.extends(12, hash(11)));
+        assert!(!window.extends(12, hash(0xaa)));
+        // no record of the parent, nothing to compare against
+        assert!(window.extends(20, hash(0xaa)));
+    }
+
+    #[test]
+    fn rewinds_below_the_window_on_a_deeper_reorg() {
+        let mut window = BlockWindow::new(3);
+        for n in 10..=15 {
+            window.push(n, hash(n as u8));
+        }
+
+        // only the last three blocks are remembered
+        assert_eq!(window.oldest(), Some(13));
+        assert_eq!(window.hash_of(12), None);
+        assert_eq!(window.rewind_floor(), 12);
+
+        let dropped = window.truncate_from(14);
+        assert_eq!(dropped, vec![(14, hash(14)), (15, hash(15))]);
+        assert_eq!(window.tip(), Some((13, hash(13))));
+    }
+
+    #[test]
+    fn pushing_a_block_twice_keeps_one_entry() {
+        let mut window = BlockWindow::new(8);
+        window.push(10, hash(10));
+        window.push(11, hash(11));
+        window.push(11, hash(11));
+
+        assert_eq!(window.truncate_from(0).len(), 2);
+
+        // a different block at a height we had replaces it and everything above it
+        let mut window = BlockWindow::new(8);
+        window.push(10, hash(10));
+        window.push(11, hash(11));
+        window.push(12, hash(12));
+        window.push(11, hash(0xbb));
+        assert_eq!(window.tip(), Some((11, hash(0xbb))));
+        assert_eq!(window.hash_of(12), None);
+    }
+
+    #[test]
+    fn empty_window_rewinds_to_genesis() {
+        let window = BlockWindow::new(4);
+        assert_eq!(window.tip(), None);
+        assert_eq!(window.rewind_floor(), 0);
+    }
+}
@@ -0,0 +1,285 @@
+//! # Revert Reasons
+//! This module provides tools for finding out why a transaction reverted.
+//!
+//! Receipts only say that a transaction failed, not why. To get the revert data the
+//! transaction is replayed with `eth_call` on top of its parent block, which reproduces the
+//! revert as long as nothing earlier in the same block changed the outcome. The data is then
+//! decoded against the custom errors in the contract's ABI, falling back to the built-in
+//! `Error(string)` and `Panic(uint256)`.
+
+use crate::pool::ProviderPool;
+use crate::primitives::utils::format_value;
+use alloy::consensus::Transaction;
+use alloy::dyn_abi::{DynSolValue, JsonAbiExt};
+use alloy::eips::BlockId;
+use alloy::hex;
+use alloy::json_abi::JsonAbi;
+use alloy::network::{AnyRpcTransaction, TransactionBuilder, TransactionResponse};
+use alloy::primitives::Bytes;
+use alloy::providers::Provider;
+use alloy::rpc::types::TransactionRequest;
+use alloy::serde::WithOtherFields;
+use alloy::sol_types::{Panic, Revert, SolError};
+use alloy::transports::TransportError;
+use std::fmt;
+
+/// A decoded revert: the error's name and its named arguments.
+#[derive(Debug, Clone)]
+pub struct RevertReason {
+    pub name: String,
+    pub arguments: Vec<(String, DynSolValue)>,
+}
+
+impl fmt::Display for RevertReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arguments: Vec<String> = self
+            .arguments
+            .iter()
+            .map(|(name, value)| format!("{}: {}", name, format_value(value)))
+            .collect();
+        write!(f, "{}({})", self.name, arguments.join(", "))
+    }
+}
+
+/// Replays a transaction at its parent block and returns the data it reverted with, or `None`
+/// if the replay didn't revert.
+pub async fn replay_revert_data(
+    pool: &ProviderPool,
+    tx: &AnyRpcTransaction,
+    block_number: u64,
+) -> Result<Option<Bytes>, anyhow::Error> {
+    let mut request = TransactionRequest::default()
+        .with_from(tx.from())
+        .with_input(tx.input().clone())
+        .with_value(tx.value())
+        .with_gas_limit(tx.gas_limit());
+    if let Some(to) = tx.to() {
+        request = request.with_to(to);
+    }
+    let request = WithOtherFields::new(request);
+    let parent = BlockId::number(block_number.saturating_sub(1));
+
+    let result = pool
+        .request(|p| {
+            let request = request.clone();
+            async move {
+                // a revert is an answer, not a failure to fail over on
+                match p.call(request).block(parent).await {
+                    Ok(_) => Ok(None),
+                    Err(e) => match revert_data(&e) {
+                        Some(data) => Ok(Some(data)),
+                        None => Err(e),
+                    },
+                }
+            }
+        })
+        .await?;
+
+    Ok(result)
+}
+
+/// Decodes revert data against the ABI's custom errors and the built-in ones.
+pub fn decode_revert(abi: &JsonAbi, data: &[u8]) -> Option<RevertReason> {
+    if data.len() < 4 {
+        return None;
+    }
+
+    if let Some(error) = abi.errors().find(|e| e.selector().as_slice() == &data[..4]) {
+        let values = error.abi_decode_input(&data[4..]).ok()?;
+        return Some(RevertReason {
+            name: error.name.clone(),
+            arguments: error
+                .inputs
+                .iter()
+                .map(|input| input.name.clone())
+                .zip(values)
+                .collect(),
+        });
+    }
+
+    if let Ok(revert) = Revert::abi_decode(data) {
+        return Some(RevertReason {
+            name: "Error".to_string(),
+            arguments: vec![("reason".to_string(), DynSolValue::String(revert.reason))],
+        });
+    }
+
+    if let Ok(panic) = Panic::abi_decode(data) {
+        return Some(RevertReason {
+            name: "Panic".to_string(),
+            arguments: vec![("code".to_string(), DynSolValue::Uint(panic.code, 256))],
+        });
+    }
+
+    None
+}
+
+/// A readable revert reason, or the raw data when it can't be decoded.
+pub fn describe_revert(abi: &JsonAbi, data: &[u8]) -> String {
+    match decode_revert(abi, data) {
+        Some(reason) => reason.to_string(),
+        None if data.is_empty() => "reverted without data".to_string(),
+        None => format!("unknown error 0x{}", hex::encode(data)),
+    }
+}
+
+/// Why a transaction reverted, for the alert. Never fails, a replay that didn't work out is
+/// described instead.
+pub async fn explain_revert(
+    pool: &ProviderPool,
+    abi: &JsonAbi,
+    tx: &AnyRpcTransaction,
+    block_number: u64,
+) -> String {
+    match replay_revert_data(pool, tx, block_number).await {
+        Ok(Some(data)) => describe_revert(abi, &data),
+        Ok(None) => "unknown, the replay at the parent block didn't revert".to_string(),
+        Err(e) => format!("unknown, the replay failed: {}", e),
+    }
+}
+
+// Helper functions
+
+fn revert_data(error: &TransportError) -> Option<Bytes> {
+    let payload = error.as_error_resp()?;
+    payload.as_revert_data().or_else(|| {
+        // some nodes report a bare revert without any data
+        payload
+            .message
+            .contains("execution reverted")
+            .then(Bytes::new)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::revert_condition_match;
+    use crate::primitives::models::Condition;
+    use alloy::primitives::{Address, U256};
+    use serde_json::json;
+
+    const ACCOUNT: Address = Address::repeat_byte(0x11);
+
+    fn abi() -> JsonAbi {
+        JsonAbi::parse([
+            "error InsufficientBalance(address account, uint256 needed)",
+            "error Paused()",
+        ])
+        .unwrap()
+    }
+
+    // the selector and the arguments
+    fn insufficient_balance(needed: u64) -> Vec<u8> {
+        abi().errors["InsufficientBalance"][0]
+            .abi_encode_input(&[
+                DynSolValue::Address(ACCOUNT),
+                DynSolValue::Uint(U256::from(needed), 256),
+            ])
+            .unwrap()
+    }
+
+    #[test]
+    fn decodes_a_custom_error_with_its_arguments() {
+        let reason = decode_revert(&abi(), &insufficient_balance(500)).unwrap();
+        assert_eq!(reason.name, "InsufficientBalance");
+        assert_eq!(
+            reason.arguments,
+            vec![
+                ("account".to_string(), DynSolValue::Address(ACCOUNT)),
+                (
+                    "needed".to_string(),
+                    DynSolValue::Uint(U256::from(500), 256)
+                ),
+            ]
+        );
+
+        let paused = abi().errors["Paused"][0].selector();
+        assert_eq!(
+            decode_revert(&abi(), paused.as_slice()).unwrap().name,
+            "Paused"
+        );
+    }
+
+    #[test]
+    fn decodes_the_built_in_errors() {
+        let data = Revert::from("Ownable: caller is not the owner").abi_encode();
+        let reason = decode_revert(&abi(), &data).unwrap();
+        assert_eq!(reason.name, "Error");
+        assert_eq!(
+            reason.arguments,
+            vec![(
+                "reason".to_string(),
+                DynSolValue::String("Ownable: caller is not the owner".to_string())
+            )]
+        );
+
+        // arithmetic overflow
+        let data = Panic::from(0x11).abi_encode();
+        let reason = decode_revert(&abi(), &data).unwrap();
+        assert_eq!(reason.name, "Panic");
+        assert_eq!(
+            reason.arguments,
+            vec![("code".to_string(), DynSolValue::Uint(U256::from(0x11), 256))]
+        );
+    }
+
+    #[test]
+    fn leaves_empty_and_unknown_data_undecoded() {
+        assert!(decode_revert(&abi(), &[]).is_none());
+        assert!(decode_revert(&abi(), &[0xde, 0xad]).is_none());
+        assert!(decode_revert(&abi(), &[0xde, 0xad, 0xbe, 0xef, 0x01]).is_none());
+
+        // a known selector with arguments that don't decode
+        assert!(decode_revert(&abi(), &insufficient_balance(1)[..20]).is_none());
+
+        assert_eq!(describe_revert(&abi(), &[]), "reverted without data");
+        assert_eq!(
+            describe_revert(&abi(), &[0xde, 0xad, 0xbe, 0xef]),
+            "unknown error 0xdeadbeef"
+        );
+    }
+
+    #[test]
+    fn describes_a_decoded_revert() {
+        assert_eq!(
+            describe_revert(&abi(), &insufficient_balance(500)),
+            format!("InsufficientBalance(account: {:?}, needed: 500)", ACCOUNT)
+        );
+    }
+
+    #[test]
+    fn matches_conditions_on_the_error() {
+        let reason = decode_revert(&abi(), &insufficient_balance(500)).unwrap();
+        let matches = |condition: serde_json::Value, reason: Option<&RevertReason>| {
+            let condition: Condition = serde_json::from_value(condition).unwrap();
+            revert_condition_match(&condition, reason)
+        };
+
+        assert!(matches(
+            json!({ "Error": "InsufficientBalance" }),
+            Some(&reason)
+        ));
+        assert!(!matches(json!({ "Error": "Paused" }), Some(&reason)));
+        assert!(!matches(json!({ "Error": "InsufficientBalance" }), None));
+
+        let needed = |operator: &str, value: &str| json!({ "ErrorArgument": { "name": "needed", "operator": operator, "value": value } });
+        assert!(matches(needed("Gt", "100"), Some(&reason)));
+        assert!(matches(needed("Eq", "500"), Some(&reason)));
+        assert!(!matches(needed("Lt", "500"), Some(&reason)));
+        assert!(!matches(needed("Gt", "100"), None));
+
+        let account = json!({ "ErrorArgument": {
+            "name": "account",
+            "operator": "Contains",
+            "value": ACCOUNT.to_string(),
+        } });
+        assert!(matches(account, Some(&reason)));
+        let missing =
+            json!({ "ErrorArgument": { "name": "owner", "operator": "Eq", "value": "1" } });
+        assert!(!matches(missing, Some(&reason)));
+
+        // conditions on anything else are left to the other checks
+        assert!(matches(json!({ "Status": "Reverted" }), None));
+    }
+}
@@ -0,0 +1,753 @@
+//! # Block Stream
+//! This module provides a shared, per-chain block ingestion service.
+//!
+//! Without it every monitor fetches the same full blocks from the same RPC. A `BlockStream`
+//! is keyed by `(chain, rpc_url)`: it fetches each new block exactly once, keeps the most
+//! recent ones in a cache and broadcasts them to every subscribed monitor. Transactions are
+//! only downloaded once a transaction monitor has subscribed, and a block's logs only when
+//! its `logsBloom` says they may hold something a subscribed event monitor is looking for.
+//!
+//! What a stream fetches follows its current subscribers: every subscription registers what it
+//! needs and withdraws it when it is dropped, so a stream stops downloading full blocks or
+//! logs nobody is looking for any more.
+//!
+//! The broadcast never waits on consumers. A monitor that falls behind skips ahead when the
+//! channel reports it lagged, and its scanner then reads the blocks it missed back out of the
+//! cache, only going to the RPC for blocks that have already been evicted.
+//!
+//! It defines the `StreamMonitor` trait, which drives the transaction and event scanners from
+//! the broadcast instead of from their own polling loops.
+
+use crate::PollingMonitor;
+use crate::bloom::{LogInterest, any_may_match};
+use crate::events::{EventScanner, poll_events};
+use crate::pool::ProviderPool;
+use crate::primitives::models::MonitorRule;
+use crate::primitives::utils::poll_interval_for;
+use crate::ratelimit::attribute;
+use crate::tx::{TxMatch, TxScanner, poll_transactions};
+use alloy::network::AnyRpcBlock;
+use alloy::providers::Provider;
+use alloy::rpc::types::{Filter, Log};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+/// How many blocks a slow consumer may fall behind before it starts missing broadcasts.
+pub const STREAM_CHANNEL_CAPACITY: usize = 64;
+
+/// How many recent blocks are kept around for consumers catching up.
+pub const STREAM_CACHE_DEPTH: usize = 256;
+
+/// How many polls in a row a stream may go without subscribers before it shuts down.
+const STREAM_IDLE_POLLS: u32 = 30;
+
+/// How long a consumer waits for a block before checking the stream is still alive.
+const STREAM_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A block as fetched by the ingestion service, shared between all monitors.
+#[derive(Debug, Clone)]
+pub struct BlockData {
+    pub block: AnyRpcBlock,
+
+    // every log emitted in the block, only fetched when the block's bloom may match one of
+    // the stream's log interests
+    pub logs: Option<Vec<Log>>,
+}
+
+impl BlockData {
+    pub fn number(&self) -> u64 {
+        self.block.header.number
+    }
+
+    /// Whether the block came with its full transactions rather than just their hashes.
+    pub fn has_transactions(&self) -> bool {
+        self.block.transactions.is_full()
+    }
+
+    /// Whether we know every log in the block that `interest` could match.
+    pub fn covers(&self, interest: &LogInterest) -> bool {
+        self.logs.is_some() || !interest.may_match(&self.block.header.logs_bloom)
+    }
+}
+
+pub struct BlockStream {
+    pub chain: String,
+    pub rpc_url: String,
+    poll_interval: Duration,
+    sender: broadcast::Sender<Arc<BlockData>>,
+    cache: RwLock<BTreeMap<u64, Arc<BlockData>>>,
+    interests: RwLock<HashMap<u64, Interest>>, // by subscription id
+    next_id: AtomicU64,
+    task: RwLock<Option<JoinHandle<()>>>,
+}
+
+/// What one subscriber needs the stream to fetch.
+#[derive(Debug, Clone)]
+struct Interest {
+    full: bool, // full transactions, for a transaction monitor
+    logs: Option<LogInterest>,
+}
+
+/// A monitor's subscription to a `BlockStream`. Dropping it withdraws what it asked the stream
+/// to fetch.
+pub struct StreamSubscription {
+    pub blocks: broadcast::Receiver<Arc<BlockData>>,
+    stream: Arc<BlockStream>,
+    id: u64,
+}
+
+impl StreamSubscription {
+    /// Fetches logs for blocks whose bloom may match `interest` instead, for a subscriber
+    /// whose contracts changed.
+    pub fn set_interest(&self, interest: LogInterest) {
+        if let Some(entry) = self.stream.interests.write().unwrap().get_mut(&self.id) {
+            entry.logs = Some(interest);
+        }
+    }
+}
+
+impl Drop for StreamSubscription {
+    fn drop(&mut self) {
+        self.stream.interests.write().unwrap().remove(&self.id);
+    }
+}
+
+impl BlockStream {
+    fn spawn(pool: Arc<ProviderPool>) -> Arc<Self> {
+        Self::new(&pool).start(pool)
+    }
+
+    fn new(pool: &ProviderPool) -> Self {
+        let (sender, _) = broadcast::channel(STREAM_CHANNEL_CAPACITY);
+        Self {
+            chain: pool.chain.clone(),
+            rpc_url: pool.primary_url().to_string(),
+            poll_interval: poll_interval_for(&pool.chain),
+            sender,
+            cache: RwLock::new(BTreeMap::new()),
+            interests: RwLock::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            task: RwLock::new(None),
+        }
+    }
+
+    fn start(self, pool: Arc<ProviderPool>) -> Arc<Self> {
+        let stream = Arc::new(self);
+        let caller = format!("block-stream:{}", pool.chain);
+        let handle = tokio::spawn(attribute(caller, stream.clone().ingest(pool)));
+        *stream.task.write().unwrap() = Some(handle);
+
+        stream
+    }
+
+    /// Receives every block the stream ingests from now on, with its full transactions.
+    pub fn subscribe_transactions(self: &Arc<Self>) -> StreamSubscription {
+        self.subscribe(Interest {
+            full: true,
+            logs: None,
+        })
+    }
+
+    /// Receives every block the stream ingests from now on, with its logs whenever the
+    /// block's bloom says they may match `interest`.
+    pub fn subscribe_logs(self: &Arc<Self>, interest: LogInterest) -> StreamSubscription {
+        self.subscribe(Interest {
+            full: false,
+            logs: Some(interest),
+        })
+    }
+
+    fn subscribe(self: &Arc<Self>, interest: Interest) -> StreamSubscription {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.interests.write().unwrap().insert(id, interest);
+        StreamSubscription {
+            blocks: self.sender.subscribe(),
+            stream: self.clone(),
+            id,
+        }
+    }
+
+    /// Whether any subscriber still needs full transactions, and the logs they are after.
+    fn wanted(&self) -> (bool, Vec<LogInterest>) {
+        let interests = self.interests.read().unwrap();
+        let full = interests.values().any(|interest| interest.full);
+        let mut logs: Vec<LogInterest> = Vec::new();
+        for interest in interests
+            .values()
+            .filter_map(|interest| interest.logs.as_ref())
+        {
+            if !logs.contains(interest) {
+                logs.push(interest.clone());
+            }
+        }
+        (full, logs)
+    }
+
+    /// A recently ingested block, if it is still in the cache.
+    pub fn cached(&self, number: u64) -> Option<Arc<BlockData>> {
+        self.cache.read().unwrap().get(&number).cloned()
+    }
+
+    /// A recently ingested block with its full transactions, if the cache has one.
+    pub fn cached_full(&self, number: u64) -> Option<Arc<BlockData>> {
+        self.cached(number).filter(|data| data.has_transactions())
+    }
+
+    /// Every block in `from..=to` with the logs `interest` could match, or nothing if any of
+    /// them isn't cached.
+    pub fn cached_range(
+        &self,
+        from: u64,
+        to: u64,
+        interest: &LogInterest,
+    ) -> Option<Vec<Arc<BlockData>>> {
+        let cache = self.cache.read().unwrap();
+        (from..=to)
+            .map(|n| cache.get(&n).filter(|data| data.covers(interest)).cloned())
+            .collect()
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    fn is_running(&self) -> bool {
+        self.task
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+    }
+
+    async fn ingest(self: Arc<Self>, pool: Arc<ProviderPool>) {
+        println!(
+            "BlockStream: Ingesting {} from {}",
+            self.chain, self.rpc_url
+        );
+
+        let mut current_block = loop {
+            match pool
+                .request(|p| async move { p.get_block_number().await })
+                .await
+            {
+                Ok(num) => break num,
+                Err(e) => {
+                    eprintln!("Error fetching latest block number: {}", e);
+                    sleep(Duration::from_secs(2)).await;
+                }
+            }
+        };
+
+        let mut idle_polls = 0;
+
+        loop {
+            // nobody has been listening for a while, the next subscriber will start a fresh stream
+            if self.sender.receiver_count() == 0 {
+                idle_polls += 1;
+                if idle_polls >= STREAM_IDLE_POLLS {
+                    println!("BlockStream: No subscribers left for {}", self.chain);
+                    return;
+                }
+            } else {
+                idle_polls = 0;
+            }
+
+            let latest_block = match pool
+                .request(|p| async move { p.get_block_number().await })
+                .await
+            {
+                Ok(num) => num,
+                Err(e) => {
+                    eprintln!("Error fetching latest block number: {}", e);
+                    sleep(self.poll_interval).await;
+                    continue;
+                }
+            };
+
+            while current_block < latest_block {
+                let target_block = current_block + 1;
+                let (full, interests) = self.wanted();
+
+                match fetch_block_data(&pool, target_block, full, &interests).await {
+                    Ok(Some(data)) => {
+                        let data = Arc::new(data);
+                        self.repair_cache(&pool, &data).await;
+                        self.insert(data.clone());
+
+                        // an error only means there are no receivers right now
+                        let _ = self.sender.send(data);
+                        current_block = target_block;
+                    }
+                    Ok(None) => sleep(Duration::from_millis(500)).await,
+                    Err(e) => {
+                        eprintln!("Error fetching block {}: {}", target_block, e);
+                        sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+
+            sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Replaces cached blocks that were orphaned by a reorg, so consumers rewinding to the
+    /// common ancestor read the new branch out of the cache rather than the stale one.
+    async fn repair_cache(&self, pool: &ProviderPool, data: &BlockData) {
+        let mut number = data.number();
+        let mut parent_hash = data.block.header.parent_hash;
+
+        while let Some(cached) = number.checked_sub(1).and_then(|n| self.cached(n)) {
+            if cached.block.header.hash == parent_hash {
+                return;
+            }
+
+            let full = cached.has_transactions();
+            let (_, interests) = self.wanted();
+            match fetch_block_data(pool, number - 1, full, &interests).await {
+                Ok(Some(replacement)) => {
+                    parent_hash = replacement.block.header.parent_hash;
+                    self.insert(Arc::new(replacement));
+                    number -= 1;
+                }
+                _ => {
+                    // can't repair it, drop the stale block so consumers go to the RPC instead
+                    self.cache.write().unwrap().remove(&(number - 1));
+                    return;
+                }
+            }
+        }
+    }
+
+    fn insert(&self, data: Arc<BlockData>) {
+        let mut cache = self.cache.write().unwrap();
+        cache.insert(data.number(), data);
+
+        while cache.len() > STREAM_CACHE_DEPTH {
+            cache.pop_first();
+        }
+    }
+}
+
+#[allow(async_fn_in_trait)]
+pub trait StreamMonitor {
+    async fn monitor_transactions_stream<F>(
+        self,
+        rules: Vec<MonitorRule>,
+        handler: F,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(TxMatch) + Send + 'static;
+
+    async fn monitor_events_stream<F>(
+        self,
+        event_names: &[&str],
+        handler: F,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(Log) + Send + 'static;
+}
+
+impl StreamMonitor for PollingMonitor {
+    async fn monitor_transactions_stream<F>(
+        self,
+        rules: Vec<MonitorRule>,
+        mut handler: F,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(TxMatch) + Send + 'static,
+    {
+        let stream = self
+            .stream
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Stream monitor needs a block stream"))?;
+
+        println!(
+            "TxMonitor: Following the {} block stream for {:?}",
+            stream.chain,
+            self.addresses()
+        );
+
+        let mut subscription = stream.subscribe_transactions();
+        let mut scanner = TxScanner::new(&self, rules, self.processing_head().await?);
+
+        while let Some(head) = next_head(&self, &stream, &mut subscription.blocks).await {
+            scanner.scan_to(&self, head, &mut handler).await;
+        }
+
+        eprintln!(
+            "TxMonitor: Block stream stopped, falling back to polling from block {}",
+            scanner.current_block()
+        );
+        poll_transactions(&self, &mut scanner, &mut handler).await
+    }
+
+    async fn monitor_events_stream<F>(
+        self,
+        event_names: &[&str],
+        mut handler: F,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(Log) + Send + 'static,
+    {
+        let stream = self
+            .stream
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Stream monitor needs a block stream"))?;
+
+        println!(
+            "EventsMonitor: Following the {} block stream for {:?}",
+            stream.chain,
+            self.addresses()
+        );
+
+        let mut scanner = EventScanner::new(&self, event_names, self.processing_head().await?);
+        let mut subscription = stream.subscribe_logs(scanner.interest());
+
+        while let Some(head) = next_head(&self, &stream, &mut subscription.blocks).await {
+            // a new contract's logs have to be fetched for the blocks the stream already has
+            if scanner.refresh(&self) {
+                subscription.set_interest(scanner.interest());
+            }
+            scanner.scan_to(&self, head, &mut handler).await;
+        }
+
+        eprintln!(
+            "EventsMonitor: Block stream stopped, falling back to polling from block {}",
+            scanner.current_block()
+        );
+        poll_events(&self, &mut scanner, &mut handler).await
+    }
+}
+
+/// Waits for the next broadcast block and turns it into the block the monitor may process.
+/// Returns `None` once the stream has stopped for good.
+async fn next_head(
+    monitor: &PollingMonitor,
+    stream: &BlockStream,
+    blocks: &mut broadcast::Receiver<Arc<BlockData>>,
+) -> Option<u64> {
+    loop {
+        let number = match timeout(STREAM_STALL_TIMEOUT, blocks.recv()).await {
+            Ok(Ok(data)) => data.number(),
+            Ok(Err(RecvError::Lagged(skipped))) => {
+                // the blocks we missed are picked up from the cache by the next scan
+                eprintln!(
+                    "Monitor for {:?} lagged {} blocks behind the {} block stream",
+                    monitor.addresses(),
+                    skipped,
+                    stream.chain
+                );
+                continue;
+            }
+            Ok(Err(RecvError::Closed)) => return None,
+            Err(_) if !stream.is_running() => return None,
+            Err(_) => continue,
+        };
+
+        match monitor.head_from_notification(number).await {
+            Ok(head) => return Some(head),
+            Err(e) => eprintln!("Error resolving processing head: {}", e),
+        }
+    }
+}
+
+/// All running block streams, keyed by `(chain, rpc_url)` of their pool's primary endpoint.
+#[derive(Default)]
+pub struct BlockStreams {
+    streams: RwLock<HashMap<(String, String), Arc<BlockStream>>>,
+}
+
+impl BlockStreams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the running stream for this chain and endpoint, starting one if needed.
+    pub fn get_or_spawn(&self, pool: Arc<ProviderPool>) -> Arc<BlockStream> {
+        let key = (pool.chain.clone(), pool.primary_url().to_string());
+        let mut streams = self.streams.write().unwrap();
+
+        if let Some(stream) = streams.get(&key)
+            && stream.is_running()
+        {
+            return stream.clone();
+        }
+
+        let stream = BlockStream::spawn(pool);
+        streams.insert(key, stream.clone());
+        stream
+    }
+}
+
+// Helper functions
+
+/// Fetches a block, with its full transactions if `full`, and every log emitted in it if its
+/// bloom may match any of the `interests`.
+pub async fn fetch_block_data(
+    pool: &ProviderPool,
+    number: u64,
+    full: bool,
+    interests: &[LogInterest],
+) -> Result<Option<BlockData>, anyhow::Error> {
+    let block = pool
+        .request(|p| async move {
+            if full {
+                p.get_block_by_number(number.into()).full().await
+            } else {
+                p.get_block_by_number(number.into()).await
+            }
+        })
+        .await?;
+    let Some(block) = block else {
+        return Ok(None);
+    };
+
+    let logs = if any_may_match(interests, &block.header.logs_bloom) {
+        // query by hash so the logs are guaranteed to belong to this exact block
+        let filter = Filter::new().at_block_hash(block.header.hash);
+        Some(
+            pool.request(|p| {
+                let filter = filter.clone();
+                async move { p.get_logs(&filter).await }
+            })
+            .await?,
+        )
+    } else {
+        None
+    };
+
+    Ok(Some(BlockData { block, logs }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Reply, RpcStub, block_json};
+    use alloy::primitives::{Address, B256};
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    // A chain whose head the test moves. Blocks from `fork_at` on are on a second branch.
+    struct TestChain {
+        head: AtomicU64,
+        fork_at: AtomicU64,
+        fetched: Mutex<Vec<(u64, bool)>>, // block number and whether it was fetched full
+    }
+
+    impl TestChain {
+        fn new(head: u64) -> Arc<Self> {
+            Arc::new(Self {
+                head: AtomicU64::new(head),
+                fork_at: AtomicU64::new(u64::MAX),
+                fetched: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn hash(&self, number: u64) -> B256 {
+            let mut hash = B256::ZERO;
+            hash[0] = (number >= self.fork_at.load(Ordering::SeqCst)) as u8;
+            hash[24..].copy_from_slice(&number.to_be_bytes());
+            hash
+        }
+
+        fn answer(&self, method: &str, params: &serde_json::Value) -> Reply {
+            match method {
+                "eth_blockNumber" => {
+                    Reply::Result(format!("{:#x}", self.head.load(Ordering::SeqCst)).into())
+                }
+                "eth_getBlockByNumber" => {
+                    let number = u64::from_str_radix(
+                        params[0].as_str().unwrap().trim_start_matches("0x"),
+                        16,
+                    )
+                    .unwrap();
+                    let full = params[1].as_bool().unwrap_or_default();
+                    self.fetched.lock().unwrap().push((number, full));
+                    if number > self.head.load(Ordering::SeqCst) {
+                        return Reply::Result(serde_json::Value::Null);
+                    }
+                    let mut block = block_json(number, self.hash(number));
+                    block["parentHash"] = serde_json::to_value(self.hash(number - 1)).unwrap();
+                    Reply::Result(block)
+                }
+                "eth_getLogs" => Reply::Result(serde_json::json!([])),
+                _ => Reply::Error(-32601, "Method not found"),
+            }
+        }
+
+        fn fetched(&self, number: u64) -> Vec<bool> {
+            let fetched = self.fetched.lock().unwrap();
+            fetched
+                .iter()
+                .filter(|(n, _)| *n == number)
+                .map(|(_, full)| *full)
+                .collect()
+        }
+    }
+
+    async fn serve(chain: &Arc<TestChain>) -> (RpcStub, Arc<ProviderPool>) {
+        let answering = chain.clone();
+        let stub = RpcStub::start(move |method, params| answering.answer(method, params)).await;
+        let pool = ProviderPool::new("testnet", std::slice::from_ref(&stub.url), None).unwrap();
+        (stub, Arc::new(pool))
+    }
+
+    // a stream polling every 10ms
+    fn start(pool: &Arc<ProviderPool>) -> Arc<BlockStream> {
+        BlockStream {
+            poll_interval: Duration::from_millis(10),
+            ..BlockStream::new(pool)
+        }
+        .start(pool.clone())
+    }
+
+    async fn eventually(what: &str, done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(
+                Instant::now() < deadline,
+                "timed out waiting until {}",
+                what
+            );
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn next_block(subscription: &mut StreamSubscription) -> u64 {
+        timeout(Duration::from_secs(5), subscription.blocks.recv())
+            .await
+            .expect("no block within 5s")
+            .unwrap()
+            .number()
+    }
+
+    fn interest(byte: u8) -> LogInterest {
+        LogInterest::new(vec![Address::repeat_byte(byte)], Vec::new())
+    }
+
+    #[tokio::test]
+    async fn fans_each_block_out_to_every_subscriber() {
+        let chain = TestChain::new(10);
+        let (stub, pool) = serve(&chain).await;
+        let stream = start(&pool);
+        let mut transactions = stream.subscribe_transactions();
+        let mut logs = stream.subscribe_logs(interest(1));
+        assert_eq!(stream.subscriber_count(), 2);
+
+        // once the stream knows where the head was, two new blocks come in
+        eventually("the stream started", || stub.requests() >= 2).await;
+        chain.head.store(12, Ordering::SeqCst);
+        for subscription in [&mut transactions, &mut logs] {
+            assert_eq!(next_block(subscription).await, 11);
+            assert_eq!(next_block(subscription).await, 12);
+        }
+        assert_eq!(chain.fetched(11), vec![true]);
+        assert!(stream.cached_full(12).is_some());
+
+        // without a transaction monitor the stream stops fetching full blocks
+        drop(transactions);
+        chain.head.store(13, Ordering::SeqCst);
+        assert_eq!(next_block(&mut logs).await, 13);
+        assert_eq!(chain.fetched(13), vec![false]);
+        assert!(stream.cached(13).is_some() && stream.cached_full(13).is_none());
+    }
+
+    #[tokio::test]
+    async fn replaces_the_blocks_a_reorg_orphaned() {
+        let chain = TestChain::new(6);
+        let (_stub, pool) = serve(&chain).await;
+        let stream = BlockStream::new(&pool);
+        for number in 1..=5 {
+            let data = fetch_block_data(&pool, number, false, &[]).await.unwrap();
+            stream.insert(Arc::new(data.unwrap()));
+        }
+
+        // blocks 4 and 5 were replaced, block 6 builds on the new 5
+        chain.fork_at.store(4, Ordering::SeqCst);
+        let head = fetch_block_data(&pool, 6, false, &[])
+            .await
+            .unwrap()
+            .unwrap();
+        stream.repair_cache(&pool, &head).await;
+
+        for number in 1..=5 {
+            let cached = stream.cached(number).unwrap();
+            assert_eq!(
+                cached.block.header.hash,
+                chain.hash(number),
+                "block {}",
+                number
+            );
+        }
+        assert_eq!(chain.fetched(3), vec![false]);
+        assert_eq!(chain.fetched(4), vec![false, false]);
+    }
+
+    #[tokio::test]
+    async fn drops_orphaned_blocks_it_cannot_replace() {
+        let chain = TestChain::new(6);
+        let (_stub, pool) = serve(&chain).await;
+        let stream = BlockStream::new(&pool);
+        for number in 4..=5 {
+            let data = fetch_block_data(&pool, number, false, &[]).await.unwrap();
+            stream.insert(Arc::new(data.unwrap()));
+        }
+
+        chain.fork_at.store(5, Ordering::SeqCst);
+        let head = fetch_block_data(&pool, 6, false, &[])
+            .await
+            .unwrap()
+            .unwrap();
+        let down = RpcStub::start(|_, _| Reply::Status(503)).await;
+        let unreachable =
+            ProviderPool::new("testnet", std::slice::from_ref(&down.url), None).unwrap();
+        stream.repair_cache(&unreachable, &head).await;
+
+        assert!(stream.cached(5).is_none());
+        assert!(stream.cached(4).is_some());
+    }
+
+    #[tokio::test]
+    async fn shuts_down_once_nobody_is_listening() {
+        let chain = TestChain::new(10);
+        let (_stub, pool) = serve(&chain).await;
+
+        let stream = start(&pool);
+        let subscription = stream.subscribe_transactions();
+        // well past the idle polls it would take to stop
+        sleep(Duration::from_millis(20 * STREAM_IDLE_POLLS as u64)).await;
+        assert!(stream.is_running());
+
+        drop(subscription);
+        eventually("the stream stopped", || !stream.is_running()).await;
+    }
+
+    #[tokio::test]
+    async fn withdraws_interest_when_a_subscription_is_dropped() {
+        let chain = TestChain::new(10);
+        let (_stub, pool) = serve(&chain).await;
+        let stream = start(&pool);
+        assert_eq!(stream.wanted(), (false, Vec::new()));
+
+        let transactions = stream.subscribe_transactions();
+        let logs = stream.subscribe_logs(interest(1));
+        let same_logs = stream.subscribe_logs(interest(1));
+        assert_eq!(stream.wanted(), (true, vec![interest(1)]));
+
+        logs.set_interest(interest(2));
+        let (_, interests) = stream.wanted();
+        assert!(interests.contains(&interest(1)) && interests.contains(&interest(2)));
+
+        drop(transactions);
+        drop(same_logs);
+        assert_eq!(stream.wanted(), (false, vec![interest(2)]));
+        drop(logs);
+        assert_eq!(stream.wanted(), (false, Vec::new()));
+    }
+}
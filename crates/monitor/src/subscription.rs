@@ -0,0 +1,333 @@
+//! # Subscription Monitor
+//! This module provides tools for monitoring over a WebSocket connection.
+//!
+//! It defines the `SubscriptionMonitor` trait, which reacts to `eth_subscribe` notifications
+//! instead of sleeping between polls. Transactions are driven by `newHeads`: every new head
+//! triggers a scan of the blocks since the last one processed, which also backfills any gap
+//! left while the socket was down. Events use a `logs` subscription when the monitor alerts
+//! at the chain tip, with new heads moving its position along between logs, and fall back to
+//! `newHeads` driven log queries when it waits for confirmations. Backfills are queried in
+//! bounded ranges. Dropped sockets are reconnected with a backoff, and once that keeps failing
+//! the monitor carries on with plain HTTP polling from where it stopped.
+
+use crate::PollingMonitor;
+use crate::events::{EventScanner, poll_events};
+use crate::primitives::models::BlockTarget;
+use crate::primitives::models::MonitorRule;
+use crate::tx::{TxMatch, TxScanner, poll_transactions};
+use alloy::network::AnyNetwork;
+use alloy::providers::{Provider, ProviderBuilder, RootProvider, WsConnect};
+use alloy::rpc::types::Log;
+use futures::StreamExt;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// How many times in a row we try to re-establish the socket before falling back to polling.
+pub const MAX_WS_RECONNECTS: u32 = 5;
+
+#[allow(async_fn_in_trait)]
+pub trait SubscriptionMonitor {
+    async fn monitor_transactions_subscription<F>(
+        self,
+        rules: Vec<MonitorRule>,
+        handler: F,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(TxMatch) + Send + 'static;
+
+    async fn monitor_events_subscription<F>(
+        self,
+        event_names: &[&str],
+        handler: F,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(Log) + Send + 'static;
+}
+
+impl SubscriptionMonitor for PollingMonitor {
+    async fn monitor_transactions_subscription<F>(
+        self,
+        rules: Vec<MonitorRule>,
+        mut handler: F,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(TxMatch) + Send + 'static,
+    {
+        let ws_url = self
+            .ws_url
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Subscription monitor needs a ws_url"))?;
+
+        println!(
+            "TxMonitor: Subscribing to new heads for {:?}",
+            self.addresses()
+        );
+
+        let mut scanner = TxScanner::new(&self, rules, self.processing_head().await?);
+        let mut failures = 0;
+
+        while failures < MAX_WS_RECONNECTS {
+            match connect_ws(&ws_url).await {
+                Ok(ws) => match ws.subscribe_blocks().await {
+                    Ok(sub) => {
+                        failures = 0;
+
+                        // this backfills the blocks we missed while the socket was down.
+                        if let Ok(head) = self.processing_head().await {
+                            scanner.scan_to(&self, head, &mut handler).await;
+                        }
+
+                        let mut stream = sub.into_stream();
+                        while let Some(header) = stream.next().await {
+                            match self.head_from_notification(header.number).await {
+                                Ok(head) => scanner.scan_to(&self, head, &mut handler).await,
+                                Err(e) => eprintln!("Error resolving processing head: {}", e),
+                            }
+                        }
+
+                        eprintln!("TxMonitor: newHeads subscription closed, reconnecting");
+                    }
+                    Err(e) => eprintln!("Error subscribing to new heads: {}", e),
+                },
+                Err(e) => eprintln!("Error connecting to {}: {}", ws_url, e),
+            }
+
+            failures += 1;
+            sleep(reconnect_backoff(failures)).await;
+        }
+
+        eprintln!(
+            "TxMonitor: WebSocket unavailable, falling back to polling from block {}",
+            scanner.current_block()
+        );
+        poll_transactions(&self, &mut scanner, &mut handler).await
+    }
+
+    async fn monitor_events_subscription<F>(
+        self,
+        event_names: &[&str],
+        mut handler: F,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(Log) + Send + 'static,
+    {
+        let ws_url = self
+            .ws_url
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Subscription monitor needs a ws_url"))?;
+
+        println!(
+            "EventsMonitor: Subscribing to events for {:?}",
+            self.addresses()
+        );
+
+        let mut scanner = EventScanner::new(&self, event_names, self.processing_head().await?);
+
+        // a logs subscription delivers at the chain tip, so it only fits monitors that alert there
+        let at_tip = self.confirmations == 0 && self.block_target == BlockTarget::Latest;
+        let mut changes = self.contracts.subscribe();
+        let mut failures = 0;
+
+        while failures < MAX_WS_RECONNECTS {
+            // the subscription filter is built from the contracts as they are now, and an
+            // address-less one would match every contract on the chain
+            scanner.refresh(&self);
+            changes.mark_unchanged();
+            let by_logs = at_tip && !self.addresses().is_empty();
+
+            match connect_ws(&ws_url).await {
+                Ok(ws) if by_logs => match ws.subscribe_logs(&scanner.filter()).await {
+                    Ok(sub) => {
+                        failures = 0;
+
+                        // this backfills the logs we missed while the socket was down.
+                        if let Ok(head) = self.processing_head().await {
+                            scanner.scan_to(&self, head, &mut handler).await;
+                        }
+
+                        // a quiet contract sends no logs, so new heads move the scanner along
+                        // too, keeping the backfill after a reconnect short
+                        let mut heads = match ws.subscribe_blocks().await {
+                            Ok(sub) => Some(sub.into_stream()),
+                            Err(e) => {
+                                eprintln!("Error subscribing to new heads: {}", e);
+                                None
+                            }
+                        };
+
+                        let mut stream = sub.into_stream();
+                        let mut resubscribe = false;
+                        loop {
+                            tokio::select! {
+                                log = stream.next() => {
+                                    let Some(log) = log else {
+                                        break;
+                                    };
+                                    // every block before this log's block has been fully delivered
+                                    if let Some(number) = log.block_number {
+                                        scanner.advance_to(number.saturating_sub(1));
+                                    }
+                                    scanner.deliver(log, &mut handler);
+                                }
+                                header = async {
+                                    match heads.as_mut() {
+                                        Some(heads) => heads.next().await,
+                                        None => std::future::pending().await,
+                                    }
+                                } => {
+                                    let Some(header) = header else {
+                                        break;
+                                    };
+                                    // the logs of the blocks before a new head have all been sent
+                                    scanner.advance_to(header.number.saturating_sub(1));
+                                }
+                                _ = changes.changed() => {
+                                    resubscribe = true;
+                                    break;
+                                }
+                            }
+                        }
+
+                        if resubscribe {
+                            println!("EventsMonitor: Monitored contracts changed, resubscribing");
+                            continue;
+                        }
+                        eprintln!("EventsMonitor: logs subscription closed, reconnecting");
+                    }
+                    Err(e) => eprintln!("Error subscribing to logs: {}", e),
+                },
+                Ok(ws) => match ws.subscribe_blocks().await {
+                    Ok(sub) => {
+                        failures = 0;
+
+                        if let Ok(head) = self.processing_head().await {
+                            scanner.scan_to(&self, head, &mut handler).await;
+                        }
+
+                        let mut stream = sub.into_stream();
+                        while let Some(header) = stream.next().await {
+                            match self.head_from_notification(header.number).await {
+                                Ok(head) => scanner.scan_to(&self, head, &mut handler).await,
+                                Err(e) => eprintln!("Error resolving processing head: {}", e),
+                            }
+                        }
+
+                        eprintln!("EventsMonitor: newHeads subscription closed, reconnecting");
+                    }
+                    Err(e) => eprintln!("Error subscribing to new heads: {}", e),
+                },
+                Err(e) => eprintln!("Error connecting to {}: {}", ws_url, e),
+            }
+
+            failures += 1;
+            sleep(reconnect_backoff(failures)).await;
+        }
+
+        eprintln!(
+            "EventsMonitor: WebSocket unavailable, falling back to polling from block {}",
+            scanner.current_block()
+        );
+        poll_events(&self, &mut scanner, &mut handler).await
+    }
+}
+
+// Helper functions
+
+pub(crate) async fn connect_ws(ws_url: &str) -> Result<RootProvider<AnyNetwork>, anyhow::Error> {
+    let provider = ProviderBuilder::new()
+        .disable_recommended_fillers()
+        .network::<AnyNetwork>()
+        .connect_ws(WsConnect::new(ws_url))
+        .await?;
+
+    Ok(provider)
+}
+
+// doubles the wait after every failed attempt, capped at half a minute
+pub(crate) fn reconnect_backoff(failures: u32) -> Duration {
+    Duration::from_secs((1u64 << failures.min(5)).min(30))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MonitoredContract;
+    use crate::pool::ProviderPool;
+    use crate::primitives::models::Condition;
+    use crate::testing::{Anvil, Proxy};
+    use alloy::network::TransactionResponse;
+    use alloy::primitives::B256;
+    use std::sync::Arc;
+    use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+    use tokio::time::{Instant, timeout};
+
+    // Mines a block a second until the transaction's match comes in.
+    async fn mine_until_matched(
+        anvil: &Anvil,
+        matches: &mut UnboundedReceiver<TxMatch>,
+        hash: B256,
+        within: Duration,
+    ) -> TxMatch {
+        let deadline = Instant::now() + within;
+        while Instant::now() < deadline {
+            anvil.mine().await;
+            if let Ok(Some(m)) = timeout(Duration::from_secs(1), matches.recv()).await {
+                assert_eq!(m.tx.tx_hash(), hash);
+                return m;
+            }
+        }
+        panic!("no match for {:?} within {:?}", hash, within);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs anvil"]
+    async fn follows_new_heads_through_reconnects_and_falls_back_to_polling() {
+        let anvil = Anvil::start(&["--no-mining"]).await;
+        let accounts = anvil.accounts().await;
+        let (from, to) = (accounts[0], accounts[1]);
+        let mut proxy = Proxy::start(anvil.port).await;
+
+        let pool = Arc::new(ProviderPool::new("anvil", &[anvil.http_url()], None).unwrap());
+        let monitor = PollingMonitor::new(pool, vec![MonitoredContract::new(to, Arc::default())])
+            .with_ws_url(proxy.ws_url())
+            .with_poll_interval(Duration::from_millis(200));
+        let rule = MonitorRule {
+            name: "to second account".to_string(),
+            conditions: vec![Condition::To(to)],
+            confirmations: None,
+            spawn: None,
+            abi_functions: Vec::new(),
+        };
+
+        let (sender, mut matches) = unbounded_channel();
+        let watching = tokio::spawn(monitor.monitor_transactions_subscription(
+            vec![rule],
+            move |m: TxMatch| {
+                let _ = sender.send(m);
+            },
+        ));
+        sleep(Duration::from_secs(1)).await;
+
+        // delivered on the new head
+        let hash = anvil.send(from, to).await;
+        let m = mine_until_matched(&anvil, &mut matches, hash, Duration::from_secs(10)).await;
+        assert_eq!(m.block_number, 1);
+
+        // mined while the socket was down, scanned once it's back
+        proxy.cut();
+        let missed = anvil.send(from, to).await;
+        anvil.mine().await;
+        let missed_block = anvil.provider().get_block_number().await.unwrap();
+        sleep(Duration::from_secs(1)).await;
+        proxy.resume().await;
+        let m = mine_until_matched(&anvil, &mut matches, missed, Duration::from_secs(60)).await;
+        assert_eq!(m.block_number, missed_block);
+
+        // gone for good, after MAX_WS_RECONNECTS attempts the monitor polls over HTTP
+        drop(proxy);
+        let polled = anvil.send(from, to).await;
+        mine_until_matched(&anvil, &mut matches, polled, Duration::from_secs(180)).await;
+
+        watching.abort();
+    }
+}
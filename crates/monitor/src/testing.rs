@@ -0,0 +1,398 @@
+//! # Test Helpers
+//! This module provides tools for the tests that talk to an RPC endpoint.
+//!
+//! `Anvil` starts a local node on a free port and kills it when dropped, and `Proxy` sits
+//! between a monitor and the node so a test can cut the monitor's connections and let it back
+//! in. The tests using them need Foundry's `anvil` on the path and are ignored unless asked
+//! for, with `cargo test -- --ignored`.
+//!
+//! `RpcStub` is a JSON-RPC endpoint that answers every request from a closure, for the tests
+//! that need an endpoint to fail, disagree or lack a method in a particular way.
+//!
+//! `transaction`, `receipt` and `log` build the RPC types the matching code works on from a
+//! plain mined transaction and a few JSON fields to change.
+
+use alloy::network::{AnyNetwork, AnyRpcTransaction, AnyTransactionReceipt};
+use alloy::primitives::{Address, B256, Bytes};
+use alloy::providers::{Provider, ProviderBuilder, RootProvider};
+use serde_json::{Value, json};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, copy_bidirectional};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::sleep;
+
+/// A local anvil node, killed when dropped.
+pub struct Anvil {
+    child: Child,
+    pub port: u16,
+}
+
+impl Anvil {
+    /// Starts anvil with `args` on a free port and waits until it answers.
+    pub async fn start(args: &[&str]) -> Self {
+        let port = free_port();
+        let child = Command::new("anvil")
+            .args(["--port", &port.to_string()])
+            .args(args)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("anvil is not installed");
+        let anvil = Self { child, port };
+
+        for _ in 0..100 {
+            if anvil.provider().get_block_number().await.is_ok() {
+                return anvil;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        panic!("anvil did not start on port {}", port);
+    }
+
+    pub fn http_url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    pub fn ws_url(&self) -> String {
+        format!("ws://127.0.0.1:{}", self.port)
+    }
+
+    pub fn provider(&self) -> RootProvider<AnyNetwork> {
+        ProviderBuilder::new()
+            .disable_recommended_fillers()
+            .network::<AnyNetwork>()
+            .connect_http(self.http_url().parse().unwrap())
+    }
+
+    /// The node's unlocked accounts.
+    pub async fn accounts(&self) -> Vec<Address> {
+        self.provider().get_accounts().await.unwrap()
+    }
+
+    /// Sends 1 wei from one unlocked account to `to`, returning the transaction's hash.
+    pub async fn send(&self, from: Address, to: Address) -> B256 {
+        self.provider()
+            .raw_request(
+                "eth_sendTransaction".into(),
+                (serde_json::json!({ "from": from, "to": to, "value": "0x1" }),),
+            )
+            .await
+            .unwrap()
+    }
+
+    /// Mines one block.
+    pub async fn mine(&self) {
+        let _: String = self
+            .provider()
+            .raw_request("evm_mine".into(), ())
+            .await
+            .unwrap();
+    }
+}
+
+impl Drop for Anvil {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Forwards connections on a port of its own to a local port, until it is cut.
+pub struct Proxy {
+    pub port: u16,
+    target: u16,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Proxy {
+    pub async fn start(target: u16) -> Self {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        Self {
+            port,
+            target,
+            task: Some(tokio::spawn(forward(listener, target))),
+        }
+    }
+
+    pub fn ws_url(&self) -> String {
+        format!("ws://127.0.0.1:{}", self.port)
+    }
+
+    /// Closes every connection and stops accepting new ones.
+    pub fn cut(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+
+    /// Accepts connections on the same port again.
+    pub async fn resume(&mut self) {
+        let listener = TcpListener::bind(("127.0.0.1", self.port)).await.unwrap();
+        self.task = Some(tokio::spawn(forward(listener, self.target)));
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.cut();
+    }
+}
+
+/// How a `RpcStub` answers a request.
+pub enum Reply {
+    Result(Value),
+    /// A JSON-RPC error response, with its code and message.
+    Error(i64, &'static str),
+    /// A bare HTTP status, without a JSON-RPC response.
+    Status(u16),
+    /// Another reply, sent after a while. A batch waits for its slowest reply.
+    After(Duration, Box<Reply>),
+}
+
+type Answer = dyn Fn(&str, &Value) -> Reply + Send + Sync;
+
+/// A JSON-RPC endpoint answering every request by method and params, until dropped.
+pub struct RpcStub {
+    pub url: String,
+    requests: Arc<AtomicUsize>,
+    task: JoinHandle<()>,
+}
+
+impl RpcStub {
+    pub async fn start(answer: impl Fn(&str, &Value) -> Reply + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let task = tokio::spawn(serve(listener, Arc::new(answer), requests.clone()));
+        Self {
+            url,
+            requests,
+            task,
+        }
+    }
+
+    /// How many JSON-RPC requests the stub has answered.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for RpcStub {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// An `eth_getBlockByNumber` result for a block without transactions.
+pub fn block_json(number: u64, hash: B256) -> Value {
+    json!({
+        "hash": hash,
+        "parentHash": B256::ZERO,
+        "sha3Uncles": B256::ZERO,
+        "miner": Address::ZERO,
+        "stateRoot": B256::ZERO,
+        "transactionsRoot": B256::ZERO,
+        "receiptsRoot": B256::ZERO,
+        "logsBloom": format!("0x{}", "0".repeat(512)),
+        "difficulty": "0x0",
+        "number": format!("{:#x}", number),
+        "gasLimit": "0x1c9c380",
+        "gasUsed": "0x0",
+        "timestamp": "0x0",
+        "extraData": "0x",
+        "mixHash": B256::ZERO,
+        "nonce": "0x0000000000000000",
+        "baseFeePerGas": "0x7",
+        "size": "0x200",
+        "uncles": [],
+        "transactions": [],
+    })
+}
+
+/// A mined EIP-1559 transaction with `fields` changed, a `null` field is left out.
+pub fn transaction(fields: Value) -> AnyRpcTransaction {
+    let tx = json!({
+        "hash": B256::repeat_byte(0xaa),
+        "blockHash": B256::repeat_byte(0xbb),
+        "blockNumber": "0x1",
+        "transactionIndex": "0x0",
+        "type": "0x2",
+        "chainId": "0x1",
+        "nonce": "0x0",
+        "from": Address::repeat_byte(0x01),
+        "to": Address::repeat_byte(0x02),
+        "value": "0x0",
+        "gas": "0x30000",
+        "maxFeePerGas": "0x77359400",
+        "maxPriorityFeePerGas": "0x3b9aca00",
+        "gasPrice": "0x59682f00",
+        "input": "0x",
+        "accessList": [],
+        "v": "0x0",
+        "yParity": "0x0",
+        "r": "0x1",
+        "s": "0x1",
+    });
+    serde_json::from_value(merged(tx, fields)).unwrap()
+}
+
+/// The receipt of a successful `transaction()` with `fields` changed, a `null` field is left
+/// out.
+pub fn receipt(fields: Value) -> AnyTransactionReceipt {
+    let receipt = json!({
+        "transactionHash": B256::repeat_byte(0xaa),
+        "blockHash": B256::repeat_byte(0xbb),
+        "blockNumber": "0x1",
+        "transactionIndex": "0x0",
+        "type": "0x2",
+        "from": Address::repeat_byte(0x01),
+        "to": Address::repeat_byte(0x02),
+        "contractAddress": null,
+        "status": "0x1",
+        "gasUsed": "0x5208",
+        "cumulativeGasUsed": "0x5208",
+        "effectiveGasPrice": "0x59682f00",
+        "logs": [],
+        "logsBloom": format!("0x{}", "0".repeat(512)),
+    });
+    serde_json::from_value(merged(receipt, fields)).unwrap()
+}
+
+/// A log entry for `receipt()`'s `logs`.
+pub fn log(address: Address, topics: &[B256], data: Bytes) -> Value {
+    json!({
+        "address": address,
+        "topics": topics,
+        "data": data,
+        "blockHash": B256::repeat_byte(0xbb),
+        "blockNumber": "0x1",
+        "transactionHash": B256::repeat_byte(0xaa),
+        "transactionIndex": "0x0",
+        "logIndex": "0x0",
+        "removed": false,
+    })
+}
+
+// Helper functions
+
+fn merged(mut base: Value, fields: Value) -> Value {
+    for (key, value) in fields.as_object().cloned().unwrap_or_default() {
+        if value.is_null() {
+            base.as_object_mut().unwrap().remove(&key);
+        } else {
+            base[key] = value;
+        }
+    }
+    base
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind(("127.0.0.1", 0))
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .unwrap()
+}
+
+// The connections live in the task's join set, so aborting the task closes all of them.
+async fn forward(listener: TcpListener, target: u16) {
+    let mut connections = JoinSet::new();
+    while let Ok((mut inbound, _)) = listener.accept().await {
+        connections.spawn(async move {
+            if let Ok(mut outbound) = TcpStream::connect(("127.0.0.1", target)).await {
+                let _ = copy_bidirectional(&mut inbound, &mut outbound).await;
+            }
+        });
+    }
+}
+
+async fn serve(listener: TcpListener, answer: Arc<Answer>, requests: Arc<AtomicUsize>) {
+    let mut connections = JoinSet::new();
+    while let Ok((stream, _)) = listener.accept().await {
+        let (answer, requests) = (answer.clone(), requests.clone());
+        connections.spawn(async move {
+            let mut stream = BufReader::new(stream);
+            // one request after another on a kept-alive connection
+            while let Some(body) = read_request(&mut stream).await {
+                let (delay, status, body) = respond(answer.as_ref(), &requests, &body);
+                sleep(delay).await;
+                let head = format!(
+                    "HTTP/1.1 {} Stub\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                let stream = stream.get_mut();
+                if stream.write_all(head.as_bytes()).await.is_err()
+                    || stream.write_all(body.as_bytes()).await.is_err()
+                {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Option<Vec<u8>> {
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await.ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            content_length = value.trim().parse().ok()?;
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await.ok()?;
+    Some(body)
+}
+
+// the delay before the response goes out, its status and its body
+fn respond(answer: &Answer, requests: &AtomicUsize, body: &[u8]) -> (Duration, u16, String) {
+    let mut delay = Duration::ZERO;
+    let mut reply = |request: &Value| {
+        requests.fetch_add(1, Ordering::SeqCst);
+        let method = request["method"].as_str().unwrap_or_default();
+        let id = request["id"].clone();
+        let mut reply = answer(method, &request["params"]);
+        while let Reply::After(after, then) = reply {
+            delay = delay.max(after);
+            reply = *then;
+        }
+        match reply {
+            Reply::Result(result) => Ok(json!({ "jsonrpc": "2.0", "id": id, "result": result })),
+            Reply::Error(code, message) => Ok(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            })),
+            Reply::Status(status) => Err(status),
+            Reply::After(..) => unreachable!(),
+        }
+    };
+
+    let request: Value = serde_json::from_slice(body).unwrap_or_default();
+    let response = match &request {
+        Value::Array(batch) => batch
+            .iter()
+            .map(&mut reply)
+            .collect::<Result<Vec<_>, _>>()
+            .map(Value::from),
+        request => reply(request),
+    };
+    match response {
+        Ok(response) => (delay, 200, response.to_string()),
+        Err(status) => (delay, status, String::new()),
+    }
+}
@@ -0,0 +1,497 @@
+//! # Call Tracing
+//! This module provides tools for looking inside transactions at the calls they make.
+//!
+//! A transaction's body only shows the top-level call. To see what happens underneath, ETH
+//! sent by contracts or calls routed through other contracts, the whole block is traced,
+//! either with Geth's `debug_traceBlockByNumber` and the `callTracer`, or with the Parity /
+//! Erigon style `trace_block`. Both are flattened into the same list of `InternalCall`s.
+//! The debug API is tried first. A block the debug API turns down as unsupported is traced
+//! with `trace_block` instead, and after a few such refusals in a row the tracer goes straight
+//! to `trace_block`. It tries the debug API again as soon as `trace_block` fails, since a pool
+//! can mix endpoints that support one or the other.
+
+use crate::pool::{ProviderPool, is_unsupported};
+use alloy::eips::BlockId;
+use alloy::primitives::{Address, B256, Bytes, U256};
+use alloy::providers::ext::{DebugApi, TraceApi};
+use alloy::rpc::types::trace::geth::{CallConfig, CallFrame, GethDebugTracingOptions, TraceResult};
+use alloy::rpc::types::trace::parity::{Action, LocalizedTransactionTrace, TraceOutput};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// One call frame of a transaction, flattened out of its call tree.
+#[derive(Debug, Clone)]
+pub struct InternalCall {
+    pub tx_hash: B256,
+
+    // position in the call tree, empty for the top-level call and `[0, 2]` for the third
+    // call made by the first call the transaction made
+    pub path: Vec<usize>,
+
+    pub call_type: String, // CALL, DELEGATECALL, STATICCALL, CREATE, SELFDESTRUCT ...
+    pub from: Address,
+    pub to: Option<Address>,
+    pub value: U256,
+    pub input: Bytes,
+
+    // this frame or one of its callers reverted, so nothing it did stuck
+    pub reverted: bool,
+}
+
+impl InternalCall {
+    /// Whether the frame actually moved ETH from `from` to `to`. Delegate and static calls
+    /// carry their caller's value without transferring anything.
+    pub fn moves_value(&self) -> bool {
+        !self.reverted
+            && !self.value.is_zero()
+            && matches!(
+                self.call_type.as_str(),
+                "CALL" | "CREATE" | "CREATE2" | "SELFDESTRUCT"
+            )
+    }
+
+    /// The call path in a readable form, `tx` for the top-level call.
+    pub fn describe_path(&self) -> String {
+        std::iter::once("tx".to_string())
+            .chain(self.path.iter().map(|idx| idx.to_string()))
+            .collect::<Vec<_>>()
+            .join(" > ")
+    }
+}
+
+/// How many times in a row the debug API has to be unsupported before we stop asking for it.
+pub const DEBUG_API_ATTEMPTS: u32 = 3;
+
+/// Traces blocks, remembering whether the endpoints support the debug API.
+#[derive(Default)]
+pub struct CallTracer {
+    unsupported: AtomicU32, // debug API refusals in a row
+}
+
+impl CallTracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every call frame of every transaction in the block, in execution order. `tx_hashes`
+    /// are the block's transaction hashes in block order, for the endpoints that leave them
+    /// out of the traces.
+    pub async fn trace_block(
+        &self,
+        pool: &ProviderPool,
+        number: u64,
+        tx_hashes: &[B256],
+    ) -> Result<Vec<InternalCall>, anyhow::Error> {
+        if self.unsupported.load(Ordering::Relaxed) < DEBUG_API_ATTEMPTS {
+            let options = GethDebugTracingOptions::call_tracer(CallConfig::default());
+            match pool
+                .request(|p| {
+                    let options = options.clone();
+                    async move { p.debug_trace_block_by_number(number.into(), options).await }
+                })
+                .await
+            {
+                Ok(results) => {
+                    self.unsupported.store(0, Ordering::Relaxed);
+                    return flatten_geth(results, tx_hashes);
+                }
+                Err(e) if is_unsupported(&e) => {
+                    eprintln!(
+                        "debug_traceBlockByNumber is not supported, using trace_block for block {}",
+                        number
+                    );
+                    self.unsupported.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        match pool
+            .request(|p| async move { p.trace_block(BlockId::number(number)).await })
+            .await
+        {
+            Ok(traces) => Ok(flatten_parity(traces, tx_hashes)),
+            Err(e) => {
+                // give the debug API another chance on the next block
+                self.unsupported.store(0, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+}
+
+// Helper functions
+
+// debug_traceBlockByNumber returns one result per transaction in block order, and older geth
+// and erigon leave `txHash` out of them, so the hashes come from the block.
+fn flatten_geth(
+    results: Vec<TraceResult>,
+    tx_hashes: &[B256],
+) -> Result<Vec<InternalCall>, anyhow::Error> {
+    if results.len() != tx_hashes.len() {
+        return Err(anyhow::anyhow!(
+            "Block trace has {} transactions, the block has {}",
+            results.len(),
+            tx_hashes.len()
+        ));
+    }
+
+    let mut calls = Vec::new();
+    for (result, block_tx_hash) in results.into_iter().zip(tx_hashes) {
+        match result {
+            TraceResult::Success { result, tx_hash } => {
+                let frame = result
+                    .try_into_call_frame()
+                    .map_err(|e| anyhow::anyhow!("Unexpected trace: {:?}", e))?;
+                walk_frame(
+                    tx_hash.unwrap_or(*block_tx_hash),
+                    frame,
+                    Vec::new(),
+                    false,
+                    &mut calls,
+                );
+            }
+            TraceResult::Error { error, tx_hash } => {
+                return Err(anyhow::anyhow!(
+                    "Tracing transaction {:?} failed: {}",
+                    tx_hash.unwrap_or(*block_tx_hash),
+                    error
+                ));
+            }
+        }
+    }
+    Ok(calls)
+}
+
+fn walk_frame(
+    tx_hash: B256,
+    frame: CallFrame,
+    path: Vec<usize>,
+    parent_reverted: bool,
+    calls: &mut Vec<InternalCall>,
+) {
+    let reverted = parent_reverted || frame.error.is_some();
+
+    calls.push(InternalCall {
+        tx_hash,
+        path: path.clone(),
+        call_type: frame.typ.to_uppercase(),
+        from: frame.from,
+        to: frame.to,
+        value: frame.value.unwrap_or_default(),
+        input: frame.input,
+        reverted,
+    });
+
+    for (idx, child) in frame.calls.into_iter().enumerate() {
+        let mut child_path = path.clone();
+        child_path.push(idx);
+        walk_frame(tx_hash, child, child_path, reverted, calls);
+    }
+}
+
+fn flatten_parity(traces: Vec<LocalizedTransactionTrace>, tx_hashes: &[B256]) -> Vec<InternalCall> {
+    let mut calls: Vec<InternalCall> = Vec::new();
+
+    // frames of the current transaction that reverted
+    let mut current_tx = None;
+    let mut reverted_paths: Vec<Vec<usize>> = Vec::new();
+
+    for localized in traces {
+        let trace = localized.trace;
+        let tx_hash = localized
+            .transaction_hash
+            .or_else(|| {
+                let position = localized.transaction_position? as usize;
+                tx_hashes.get(position).copied()
+            })
+            .unwrap_or_default();
+
+        if current_tx != Some(tx_hash) {
+            current_tx = Some(tx_hash);
+            reverted_paths.clear();
+        }
+
+        let (call_type, from, to, value, input) = match trace.action {
+            Action::Call(call) => (
+                call.call_type.to_string().to_uppercase(),
+                call.from,
+                Some(call.to),
+                call.value,
+                call.input,
+            ),
+            Action::Create(create) => {
+                let created = match &trace.result {
+                    Some(TraceOutput::Create(output)) => Some(output.address),
+                    _ => None,
+                };
+                (
+                    "CREATE".to_string(),
+                    create.from,
+                    created,
+                    create.value,
+                    create.init,
+                )
+            }
+            Action::Selfdestruct(destruct) => (
+                "SELFDESTRUCT".to_string(),
+                destruct.address,
+                Some(destruct.refund_address),
+                destruct.balance,
+                Bytes::new(),
+            ),
+            Action::Reward(_) => continue,
+        };
+
+        // traces come parent first, so a reverted caller is always seen before its calls
+        let reverted = trace.error.is_some()
+            || reverted_paths
+                .iter()
+                .any(|path| trace.trace_address.starts_with(path));
+        if reverted {
+            reverted_paths.push(trace.trace_address.clone());
+        }
+
+        calls.push(InternalCall {
+            tx_hash,
+            path: trace.trace_address,
+            call_type,
+            from,
+            to,
+            value,
+            input,
+            reverted,
+        });
+    }
+
+    calls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::address;
+    use serde_json::json;
+
+    const EOA: Address = address!("0x1111111111111111111111111111111111111111");
+    const ROUTER: Address = address!("0x2222222222222222222222222222222222222222");
+    const VAULT: Address = address!("0x3333333333333333333333333333333333333333");
+
+    fn hashes() -> Vec<B256> {
+        vec![B256::repeat_byte(0xa1), B256::repeat_byte(0xa2)]
+    }
+
+    fn call(call_type: &str, value: u64, reverted: bool) -> InternalCall {
+        InternalCall {
+            tx_hash: B256::ZERO,
+            path: vec![0],
+            call_type: call_type.to_string(),
+            from: ROUTER,
+            to: Some(VAULT),
+            value: U256::from(value),
+            input: Bytes::new(),
+            reverted,
+        }
+    }
+
+    // debug_traceBlockByNumber with the callTracer, the way older geth answers it: no txHash
+    fn geth_fixture() -> Vec<TraceResult> {
+        serde_json::from_value(json!([
+            {
+                "result": {
+                    "type": "CALL",
+                    "from": EOA,
+                    "to": ROUTER,
+                    "value": "0xde0b6b3a7640000",
+                    "gas": "0x30000",
+                    "gasUsed": "0x20000",
+                    "input": "0x12345678",
+                    "calls": [
+                        {
+                            "type": "DELEGATECALL",
+                            "from": ROUTER,
+                            "to": VAULT,
+                            "value": "0xde0b6b3a7640000",
+                            "gas": "0x10000",
+                            "gasUsed": "0x5000",
+                            "input": "0x",
+                        },
+                        {
+                            "type": "CALL",
+                            "from": ROUTER,
+                            "to": VAULT,
+                            "value": "0x64",
+                            "gas": "0x10000",
+                            "gasUsed": "0x5000",
+                            "input": "0x",
+                            "error": "execution reverted",
+                            "calls": [
+                                {
+                                    "type": "CALL",
+                                    "from": VAULT,
+                                    "to": EOA,
+                                    "value": "0x1",
+                                    "gas": "0x1000",
+                                    "gasUsed": "0x0",
+                                    "input": "0x",
+                                }
+                            ],
+                        },
+                    ],
+                }
+            },
+            {
+                "result": {
+                    "type": "CALL",
+                    "from": EOA,
+                    "to": VAULT,
+                    "value": "0x0",
+                    "gas": "0x5208",
+                    "gasUsed": "0x5208",
+                    "input": "0x",
+                }
+            }
+        ]))
+        .unwrap()
+    }
+
+    // trace_block for the same block
+    fn parity_fixture() -> Vec<LocalizedTransactionTrace> {
+        let hashes = hashes();
+        let trace = |position: usize, address: serde_json::Value, action, error: Option<&str>| {
+            let mut trace = json!({
+                "action": action,
+                "blockHash": B256::ZERO,
+                "blockNumber": 1,
+                "result": { "gasUsed": "0x0", "output": "0x" },
+                "subtraces": 0,
+                "traceAddress": address,
+                "transactionHash": hashes[position],
+                "transactionPosition": position,
+                "type": "call",
+            });
+            if let Some(error) = error {
+                trace["error"] = error.into();
+                trace["result"] = serde_json::Value::Null;
+            }
+            trace
+        };
+        let action = |call_type: &str, from: Address, to: Address, value: &str| {
+            json!({
+                "callType": call_type,
+                "from": from,
+                "to": to,
+                "gas": "0x10000",
+                "input": "0x",
+                "value": value,
+            })
+        };
+
+        let mut without_hash = trace(1, json!([]), action("call", EOA, VAULT, "0x0"), None);
+        without_hash["transactionHash"] = serde_json::Value::Null;
+
+        serde_json::from_value(json!([
+            trace(0, json!([]), action("call", EOA, ROUTER, "0xde0b6b3a7640000"), None),
+            trace(0, json!([0]), action("delegatecall", ROUTER, VAULT, "0xde0b6b3a7640000"), None),
+            trace(0, json!([1]), action("call", ROUTER, VAULT, "0x64"), Some("Reverted")),
+            trace(0, json!([1, 0]), action("call", VAULT, EOA, "0x1"), None),
+            without_hash,
+            {
+                "action": { "author": EOA, "rewardType": "block", "value": "0x1bc16d674ec80000" },
+                "blockHash": B256::ZERO,
+                "blockNumber": 1,
+                "result": null,
+                "subtraces": 0,
+                "traceAddress": [],
+                "transactionHash": null,
+                "transactionPosition": null,
+                "type": "reward",
+            },
+        ]))
+        .unwrap()
+    }
+
+    // what both fixtures flatten to
+    fn assert_flattened(calls: &[InternalCall]) {
+        let hashes = hashes();
+        let summary: Vec<(B256, Vec<usize>, &str, bool)> = calls
+            .iter()
+            .map(|call| {
+                (
+                    call.tx_hash,
+                    call.path.clone(),
+                    call.call_type.as_str(),
+                    call.reverted,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (hashes[0], vec![], "CALL", false),
+                (hashes[0], vec![0], "DELEGATECALL", false),
+                (hashes[0], vec![1], "CALL", true),
+                (hashes[0], vec![1, 0], "CALL", true),
+                (hashes[1], vec![], "CALL", false),
+            ]
+        );
+
+        assert_eq!(calls[0].from, EOA);
+        assert_eq!(calls[0].to, Some(ROUTER));
+        assert_eq!(calls[0].value, U256::from(10u64.pow(18)));
+        assert_eq!(calls[3].describe_path(), "tx > 1 > 0");
+
+        let moving: Vec<&str> = calls
+            .iter()
+            .filter(|call| call.moves_value())
+            .map(|call| call.call_type.as_str())
+            .collect();
+        assert_eq!(moving, vec!["CALL"]);
+    }
+
+    #[test]
+    fn flattens_geth_call_frames_with_the_blocks_hashes() {
+        let calls = flatten_geth(geth_fixture(), &hashes()).unwrap();
+        assert_flattened(&calls);
+    }
+
+    #[test]
+    fn prefers_the_hash_geth_reports() {
+        let mut results = geth_fixture();
+        let reported = B256::repeat_byte(0xff);
+        if let TraceResult::Success { tx_hash, .. } = &mut results[1] {
+            *tx_hash = Some(reported);
+        }
+
+        let calls = flatten_geth(results, &hashes()).unwrap();
+        assert_eq!(calls.last().unwrap().tx_hash, reported);
+    }
+
+    #[test]
+    fn rejects_a_geth_trace_that_does_not_fit_the_block() {
+        assert!(flatten_geth(geth_fixture(), &hashes()[..1]).is_err());
+
+        let failed: Vec<TraceResult> =
+            serde_json::from_value(json!([{ "error": "execution timeout" }])).unwrap();
+        let error = flatten_geth(failed, &hashes()[..1]).unwrap_err();
+        assert!(error.to_string().contains(&format!("{:?}", hashes()[0])));
+    }
+
+    #[test]
+    fn flattens_parity_traces() {
+        let calls = flatten_parity(parity_fixture(), &hashes());
+        assert_flattened(&calls);
+    }
+
+    #[test]
+    fn only_calls_that_stick_move_value() {
+        assert!(call("CALL", 1, false).moves_value());
+        assert!(call("CREATE", 1, false).moves_value());
+        assert!(call("CREATE2", 1, false).moves_value());
+        assert!(call("SELFDESTRUCT", 1, false).moves_value());
+
+        assert!(!call("CALL", 0, false).moves_value());
+        assert!(!call("CALL", 1, true).moves_value());
+        assert!(!call("DELEGATECALL", 1, false).moves_value());
+        assert!(!call("STATICCALL", 1, false).moves_value());
+    }
+}
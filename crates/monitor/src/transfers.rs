@@ -0,0 +1,202 @@
+//! # Native Transfers
+//! This module provides tools for watching addresses for native ETH transfers.
+//!
+//! A `TransferWatch` needs no contract and no ABI, just the addresses to watch. A transfer
+//! is incoming when a watched address receives ETH and outgoing when one sends it. Top-level
+//! transfers are read straight from the transaction's `value`. ETH moved by contracts during
+//! a transaction never shows up there, so with `internal` set every block is traced and the
+//! calls that moved value are checked too. Only transfers that stuck count, a reverted
+//! transaction or call moved nothing.
+
+use crate::primitives::models::{TransferConfig, TransferDirection};
+use alloy::primitives::utils::format_ether;
+use alloy::primitives::{Address, U256};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// Which transfers of the watched addresses to alert on.
+#[derive(Debug, Clone)]
+pub struct TransferWatch {
+    addresses: HashSet<Address>,
+    direction: TransferDirection,
+    min_value: Option<U256>,
+    max_value: Option<U256>,
+    allow: Option<HashSet<Address>>,
+    deny: HashSet<Address>,
+    pub internal: bool,
+}
+
+impl TransferWatch {
+    pub fn new(config: &TransferConfig) -> Result<Self, anyhow::Error> {
+        if config.addresses.is_empty() {
+            return Err(anyhow::anyhow!(
+                "A transfers monitor needs addresses to watch"
+            ));
+        }
+
+        Ok(Self {
+            addresses: config.addresses.iter().copied().collect(),
+            direction: config.direction.unwrap_or_default(),
+            min_value: parse_wei(config.min_value.as_deref())?,
+            max_value: parse_wei(config.max_value.as_deref())?,
+            allow: config
+                .allow
+                .as_ref()
+                .map(|allow| allow.iter().copied().collect()),
+            deny: config.deny.iter().flatten().copied().collect(),
+            internal: config.internal.unwrap_or_default(),
+        })
+    }
+
+    /// Whether moving `value` from `from` to `to` is a transfer worth an alert.
+    pub fn matches(&self, from: Address, to: Address, value: U256) -> bool {
+        if value.is_zero()
+            || self.min_value.is_some_and(|min| value < min)
+            || self.max_value.is_some_and(|max| value > max)
+        {
+            return false;
+        }
+
+        let incoming = self.direction != TransferDirection::Outgoing
+            && self.addresses.contains(&to)
+            && self.counterparty_ok(from);
+        let outgoing = self.direction != TransferDirection::Incoming
+            && self.addresses.contains(&from)
+            && self.counterparty_ok(to);
+
+        incoming || outgoing
+    }
+
+    fn counterparty_ok(&self, address: Address) -> bool {
+        !self.deny.contains(&address)
+            && self
+                .allow
+                .as_ref()
+                .is_none_or(|allow| allow.contains(&address))
+    }
+}
+
+/// A native transfer found in a transaction.
+#[derive(Debug, Clone, Serialize)]
+pub struct NativeTransfer {
+    pub from: Address,
+    pub to: Address,
+    pub value: U256,
+
+    // where in the call tree an internal transfer happened, `None` for the transaction's own
+    pub call_path: Option<String>,
+}
+
+// Formats a matched transfer for the alert.
+pub fn get_transfer_details(transfer: &NativeTransfer) -> String {
+    let mut output = format!(
+        "Native Transfer: {} ETH ({} wei)\nFrom: {:?}\nTo: {:?}\n",
+        format_ether(transfer.value),
+        transfer.value,
+        transfer.from,
+        transfer.to
+    );
+    if let Some(path) = &transfer.call_path {
+        output.push_str(&format!("Internal Call: {}\n", path));
+    }
+    output
+}
+
+// Helper functions
+
+fn parse_wei(value: Option<&str>) -> Result<Option<U256>, anyhow::Error> {
+    value
+        .map(|value| {
+            U256::from_str(value)
+                .map_err(|e| anyhow::anyhow!("Invalid wei amount {}: {}", value, e))
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const WATCHED: Address = Address::repeat_byte(0x01);
+    const ALICE: Address = Address::repeat_byte(0x0a);
+    const BOB: Address = Address::repeat_byte(0x0b);
+
+    fn watch(mut config: serde_json::Value) -> TransferWatch {
+        config["addresses"] = json!([WATCHED]);
+        TransferWatch::new(&serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn matches_by_direction_value_and_counterparty() {
+        let both = json!({});
+        let incoming = json!({ "direction": "incoming" });
+        let outgoing = json!({ "direction": "outgoing" });
+        let between = json!({ "min_value": "100", "max_value": "1000" });
+        let allow_alice = json!({ "allow": [ALICE] });
+        let deny_alice = json!({ "deny": [ALICE] });
+
+        // (watch, from, to, value, matches)
+        let cases = [
+            (&both, ALICE, WATCHED, 1, true),
+            (&both, WATCHED, ALICE, 1, true),
+            (&both, ALICE, BOB, 1, false),
+            (&both, ALICE, WATCHED, 0, false),
+            (&incoming, ALICE, WATCHED, 1, true),
+            (&incoming, WATCHED, ALICE, 1, false),
+            (&outgoing, WATCHED, ALICE, 1, true),
+            (&outgoing, ALICE, WATCHED, 1, false),
+            (&between, ALICE, WATCHED, 99, false),
+            (&between, ALICE, WATCHED, 100, true),
+            (&between, ALICE, WATCHED, 1000, true),
+            (&between, ALICE, WATCHED, 1001, false),
+            (&allow_alice, ALICE, WATCHED, 1, true),
+            (&allow_alice, WATCHED, ALICE, 1, true),
+            (&allow_alice, BOB, WATCHED, 1, false),
+            (&allow_alice, WATCHED, BOB, 1, false),
+            (&deny_alice, ALICE, WATCHED, 1, false),
+            (&deny_alice, WATCHED, ALICE, 1, false),
+            (&deny_alice, BOB, WATCHED, 1, true),
+        ];
+
+        for (config, from, to, value, expected) in cases {
+            assert_eq!(
+                watch(config.clone()).matches(from, to, U256::from(value)),
+                expected,
+                "{} moving {} from {:?} to {:?}",
+                config,
+                value,
+                from,
+                to
+            );
+        }
+    }
+
+    #[test]
+    fn a_transfer_between_watched_addresses_goes_both_ways() {
+        let config: TransferConfig = serde_json::from_value(json!({
+            "addresses": [WATCHED, ALICE],
+            "direction": "incoming",
+            "deny": [ALICE],
+        }))
+        .unwrap();
+        let watch = TransferWatch::new(&config).unwrap();
+
+        // incoming for the watched address, but from a denied sender
+        assert!(!watch.matches(ALICE, WATCHED, U256::from(1)));
+        assert!(watch.matches(WATCHED, ALICE, U256::from(1)));
+    }
+
+    #[test]
+    fn rejects_bad_configs() {
+        let no_addresses: TransferConfig =
+            serde_json::from_value(json!({ "addresses": [] })).unwrap();
+        assert!(TransferWatch::new(&no_addresses).is_err());
+
+        let bad_value: TransferConfig =
+            serde_json::from_value(json!({ "addresses": [WATCHED], "min_value": "1 ether" }))
+                .unwrap();
+        assert!(TransferWatch::new(&bad_value).is_err());
+    }
+}
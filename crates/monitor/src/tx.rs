@@ -5,20 +5,96 @@
 //! transaction calls to targeted functions on the blockchain. The monitoring process
 //! involves polling the blockchain for new blocks, fetching full transaction data, and
 //! analyzing the `input` field of each transaction to match function selectors.
+//!
+//! Each processed block's hash is remembered in a `BlockWindow`. When a new block doesn't
+//! build on the last one we saw, the monitor rewinds to the common ancestor, re-sends the
+//! matches from the orphaned blocks as `AlertStatus::Retracted` and rescans the new branch.
+//!
+//! Blocks are only processed once they are `confirmations` deep behind the monitor's
+//! `BlockTarget`. Rules asking for a deeper confirmation hold their matches back until the
+//! monitor has processed enough blocks on top of them.
+//!
+//! Rules with conditions on the outcome of a transaction are checked against its receipt,
+//! which is only fetched once the transaction passed every other condition. Alerts on
+//! reverted transactions carry the revert reason, decoded against the ABI's custom errors,
+//! and rules can match on the custom error a call to the contract reverted with.
+//!
+//! Monitors watching addresses for native transfers get a match for every transfer that
+//! went through, found in the transaction's value or, when asked for, in the block's traces.
+//!
+//! With call tracing on, the rules are also checked against every call made to a monitored
+//! contract from inside a transaction, so calls routed through a router or a multisig are
+//! caught too. Those alerts carry the call's path in the transaction's call tree.
+//!
+//! Calls bundled in a batching wrapper (Multicall, Safe `execTransaction`, `multiSend`) are
+//! unwrapped without tracing and checked the same way when the wrapper itself didn't match.
+//! ERC-4337 `handleOps` bundles are unwrapped into the calls each smart account made, sent
+//! by the account rather than the bundler, and `Status` conditions on them look at whether
+//! the user operation went through.
 
-use crate::PollingMonitor;
+use crate::batch::{InnerCall, unwrap_calls, wrapper_name};
+use crate::bloom::LogInterest;
+use crate::contracts::joined_late;
+use crate::deploy::deployed_address;
+use crate::prefetch::BlockPrefetcher;
 use crate::primitives::models::{Condition, MonitorRule};
-use crate::primitives::utils::format_value;
+use crate::primitives::utils::{format_value, select_functions};
+use crate::receipts::ReceiptFetcher;
+use crate::reorg::{BlockWindow, DEFAULT_REORG_WINDOW, find_common_ancestor};
+use crate::revert::{decode_revert, describe_revert, explain_revert, replay_revert_data};
+use crate::trace::{CallTracer, InternalCall};
+use crate::transfers::NativeTransfer;
+use crate::{MonitoredContract, PollingMonitor};
 use alloy::consensus::Transaction;
 use alloy::dyn_abi::JsonAbiExt;
 use alloy::hex;
 use alloy::json_abi::JsonAbi;
-use alloy::network::AnyRpcTransaction;
-use alloy::providers::Provider;
+use alloy::network::{
+    AnyRpcTransaction, AnyTransactionReceipt, ReceiptResponse, TransactionResponse,
+};
+use alloy::primitives::{Address, B256, Bytes};
 use alloy::rpc::types::BlockTransactions;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::sleep;
 
+/// What happened to a transaction that matched a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertStatus {
+    /// The transaction was found in a block on the canonical chain.
+    Confirmed,
+    /// The block we alerted on was orphaned by a reorg, so the earlier alert no longer holds.
+    Retracted,
+}
+
+#[derive(Debug, Clone)]
+pub struct TxMatch {
+    pub tx: AnyRpcTransaction,
+    pub rule: String,
+    pub block_number: u64,
+    pub status: AlertStatus,
+
+    // only fetched when one of the rules needed it
+    pub receipt: Option<AnyTransactionReceipt>,
+    pub revert_reason: Option<String>,
+
+    // the contract the transaction created, if it was a deployment
+    pub deployed: Option<Address>,
+
+    // the native transfer a transfers monitor matched
+    pub transfer: Option<NativeTransfer>,
+
+    // the call to the contract made from inside the transaction, for internal call matches
+    pub call: Option<InternalCall>,
+
+    // the call a batching wrapper made, for matches on a call inside Multicall, Safe and the like
+    pub inner: Option<InnerCall>,
+}
+
+/// The rule name native transfer matches are reported under.
+pub const TRANSFER_RULE: &str = "Native transfer";
+
 #[allow(async_fn_in_trait)]
 pub trait TransactionMonitor {
     async fn monitor_transactions_polling<F>(
@@ -27,7 +103,7 @@ pub trait TransactionMonitor {
         handler: F,
     ) -> Result<(), anyhow::Error>
     where
-        F: FnMut(AnyRpcTransaction) + Send + 'static;
+        F: FnMut(TxMatch) + Send + 'static;
 }
 
 impl TransactionMonitor for PollingMonitor {
@@ -37,65 +113,631 @@ impl TransactionMonitor for PollingMonitor {
         mut handler: F,
     ) -> Result<(), anyhow::Error>
     where
-        F: FnMut(AnyRpcTransaction) + Send + 'static,
+        F: FnMut(TxMatch) + Send + 'static,
     {
-        println!(
-            "TxMonitor: Watching transactions for {:?}",
-            self.contract_address
-        );
+        if self.transfers.is_some() && rules.is_empty() {
+            println!("TxMonitor: Watching native transfers");
+        } else {
+            println!(
+                "TxMonitor: Watching transactions for {:?}",
+                self.addresses()
+            );
+        }
+
+        let mut scanner = TxScanner::new(&self, rules, self.processing_head().await?);
+        poll_transactions(&self, &mut scanner, &mut handler).await
+    }
+}
 
-        let mut current_block = self.provider.get_block_number().await?;
+/// Polls for new blocks every `poll_interval` and feeds them through the scanner.
+pub(crate) async fn poll_transactions<F>(
+    monitor: &PollingMonitor,
+    scanner: &mut TxScanner,
+    handler: &mut F,
+) -> Result<(), anyhow::Error>
+where
+    F: FnMut(TxMatch),
+{
+    loop {
+        match monitor.processing_head().await {
+            Ok(latest_block) => scanner.scan_to(monitor, latest_block, handler).await,
+            Err(e) => eprintln!("Error fetching latest block number: {}", e),
+        }
 
-        loop {
-            let latest_block = match self.provider.get_block_number().await {
-                Ok(num) => num,
-                Err(e) => {
-                    eprintln!("Error fetching latest block number: {}", e);
-                    sleep(Duration::from_secs(2)).await;
+        sleep(monitor.poll_interval).await;
+    }
+}
+
+/// The block-by-block scanning state of a transaction monitor.
+///
+/// It is kept apart from the loop that drives it so the polling and subscription monitors
+/// can hand it over to each other without losing their place, their reorg window or the
+/// matches still waiting for confirmations.
+pub struct TxScanner {
+    rules: Vec<MonitorRule>,
+    current_block: u64,
+
+    // the monitored contracts as of the last scan, and the rules mapped to the ABI of each
+    // of them, for calls made to it
+    contracts: Arc<Vec<MonitoredContract>>,
+    contract_rules: HashMap<Address, Vec<MonitorRule>>,
+
+    // recent block hashes and the matches we alerted on in them, so a reorg can be unwound
+    window: BlockWindow,
+    alerted: BTreeMap<u64, Vec<TxMatch>>,
+
+    // matches for rules that want more confirmations than the monitor, keyed by the block
+    // the monitor has to reach before they are released
+    held: BTreeMap<u64, Vec<TxMatch>>,
+
+    // blocks fetched ahead of `current_block` while catching up
+    prefetch: BlockPrefetcher,
+    receipts: ReceiptFetcher,
+    tracer: CallTracer,
+
+    // contracts that joined after we passed the block they were created in, and the contract
+    // a scanner backfilling one of them is limited to
+    backfill: Vec<(Address, u64)>,
+    only: Option<Address>,
+}
+
+impl TxScanner {
+    pub fn new(monitor: &PollingMonitor, rules: Vec<MonitorRule>, start_block: u64) -> Self {
+        let mut scanner = Self {
+            rules,
+            current_block: start_block,
+            contracts: Arc::default(),
+            contract_rules: HashMap::new(),
+            window: BlockWindow::new(DEFAULT_REORG_WINDOW),
+            alerted: BTreeMap::new(),
+            held: BTreeMap::new(),
+            prefetch: BlockPrefetcher::new(),
+            receipts: ReceiptFetcher::new(),
+            tracer: CallTracer::new(),
+            backfill: Vec::new(),
+            only: None,
+        };
+        // the contracts there are to begin with are scanned from `start_block` on, like the rest
+        scanner.set_contracts(monitor.contracts.snapshot());
+        scanner
+    }
+
+    // A scanner going over the blocks from `from_block` up to ours again for calls to `address`
+    // alone, with no backfills of its own.
+    fn backfill_scanner(
+        &self,
+        monitor: &PollingMonitor,
+        address: Address,
+        from_block: u64,
+    ) -> Self {
+        let mut scanner = TxScanner::new(monitor, self.rules.clone(), from_block.saturating_sub(1));
+        scanner.only = Some(address);
+        scanner
+    }
+
+    pub fn current_block(&self) -> u64 {
+        self.current_block
+    }
+
+    /// Picks up contracts added to or removed from the monitor since the last scan.
+    fn refresh(&mut self, monitor: &PollingMonitor) {
+        let contracts = monitor.contracts.snapshot();
+        if Arc::ptr_eq(&contracts, &self.contracts) {
+            return;
+        }
+
+        // blocks fetched ahead were gated on the old contracts' logs
+        self.prefetch.reset(self.current_block + 1);
+        if self.only.is_none() {
+            self.backfill
+                .extend(joined_late(&self.contracts, &contracts, self.current_block));
+        }
+        self.set_contracts(contracts);
+    }
+
+    fn set_contracts(&mut self, contracts: Arc<Vec<MonitoredContract>>) {
+        self.contract_rules = contracts
+            .iter()
+            .map(|contract| {
+                (
+                    contract.address,
+                    map_rules_to_abi(self.rules.clone(), &contract.abi),
+                )
+            })
+            .collect();
+        self.contracts = contracts;
+    }
+
+    /// The rules to check a call to `to` against, mapped to the ABI of the contract it calls.
+    fn rules_for(&self, to: Option<Address>) -> &[MonitorRule] {
+        to.and_then(|to| self.contract_rules.get(&to))
+            .unwrap_or(&self.rules)
+    }
+
+    /// Fetches and processes every block after the last one we scanned, up to `latest_block`.
+    pub async fn scan_to<F>(&mut self, monitor: &PollingMonitor, latest_block: u64, handler: &mut F)
+    where
+        F: FnMut(TxMatch),
+    {
+        self.refresh(monitor);
+        self.scan_backfill(monitor, handler).await;
+        let gate = self.bloom_gate(monitor);
+
+        while self.current_block < latest_block {
+            let target_block = self.current_block + 1;
+
+            // We request the block by number to get all the transactio details in that block,
+            // the blocks after it are already being fetched in the background
+            match self
+                .prefetch
+                .next(monitor, target_block, latest_block, gate.as_ref())
+                .await
+            {
+                Ok(Some(data)) => {
+                    let block = &data.block;
+
+                    // high-value monitors only act on a block a quorum of endpoints agree on
+                    if monitor.quorum
+                        && monitor.pool.quorum_block_hash(target_block).await
+                            != Some(block.header.hash)
+                    {
+                        eprintln!(
+                            "TxMonitor: No quorum on block {} yet, retrying",
+                            target_block
+                        );
+                        sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+
+                    // the new block has to build on the one we processed before it,
+                    // otherwise the chain was reorganized underneath us
+                    if !self.window.extends(target_block, block.header.parent_hash) {
+                        let ancestor = match find_common_ancestor(&monitor.pool, &self.window).await
+                        {
+                            Ok(n) => n,
+                            Err(e) => {
+                                eprintln!("Error resolving reorg at block {}: {}", target_block, e);
+                                sleep(Duration::from_secs(1)).await;
+                                continue;
+                            }
+                        };
+
+                        println!(
+                            "TxMonitor: Reorg detected at block {}, rewinding to {}",
+                            target_block, ancestor
+                        );
+                        self.rewind(ancestor, handler);
+                        continue;
+                    }
+
+                    // Alloy returns BlockTransactions enum: either Hashes(Vec<B256>) or Full(Vec<Transaction>).
+                    // We only get hashes for blocks the bloom gate ruled out, they can't match.
+                    if let BlockTransactions::Full(txs) = &block.transactions {
+                        let matches = match self
+                            .match_block(monitor, target_block, block.header.hash, txs)
+                            .await
+                        {
+                            Ok(matches) => matches,
+                            Err(e) => {
+                                eprintln!("Error matching block {}: {}", target_block, e);
+                                sleep(Duration::from_secs(1)).await;
+                                continue;
+                            }
+                        };
+
+                        for (release, m) in matches {
+                            self.held.entry(release).or_default().push(m);
+                        }
+                    }
+
+                    // release every held match whose rule is now deep enough
+                    let pending = self.held.split_off(&(target_block + 1));
+                    for (_, matches) in std::mem::replace(&mut self.held, pending) {
+                        for m in matches {
+                            self.alerted
+                                .entry(m.block_number)
+                                .or_default()
+                                .push(m.clone());
+                            handler(m);
+                        }
+                    }
+
+                    self.window.push(target_block, block.header.hash);
+                    if let Some(oldest) = self.window.oldest() {
+                        self.alerted = self.alerted.split_off(&oldest);
+                    }
+                    self.current_block = target_block;
+                }
+                Ok(None) => {
+                    // The block number exists (latest_block) but the block data isn't available yet.
+                    // This happens due to eventual consistency in nodes. Wait briefly.
+                    sleep(Duration::from_millis(500)).await;
                     continue;
                 }
+                Err(e) => {
+                    eprintln!("Error fetching block {}: {}", target_block, e);
+                    sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    // Scans the blocks we passed before a late-joining contract joined again, for calls to that
+    // contract alone, with a scanner of its own that hands its matches over when it's done.
+    async fn scan_backfill<F>(&mut self, monitor: &PollingMonitor, handler: &mut F)
+    where
+        F: FnMut(TxMatch),
+    {
+        while let Some((address, from_block)) = self.backfill.pop() {
+            if from_block > self.current_block {
+                continue;
+            }
+            println!(
+                "TxMonitor: Backfilling blocks {} to {} for {:?}",
+                from_block, self.current_block, address
+            );
+
+            let mut backfill = self.backfill_scanner(monitor, address, from_block);
+            Box::pin(backfill.scan_to(monitor, self.current_block, handler)).await;
+
+            // matches still waiting for confirmations are released with everything else's
+            for (release, matches) in backfill.held {
+                self.held.entry(release).or_default().extend(matches);
+            }
+            for (block, matches) in backfill.alerted {
+                self.alerted.entry(block).or_default().extend(matches);
+            }
+        }
+    }
+
+    // Whether a call to `to` is matched, everything unless the scanner is backfilling a contract.
+    fn includes(&self, to: Option<Address>) -> bool {
+        self.only.is_none_or(|only| to == Some(only))
+    }
+
+    /// Every transaction in the block that matches a rule, with the block the monitor has to
+    /// reach before its alert is released. Nothing is returned unless the whole block could
+    /// be matched, so a failed block can simply be retried.
+    async fn match_block(
+        &self,
+        monitor: &PollingMonitor,
+        block_number: u64,
+        block_hash: B256,
+        txs: &[AnyRpcTransaction],
+    ) -> Result<Vec<(u64, TxMatch)>, anyhow::Error> {
+        let by_hash: HashMap<B256, &AnyRpcTransaction> =
+            txs.iter().map(|tx| (tx.tx_hash(), tx)).collect();
+
+        // every call frame in the block, when calls or transfers inside transactions are watched
+        let traced = monitor.trace_calls
+            || monitor
+                .transfers
+                .as_ref()
+                .is_some_and(|watch| watch.internal);
+        let calls = if traced {
+            let tx_hashes: Vec<B256> = txs.iter().map(|tx| tx.tx_hash()).collect();
+            self.tracer
+                .trace_block(&monitor.pool, block_number, &tx_hashes)
+                .await?
+        } else {
+            Vec::new()
+        };
+
+        // calls to the monitored contracts made from inside a transaction, with the rules
+        // mapped to the called contract's ABI. Top-level calls are checked as transactions
+        let internal: Vec<(&AnyRpcTransaction, &InternalCall, &[MonitorRule])> =
+            if monitor.trace_calls {
+                calls
+                    .iter()
+                    .filter(|call| !call.path.is_empty() && self.includes(call.to))
+                    .filter_map(|call| {
+                        let rules = self.contract_rules.get(&call.to?)?;
+                        let tx = by_hash.get(&call.tx_hash)?;
+                        Some((*tx, call, rules.as_slice()))
+                    })
+                    .collect()
+            } else {
+                Vec::new()
             };
 
-            while current_block < latest_block {
-                let target_block = current_block + 1;
+        // calls made by batching wrappers, Multicall, Safe, ERC-4337 bundles and the like
+        let wrapped: Vec<(&AnyRpcTransaction, InnerCall)> = txs
+            .iter()
+            .filter_map(|tx| Some((tx, tx.to()?)))
+            .flat_map(|(tx, to)| {
+                unwrap_calls(tx.from(), to, tx.input())
+                    .into_iter()
+                    .map(move |call| (tx, call))
+            })
+            .filter(|(_, call)| self.includes(Some(call.to)))
+            .collect();
 
-                // We request the block by number to get all the transactio details in that block
-                match self
-                    .provider
-                    .get_block_by_number(target_block.into())
-                    .full()
-                    .await
+        // receipts are only fetched for transactions that passed everything else, and for
+        // transfers, which only happened if the transaction went through
+        let mut wanted: Vec<B256> = txs
+            .iter()
+            .filter(|&tx| self.includes(tx.to()))
+            .filter(|&tx| {
+                self.rules_for(tx.to())
+                    .iter()
+                    .any(|rule| rule.needs_receipt() && rule.tx_match(tx))
+                    || top_level_transfer(monitor, tx).is_some()
+            })
+            .map(|tx| tx.tx_hash())
+            .collect();
+        for (tx, call, rules) in &internal {
+            if !wanted.contains(&tx.tx_hash())
+                && rules
+                    .iter()
+                    .any(|rule| rule.needs_receipt() && rule.call_match(tx, call))
+            {
+                wanted.push(tx.tx_hash());
+            }
+        }
+        for (tx, call) in &wrapped {
+            if !wanted.contains(&tx.tx_hash())
+                && self.rules_for(Some(call.to)).iter().any(|rule| {
+                    // a user operation's outcome is only in the receipt
+                    (rule.needs_receipt() || call.user_op.is_some()) && rule.inner_match(tx, call)
+                })
+            {
+                wanted.push(tx.tx_hash());
+            }
+        }
+        let receipts = self
+            .receipts
+            .fetch(&monitor.pool, block_hash, &wanted)
+            .await?;
+
+        let mut matches = Vec::new();
+        let mut matched_txs: HashSet<B256> = HashSet::new();
+        for tx in txs {
+            if !self.includes(tx.to()) {
+                continue;
+            }
+            let receipt = receipts.get(&tx.tx_hash());
+            let abi = monitor.abi_for(tx.to());
+            let abi = abi.as_ref();
+
+            // a reverted transaction is replayed at most once, and only if a rule needs it
+            let mut revert_data: Option<Option<Bytes>> = None;
+            let mut matched = None;
+
+            for rule in self.rules_for(tx.to()) {
+                if !rule.tx_match(tx) {
+                    continue;
+                }
+                if rule.needs_receipt()
+                    && !receipt.is_some_and(|receipt| rule.receipt_match(receipt, &self.contracts))
                 {
-                    Ok(Some(block)) => {
-                        // Alloy returns BlockTransactions enum: either Hashes(Vec<B256>) or Full(Vec<Transaction>)
-                        if let BlockTransactions::Full(txs) = &block.transactions {
-                            for tx in txs {
-                                for rule in &rules {
-                                    if rule.tx_match(tx) {
-                                        println!("Match found for rule: {}", rule.name);
-                                        handler(tx.clone());
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                        current_block = target_block;
+                    continue;
+                }
+                if rule.needs_revert() {
+                    if revert_data.is_none() {
+                        revert_data =
+                            Some(replay_revert_data(&monitor.pool, tx, block_number).await?);
                     }
-                    Ok(None) => {
-                        // The block number exists (latest_block) but the block data isn't available yet.
-                        // This happens due to eventual consistency in nodes. Wait briefly.
-                        sleep(Duration::from_millis(500)).await;
+                    let reason = revert_data
+                        .as_ref()
+                        .and_then(|data| data.as_ref())
+                        .and_then(|data| decode_revert(abi, data));
+                    if !rule.revert_match(reason.as_ref()) {
                         continue;
                     }
-                    Err(e) => {
-                        eprintln!("Error fetching block {}: {}", target_block, e);
-                        sleep(Duration::from_secs(1)).await;
-                    }
                 }
+
+                matched = Some(rule);
+                break;
+            }
+
+            let Some(rule) = matched else {
+                continue;
+            };
+            println!("Match found for rule: {}", rule.name);
+            matched_txs.insert(tx.tx_hash());
+
+            let revert_reason = match (receipt, revert_data) {
+                (Some(receipt), _) if receipt.status() => None,
+                (Some(_), Some(Some(data))) => Some(describe_revert(abi, &data)),
+                (Some(_), _) => Some(explain_revert(&monitor.pool, abi, tx, block_number).await),
+                (None, _) => None,
+            };
+
+            matches.push((
+                release_block(monitor, rule, block_number),
+                TxMatch {
+                    tx: tx.clone(),
+                    rule: rule.name.clone(),
+                    block_number,
+                    status: AlertStatus::Confirmed,
+                    receipt: receipt.cloned(),
+                    revert_reason,
+                    deployed: deployed_address(tx, receipt)
+                        .filter(|_| receipt.is_none_or(|receipt| receipt.status())),
+                    transfer: None,
+                    call: None,
+                    inner: None,
+                },
+            ));
+        }
+
+        // the calls inside a wrapper only count when the wrapper itself didn't match
+        for (tx, mut call) in wrapped {
+            if matched_txs.contains(&tx.tx_hash()) {
+                continue;
+            }
+            let receipt = receipts.get(&tx.tx_hash());
+
+            // as with traced calls, a replayed revert can't be pinned on one inner call
+            let matched = self.rules_for(Some(call.to)).iter().find(|rule| {
+                !rule.needs_revert()
+                    && rule.inner_match(tx, &call)
+                    && (!rule.needs_receipt()
+                        || receipt.is_some_and(|receipt| {
+                            rule.inner_receipt_match(receipt, &self.contracts, &call)
+                        }))
+            });
+            let Some(rule) = matched else {
+                continue;
+            };
+            println!(
+                "Match found for rule: {} (inner call {})",
+                rule.name, call.context
+            );
+            if let (Some(op), Some(receipt)) = (call.user_op.as_mut(), receipt) {
+                op.success = op.outcome(receipt);
+            }
+
+            matches.push((
+                release_block(monitor, rule, block_number),
+                TxMatch {
+                    tx: tx.clone(),
+                    rule: rule.name.clone(),
+                    block_number,
+                    status: AlertStatus::Confirmed,
+                    receipt: receipt.cloned(),
+                    revert_reason: None,
+                    deployed: None,
+                    transfer: None,
+                    call: None,
+                    inner: Some(call),
+                },
+            ));
+        }
+
+        for (tx, call, rules) in internal {
+            let receipt = receipts.get(&tx.tx_hash());
+
+            // the revert data a replay gives back belongs to the transaction as a whole, so
+            // rules on custom errors are left to the calls sent as transactions
+            let matched = rules.iter().find(|rule| {
+                !rule.needs_revert()
+                    && rule.call_match(tx, call)
+                    && (!rule.needs_receipt()
+                        || receipt
+                            .is_some_and(|receipt| rule.receipt_match(receipt, &self.contracts)))
+            });
+            let Some(rule) = matched else {
+                continue;
+            };
+            println!(
+                "Match found for rule: {} (call {})",
+                rule.name,
+                call.describe_path()
+            );
+
+            matches.push((
+                release_block(monitor, rule, block_number),
+                TxMatch {
+                    tx: tx.clone(),
+                    rule: rule.name.clone(),
+                    block_number,
+                    status: AlertStatus::Confirmed,
+                    receipt: receipt.cloned(),
+                    revert_reason: None,
+                    deployed: None,
+                    transfer: None,
+                    call: Some(call.clone()),
+                    inner: None,
+                },
+            ));
+        }
+
+        // a backfill is only about the contract, transfers were already checked
+        let Some(watch) = monitor.transfers.as_ref().filter(|_| self.only.is_none()) else {
+            return Ok(matches);
+        };
+
+        let mut transfers: Vec<(&AnyRpcTransaction, NativeTransfer)> = txs
+            .iter()
+            .filter_map(|tx| Some((tx, top_level_transfer(monitor, tx)?)))
+            .filter(|(tx, _)| {
+                receipts
+                    .get(&tx.tx_hash())
+                    .is_some_and(|receipt| receipt.status())
+            })
+            .collect();
+
+        if watch.internal {
+            for call in &calls {
+                // the top-level call was already checked against the transaction itself
+                if call.path.is_empty() || !call.moves_value() {
+                    continue;
+                }
+                let (Some(to), Some(tx)) = (call.to, by_hash.get(&call.tx_hash)) else {
+                    continue;
+                };
+                if watch.matches(call.from, to, call.value) {
+                    transfers.push((
+                        tx,
+                        NativeTransfer {
+                            from: call.from,
+                            to,
+                            value: call.value,
+                            call_path: Some(call.describe_path()),
+                        },
+                    ));
+                }
+            }
+        }
+
+        for (tx, transfer) in transfers {
+            println!("Match found for rule: {}", TRANSFER_RULE);
+            matches.push((
+                block_number,
+                TxMatch {
+                    tx: tx.clone(),
+                    rule: TRANSFER_RULE.to_string(),
+                    block_number,
+                    status: AlertStatus::Confirmed,
+                    receipt: receipts.get(&tx.tx_hash()).cloned(),
+                    revert_reason: None,
+                    deployed: None,
+                    transfer: Some(transfer),
+                    call: None,
+                    inner: None,
+                },
+            ));
+        }
+
+        Ok(matches)
+    }
+
+    /// The logs a block must contain for any rule to match, when every rule requires one.
+    fn bloom_gate(&self, monitor: &PollingMonitor) -> Option<Arc<[LogInterest]>> {
+        // a transfer leaves no log behind
+        if monitor.transfers.is_some() {
+            return None;
+        }
+
+        self.rules
+            .iter()
+            .map(|rule| rule.log_interest(&self.contracts))
+            .collect::<Option<Vec<_>>>()
+            .filter(|interests| !interests.is_empty())
+            .map(Arc::from)
+    }
+
+    /// Unwinds everything above `ancestor`, retracting the alerts sent for orphaned blocks.
+    fn rewind<F>(&mut self, ancestor: u64, handler: &mut F)
+    where
+        F: FnMut(TxMatch),
+    {
+        self.window.truncate_from(ancestor + 1);
+        self.prefetch.reset(ancestor + 1);
+        for (_, matches) in self.alerted.split_off(&(ancestor + 1)) {
+            for mut m in matches {
+                m.status = AlertStatus::Retracted;
+                handler(m);
             }
+        }
 
-            sleep(Duration::from_secs(2)).await;
+        // held matches from the orphaned blocks were never sent, just drop them
+        for matches in self.held.values_mut() {
+            matches.retain(|m| m.block_number <= ancestor);
         }
+
+        self.current_block = ancestor;
     }
 }
 
@@ -114,12 +756,9 @@ pub fn map_rules_to_abi(mut rules: Vec<MonitorRule>, abi: &JsonAbi) -> Vec<Monit
             }
         });
 
-        // map it to the first instance of function in the ABI
-        // this is done incase there are multiple instances of the same function in the abi (function overloading)
+        // a plain name maps to every overload of the function, a signature or selector to one
         if let Some(name) = target_name {
-            if let Some(func) = abi.function(&name).and_then(|f| f.first()) {
-                rule.abi_function = Some(func.clone());
-            }
+            rule.abi_functions = select_functions(abi, &name).into_iter().cloned().collect();
         }
     }
     rules
@@ -127,9 +766,61 @@ pub fn map_rules_to_abi(mut rules: Vec<MonitorRule>, abi: &JsonAbi) -> Vec<Monit
 
 // This function decodes transaction input data using the provided ABI and returns a formatted string.
 // This is used to send clear notifications about the transaction details.
+// Calls made through a batching wrapper are listed one by one under it.
 pub fn get_tx_details(tx: &AnyRpcTransaction, abi: &JsonAbi) -> String {
-    let input = tx.input();
+    let mut output = decode_input(tx.input(), abi);
+
+    if let Some(to) = tx.to() {
+        for call in unwrap_calls(tx.from(), to, tx.input()) {
+            output.push_str(&format!(
+                "Inner Call {} to {:?}:\n{}\n",
+                call.context,
+                call.to,
+                decode_input(&call.input, abi).trim_end()
+            ));
+        }
+    }
+    output
+}
+
+// Describes a call made by a batching wrapper, with the wrappers it came through and the
+// user operation it was made for.
+pub fn get_inner_call_details(call: &InnerCall, abi: &JsonAbi) -> String {
+    let mut output = format!(
+        "Wrapped In: {}\nInner Call From: {:?}\nInner Call To: {:?}\n",
+        call.context, call.from, call.to
+    );
+    if let Some(op) = &call.user_op {
+        let outcome = match op.success {
+            Some(true) => "succeeded",
+            Some(false) => "failed",
+            None => "unknown",
+        };
+        output.push_str(&format!(
+            "User Operation: sender {:?}, nonce {}, {}\n",
+            op.sender, op.nonce, outcome
+        ));
+    }
+    output.push_str(&decode_input(&call.input, abi));
+    output
+}
+
+// Describes a call made from inside a transaction: where it sits in the call tree, who made
+// it and what it called.
+pub fn get_call_details(call: &InternalCall, abi: &JsonAbi) -> String {
+    let mut output = format!(
+        "Call Path: {}\nCalled By: {:?}\n",
+        call.describe_path(),
+        call.from
+    );
+    if call.reverted {
+        output.push_str("Call Reverted: yes\n");
+    }
+    output.push_str(&decode_input(&call.input, abi));
+    output
+}
 
+fn decode_input(input: &[u8], abi: &JsonAbi) -> String {
     if input.len() < 4 {
         return "Transaction has no function data".to_string();
     }
@@ -141,6 +832,12 @@ pub fn get_tx_details(tx: &AnyRpcTransaction, abi: &JsonAbi) -> String {
         .functions()
         .find(|f| f.selector().as_slice() == selector);
 
+    if func.is_none()
+        && let Some(wrapper) = wrapper_name(input)
+    {
+        return format!("Function: {} (batched calls)\n", wrapper);
+    }
+
     if let Some(f) = func {
         match f.abi_decode_input(data) {
             Ok(decoded_inputs) => {
@@ -158,3 +855,120 @@ pub fn get_tx_details(tx: &AnyRpcTransaction, abi: &JsonAbi) -> String {
         format!("Unknown Function (Selector: {})", hex::encode(selector))
     }
 }
+
+// Outcome of a matched transaction, for rules that looked at its receipt.
+pub fn get_receipt_details(m: &TxMatch) -> String {
+    let Some(receipt) = &m.receipt else {
+        return String::new();
+    };
+
+    let mut output = format!(
+        "Status: {}\nGas Used: {}\n",
+        if receipt.status() {
+            "Success"
+        } else {
+            "Reverted"
+        },
+        receipt.gas_used()
+    );
+    if let Some(reason) = &m.revert_reason {
+        output.push_str(&format!("Revert Reason: {}\n", reason));
+    }
+    if let Some(address) = &m.deployed {
+        output.push_str(&format!("Deployed Contract: {:?}\n", address));
+    }
+    output
+}
+
+// The block the monitor has to reach before a match for this rule is released.
+fn release_block(monitor: &PollingMonitor, rule: &MonitorRule, block_number: u64) -> u64 {
+    let extra = rule
+        .confirmations
+        .unwrap_or_default()
+        .saturating_sub(monitor.confirmations);
+    block_number + extra
+}
+
+// The transfer a transaction makes with its own value, if the monitor watches for it. A
+// deployment sends its value to the contract it creates.
+pub(crate) fn top_level_transfer(
+    monitor: &PollingMonitor,
+    tx: &AnyRpcTransaction,
+) -> Option<NativeTransfer> {
+    let watch = monitor.transfers.as_ref()?;
+    let to = tx.to().or_else(|| deployed_address(tx, None))?;
+
+    watch
+        .matches(tx.from(), to, tx.value())
+        .then(|| NativeTransfer {
+            from: tx.from(),
+            to,
+            value: tx.value(),
+            call_path: None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::ProviderPool;
+
+    // a monitor on no contracts, nothing here sends it a request
+    fn monitor() -> PollingMonitor {
+        let pool = ProviderPool::new("mainnet", &["http://127.0.0.1:1".to_string()], None).unwrap();
+        PollingMonitor::new(Arc::new(pool), Vec::new())
+    }
+
+    fn child(byte: u8, created: u64) -> MonitoredContract {
+        MonitoredContract::new(Address::repeat_byte(byte), Arc::default()).starting_at(created)
+    }
+
+    #[test]
+    fn contracts_there_from_the_start_are_not_backfilled() {
+        let monitor = monitor();
+        monitor.contracts.add(child(1, 50));
+
+        let scanner = TxScanner::new(&monitor, Vec::new(), 100);
+        assert!(scanner.backfill.is_empty());
+    }
+
+    #[test]
+    fn backfills_each_late_child_once() {
+        let monitor = monitor();
+        let mut scanner = TxScanner::new(&monitor, Vec::new(), 100);
+
+        // two children found after the scanner went past where they were created
+        monitor.contracts.add(child(1, 50));
+        monitor.contracts.add(child(2, 80));
+        scanner.refresh(&monitor);
+        assert_eq!(
+            scanner.backfill,
+            vec![(Address::repeat_byte(1), 50), (Address::repeat_byte(2), 80)]
+        );
+
+        // the scanner going back for the second one doesn't go back for the first one again
+        let mut backfill = scanner.backfill_scanner(&monitor, Address::repeat_byte(2), 80);
+        assert_eq!(backfill.current_block(), 79);
+        assert!(backfill.backfill.is_empty());
+        monitor.contracts.add(child(3, 90));
+        backfill.refresh(&monitor);
+        assert!(backfill.backfill.is_empty());
+
+        // and the third one is the only one left for the scanner itself
+        scanner.backfill.clear();
+        scanner.refresh(&monitor);
+        assert_eq!(scanner.backfill, vec![(Address::repeat_byte(3), 90)]);
+    }
+
+    #[test]
+    fn a_backfill_only_matches_its_contract() {
+        let monitor = monitor();
+        let scanner = TxScanner::new(&monitor, Vec::new(), 100);
+        let backfill = scanner.backfill_scanner(&monitor, Address::repeat_byte(2), 80);
+
+        assert!(scanner.includes(Some(Address::repeat_byte(1))));
+        assert!(backfill.includes(Some(Address::repeat_byte(2))));
+        assert!(!backfill.includes(Some(Address::repeat_byte(1))));
+        assert!(!backfill.includes(None));
+    }
+}
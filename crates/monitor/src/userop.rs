@@ -0,0 +1,185 @@
+//! # User Operations
+//! This module provides tools for account abstraction (ERC-4337) transactions.
+//!
+//! Smart accounts don't send transactions themselves. A bundler sends the EntryPoint a
+//! `handleOps` call carrying the accounts' user operations, and the EntryPoint calls each
+//! account with its operation's `callData`. The transaction therefore shows the bundler as
+//! the sender and the EntryPoint as the recipient. Unwrapping the bundle (see `batch`) gives
+//! the calls each account made, and the EntryPoint's `UserOperationEvent` in the receipt says
+//! whether an operation went through, which the transaction's own status doesn't.
+
+use alloy::network::AnyTransactionReceipt;
+use alloy::primitives::{Address, U256};
+use alloy::sol;
+use alloy::sol_types::SolEvent;
+
+sol! {
+    event UserOperationEvent(
+        bytes32 indexed userOpHash,
+        address indexed sender,
+        address indexed paymaster,
+        uint256 nonce,
+        bool success,
+        uint256 actualGasCost,
+        uint256 actualGasUsed
+    );
+}
+
+/// The user operation a call was made for.
+#[derive(Debug, Clone)]
+pub struct UserOperation {
+    pub entry_point: Address,
+    pub sender: Address, // the smart account
+    pub nonce: U256,
+
+    // from the operation's `UserOperationEvent`, once the receipt was looked at
+    pub success: Option<bool>,
+}
+
+impl UserOperation {
+    /// Whether the operation went through, according to the EntryPoint's event in the receipt.
+    pub fn outcome(&self, receipt: &AnyTransactionReceipt) -> Option<bool> {
+        receipt
+            .inner
+            .logs()
+            .iter()
+            .filter(|log| log.address() == self.entry_point)
+            .filter_map(|log| UserOperationEvent::decode_log_data(log.data()).ok())
+            .find(|event| event.sender == self.sender && event.nonce == self.nonce)
+            .map(|event| event.success)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::unwrap_calls;
+    use crate::primitives::models::{Condition, MonitorRule, TxStatus};
+    use crate::testing::{log, receipt};
+    use alloy::network::ReceiptResponse;
+    use alloy::primitives::{B256, Bytes, address};
+    use alloy::sol_types::SolCall;
+    use serde_json::json;
+
+    // the v0.7 EntryPoint and a smart account, as their ABIs have them
+    sol! {
+        struct PackedUserOperation {
+            address sender;
+            uint256 nonce;
+            bytes initCode;
+            bytes callData;
+            bytes32 accountGasLimits;
+            uint256 preVerificationGas;
+            bytes32 gasFees;
+            bytes paymasterAndData;
+            bytes signature;
+        }
+
+        function handleOps(PackedUserOperation[] ops, address beneficiary) external;
+        function execute(address dest, uint256 value, bytes func) external;
+    }
+
+    const ENTRY_POINT: Address = address!("0x0000000071727de22e5e9d8baf0edac6f37da032");
+    const BUNDLER: Address = address!("0x00000000000000000000000000000000000000b0");
+    const ACCOUNT: Address = address!("0x00000000000000000000000000000000000000a1");
+    const TOKEN: Address = address!("0x00000000000000000000000000000000000000a2");
+
+    // a bundle with one operation, the account sending 5 wei along with a call to the token
+    fn bundle() -> Vec<u8> {
+        let op = PackedUserOperation {
+            sender: ACCOUNT,
+            nonce: U256::from(3),
+            initCode: Bytes::new(),
+            callData: executeCall {
+                dest: TOKEN,
+                value: U256::from(5),
+                func: Bytes::from(vec![0xa9, 0x05, 0x9c, 0xbb]),
+            }
+            .abi_encode()
+            .into(),
+            accountGasLimits: B256::ZERO,
+            preVerificationGas: U256::ZERO,
+            gasFees: B256::ZERO,
+            paymasterAndData: Bytes::new(),
+            signature: Bytes::new(),
+        };
+        handleOpsCall {
+            ops: vec![op],
+            beneficiary: BUNDLER,
+        }
+        .abi_encode()
+    }
+
+    // the receipt of the bundle, with the EntryPoint's event for an operation
+    fn bundle_receipt(emitter: Address, nonce: u64, success: bool) -> AnyTransactionReceipt {
+        let event = UserOperationEvent {
+            userOpHash: B256::repeat_byte(0x44),
+            sender: ACCOUNT,
+            paymaster: Address::ZERO,
+            nonce: U256::from(nonce),
+            success,
+            actualGasCost: U256::from(1),
+            actualGasUsed: U256::from(1),
+        }
+        .encode_log_data();
+        receipt(json!({
+            "from": BUNDLER,
+            "to": ENTRY_POINT,
+            "logs": [log(emitter, event.topics(), event.data.clone())],
+        }))
+    }
+
+    #[test]
+    fn attributes_a_bundled_call_to_the_account_and_reads_its_outcome() {
+        let calls = unwrap_calls(BUNDLER, ENTRY_POINT, &bundle());
+        let summary: Vec<(&str, Address, Address, U256)> = calls
+            .iter()
+            .map(|call| (call.context.as_str(), call.from, call.to, call.value))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("handleOps[0]", ENTRY_POINT, ACCOUNT, U256::ZERO),
+                ("handleOps[0] > execute", ACCOUNT, TOKEN, U256::from(5)),
+            ]
+        );
+
+        let op = calls[1].user_op.clone().unwrap();
+        assert_eq!(
+            (op.entry_point, op.sender, op.nonce),
+            (ENTRY_POINT, ACCOUNT, U256::from(3))
+        );
+
+        assert_eq!(
+            op.outcome(&bundle_receipt(ENTRY_POINT, 3, true)),
+            Some(true)
+        );
+        assert_eq!(
+            op.outcome(&bundle_receipt(ENTRY_POINT, 3, false)),
+            Some(false)
+        );
+        // another operation of the account, or the event from somewhere else
+        assert_eq!(op.outcome(&bundle_receipt(ENTRY_POINT, 4, true)), None);
+        assert_eq!(op.outcome(&bundle_receipt(TOKEN, 3, true)), None);
+    }
+
+    #[test]
+    fn a_failed_operation_reverts_its_calls_in_a_bundle_that_went_through() {
+        let calls = unwrap_calls(BUNDLER, ENTRY_POINT, &bundle());
+        let status = |status| MonitorRule {
+            name: "test".to_string(),
+            conditions: vec![Condition::Status(status)],
+            confirmations: None,
+            spawn: None,
+            abi_functions: Vec::new(),
+        };
+
+        let failed = bundle_receipt(ENTRY_POINT, 3, false);
+        assert!(failed.status());
+        assert!(status(TxStatus::Reverted).inner_receipt_match(&failed, &[], &calls[1]));
+        assert!(!status(TxStatus::Success).inner_receipt_match(&failed, &[], &calls[1]));
+
+        let succeeded = bundle_receipt(ENTRY_POINT, 3, true);
+        assert!(status(TxStatus::Success).inner_receipt_match(&succeeded, &[], &calls[1]));
+    }
+}
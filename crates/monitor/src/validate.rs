@@ -0,0 +1,524 @@
+//! # Rule Validation
+//! This module provides tools for checking a monitor's configuration before it starts.
+//!
+//! Rules are matched against the ABI leniently: a `Function` condition naming a function the
+//! ABI doesn't have, or a comparison that can't be made, simply never matches, so a typo
+//! silences the monitor. `validate_config` looks for these mistakes up front and reports every
+//! one of them, each with the JSON path of the offending field in the request.
+//!
+//! Every contract's rules are mapped to that contract's own ABI, so a rule is checked against
+//! the ABIs it will be mapped to: the contract's named in its `To` condition when it has one,
+//! every contract's and the factory children's otherwise. A function only has to be found in
+//! one of them, but an argument has to exist in every ABI that has the function.
+
+use crate::primitives::models::{Condition, MonitorConfig, MonitorRule, Operator};
+use crate::primitives::utils::{parse_selector, select_events, select_functions};
+use alloy::dyn_abi::{DynSolType, Specifier};
+use alloy::json_abi::{JsonAbi, Param};
+use alloy::primitives::utils::parse_units;
+use alloy::primitives::{Address, U256};
+use serde::Serialize;
+use std::str::FromStr;
+
+/// A mistake in a monitor's configuration.
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationIssue {
+    pub path: String, // e.g. `$.functions[0].conditions[1].Argument.name`
+    pub message: String,
+}
+
+/// An ABI a monitor's rules are mapped to.
+#[derive(Debug, Clone, Copy)]
+pub struct AbiTarget<'a> {
+    pub contract: Option<Address>, // `None` for the ABI a factory's children share
+    pub abi: &'a JsonAbi,
+}
+
+impl AbiTarget<'_> {
+    fn describe(&self) -> String {
+        match self.contract {
+            Some(address) => format!("{:?}", address),
+            None => "the factory's children".to_string(),
+        }
+    }
+}
+
+/// Checks the rules and events of `config` against the ABIs they are mapped to, the monitored
+/// contracts' in order and then the factory children's.
+pub fn validate_config(config: &MonitorConfig, targets: &[AbiTarget]) -> Vec<ValidationIssue> {
+    let mut issues = Vec::new();
+    let abis: Vec<&JsonAbi> = targets.iter().map(|target| target.abi).collect();
+
+    for (idx, name) in config.events.iter().flatten().enumerate() {
+        check_event(&abis, name, &format!("$.events[{}]", idx), &mut issues);
+    }
+
+    for (idx, rule) in config.functions.iter().flatten().enumerate() {
+        let path = format!("$.functions[{}]", idx);
+        validate_rule(rule, targets, &path, &mut issues);
+
+        // spawned monitors decode with the first contract's ABI
+        if let Some(spawn) = &rule.spawn {
+            let parent = &targets[..targets.len().min(1)];
+            let abis: Vec<&JsonAbi> = parent.iter().map(|target| target.abi).collect();
+            for (idx, name) in spawn.events.iter().flatten().enumerate() {
+                let path = format!("{}.spawn.events[{}]", path, idx);
+                check_event(&abis, name, &path, &mut issues);
+            }
+            for (idx, child) in spawn.functions.iter().flatten().enumerate() {
+                let path = format!("{}.spawn.functions[{}]", path, idx);
+                validate_rule(child, parent, &path, &mut issues);
+            }
+        }
+    }
+
+    issues
+}
+
+/// Checks one rule's conditions against the ABIs it is mapped to, `path` being the rule's own
+/// JSON path.
+pub fn validate_rule(
+    rule: &MonitorRule,
+    targets: &[AbiTarget],
+    path: &str,
+    issues: &mut Vec<ValidationIssue>,
+) {
+    let targets = rule_targets(rule, targets);
+    let abis: Vec<&JsonAbi> = targets.iter().map(|target| target.abi).collect();
+
+    let function = rule.conditions.iter().find_map(|c| match c {
+        Condition::Function(name) => Some(name.as_str()),
+        _ => None,
+    });
+    let error = rule.conditions.iter().find_map(|c| match c {
+        Condition::Error(name) => Some(name.as_str()),
+        _ => None,
+    });
+
+    for (idx, condition) in rule.conditions.iter().enumerate() {
+        let path = format!("{}.conditions[{}]", path, idx);
+        let mut issue = |field: &str, message: String| {
+            issues.push(ValidationIssue {
+                path: format!("{}.{}", path, field),
+                message,
+            })
+        };
+
+        match condition {
+            Condition::Function(name) => {
+                // a selector the ABI doesn't know is still matched as it is
+                if parse_selector(name).is_none()
+                    && abis
+                        .iter()
+                        .all(|abi| select_functions(abi, name).is_empty())
+                {
+                    issue("Function", format!("The ABI has no function {}", name));
+                }
+            }
+            Condition::Argument {
+                name,
+                operator,
+                value,
+            } => {
+                let Some(function) = function else {
+                    issue(
+                        "Argument",
+                        "An Argument condition needs a Function condition in the same rule"
+                            .to_string(),
+                    );
+                    continue;
+                };
+                // every ABI the function is in decodes the argument for its contract
+                let mut params: Vec<&Param> = Vec::new();
+                for target in &targets {
+                    let functions = select_functions(target.abi, function);
+                    let found: Vec<&Param> = functions
+                        .iter()
+                        .flat_map(|f| f.inputs.iter().filter(|input| input.name == *name))
+                        .collect();
+                    if !functions.is_empty() && found.is_empty() && targets.len() > 1 {
+                        issue(
+                            "Argument.name",
+                            format!(
+                                "{} has no argument {} in the ABI of {}",
+                                function,
+                                name,
+                                target.describe()
+                            ),
+                        );
+                    }
+                    params.extend(found);
+                }
+                check_argument(
+                    "Argument",
+                    &params,
+                    function,
+                    (name, operator, value),
+                    &mut issue,
+                );
+            }
+            Condition::ErrorArgument {
+                name,
+                operator,
+                value,
+            } => {
+                let Some(error) = error else {
+                    issue(
+                        "ErrorArgument",
+                        "An ErrorArgument condition needs an Error condition in the same rule"
+                            .to_string(),
+                    );
+                    continue;
+                };
+                let params: Vec<&Param> = abis
+                    .iter()
+                    .filter_map(|abi| abi.errors.get(error))
+                    .flatten()
+                    .flat_map(|e| e.inputs.iter().filter(|input| input.name == *name))
+                    .collect();
+                check_argument(
+                    "ErrorArgument",
+                    &params,
+                    error,
+                    (name, operator, value),
+                    &mut issue,
+                );
+            }
+            Condition::Error(name) => {
+                if abis.iter().all(|abi| !abi.errors.contains_key(name)) {
+                    issue("Error", format!("The ABI has no error {}", name));
+                }
+            }
+            Condition::EmitsEvent(name) => {
+                if abis.iter().all(|abi| select_events(abi, name).is_empty()) {
+                    issue("EmitsEvent", format!("The ABI has no event {}", name));
+                }
+            }
+            Condition::Value { operator, value } => {
+                check_numeric(operator, "Value.operator", &mut issue);
+                if U256::from_str(value).is_err() {
+                    issue("Value.value", format!("{} is not an amount in wei", value));
+                }
+            }
+            Condition::GasPrice { operator, value } => {
+                check_numeric(operator, "GasPrice.operator", &mut issue);
+                if parse_units(value, "gwei").is_err() {
+                    issue(
+                        "GasPrice.value",
+                        format!("{} is not an amount in gwei", value),
+                    );
+                }
+            }
+            Condition::MaxPriorityFee { operator, value } => {
+                check_numeric(operator, "MaxPriorityFee.operator", &mut issue);
+                if parse_units(value, "gwei").is_err() {
+                    issue(
+                        "MaxPriorityFee.value",
+                        format!("{} is not an amount in gwei", value),
+                    );
+                }
+            }
+            Condition::Nonce { operator, .. } => {
+                check_numeric(operator, "Nonce.operator", &mut issue);
+            }
+            Condition::GasUsed { operator, .. } => {
+                check_numeric(operator, "GasUsed.operator", &mut issue);
+            }
+            Condition::From(_)
+            | Condition::To(_)
+            | Condition::TxType(_)
+            | Condition::Status(_)
+            | Condition::Deploys(_) => {}
+        }
+    }
+}
+
+// Helper functions
+
+// The ABIs a rule is mapped to: the contract's its `To` condition names, if that's one of them.
+fn rule_targets<'a>(rule: &MonitorRule, targets: &[AbiTarget<'a>]) -> Vec<AbiTarget<'a>> {
+    let to = rule.conditions.iter().find_map(|c| match c {
+        Condition::To(address) => Some(*address),
+        _ => None,
+    });
+    let named: Vec<AbiTarget> = targets
+        .iter()
+        .filter(|target| to.is_some() && target.contract == to)
+        .copied()
+        .collect();
+
+    if named.is_empty() {
+        targets.to_vec()
+    } else {
+        named
+    }
+}
+
+fn check_event(abis: &[&JsonAbi], name: &str, path: &str, issues: &mut Vec<ValidationIssue>) {
+    if abis.iter().all(|abi| select_events(abi, name).is_empty()) {
+        issues.push(ValidationIssue {
+            path: path.to_string(),
+            message: format!("The ABI has no event {}", name),
+        });
+    }
+}
+
+fn check_numeric(operator: &Operator, field: &str, issue: &mut impl FnMut(&str, String)) {
+    if matches!(operator, Operator::Contains) {
+        issue(
+            field,
+            "Contains can't compare numbers, use Eq, Gt or Lt".to_string(),
+        );
+    }
+}
+
+// `params` are the inputs called `name` in every overload of `owner`, and the value has to be
+// comparable the way `check_value` compares it for each of them. `variant` is the condition's
+// key in the request, `Argument` or `ErrorArgument`.
+fn check_argument(
+    variant: &str,
+    params: &[&Param],
+    owner: &str,
+    (name, operator, value): (&str, &Operator, &str),
+    issue: &mut impl FnMut(&str, String),
+) {
+    let field = |field: &str| format!("{}.{}", variant, field);
+    if params.is_empty() {
+        issue(
+            &field("name"),
+            format!("{} has no argument {}", owner, name),
+        );
+        return;
+    }
+
+    // overloads sharing the argument's type would only repeat the same problems
+    let mut types: Vec<&str> = Vec::new();
+    for param in params {
+        if types.contains(&param.ty.as_str()) {
+            continue;
+        }
+        types.push(&param.ty);
+
+        match param.resolve() {
+            Ok(DynSolType::Uint(_)) => {
+                if matches!(operator, Operator::Contains) {
+                    issue(
+                        &field("operator"),
+                        format!("{} is a {}, compare it with Eq, Gt or Lt", name, param.ty),
+                    );
+                }
+                if U256::from_str(value).is_err() {
+                    issue(
+                        &field("value"),
+                        format!("{} is not a valid {} for {}", value, param.ty, name),
+                    );
+                }
+            }
+            Ok(DynSolType::Address) => {
+                if !matches!(operator, Operator::Contains) {
+                    issue(
+                        &field("operator"),
+                        format!("{} is an address, compare it with Contains", name),
+                    );
+                }
+                if Address::from_str(value).is_err() {
+                    issue(
+                        &field("value"),
+                        format!("{} is not a valid address for {}", value, name),
+                    );
+                }
+            }
+            _ => issue(
+                &field("name"),
+                format!(
+                    "{} is a {}, only uint and address arguments can be compared",
+                    name, param.ty
+                ),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token() -> JsonAbi {
+        JsonAbi::parse([
+            "function transfer(address to, uint256 amount) returns (bool)",
+            "function approve(address spender, uint256 amount) returns (bool)",
+        ])
+        .unwrap()
+    }
+
+    fn weth() -> JsonAbi {
+        JsonAbi::parse(["function transfer(address dst, uint256 wad) returns (bool)"]).unwrap()
+    }
+
+    fn config(functions: serde_json::Value) -> MonitorConfig {
+        serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "rpc_url": "",
+            "chain": "mainnet",
+            "functions": functions,
+        }))
+        .unwrap()
+    }
+
+    fn paths(issues: &[ValidationIssue]) -> Vec<&str> {
+        issues.iter().map(|issue| issue.path.as_str()).collect()
+    }
+
+    #[test]
+    fn accepts_a_rule_the_abi_has() {
+        let abi = token();
+        let targets = [AbiTarget {
+            contract: Some(Address::repeat_byte(1)),
+            abi: &abi,
+        }];
+        let config = config(serde_json::json!([{
+            "name": "large transfer",
+            "conditions": [
+                { "Function": "transfer" },
+                { "Argument": { "name": "amount", "operator": "Gt", "value": "1000" } },
+            ],
+        }]));
+
+        assert!(validate_config(&config, &targets).is_empty());
+    }
+
+    #[test]
+    fn reports_an_unknown_function() {
+        let abi = token();
+        let targets = [AbiTarget {
+            contract: Some(Address::repeat_byte(1)),
+            abi: &abi,
+        }];
+        let config = config(serde_json::json!([{
+            "name": "mint",
+            "conditions": [{ "Function": "mint" }],
+        }]));
+
+        let issues = validate_config(&config, &targets);
+        assert_eq!(
+            paths(&issues),
+            vec!["$.functions[0].conditions[0].Function"]
+        );
+    }
+
+    #[test]
+    fn reports_a_wrong_argument_name() {
+        let abi = token();
+        let targets = [AbiTarget {
+            contract: Some(Address::repeat_byte(1)),
+            abi: &abi,
+        }];
+        let config = config(serde_json::json!([{
+            "name": "large transfer",
+            "conditions": [
+                { "Function": "transfer" },
+                { "Argument": { "name": "value", "operator": "Gt", "value": "1000" } },
+            ],
+        }]));
+
+        let issues = validate_config(&config, &targets);
+        assert_eq!(
+            paths(&issues),
+            vec!["$.functions[0].conditions[1].Argument.name"]
+        );
+    }
+
+    #[test]
+    fn reports_an_argument_missing_from_one_of_the_abis() {
+        let (token, weth) = (token(), weth());
+        let targets = [
+            AbiTarget {
+                contract: Some(Address::repeat_byte(1)),
+                abi: &token,
+            },
+            AbiTarget {
+                contract: Some(Address::repeat_byte(2)),
+                abi: &weth,
+            },
+        ];
+        let config = config(serde_json::json!([{
+            "name": "large transfer",
+            "conditions": [
+                { "Function": "transfer" },
+                { "Argument": { "name": "amount", "operator": "Gt", "value": "1000" } },
+            ],
+        }]));
+
+        let issues = validate_config(&config, &targets);
+        assert_eq!(
+            paths(&issues),
+            vec!["$.functions[0].conditions[1].Argument.name"]
+        );
+        assert!(
+            issues[0]
+                .message
+                .contains(&format!("{:?}", Address::repeat_byte(2)))
+        );
+    }
+
+    #[test]
+    fn checks_a_rule_against_the_contract_it_is_sent_to() {
+        let (token, weth) = (token(), weth());
+        let targets = [
+            AbiTarget {
+                contract: Some(Address::repeat_byte(1)),
+                abi: &token,
+            },
+            AbiTarget {
+                contract: Some(Address::repeat_byte(2)),
+                abi: &weth,
+            },
+        ];
+
+        // approve is only in the first contract's ABI
+        let config = config(serde_json::json!([
+            {
+                "name": "approval",
+                "conditions": [
+                    { "To": format!("{:?}", Address::repeat_byte(1)) },
+                    { "Function": "approve" },
+                ],
+            },
+            {
+                "name": "weth approval",
+                "conditions": [
+                    { "To": format!("{:?}", Address::repeat_byte(2)) },
+                    { "Function": "approve" },
+                ],
+            },
+        ]));
+
+        let issues = validate_config(&config, &targets);
+        assert_eq!(
+            paths(&issues),
+            vec!["$.functions[1].conditions[1].Function"]
+        );
+    }
+
+    #[test]
+    fn checks_a_factory_rule_against_the_children_abi() {
+        let weth = weth();
+        let targets = [AbiTarget {
+            contract: None,
+            abi: &weth,
+        }];
+        let config = config(serde_json::json!([{
+            "name": "large transfer",
+            "conditions": [
+                { "Function": "transfer" },
+                { "Argument": { "name": "amount", "operator": "Gt", "value": "1000" } },
+            ],
+        }]));
+
+        let issues = validate_config(&config, &targets);
+        assert_eq!(
+            paths(&issues),
+            vec!["$.functions[0].conditions[1].Argument.name"]
+        );
+    }
+}
@@ -1,9 +1,26 @@
 use crate::state::AppState;
+use alloy::json_abi::JsonAbi;
+use alloy::primitives::{Address, B256};
+use alloy_chains::Chain;
+use axum::extract::Path;
+use axum::extract::rejection::JsonRejection;
+use axum::response::{IntoResponse, Response};
 use axum::{Json, extract::State, http::StatusCode};
-use monitor::PollingMonitor;
-use monitor::primitives::models::MonitorConfig;
-use monitor::primitives::utils::fetch_abi;
+use futures::future::join_all;
+use monitor::abi::{AbiFetcher, CachedAbiEntry, load_abi};
+use monitor::backtest::{BacktestReport, backtest};
+use monitor::evaluate::{EvaluationReport, evaluate_transaction};
+use monitor::factory::FactoryWatch;
+use monitor::prefetch::DEFAULT_FETCH_CONCURRENCY;
+use monitor::primitives::models::{BlockTarget, MonitorConfig, MonitorRule};
+use monitor::primitives::utils::poll_interval_for;
+use monitor::ratelimit::{EndpointUsage, attribute};
+use monitor::transfers::TransferWatch;
 use monitor::tx::map_rules_to_abi;
+use monitor::validate::{AbiTarget, ValidationIssue, validate_config};
+use monitor::{MonitoredContract, PollingMonitor};
+use std::error::Error;
+use std::str::FromStr;
 use std::sync::Arc;
 use uuid::Uuid;
 
@@ -13,35 +30,96 @@ pub struct CreateMonitorResponse {
     pub status: String,
 }
 
+/// Every problem found in a monitor's rules, sent back with a 422.
+#[derive(serde::Serialize)]
+pub struct ValidationErrorResponse {
+    pub error: String,
+    pub issues: Vec<ValidationIssue>,
+}
+
+/// Why a request on a monitor config failed.
+pub enum ApiError {
+    Status(StatusCode),
+    Invalid(Vec<ValidationIssue>),
+}
+
+impl From<StatusCode> for ApiError {
+    fn from(status: StatusCode) -> Self {
+        Self::Status(status)
+    }
+}
+
+// A body that isn't valid JSON or doesn't fit the request is reported like any other mistake
+// in it, with the path serde got to.
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        if !matches!(
+            rejection,
+            JsonRejection::JsonDataError(_) | JsonRejection::JsonSyntaxError(_)
+        ) {
+            return Self::Status(rejection.status());
+        }
+
+        let error = rejection
+            .source()
+            .and_then(|e| e.source())
+            .and_then(|e| e.downcast_ref::<serde_path_to_error::Error<serde_json::Error>>());
+        let issue = match error {
+            Some(error) => ValidationIssue {
+                path: match error.path().to_string().as_str() {
+                    "." => "$".to_string(),
+                    path => format!("$.{}", path),
+                },
+                message: error.inner().to_string(),
+            },
+            None => ValidationIssue {
+                path: "$".to_string(),
+                message: rejection.body_text(),
+            },
+        };
+        Self::Invalid(vec![issue])
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Status(status) => status.into_response(),
+            Self::Invalid(issues) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(ValidationErrorResponse {
+                    error: "Invalid monitor config".to_string(),
+                    issues,
+                }),
+            )
+                .into_response(),
+        }
+    }
+}
+
 pub async fn create_monitor(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<MonitorConfig>,
-) -> Result<Json<CreateMonitorResponse>, StatusCode> {
+    payload: Result<Json<MonitorConfig>, JsonRejection>,
+) -> Result<Json<CreateMonitorResponse>, ApiError> {
+    let Json(payload) = payload?;
     let monitor_id = Uuid::new_v4().to_string();
-    let rpc_url = if payload.rpc_url.is_empty() {
-        state.default_rpc_url.clone()
-    } else {
-        payload.rpc_url.clone()
-    };
-
     println!("Creating Monitor '{}' [{}]", payload.name, monitor_id);
 
-    // fetch ABI
-    let abi = fetch_abi(&payload.chain, payload.address, &rpc_url)
-        .await
-        .map_err(|e| {
-            eprintln!("❌ ABI Error: {}", e);
-            StatusCode::BAD_REQUEST
-        })?;
-
-    //  prepare Rules
-    let tx_rules = map_rules_to_abi(payload.functions.unwrap_or_default(), &abi);
-    let event_names = payload.events.unwrap_or_default();
-
-    let contract_addr = payload.address;
+    let PreparedMonitor {
+        mut monitor,
+        tx_rules,
+        event_names,
+    } = prepare_monitor(&state, &payload).await?;
+    let config = payload.clone();
 
-    let monitor = PollingMonitor::new(&rpc_url, contract_addr, abi)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // a monitor with its own socket follows it, the rest share one block ingestion
+    // service per chain and endpoint
+    if let Some(ws_url) = payload.ws_url {
+        monitor = monitor.with_ws_url(ws_url);
+    } else {
+        let stream = state.block_streams.get_or_spawn(monitor.pool.clone());
+        monitor = monitor.with_stream(stream);
+    }
 
     let handle = monitor.start_background_monitoring(
         payload.name,
@@ -55,9 +133,363 @@ pub async fn create_monitor(
         .write()
         .await
         .insert(monitor_id.clone(), handle);
+    state
+        .monitor_configs
+        .write()
+        .await
+        .insert(monitor_id.clone(), config);
 
     Ok(Json(CreateMonitorResponse {
         id: monitor_id,
         status: "Running".to_string(),
     }))
 }
+
+/// How much of every RPC endpoint's request budget each monitor has been using.
+pub async fn rpc_metrics(State(state): State<Arc<AppState>>) -> Json<Vec<EndpointUsage>> {
+    Json(state.provider_pools.usage())
+}
+
+/// A monitor config and the past blocks to run it over.
+#[derive(serde::Deserialize)]
+pub struct BacktestRequest {
+    pub monitor: MonitorConfig,
+    pub from_block: u64,
+    pub to_block: u64,
+}
+
+/// Runs a monitor's rules and events over past blocks without starting it or sending alerts.
+pub async fn backtest_monitor(
+    State(state): State<Arc<AppState>>,
+    payload: Result<Json<BacktestRequest>, JsonRejection>,
+) -> Result<Json<BacktestReport>, ApiError> {
+    let Json(payload) = payload?;
+    println!(
+        "Backtesting Monitor '{}' over blocks {} to {}",
+        payload.monitor.name, payload.from_block, payload.to_block
+    );
+
+    let PreparedMonitor {
+        monitor,
+        tx_rules,
+        event_names,
+    } = prepare_monitor(&state, &payload.monitor).await?;
+
+    // past blocks are as deep as they get, they only have to exist
+    let monitor = monitor.with_confirmations(0, BlockTarget::Latest);
+    let caller = format!("{}:backtest", payload.monitor.name);
+    let report = attribute(
+        caller,
+        backtest(
+            &monitor,
+            tx_rules,
+            &event_names,
+            payload.from_block,
+            payload.to_block,
+        ),
+    )
+    .await
+    .map_err(|e| {
+        eprintln!("❌ Backtest Error: {}", e);
+        StatusCode::BAD_REQUEST
+    })?;
+
+    Ok(Json(report))
+}
+
+/// A transaction and the rules to explain against it: those of a running monitor, by its id,
+/// or of a config sent along, optionally narrowed down to the one rule called `rule`.
+#[derive(serde::Deserialize)]
+pub struct EvaluateRequest {
+    pub tx_hash: B256,
+    pub monitor_id: Option<String>,
+    pub monitor: Option<MonitorConfig>,
+    pub rule: Option<String>,
+}
+
+/// Checks a monitor's rules against one transaction and reports how every condition fared.
+pub async fn evaluate_rule(
+    State(state): State<Arc<AppState>>,
+    payload: Result<Json<EvaluateRequest>, JsonRejection>,
+) -> Result<Json<EvaluationReport>, ApiError> {
+    let Json(payload) = payload?;
+    let config = match (&payload.monitor_id, payload.monitor) {
+        (Some(id), _) => state
+            .monitor_configs
+            .read()
+            .await
+            .get(id)
+            .cloned()
+            .ok_or_else(|| {
+                eprintln!("❌ Config Error: No monitor {}", id);
+                StatusCode::NOT_FOUND
+            })?,
+        (None, Some(config)) => config,
+        (None, None) => {
+            eprintln!("❌ Config Error: Either monitor_id or monitor is required");
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+    };
+    println!(
+        "Evaluating Monitor '{}' against transaction {:?}",
+        config.name, payload.tx_hash
+    );
+
+    let PreparedMonitor {
+        monitor,
+        mut tx_rules,
+        event_names,
+    } = prepare_monitor(&state, &config).await?;
+
+    if let Some(rule) = &payload.rule {
+        tx_rules.retain(|r| r.name == *rule);
+        if tx_rules.is_empty() {
+            eprintln!(
+                "❌ Config Error: Monitor '{}' has no rule '{}'",
+                config.name, rule
+            );
+            return Err(StatusCode::NOT_FOUND.into());
+        }
+    }
+
+    let caller = format!("{}:evaluate", config.name);
+    let report = attribute(
+        caller,
+        evaluate_transaction(&monitor, tx_rules, &event_names, payload.tx_hash),
+    )
+    .await
+    .map_err(|e| {
+        eprintln!("❌ Evaluation Error: {}", e);
+        StatusCode::BAD_REQUEST
+    })?
+    .ok_or_else(|| {
+        eprintln!(
+            "❌ Evaluation Error: Transaction {:?} not found",
+            payload.tx_hash
+        );
+        StatusCode::NOT_FOUND
+    })?;
+
+    Ok(Json(report))
+}
+
+#[derive(serde::Serialize)]
+pub struct InvalidateAbiResponse {
+    pub removed: usize,
+}
+
+/// Every ABI in the cache, with when it was fetched and when it expires.
+pub async fn list_cached_abis(State(state): State<Arc<AppState>>) -> Json<Vec<CachedAbiEntry>> {
+    Json(state.abi_cache.entries())
+}
+
+/// Drops the cached ABI of one contract, given by chain name or id and implementation
+/// address, so the next monitor on it fetches it again.
+pub async fn invalidate_abi(
+    State(state): State<Arc<AppState>>,
+    Path((chain, address)): Path<(String, Address)>,
+) -> Result<Json<InvalidateAbiResponse>, ApiError> {
+    let chain_id = Chain::from_str(&chain)
+        .map_err(|_| {
+            eprintln!("❌ Config Error: Unknown chain {}", chain);
+            StatusCode::BAD_REQUEST
+        })?
+        .id();
+
+    if !state.abi_cache.invalidate(chain_id, address) {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    println!("Dropped the cached ABI of {:?} on {}", address, chain);
+    Ok(Json(InvalidateAbiResponse { removed: 1 }))
+}
+
+/// Drops every cached ABI.
+pub async fn clear_abi_cache(State(state): State<Arc<AppState>>) -> Json<InvalidateAbiResponse> {
+    let removed = state.abi_cache.clear();
+    println!("Dropped {} cached ABI(s)", removed);
+    Json(InvalidateAbiResponse { removed })
+}
+
+// Helper functions
+
+/// A monitor built from its config, not started yet.
+struct PreparedMonitor {
+    monitor: PollingMonitor,
+    tx_rules: Vec<MonitorRule>,
+    event_names: Vec<String>,
+}
+
+// Fetches the ABIs, checks the rules against them and sets the monitor up the way its config
+// asks, everything short of picking how it gets its blocks.
+async fn prepare_monitor(
+    state: &AppState,
+    payload: &MonitorConfig,
+) -> Result<PreparedMonitor, ApiError> {
+    let rpc_url = if payload.rpc_url.is_empty() {
+        state.default_rpc_url.clone()
+    } else {
+        payload.rpc_url.clone()
+    };
+
+    // `address` first, then `addresses` in the order they were given
+    let mut addresses: Vec<Address> = Vec::new();
+    for address in payload
+        .address
+        .into_iter()
+        .chain(payload.addresses.clone().unwrap_or_default())
+    {
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+
+    // only a transfers monitor can go without a contract, and it has to watch something
+    let has_contract_rules = payload.events.is_some() || payload.functions.is_some();
+    if addresses.is_empty()
+        && payload.factory.is_none()
+        && (has_contract_rules || payload.transfers.is_none())
+    {
+        eprintln!("❌ Config Error: events and functions need a contract address");
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    // pending transactions only come over a socket
+    let pending = payload.pending.unwrap_or_default();
+    if pending && payload.ws_url.is_none() {
+        eprintln!("❌ Config Error: pending transaction monitoring needs a ws_url");
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    // ABIs come from the config when it has one, from the providers otherwise
+    let mut fetcher = AbiFetcher::new(&payload.chain, &rpc_url).with_cache(state.abi_cache.clone());
+    if let Some(providers) = &payload.abi_providers {
+        fetcher = fetcher.with_providers(providers.clone());
+    }
+
+    // fetch ABIs, just the first contract's when they all share it
+    let abis = match &payload.abi {
+        Some(source) => load_abi(source).map(|abi| vec![abi]),
+        None => {
+            let to_fetch = if payload.shared_abi.unwrap_or(true) {
+                &addresses[..addresses.len().min(1)]
+            } else {
+                &addresses[..]
+            };
+            join_all(to_fetch.iter().map(|address| fetcher.fetch(*address)))
+                .await
+                .into_iter()
+                .collect::<Result<Vec<JsonAbi>, _>>()
+        }
+    }
+    .map_err(|e| {
+        eprintln!("❌ ABI Error: {}", e);
+        StatusCode::BAD_REQUEST
+    })?;
+
+    let contracts: Vec<MonitoredContract> = addresses
+        .iter()
+        .enumerate()
+        .map(|(idx, address)| {
+            let abi = abis.get(idx).or(abis.first()).cloned().unwrap_or_default();
+            MonitoredContract::new(*address, Arc::new(abi))
+        })
+        .collect();
+
+    // a factory's ABI and the one its children share
+    let factory = match &payload.factory {
+        Some(config) => {
+            let (abi, child_abi) = futures::try_join!(
+                fetcher.resolve(config.abi.as_ref(), Some(config.address)),
+                fetcher.resolve(config.child_abi.as_ref(), config.child_abi_address),
+            )
+            .map_err(|e| {
+                eprintln!("❌ ABI Error: {}", e);
+                StatusCode::BAD_REQUEST
+            })?;
+            let watch = FactoryWatch::new(&payload.chain, config, abi, child_abi).map_err(|e| {
+                eprintln!("❌ Config Error: {}", e);
+                StatusCode::BAD_REQUEST
+            })?;
+            Some(watch)
+        }
+        None => None,
+    };
+
+    // reject rules that could never match before starting, each against the ABIs it's mapped to
+    let mut targets: Vec<AbiTarget> = contracts
+        .iter()
+        .map(|c| AbiTarget {
+            contract: Some(c.address),
+            abi: c.abi.as_ref(),
+        })
+        .collect();
+    if let Some(factory) = &factory {
+        targets.push(AbiTarget {
+            contract: None,
+            abi: factory.child_abi.as_ref(),
+        });
+    }
+    let issues = validate_config(payload, &targets);
+    if !issues.is_empty() {
+        eprintln!(
+            "❌ Config Error: {} problem(s) in the monitor's rules",
+            issues.len()
+        );
+        return Err(ApiError::Invalid(issues));
+    }
+
+    //  prepare Rules, against the children's ABI when there's nothing else to go by
+    let abi = contracts
+        .first()
+        .map(|contract| contract.abi.clone())
+        .or_else(|| factory.as_ref().map(|factory| factory.child_abi.clone()))
+        .unwrap_or_default();
+    let tx_rules = map_rules_to_abi(payload.functions.clone().unwrap_or_default(), &abi);
+    let event_names = payload.events.clone().unwrap_or_default();
+
+    // the primary endpoint first, then the fallbacks in the order they were given
+    let mut rpc_urls = vec![rpc_url.clone()];
+    rpc_urls.extend(payload.fallback_rpc_urls.clone().unwrap_or_default());
+
+    let pool = state
+        .provider_pools
+        .get_or_create(&payload.chain, &rpc_urls, payload.max_requests_per_second)
+        .map_err(|e| {
+            eprintln!("❌ RPC Error: {}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+
+    let mut monitor = PollingMonitor::new(pool, contracts)
+        .with_quorum(payload.quorum.unwrap_or_default())
+        .with_call_tracing(payload.trace_calls.unwrap_or_default())
+        .with_pending(pending)
+        .with_poll_interval(poll_interval_for(&payload.chain))
+        .with_fetch_concurrency(
+            payload
+                .fetch_concurrency
+                .unwrap_or(DEFAULT_FETCH_CONCURRENCY),
+            payload.batch_size.unwrap_or(1),
+        )
+        .with_confirmations(
+            payload.confirmations.unwrap_or_default(),
+            payload.block_target.unwrap_or_default(),
+        );
+
+    if let Some(factory) = factory {
+        monitor = monitor.with_factory(factory);
+    }
+
+    if let Some(transfers) = &payload.transfers {
+        let watch = TransferWatch::new(transfers).map_err(|e| {
+            eprintln!("❌ Config Error: {}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+        monitor = monitor.with_transfers(watch);
+    }
+
+    Ok(PreparedMonitor {
+        monitor,
+        tx_rules,
+        event_names,
+    })
+}
@@ -1,3 +1,7 @@
+use monitor::abi::AbiCache;
+use monitor::pool::ProviderPools;
+use monitor::primitives::models::MonitorConfig;
+use monitor::stream::BlockStreams;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
@@ -6,14 +10,26 @@ use tokio::task::JoinHandle;
 pub struct AppState {
     // maps a uuid to a task handler
     pub active_monitors: Arc<RwLock<HashMap<String, JoinHandle<()>>>>,
+    // the config each running monitor was created from, by the same uuid
+    pub monitor_configs: Arc<RwLock<HashMap<String, MonitorConfig>>>,
     pub default_rpc_url: String,
+    // one provider pool per chain and endpoint list, so health and rate limits are shared
+    pub provider_pools: Arc<ProviderPools>,
+    // one block ingestion service per (chain, rpc_url), shared by every monitor on it
+    pub block_streams: Arc<BlockStreams>,
+    // ABIs fetched from block explorers, shared by every monitor and kept across restarts
+    pub abi_cache: Arc<AbiCache>,
 }
 
 impl AppState {
     pub fn new(default_rpc: String) -> Self {
         Self {
             active_monitors: Arc::new(RwLock::new(HashMap::new())),
+            monitor_configs: Arc::new(RwLock::new(HashMap::new())),
             default_rpc_url: default_rpc,
+            provider_pools: Arc::new(ProviderPools::new()),
+            block_streams: Arc::new(BlockStreams::new()),
+            abi_cache: Arc::new(AbiCache::new()),
         }
     }
 }